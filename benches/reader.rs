@@ -0,0 +1,111 @@
+//! the connection reader, epoll against io_uring, with the client the `client`
+//! binary uses. `cargo bench --bench reader --features io-uring`, without the
+//! feature only epoll runs
+
+use broker::net::message::PayloadHeader;
+use broker::{BrokerClient, BrokerServer, ServerConfig};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+const MESSAGE_SIZE: usize = 1024;
+const CLIENTS: usize = 4;
+const MESSAGES_PER_CLIENT: u64 = 500_000;
+/// the best of these is reported, the first one warms up
+const ROUNDS: usize = 3;
+
+struct Run {
+    elapsed: Duration,
+    /// CPU the whole process spent in the kernel, clients included
+    system: Duration,
+}
+
+/// user and system CPU time of the process so far
+fn cpu_time() -> (Duration, Duration) {
+    // SAFETY: getrusage only writes the struct it is given
+    let usage = unsafe {
+        let mut usage = std::mem::zeroed::<libc::rusage>();
+        libc::getrusage(libc::RUSAGE_SELF, &mut usage);
+        usage
+    };
+    let duration = |tv: libc::timeval| {
+        Duration::from_secs(tv.tv_sec as u64) + Duration::from_micros(tv.tv_usec as u64)
+    };
+    (duration(usage.ru_utime), duration(usage.ru_stime))
+}
+
+async fn run(config: ServerConfig) -> Run {
+    let handle = BrokerServer::with_config(config).start().await.unwrap();
+    let addr = handle.local_addr();
+    let total = CLIENTS as u64 * MESSAGES_PER_CLIENT;
+
+    let (_, system_before) = cpu_time();
+    let start = Instant::now();
+    let clients: Vec<_> = (0..CLIENTS)
+        .map(|_| {
+            tokio::spawn(async move {
+                let mut client = BrokerClient::connect(addr).await.unwrap();
+                let mut data = vec![0u8; MESSAGE_SIZE];
+                for sequence in 0..MESSAGES_PER_CLIENT {
+                    let header = PayloadHeader::for_body(0, sequence, &data[PayloadHeader::LEN..]);
+                    data[..PayloadHeader::LEN].copy_from_slice(&header.to_le_bytes());
+                    client.send(&data).await.unwrap();
+                }
+                client.flush().await.unwrap();
+                client
+            })
+        })
+        .collect();
+    let mut connected = Vec::with_capacity(CLIENTS);
+    for client in clients {
+        connected.push(client.await.unwrap());
+    }
+    while handle.stats().messages_in < total {
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+    let elapsed = start.elapsed();
+    let (_, system_after) = cpu_time();
+
+    drop(connected);
+    handle.shutdown().await.unwrap();
+    Run {
+        elapsed,
+        system: system_after - system_before,
+    }
+}
+
+async fn bench(name: &str, config: impl Fn() -> ServerConfig) {
+    let mut best: Option<Run> = None;
+    for _ in 0..ROUNDS {
+        let run = run(config()).await;
+        if best.as_ref().is_none_or(|best| run.elapsed < best.elapsed) {
+            best = Some(run);
+        }
+    }
+    let best = best.unwrap();
+    let messages = (CLIENTS as u64 * MESSAGES_PER_CLIENT) as f64;
+    let secs = best.elapsed.as_secs_f64();
+    println!(
+        "{:<9} {:>14.0} msgs/s {:>8.2} GB/s {:>10.2?} {:>12.2?} system",
+        name,
+        messages / secs,
+        messages * MESSAGE_SIZE as f64 / secs / 1e9,
+        best.elapsed,
+        best.system,
+    );
+}
+
+#[tokio::main]
+async fn main() {
+    let localhost = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+    println!(
+        "{} clients, {} messages of {} bytes each",
+        CLIENTS, MESSAGES_PER_CLIENT, MESSAGE_SIZE
+    );
+    bench("epoll", || ServerConfig::bind(localhost)).await;
+    #[cfg(feature = "io-uring")]
+    bench("io_uring", || ServerConfig {
+        io_uring: Some(broker::UringConfig::default()),
+        ..ServerConfig::bind(localhost)
+    })
+    .await;
+}
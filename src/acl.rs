@@ -0,0 +1,338 @@
+use crate::config::AclConfig;
+use parking_lot::{Mutex, RwLock};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::watch;
+use tracing::warn;
+
+/// the principal of connections that didn't authenticate, for ACL rules
+pub const ANONYMOUS: &str = "anonymous";
+
+/// denials the audit log writer may fall behind by before they are dropped
+const AUDIT_QUEUE: usize = 4096;
+
+/// a denial repeated on the same connection within this long of the last one
+/// logged is counted instead of logged again
+const AUDIT_COALESCE: Duration = Duration::from_secs(1);
+
+/// connections whose last denial is remembered before expired ones are swept
+const AUDIT_RECENT: usize = 1024;
+
+/// what an ACL rule allows on a topic
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operation {
+    /// publish and declare
+    Publish,
+    Subscribe,
+}
+
+impl Operation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Operation::Publish => "publish",
+            Operation::Subscribe => "subscribe",
+        }
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Rule {
+    principal: String,
+    publish: bool,
+    subscribe: bool,
+    topic: String,
+}
+
+/// allow rules for topics, read from a file with one per line:
+///
+/// ```text
+/// # principal  operations         topic
+/// ingest       publish            orders.*
+/// analytics    subscribe          *
+/// *            publish,subscribe  scratch
+/// ```
+///
+/// operations are `publish`, `subscribe` or `all`. principals and topics may use
+/// `*` for any run of characters, connections without a principal are `anonymous`.
+/// anything no rule allows is denied
+#[derive(Debug, Default)]
+pub struct Acl {
+    rules: Vec<Rule>,
+}
+
+impl Acl {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)?;
+        Self::parse(&text)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+    }
+
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut rules = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |what: &str| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {}: {}", number + 1, what),
+                )
+            };
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let [principal, operations, topic] = fields[..] else {
+                return Err(invalid("expected a principal, operations and a topic"));
+            };
+            let mut rule = Rule {
+                principal: principal.to_string(),
+                publish: false,
+                subscribe: false,
+                topic: topic.to_string(),
+            };
+            for operation in operations.split(',') {
+                match operation {
+                    "publish" => rule.publish = true,
+                    "subscribe" => rule.subscribe = true,
+                    "all" => (rule.publish, rule.subscribe) = (true, true),
+                    other => return Err(invalid(&format!("unknown operation {:?}", other))),
+                }
+            }
+            rules.push(rule);
+        }
+        Ok(Self { rules })
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// whether a rule lets `principal` do `operation` on `topic`
+    pub fn allows(&self, principal: Option<&str>, operation: Operation, topic: &str) -> bool {
+        let principal = principal.unwrap_or(ANONYMOUS);
+        self.rules.iter().any(|rule| {
+            let allowed = match operation {
+                Operation::Publish => rule.publish,
+                Operation::Subscribe => rule.subscribe,
+            };
+            allowed && matches(&rule.principal, principal) && matches(&rule.topic, topic)
+        })
+    }
+}
+
+/// glob matching where `*` stands for any run of characters
+fn matches(pattern: &str, text: &str) -> bool {
+    let (pattern, text) = (pattern.as_bytes(), text.as_bytes());
+    let (mut p, mut t) = (0, 0);
+    // where the last `*` was, and how much of the text it covers so far
+    let mut star = None;
+    while t < text.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, t));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == text[t] {
+            p += 1;
+            t += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// an access the ACL turned down, as it is written to the audit log
+#[derive(Debug, Serialize)]
+pub(crate) struct Denial<'a> {
+    /// unix time in milliseconds
+    pub(crate) at: u64,
+    pub(crate) connection: u64,
+    pub(crate) peer: &'a str,
+    pub(crate) principal: Option<&'a str>,
+    pub(crate) operation: &'static str,
+    pub(crate) topic: &'a str,
+    /// identical denials left out of the log since the previous one
+    pub(crate) repeated: u64,
+}
+
+/// when a denial was last logged and how often it came again since
+struct Repeats {
+    logged: Instant,
+    repeated: u64,
+}
+
+/// the broker's ACL, swapped out whole when the file changes
+pub(crate) struct AccessControl {
+    path: PathBuf,
+    acl: RwLock<Acl>,
+    /// bumped on every reload, connections re-check what they cached or subscribed
+    /// to when it moves
+    generation: watch::Sender<u64>,
+    /// of the file the current rules came from
+    modified: Mutex<Option<SystemTime>>,
+    /// lines for the audit log, written on a thread of their own
+    audit: Option<SyncSender<Vec<u8>>>,
+    /// denials the audit queue had no room for
+    dropped: Arc<AtomicU64>,
+    /// by connection, operation and topic
+    recent: Mutex<HashMap<(u64, &'static str, String), Repeats>>,
+}
+
+impl AccessControl {
+    pub(crate) fn open(config: &AclConfig) -> io::Result<Self> {
+        let modified = modified(&config.path);
+        let acl = Acl::load(&config.path)?;
+        let dropped = Arc::new(AtomicU64::new(0));
+        let audit = match &config.audit_log {
+            Some(path) => {
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                let (lines, queued) = mpsc::sync_channel(AUDIT_QUEUE);
+                let dropped = dropped.clone();
+                thread::Builder::new()
+                    .name("broker-audit".into())
+                    .spawn(move || write_audit(file, queued, &dropped))?;
+                Some(lines)
+            }
+            None => None,
+        };
+        Ok(Self {
+            path: config.path.clone(),
+            acl: RwLock::new(acl),
+            generation: watch::Sender::new(1),
+            modified: Mutex::new(modified),
+            audit,
+            dropped,
+            recent: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.acl.read().len()
+    }
+
+    pub(crate) fn generation(&self) -> u64 {
+        *self.generation.borrow()
+    }
+
+    /// changes with every reload
+    pub(crate) fn reloads(&self) -> watch::Receiver<u64> {
+        self.generation.subscribe()
+    }
+
+    pub(crate) fn allows(
+        &self,
+        principal: Option<&str>,
+        operation: Operation,
+        topic: &str,
+    ) -> bool {
+        self.acl.read().allows(principal, operation, topic)
+    }
+
+    /// whether the file changed since the rules were last read
+    pub(crate) fn changed(&self) -> bool {
+        modified(&self.path) != *self.modified.lock()
+    }
+
+    /// read the file again. a file that doesn't parse leaves the current rules in
+    /// place, and isn't tried again until it changes once more
+    pub(crate) fn reload(&self) -> io::Result<usize> {
+        *self.modified.lock() = modified(&self.path);
+        let acl = Acl::load(&self.path)?;
+        let rules = acl.len();
+        *self.acl.write() = acl;
+        self.generation.send_modify(|generation| *generation += 1);
+        Ok(rules)
+    }
+
+    /// log a denial to the `broker::audit` target and the audit log file, unless
+    /// the connection was denied the same within `AUDIT_COALESCE`
+    pub(crate) fn audit(&self, mut denial: Denial<'_>) {
+        let key = (
+            denial.connection,
+            denial.operation,
+            denial.topic.to_string(),
+        );
+        let now = Instant::now();
+        let mut recent = self.recent.lock();
+        if let Some(repeats) = recent.get_mut(&key) {
+            if now.duration_since(repeats.logged) < AUDIT_COALESCE {
+                repeats.repeated += 1;
+                return;
+            }
+            denial.repeated = repeats.repeated;
+        }
+        if recent.len() >= AUDIT_RECENT {
+            recent.retain(|_, repeats| now.duration_since(repeats.logged) < AUDIT_COALESCE);
+        }
+        recent.insert(
+            key,
+            Repeats {
+                logged: now,
+                repeated: 0,
+            },
+        );
+        drop(recent);
+
+        warn!(
+            target: "broker::audit",
+            connection = denial.connection,
+            peer = denial.peer,
+            principal = denial.principal.unwrap_or(ANONYMOUS),
+            operation = denial.operation,
+            topic = denial.topic,
+            repeated = denial.repeated,
+            "access denied"
+        );
+        let Some(lines) = &self.audit else {
+            return;
+        };
+        let mut line = serde_json::to_vec(&denial).expect("denials serialize");
+        line.push(b'\n');
+        if let Err(TrySendError::Full(_)) = lines.try_send(line) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// append queued lines to the audit log until the ACL goes away
+fn write_audit(mut file: File, queued: mpsc::Receiver<Vec<u8>>, dropped: &AtomicU64) {
+    for line in queued {
+        if let Err(e) = file.write_all(&line) {
+            warn!(error = %e, "writing the audit log failed");
+        }
+        let lost = dropped.swap(0, Ordering::Relaxed);
+        if lost > 0 {
+            warn!(
+                denials = lost,
+                "the audit log fell behind, denials were left out"
+            );
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
@@ -0,0 +1,163 @@
+use crate::net::codec::Mechanism;
+use bytes::Bytes;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use subtle::ConstantTimeEq;
+
+/// bytes of the nonce in a `Frame::Challenge`
+pub const NONCE_LEN: usize = 32;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// what a client authenticates with, see `ClientOptions::credential`
+#[derive(Clone, PartialEq, Eq)]
+pub enum Credential {
+    /// sent as is, only use it over trusted networks or TLS
+    Token { principal: String, token: String },
+    /// proves knowledge of `secret` without sending it
+    Hmac { principal: String, secret: String },
+}
+
+impl Credential {
+    pub fn principal(&self) -> &str {
+        match self {
+            Credential::Token { principal, .. } | Credential::Hmac { principal, .. } => principal,
+        }
+    }
+
+    pub fn mechanism(&self) -> Mechanism {
+        match self {
+            Credential::Token { .. } => Mechanism::Token,
+            Credential::Hmac { .. } => Mechanism::Hmac,
+        }
+    }
+}
+
+impl std::fmt::Debug for Credential {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // keeps secrets out of logs
+        f.debug_struct("Credential")
+            .field("principal", &self.principal())
+            .field("mechanism", &self.mechanism())
+            .finish_non_exhaustive()
+    }
+}
+
+/// the principals a broker accepts, read from a file with one per line:
+///
+/// ```text
+/// # principal  mechanism  secret
+/// ingest-1     token      4f1c...
+/// analytics    hmac       9a0e...
+/// ```
+///
+/// the secret is the rest of the line, blank lines and `#` comments are skipped
+#[derive(Default)]
+pub struct Credentials {
+    principals: HashMap<String, (Mechanism, String)>,
+}
+
+impl Credentials {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)?;
+        Self::parse(&text)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+    }
+
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut principals = HashMap::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |what: &str| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {}: {}", number + 1, what),
+                )
+            };
+            let (principal, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let rest = rest.trim_start();
+            let (mechanism, secret) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+            let mechanism = match mechanism {
+                "token" => Mechanism::Token,
+                "hmac" => Mechanism::Hmac,
+                "" => return Err(invalid("missing mechanism")),
+                other => return Err(invalid(&format!("unknown mechanism {:?}", other))),
+            };
+            let secret = secret.trim();
+            if secret.is_empty() {
+                return Err(invalid("missing secret"));
+            }
+            if principal.len() > u8::MAX as usize {
+                return Err(invalid("principal longer than 255 bytes"));
+            }
+            if principals
+                .insert(principal.to_string(), (mechanism, secret.to_string()))
+                .is_some()
+            {
+                return Err(invalid(&format!("{} is listed twice", principal)));
+            }
+        }
+        Ok(Self { principals })
+    }
+
+    pub fn len(&self) -> usize {
+        self.principals.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.principals.is_empty()
+    }
+
+    /// how `principal` has to authenticate, `None` if it is unknown
+    pub fn mechanism(&self, principal: &str) -> Option<Mechanism> {
+        self.principals
+            .get(principal)
+            .map(|(mechanism, _)| *mechanism)
+    }
+
+    /// whether `proof` authenticates `principal` with `mechanism`. for HMAC the
+    /// proof is checked against `nonce`
+    pub(crate) fn verify(
+        &self,
+        principal: &str,
+        mechanism: Mechanism,
+        nonce: &[u8],
+        proof: &[u8],
+    ) -> bool {
+        match self.principals.get(principal) {
+            Some((Mechanism::Token, token)) if mechanism == Mechanism::Token => {
+                token.as_bytes().ct_eq(proof).into()
+            }
+            Some((Mechanism::Hmac, secret)) if mechanism == Mechanism::Hmac => {
+                let mut mac = HmacSha256::new_from_slice(secret.as_bytes())
+                    .expect("HMAC takes keys of any length");
+                mac.update(nonce);
+                mac.verify_slice(proof).is_ok()
+            }
+            _ => false,
+        }
+    }
+}
+
+/// a fresh challenge
+pub(crate) fn nonce() -> io::Result<Bytes> {
+    let mut nonce = [0; NONCE_LEN];
+    getrandom::getrandom(&mut nonce).map_err(io::Error::other)?;
+    Ok(Bytes::copy_from_slice(&nonce))
+}
+
+/// the proof for a `Mechanism::Hmac` challenge
+pub fn sign(secret: &str, nonce: &[u8]) -> Vec<u8> {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(nonce);
+    mac.finalize().into_bytes().to_vec()
+}
@@ -0,0 +1,220 @@
+use broker::DEFAULT_ADMIN_PORT;
+use serde_json::Value;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const USAGE: &str = "usage: broker-admin [--admin ADDR] [--token-file PATH] COMMAND
+
+  connections         list open connections with their counters
+  topics              list topics with ring occupancy and consumer state
+  disconnect ID       close connection ID
+  pause TOPIC         hold off TOPIC's consumer, publishers keep filling the ring
+  resume TOPIC        let TOPIC's consumer continue
+  drain               stop accepting, deliver what is in the rings and exit
+
+      --admin ADDR    the broker's admin listener, default 127.0.0.1:7879
+      --token-file PATH  send the token in PATH, for a broker started with
+                      --admin-token-file
+  -h, --help          print this help";
+
+enum Command {
+    Connections,
+    Topics,
+    Disconnect(u64),
+    Pause(String),
+    Resume(String),
+    Drain,
+}
+
+struct Args {
+    admin: String,
+    token: Option<String>,
+    command: Command,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = std::env::args().skip(1);
+    let mut admin = format!("127.0.0.1:{}", DEFAULT_ADMIN_PORT);
+    let mut token = None;
+    let mut command = None;
+    while let Some(arg) = args.next() {
+        let parsed = match arg.as_str() {
+            "--admin" => {
+                admin = args.next().ok_or("--admin needs an address")?;
+                continue;
+            }
+            "--token-file" => {
+                let path = args.next().ok_or("--token-file needs a path")?;
+                let read = std::fs::read_to_string(&path)
+                    .map_err(|e| format!("can't read the token from {}: {}", path, e))?;
+                token = Some(read.trim().to_string());
+                continue;
+            }
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            "connections" => Command::Connections,
+            "topics" => Command::Topics,
+            "disconnect" => {
+                let id = args.next().ok_or("disconnect needs a connection id")?;
+                Command::Disconnect(
+                    id.parse()
+                        .map_err(|_| format!("invalid connection id {}", id))?,
+                )
+            }
+            "pause" => Command::Pause(args.next().ok_or("pause needs a topic")?),
+            "resume" => Command::Resume(args.next().ok_or("resume needs a topic")?),
+            "drain" => Command::Drain,
+            other => return Err(format!("unknown argument {}", other)),
+        };
+        if command.replace(parsed).is_some() {
+            return Err("one command at a time".to_string());
+        }
+    }
+    Ok(Args {
+        admin,
+        token,
+        command: command.ok_or("missing command")?,
+    })
+}
+
+/// one request over a fresh connection. returns the status code and the JSON body
+async fn request(
+    admin: &str,
+    token: Option<&str>,
+    method: &str,
+    path: &str,
+) -> Result<(u16, Value), String> {
+    let mut socket = TcpStream::connect(admin)
+        .await
+        .map_err(|e| format!("can't reach the admin listener at {}: {}", admin, e))?;
+    let authorization = token
+        .map(|token| format!("Authorization: Bearer {}\r\n", token))
+        .unwrap_or_default();
+    let head = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\n{}Content-Length: 0\r\nConnection: close\r\n\r\n",
+        method, path, admin, authorization
+    );
+    let mut response = Vec::new();
+    let io = async {
+        socket.write_all(head.as_bytes()).await?;
+        socket.read_to_end(&mut response).await
+    };
+    io.await
+        .map_err(|e| format!("admin request failed: {}", e))?;
+
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or("malformed admin response")?;
+    let status = head
+        .split(' ')
+        .nth(1)
+        .and_then(|s| s.parse().ok())
+        .ok_or("malformed admin response")?;
+    let body =
+        serde_json::from_str(body).map_err(|e| format!("malformed admin response: {}", e))?;
+    Ok((status, body))
+}
+
+fn field(value: &Value, name: &str) -> String {
+    match &value[name] {
+        Value::String(s) => s.clone(),
+        Value::Null => "-".to_string(),
+        other => other.to_string(),
+    }
+}
+
+/// print `rows` as aligned columns
+fn table(columns: &[&str], rows: &[Value]) {
+    let cells: Vec<Vec<String>> = rows
+        .iter()
+        .map(|row| columns.iter().map(|c| field(row, c)).collect())
+        .collect();
+    let widths: Vec<usize> = columns
+        .iter()
+        .enumerate()
+        .map(|(i, c)| cells.iter().map(|r| r[i].len()).fold(c.len(), usize::max))
+        .collect();
+    let line = |values: Vec<&str>| {
+        let padded: Vec<String> = values
+            .iter()
+            .zip(&widths)
+            .map(|(v, w)| format!("{:<w$}", v, w = *w))
+            .collect();
+        println!("{}", padded.join("  ").trim_end());
+    };
+    line(columns.to_vec());
+    for row in &cells {
+        line(row.iter().map(String::as_str).collect());
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    let (method, path) = match &args.command {
+        Command::Connections => ("GET", "/connections".to_string()),
+        Command::Topics => ("GET", "/topics".to_string()),
+        Command::Disconnect(id) => ("DELETE", format!("/connections/{}", id)),
+        Command::Pause(topic) => ("POST", format!("/topics/{}/pause", topic)),
+        Command::Resume(topic) => ("POST", format!("/topics/{}/resume", topic)),
+        Command::Drain => ("POST", "/drain".to_string()),
+    };
+    let (status, body) = match request(&args.admin, args.token.as_deref(), method, &path).await {
+        Ok(response) => response,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    if !(200..300).contains(&status) {
+        eprintln!("{}", field(&body, "error"));
+        std::process::exit(1);
+    }
+
+    let rows = body.as_array().map(Vec::as_slice).unwrap_or_default();
+    match args.command {
+        Command::Connections => table(
+            &[
+                "id",
+                "peer",
+                "principal",
+                "connected_at",
+                "frames_in",
+                "messages_in",
+                "bytes_in",
+                "quota_hits",
+            ],
+            rows,
+        ),
+        Command::Topics => table(
+            &[
+                "id",
+                "name",
+                "capacity",
+                "used",
+                "head",
+                "tail",
+                "stalls",
+                "drops",
+                "quota_hits",
+                "consumer",
+                "paused",
+            ],
+            rows,
+        ),
+        Command::Disconnect(id) => println!("disconnected connection {}", id),
+        Command::Pause(topic) => println!("paused {}", topic),
+        Command::Resume(topic) => println!("resumed {}", topic),
+        Command::Drain => println!("draining"),
+    }
+}
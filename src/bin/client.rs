@@ -1,15 +1,19 @@
+use broker::net::message::PayloadHeader;
 use broker::BrokerClient;
-use std::collections::hash_map::DefaultHasher;
-use std::hash::Hasher;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::Instant;
 use tokio::pin;
 use tokio::signal;
+use tracing_subscriber::EnvFilter;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     const MESSAGE_SIZE: usize = 1024;
+    // library diagnostics are off unless asked for with RUST_LOG
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .init();
 
     println!("Starting client. Press Ctrl+C to stop.");
     println!("test_start msg_bytes:{}", MESSAGE_SIZE);
@@ -45,24 +49,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
             // Prepare and send the message
             result = async {
-                // Add timestamp
+                // Add timestamp, sequence number and checksum
                 let now = std::time::SystemTime::now()
                     .duration_since(std::time::UNIX_EPOCH)
                     .unwrap()
                     .as_nanos() as u64;
-                data[0..8].copy_from_slice(&now.to_le_bytes());
-
-                // Add sequence number
-                let sequence = i;
-                data[8..16].copy_from_slice(&sequence.to_le_bytes());
-
-                // Calculate checksum
-                let mut hasher = DefaultHasher::new();
-                hasher.write_u64(now);
-                hasher.write_u64(sequence);
-                hasher.write(&data[20..]);
-                let checksum = hasher.finish() as u32;
-                data[16..20].copy_from_slice(&checksum.to_le_bytes());
+                let header = PayloadHeader::for_body(now, i, &data[PayloadHeader::LEN..]);
+                data[..PayloadHeader::LEN].copy_from_slice(&header.to_le_bytes());
 
                 // Send the message
                 client.send(&data).await
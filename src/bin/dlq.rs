@@ -0,0 +1,145 @@
+use broker::dead_letter::{DeadLetter, DeadLetterReader};
+use broker::BrokerClient;
+use std::path::PathBuf;
+
+const USAGE: &str = "usage: dlq inspect PATH [--payload]
+       dlq replay PATH (--to ADDR | --unix PATH) [--topic NAME]
+
+  inspect             print the records of a dead letter log, oldest first
+      --payload       dump every payload in hex instead of its first bytes
+  replay              publish the payloads again, each to the topic it was
+                      rejected on
+      --to ADDR       broker to publish to, e.g. 127.0.0.1:7878
+      --unix PATH     broker unix socket to publish to
+      --topic NAME    publish everything to NAME instead
+  -h, --help          print this help
+
+a full log is moved to PATH.1, inspect or replay that one for older records";
+
+/// bytes of the payload `inspect` shows without --payload
+const PREVIEW: usize = 32;
+
+enum Command {
+    Inspect {
+        path: PathBuf,
+        payload: bool,
+    },
+    Replay {
+        path: PathBuf,
+        to: Target,
+        topic: Option<String>,
+    },
+}
+
+enum Target {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+fn parse_args() -> Result<Command, String> {
+    let mut args = std::env::args().skip(1);
+    let command = args.next().ok_or("missing command")?;
+    if command == "-h" || command == "--help" {
+        println!("{}", USAGE);
+        std::process::exit(0);
+    }
+    let path = PathBuf::from(args.next().ok_or("missing dead letter log path")?);
+
+    let mut payload = false;
+    let mut to = None;
+    let mut topic = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--payload" => payload = true,
+            "--to" => to = Some(Target::Tcp(args.next().ok_or("--to needs an address")?)),
+            "--unix" => {
+                to = Some(Target::Unix(
+                    args.next().ok_or("--unix needs a path")?.into(),
+                ))
+            }
+            "--topic" => topic = Some(args.next().ok_or("--topic needs a name")?),
+            other => return Err(format!("unknown argument {}", other)),
+        }
+    }
+
+    match command.as_str() {
+        "inspect" => Ok(Command::Inspect { path, payload }),
+        "replay" => Ok(Command::Replay {
+            path,
+            to: to.ok_or("replay needs --to or --unix")?,
+            topic,
+        }),
+        other => Err(format!("unknown command {}", other)),
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn print_letter(letter: &DeadLetter, payload: bool) {
+    let from = match (&letter.connection, &letter.peer) {
+        (Some(connection), Some(peer)) => format!("connection {} ({})", connection, peer),
+        _ => "unknown publisher".to_string(),
+    };
+    println!(
+        "{} {}@{} from {}: {}, {} bytes",
+        letter.captured_at,
+        letter.topic,
+        letter.offset,
+        from,
+        letter.reason,
+        letter.payload.len()
+    );
+    if payload {
+        for chunk in letter.payload.chunks(32) {
+            println!("    {}", hex(chunk));
+        }
+    } else {
+        let preview = &letter.payload[..letter.payload.len().min(PREVIEW)];
+        let more = if letter.payload.len() > PREVIEW {
+            "..."
+        } else {
+            ""
+        };
+        println!("    {}{}", hex(preview), more);
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let command = match parse_args() {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    match command {
+        Command::Inspect { path, payload } => {
+            let mut count = 0;
+            for letter in DeadLetterReader::open(&path)? {
+                print_letter(&letter?, payload);
+                count += 1;
+            }
+            println!("{} dead letters in {}", count, path.display());
+        }
+        Command::Replay { path, to, topic } => {
+            let mut client = match to {
+                Target::Tcp(addr) => BrokerClient::connect(addr).await?,
+                Target::Unix(path) => BrokerClient::connect_unix(path).await?,
+            };
+            let mut count = 0;
+            for letter in DeadLetterReader::open(&path)? {
+                let letter = letter?;
+                let topic = topic.as_deref().unwrap_or(&letter.topic);
+                client.send_to(topic, &letter.payload).await?;
+                count += 1;
+            }
+            client.flush().await?;
+            println!("replayed {} dead letters from {}", count, path.display());
+        }
+    }
+    Ok(())
+}
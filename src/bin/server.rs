@@ -1,9 +1,381 @@
-use broker::BrokerServer;
+use broker::net::tls;
+#[cfg(feature = "io-uring")]
+use broker::UringConfig;
+use broker::{
+    AclConfig, AuthConfig, BrokerServer, DeadLetterConfig, ExpiredAction, Keepalive, Quota,
+    QuotaAction, ServerConfig, ShardConfig, Staleness, UnixListenConfig, DEFAULT_SOCKET_MODE,
+};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{info, warn};
+use tracing_subscriber::EnvFilter;
+
+const USAGE: &str = "usage: server [--listen ADDR]... [--unix PATH]... [--unix-mode MODE]
+              [--dead-letters PATH] [--reject-duplicates]
+              [--ttl MILLIS [--expired drop|dead-letter|deliver]]
+              [--heartbeat SECS] [--idle-timeout SECS] [--keepalive SECS]
+              [--connection-memory MIB] [--memory-limit MIB] [--ring-memory MIB]
+              [--client-msgs N] [--client-bytes N] [--topic-msgs N] [--topic-bytes N]
+              [--quota-action delay|reject] [--auth PATH]
+              [--acl PATH [--audit-log PATH]]
+              [--tls-cert PATH --tls-key PATH [--tls-client-ca PATH]]
+              [--io-uring] [--shards N [--no-pin]]
+              [--admin ADDR [--admin-token-file PATH]] [--log FILTER] [--log-json]
+
+  -l, --listen ADDR   accept connections on ADDR, e.g. 127.0.0.1:7878 or [::1]:0.
+                      repeat for more listeners. defaults to 0.0.0.0:7878 unless
+                      only unix sockets are given
+  -u, --unix PATH     accept connections on a unix socket at PATH, repeatable
+      --unix-mode MODE  octal permissions of the unix sockets, default 660
+      --dead-letters PATH  append records that fail validation to PATH,
+                      see the dlq tool
+      --reject-duplicates  drop messages whose sequence number their
+                      producer already published. a producer is a principal,
+                      or a connection without --auth
+      --ttl MILLIS    expire messages older than this when consumed
+      --expired ACTION  what happens to expired messages, default drop
+      --heartbeat SECS  send clients a heartbeat frame this often
+      --idle-timeout SECS  close connections that sent nothing for this long
+      --keepalive SECS  probe TCP connections quiet for this long
+      --connection-memory MIB  most one connection may buffer, default 16
+      --memory-limit MIB  most all connections may buffer together, new
+                      connections are refused beyond it. default 1024
+      --ring-memory MIB  most the rings of all topics may take together, new
+                      topics are refused beyond it. default 4096
+      --client-msgs N  messages per second each client may publish, all its
+                      connections together
+      --client-bytes N  message bytes per second each client may publish
+      --topic-msgs N  messages per second each topic takes, all producers together
+      --topic-bytes N  message bytes per second each topic takes
+      --quota-action ACTION  delay reading from clients over quota, or reject
+                      their publishes with an error. default delay
+      --auth PATH     clients authenticate with a token or HMAC secret listed
+                      in PATH, one `principal token|hmac secret` per line
+      --acl PATH      only allow the publishes and subscribes PATH lists, one
+                      `principal publish|subscribe|all topic` per line. the
+                      file is reloaded when it changes and on SIGHUP
+      --audit-log PATH  append ACL denials to PATH as JSON lines
+      --tls-cert PATH  serve TLS on the TCP listeners with the PEM certificate
+                      chain in PATH
+      --tls-key PATH  the PEM private key of --tls-cert
+      --tls-client-ca PATH  require client certificates issued by the PEM CA
+                      certificates in PATH. their subject names the principal
+      --io-uring      read plaintext connections through io_uring instead of
+                      epoll. needs a build with the io-uring feature
+      --shards N      accept TCP connections on N threads with a runtime and
+                      rings of their own, sharing the port with SO_REUSEPORT.
+                      0 starts one per core
+      --no-pin        don't pin the shard threads to cores
+      --admin ADDR    serve Prometheus metrics at http://ADDR/metrics and
+                      the control API the broker-admin tool talks to
+      --admin-token-file PATH  require the token in PATH as a bearer token on
+                      admin requests. needed for an --admin address that
+                      isn't loopback
+      --log FILTER    log levels, e.g. debug or info,broker::consumer=trace.
+                      defaults to RUST_LOG, or info without it
+      --log-json      log one JSON object per line
+  -h, --help          print this help";
+
+struct Args {
+    config: ServerConfig,
+    log: Option<String>,
+    log_json: bool,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut config = ServerConfig::default();
+    let mut log = None;
+    let mut log_json = false;
+    let mut listen = Vec::new();
+    let mut unix_listen = Vec::new();
+    let mut unix_mode = DEFAULT_SOCKET_MODE;
+    let mut expired = ExpiredAction::default();
+    let mut client_quota = Quota::default();
+    let mut topic_quota = Quota::default();
+    let mut acl: Option<AclConfig> = None;
+    let mut audit_log = None;
+    let mut tls_cert = None;
+    let mut tls_key = None;
+    let mut tls_client_ca: Option<PathBuf> = None;
+    let mut shards: Option<ShardConfig> = None;
+    let mut pin_cores = true;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-l" | "--listen" => {
+                let addr = args.next().ok_or("--listen needs an address")?;
+                let addr: SocketAddr = addr
+                    .parse()
+                    .map_err(|e| format!("invalid listen address {}: {}", addr, e))?;
+                listen.push(addr);
+            }
+            "-u" | "--unix" => {
+                let path = args.next().ok_or("--unix needs a path")?;
+                unix_listen.push(UnixListenConfig::new(path));
+            }
+            "--unix-mode" => {
+                let mode = args.next().ok_or("--unix-mode needs a mode")?;
+                unix_mode = u32::from_str_radix(&mode, 8)
+                    .map_err(|e| format!("invalid unix socket mode {}: {}", mode, e))?;
+            }
+            "--dead-letters" => {
+                let path = args.next().ok_or("--dead-letters needs a path")?;
+                config.dead_letters = Some(DeadLetterConfig::new(path));
+            }
+            "--auth" => {
+                let path = args.next().ok_or("--auth needs a path")?;
+                config.auth = Some(AuthConfig::new(path));
+            }
+            "--acl" => acl = Some(AclConfig::new(args.next().ok_or("--acl needs a path")?)),
+            "--audit-log" => audit_log = Some(args.next().ok_or("--audit-log needs a path")?),
+            "--tls-cert" => tls_cert = Some(args.next().ok_or("--tls-cert needs a path")?),
+            "--tls-key" => tls_key = Some(args.next().ok_or("--tls-key needs a path")?),
+            "--tls-client-ca" => {
+                tls_client_ca = Some(args.next().ok_or("--tls-client-ca needs a path")?.into())
+            }
+            #[cfg(feature = "io-uring")]
+            "--io-uring" => config.io_uring = Some(UringConfig::default()),
+            #[cfg(not(feature = "io-uring"))]
+            "--io-uring" => return Err("built without the io-uring feature".to_string()),
+            "--shards" => {
+                let count = args.next().ok_or("--shards needs a count")?;
+                shards = match count.parse() {
+                    Ok(0) => Some(ShardConfig::default()),
+                    Ok(count) => Some(ShardConfig::new(count)),
+                    Err(e) => return Err(format!("invalid shard count {}: {}", count, e)),
+                };
+            }
+            "--no-pin" => pin_cores = false,
+            "--reject-duplicates" => config.reject_duplicates = true,
+            "--ttl" => {
+                let ttl = args.next().ok_or("--ttl needs milliseconds")?;
+                let ttl = ttl
+                    .parse()
+                    .map_err(|e| format!("invalid ttl {}: {}", ttl, e))?;
+                config.topic_defaults.staleness = Some(Staleness::new(Duration::from_millis(ttl)));
+            }
+            "--heartbeat" => config.heartbeat_interval = Some(seconds(args.next(), "--heartbeat")?),
+            "--idle-timeout" => config.idle_timeout = Some(seconds(args.next(), "--idle-timeout")?),
+            "--keepalive" => {
+                config.keepalive = Some(Keepalive {
+                    idle: seconds(args.next(), "--keepalive")?,
+                    ..Keepalive::default()
+                })
+            }
+            "--expired" => {
+                expired = match args.next().as_deref() {
+                    Some("drop") => ExpiredAction::Drop,
+                    Some("dead-letter") => ExpiredAction::DeadLetter,
+                    Some("deliver") => ExpiredAction::Deliver,
+                    _ => return Err("--expired needs drop, dead-letter or deliver".to_string()),
+                };
+            }
+            "--connection-memory" => {
+                config.connection_memory = mebibytes(args.next(), "--connection-memory")?
+            }
+            "--memory-limit" => config.memory_limit = mebibytes(args.next(), "--memory-limit")?,
+            "--ring-memory" => config.ring_memory = mebibytes(args.next(), "--ring-memory")?,
+            "--client-msgs" => {
+                client_quota.messages_per_sec = Some(rate(args.next(), "--client-msgs")?)
+            }
+            "--client-bytes" => {
+                client_quota.bytes_per_sec = Some(rate(args.next(), "--client-bytes")?)
+            }
+            "--topic-msgs" => {
+                topic_quota.messages_per_sec = Some(rate(args.next(), "--topic-msgs")?)
+            }
+            "--topic-bytes" => {
+                topic_quota.bytes_per_sec = Some(rate(args.next(), "--topic-bytes")?)
+            }
+            "--quota-action" => {
+                config.quota_action = match args.next().as_deref() {
+                    Some("delay") => QuotaAction::Delay,
+                    Some("reject") => QuotaAction::Reject,
+                    _ => return Err("--quota-action needs delay or reject".to_string()),
+                };
+            }
+            "--admin" => {
+                let addr = args.next().ok_or("--admin needs an address")?;
+                let addr = addr
+                    .parse()
+                    .map_err(|e| format!("invalid admin address {}: {}", addr, e))?;
+                config.admin = Some(addr);
+            }
+            "--admin-token-file" => {
+                let path = args.next().ok_or("--admin-token-file needs a path")?;
+                config.admin_token = Some(read_token(&path)?);
+            }
+            "--log" => log = Some(args.next().ok_or("--log needs a filter")?),
+            "--log-json" => log_json = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            other => return Err(format!("unknown argument {}", other)),
+        }
+    }
+
+    if !listen.is_empty() || !unix_listen.is_empty() {
+        config.listen = listen;
+    }
+    if client_quota != Quota::default() {
+        config.client_quota = Some(client_quota);
+    }
+    if topic_quota != Quota::default() {
+        config.topic_defaults.quota = Some(topic_quota);
+    }
+    if let Some(staleness) = &mut config.topic_defaults.staleness {
+        staleness.action = expired;
+    }
+    match (tls_cert, tls_key) {
+        (Some(cert), Some(key)) => {
+            let tls = tls::server_config(&cert, &key, tls_client_ca.as_deref())
+                .map_err(|e| format!("invalid TLS setup: {}", e))?;
+            config.tls = Some(tls);
+        }
+        (None, None) if tls_client_ca.is_none() => {}
+        _ => return Err("TLS needs both --tls-cert and --tls-key".to_string()),
+    }
+    match (acl, audit_log) {
+        (Some(acl), audit_log) => {
+            config.acl = Some(AclConfig {
+                audit_log: audit_log.map(PathBuf::from),
+                ..acl
+            })
+        }
+        (None, Some(_)) => return Err("--audit-log needs --acl".to_string()),
+        (None, None) => {}
+    }
+    match shards {
+        Some(shards) => {
+            config.shards = Some(ShardConfig {
+                pin_cores,
+                ..shards
+            })
+        }
+        None if !pin_cores => return Err("--no-pin needs --shards".to_string()),
+        None => {}
+    }
+    for unix in &mut unix_listen {
+        unix.mode = unix_mode;
+    }
+    config.unix_listen = unix_listen;
+    Ok(Args {
+        config,
+        log,
+        log_json,
+    })
+}
+
+/// a positive number of seconds for `flag`
+fn seconds(value: Option<String>, flag: &str) -> Result<Duration, String> {
+    let value = value.ok_or(format!("{} needs seconds", flag))?;
+    match value.parse() {
+        Ok(secs) if secs > 0 => Ok(Duration::from_secs(secs)),
+        _ => Err(format!("invalid {} {}", flag, value)),
+    }
+}
+
+/// a positive rate per second for `flag`
+fn rate(value: Option<String>, flag: &str) -> Result<u64, String> {
+    let value = value.ok_or(format!("{} needs a rate per second", flag))?;
+    match value.parse() {
+        Ok(rate) if rate > 0 => Ok(rate),
+        _ => Err(format!("invalid {} {}", flag, value)),
+    }
+}
+
+/// a positive number of MiB for `flag`, in bytes
+fn mebibytes(value: Option<String>, flag: &str) -> Result<usize, String> {
+    let value = value.ok_or(format!("{} needs MiB", flag))?;
+    match value.parse::<usize>() {
+        Ok(mib) if mib > 0 => mib
+            .checked_mul(1024 * 1024)
+            .ok_or(format!("{} {} is too large", flag, value)),
+        _ => Err(format!("invalid {} {}", flag, value)),
+    }
+}
+
+/// the admin token in `path`, without surrounding whitespace
+fn read_token(path: &str) -> Result<String, String> {
+    let token = std::fs::read_to_string(path)
+        .map_err(|e| format!("can't read the admin token from {}: {}", path, e))?;
+    match token.trim() {
+        "" => Err(format!("the admin token in {} is empty", path)),
+        token => Ok(token.to_string()),
+    }
+}
+
+fn init_logging(filter: Option<&str>, json: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let filter = match filter {
+        Some(filter) => EnvFilter::try_new(filter)?,
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+    };
+    let logger = tracing_subscriber::fmt().with_env_filter(filter);
+    if json {
+        logger.json().init();
+    } else {
+        logger.init();
+    }
+    Ok(())
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let mut server = BrokerServer::new(7878);
-    println!("starting server");
-    server.run().await?;
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+    init_logging(args.log.as_deref(), args.log_json)?;
+
+    let drain_timeout = args.config.drain_timeout;
+    let server = BrokerServer::with_config(args.config);
+    let mut handle = server.start().await?;
+
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut hangup = signal(SignalKind::hangup())?;
+    loop {
+        tokio::select! {
+            result = handle.wait() => {
+                result?;
+                info!("drain requested through the admin API");
+            }
+            _ = interrupt.recv() => info!("SIGINT received, draining"),
+            _ = terminate.recv() => info!("SIGTERM received, draining"),
+            _ = hangup.recv() => {
+                // reload_acl logs the outcome when there is an ACL to reload
+                if let Err(e) = handle.reload_acl() {
+                    warn!(error = %e, "SIGHUP received, ACL not reloaded");
+                }
+                continue;
+            }
+        }
+        break;
+    }
+
+    let report = handle.shutdown_with_drain(drain_timeout).await?;
+    let stats = report.stats;
+    info!(
+        connections = stats.connections,
+        frames = stats.frames_in,
+        published = stats.messages_in,
+        bytes = stats.bytes_in,
+        consumed = stats.messages_consumed,
+        processed = stats.messages_processed,
+        errors = stats.processing_errors,
+        dead_letters = stats.dead_letters,
+        expired = stats.messages_expired,
+        idle_timeouts = stats.idle_timeouts,
+        undrained = report.undrained,
+        timed_out = report.timed_out,
+        "server stopped"
+    );
     Ok(())
 }
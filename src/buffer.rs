@@ -2,9 +2,14 @@ use std::alloc::{self, Layout};
 use std::ptr;
 use std::sync::atomic::{AtomicU64, Ordering};
 
+use bytes::BytesMut;
+
 use crate::error::BrokerError;
 use crate::{CACHE_LINE_SIZE, RING_BUFFER_SIZE};
 
+/// smallest ring that still fits a reasonable message (rings cap messages at a quarter of capacity)
+pub const MIN_RING_CAPACITY: usize = 4096;
+
 #[repr(C, align(64))]
 pub struct RingBuffer {
     data: *mut u8,
@@ -19,26 +24,55 @@ unsafe impl Sync for RingBuffer {}
 
 impl RingBuffer {
     pub fn new() -> Result<Self, BrokerError> {
-        let layout = Layout::from_size_align(RING_BUFFER_SIZE, CACHE_LINE_SIZE)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        Self::with_capacity(RING_BUFFER_SIZE)
+    }
+
+    /// `capacity` must be a power of two, and is the hard memory limit of the ring
+    pub fn with_capacity(capacity: usize) -> Result<Self, BrokerError> {
+        if !capacity.is_power_of_two() {
+            return Err(BrokerError::InvalidCapacity(capacity));
+        }
+        if capacity < MIN_RING_CAPACITY {
+            return Err(BrokerError::BufferTooSmall);
+        }
+
+        let layout =
+            Layout::from_size_align(capacity, CACHE_LINE_SIZE).map_err(std::io::Error::other)?;
 
         let data = unsafe { alloc::alloc_zeroed(layout) };
         if data.is_null() {
-            return Err(BrokerError::SystemError(std::io::Error::new(
-                std::io::ErrorKind::Other,
+            return Err(BrokerError::SystemError(std::io::Error::other(
                 "Memory allocation failed",
             )));
         }
 
         Ok(RingBuffer {
             data,
-            mask: RING_BUFFER_SIZE - 1,
+            mask: capacity - 1,
             producer_index: AtomicU64::new(0),
             consumer_index: AtomicU64::new(0),
             _pad: [0; CACHE_LINE_SIZE - 32],
         })
     }
 
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.mask + 1
+    }
+
+    /// bytes written but not yet read
+    #[inline]
+    pub fn len(&self) -> usize {
+        let producer_index = self.producer_index.load(Ordering::Acquire);
+        let consumer_index = self.consumer_index.load(Ordering::Acquire);
+        producer_index.wrapping_sub(consumer_index) as usize
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     #[inline(always)]
     pub fn try_write(&self, data: &[u8]) -> Result<(), BrokerError> {
         let size = data.len();
@@ -114,10 +148,177 @@ impl RingBuffer {
     }
 }
 
+/// length prefix in front of every record
+pub const RECORD_HEADER_LEN: usize = 4;
+
+/// order number in front of the records `try_push_stamped` writes
+pub(crate) const STAMP_LEN: usize = 8;
+
+/// record api: the ring as a log of length-prefixed messages addressed by byte offset.
+///
+/// a record ring is read through offsets instead of `try_read`, and space is only
+/// reclaimed when the owner calls `release`. don't mix it with the raw byte api.
+impl RingBuffer {
+    /// offset the next record will be written at
+    #[inline]
+    pub fn head(&self) -> u64 {
+        self.producer_index.load(Ordering::Acquire)
+    }
+
+    /// oldest offset still retained
+    #[inline]
+    pub fn tail(&self) -> u64 {
+        self.consumer_index.load(Ordering::Acquire)
+    }
+
+    /// append one record, returning its offset
+    #[inline(always)]
+    pub fn try_push(&self, record: &[u8]) -> Result<u64, BrokerError> {
+        let size = RECORD_HEADER_LEN + record.len();
+        if size > (self.mask + 1) / 4 {
+            return Err(BrokerError::MessageTooLarge);
+        }
+
+        let producer_index = self.producer_index.load(Ordering::Relaxed);
+        let consumer_index = self.consumer_index.load(Ordering::Acquire);
+
+        if producer_index.wrapping_sub(consumer_index) > (self.mask as u64 - size as u64) {
+            return Err(BrokerError::BufferFull);
+        }
+
+        let len = (record.len() as u32).to_le_bytes();
+        self.copy_in(producer_index, &len);
+        self.copy_in(producer_index + RECORD_HEADER_LEN as u64, record);
+
+        self.producer_index
+            .store(producer_index.wrapping_add(size as u64), Ordering::Release);
+        Ok(producer_index)
+    }
+
+    /// append one record behind the next number from `stamps`, which is only taken
+    /// once the record is sure to fit. returns the offset. single producer only
+    #[inline(always)]
+    pub(crate) fn try_push_stamped(
+        &self,
+        stamps: &AtomicU64,
+        record: &[u8],
+    ) -> Result<u64, BrokerError> {
+        let size = RECORD_HEADER_LEN + STAMP_LEN + record.len();
+        if size > (self.mask + 1) / 4 {
+            return Err(BrokerError::MessageTooLarge);
+        }
+
+        let producer_index = self.producer_index.load(Ordering::Relaxed);
+        let consumer_index = self.consumer_index.load(Ordering::Acquire);
+
+        if producer_index.wrapping_sub(consumer_index) > (self.mask as u64 - size as u64) {
+            return Err(BrokerError::BufferFull);
+        }
+
+        let stamp = stamps.fetch_add(1, Ordering::AcqRel);
+        let len = ((STAMP_LEN + record.len()) as u32).to_le_bytes();
+        self.copy_in(producer_index, &len);
+        self.copy_in(
+            producer_index + RECORD_HEADER_LEN as u64,
+            &stamp.to_le_bytes(),
+        );
+        self.copy_in(
+            producer_index + (RECORD_HEADER_LEN + STAMP_LEN) as u64,
+            record,
+        );
+
+        self.producer_index
+            .store(producer_index.wrapping_add(size as u64), Ordering::Release);
+        Ok(producer_index)
+    }
+
+    /// append the record at `offset` to `dst` and return the offset of the next record.
+    ///
+    /// `offset` must be a record boundary handed out by the ring, at or after `tail`
+    #[inline(always)]
+    pub fn read_record(&self, offset: u64, dst: &mut BytesMut) -> Result<u64, BrokerError> {
+        let producer_index = self.producer_index.load(Ordering::Acquire);
+        if offset >= producer_index {
+            return Err(BrokerError::BufferEmpty);
+        }
+        if offset < self.consumer_index.load(Ordering::Acquire) {
+            return Err(BrokerError::OffsetOutOfRange(offset));
+        }
+
+        let mut len = [0u8; RECORD_HEADER_LEN];
+        self.copy_out(offset, &mut len);
+        let len = u32::from_le_bytes(len) as usize;
+        if RECORD_HEADER_LEN + len > (self.mask + 1) / 4 {
+            return Err(BrokerError::OffsetOutOfRange(offset));
+        }
+
+        let start = dst.len();
+        dst.resize(start + len, 0);
+        self.copy_out(offset + RECORD_HEADER_LEN as u64, &mut dst[start..]);
+        Ok(offset + (RECORD_HEADER_LEN + len) as u64)
+    }
+
+    /// whether a record starts at `offset`, or the next one will. walks the records
+    /// from `tail`, the caller keeps the tail from moving meanwhile
+    pub fn is_record_boundary(&self, offset: u64) -> bool {
+        let head = self.producer_index.load(Ordering::Acquire);
+        let mut position = self.consumer_index.load(Ordering::Acquire);
+        if !(position..=head).contains(&offset) {
+            return false;
+        }
+        while position < offset {
+            position = self.next_record(position);
+        }
+        position == offset
+    }
+
+    /// where the record after the one at `offset` starts. the caller keeps it retained
+    #[inline]
+    pub(crate) fn next_record(&self, offset: u64) -> u64 {
+        let mut len = [0u8; RECORD_HEADER_LEN];
+        self.copy_out(offset, &mut len);
+        offset + (RECORD_HEADER_LEN + u32::from_le_bytes(len) as usize) as u64
+    }
+
+    /// reclaim everything before `offset`. the caller guarantees nobody reads below it anymore
+    #[inline]
+    pub fn release(&self, offset: u64) {
+        self.consumer_index.store(offset, Ordering::Release);
+    }
+
+    #[inline(always)]
+    fn copy_in(&self, position: u64, data: &[u8]) {
+        let index = (position as usize) & self.mask;
+        let first_part = (self.mask + 1 - index).min(data.len());
+        unsafe {
+            ptr::copy_nonoverlapping(data.as_ptr(), self.data.add(index), first_part);
+            ptr::copy_nonoverlapping(
+                data.as_ptr().add(first_part),
+                self.data,
+                data.len() - first_part,
+            );
+        }
+    }
+
+    #[inline(always)]
+    fn copy_out(&self, position: u64, buf: &mut [u8]) {
+        let index = (position as usize) & self.mask;
+        let first_part = (self.mask + 1 - index).min(buf.len());
+        unsafe {
+            ptr::copy_nonoverlapping(self.data.add(index), buf.as_mut_ptr(), first_part);
+            ptr::copy_nonoverlapping(
+                self.data,
+                buf.as_mut_ptr().add(first_part),
+                buf.len() - first_part,
+            );
+        }
+    }
+}
+
 impl Drop for RingBuffer {
     fn drop(&mut self) {
         unsafe {
-            let layout = Layout::from_size_align_unchecked(RING_BUFFER_SIZE, CACHE_LINE_SIZE);
+            let layout = Layout::from_size_align_unchecked(self.capacity(), CACHE_LINE_SIZE);
             alloc::dealloc(self.data, layout);
         }
     }
@@ -0,0 +1,298 @@
+use crate::handler::ErrorPolicy;
+use crate::net::rustls;
+use crate::quota::{Quota, QuotaAction};
+use crate::topic::TopicConfig;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const DEFAULT_PORT: u16 = 7878;
+/// where `broker-admin` looks for the admin listener by default
+pub const DEFAULT_ADMIN_PORT: u16 = 7879;
+/// owner and group may connect
+pub const DEFAULT_SOCKET_MODE: u32 = 0o660;
+
+/// what happens to existing connections when a new one is accepted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionMode {
+    /// every connection gets its own reader, all of them publish side by side
+    Concurrent,
+    /// a new connection closes all previous ones, so there is one client at a time
+    Replace,
+}
+
+/// a unix socket listener, for producers on the same host
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnixListenConfig {
+    /// a stale socket file left here by a previous server is replaced
+    pub path: PathBuf,
+    /// permission bits of the socket file, connecting needs write permission
+    pub mode: u32,
+}
+
+impl UnixListenConfig {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            mode: DEFAULT_SOCKET_MODE,
+        }
+    }
+}
+
+/// where records that fail validation or expire are kept, see `dead_letter::DeadLetterReader`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeadLetterConfig {
+    pub path: PathBuf,
+    /// size at which the log is moved to `<path>.1` and started over
+    pub max_bytes: u64,
+}
+
+impl DeadLetterConfig {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            max_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+/// connections have to authenticate before anything else, see `auth::Credentials`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthConfig {
+    /// the credentials file, read once at startup
+    pub path: PathBuf,
+    /// time a new connection gets to finish the handshake
+    pub timeout: Duration,
+}
+
+impl AuthConfig {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// who may publish and subscribe to which topics, see `acl::Acl`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AclConfig {
+    pub path: PathBuf,
+    /// how often the file is checked for changes. `None` only reloads it through
+    /// `ServerHandle::reload_acl`
+    pub reload_interval: Option<Duration>,
+    /// denials are appended here as JSON lines, besides going to the
+    /// `broker::audit` log target. a denial repeated on a connection within a
+    /// second is counted in the `repeated` field of the next line instead
+    pub audit_log: Option<PathBuf>,
+}
+
+impl AclConfig {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            reload_interval: Some(Duration::from_secs(5)),
+            audit_log: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// one listener per address. port 0 binds a free port, see `ServerHandle::local_addrs`
+    pub listen: Vec<SocketAddr>,
+    pub unix_listen: Vec<UnixListenConfig>,
+    pub connection_mode: ConnectionMode,
+    /// config for topics created on first publish
+    pub topic_defaults: TopicConfig,
+    /// create unknown topics on first publish instead of rejecting the frame
+    pub auto_create_topics: bool,
+    pub max_topics: usize,
+    /// most bytes the rings of all topics together may take. topics that would
+    /// go over it are refused, auto-created ones with an `OVERLOADED` error
+    pub ring_memory: usize,
+    /// what topic consumers do when their handler fails
+    pub error_policy: ErrorPolicy,
+    /// message bytes a producer with flow control may have in the rings,
+    /// see `BrokerClient::enable_flow_control`
+    pub producer_window: u32,
+    /// drop messages whose sequence number their producer already published, so
+    /// producers can resend after an error without duplicating. a producer is a
+    /// principal across its connections, or one connection without authentication
+    pub reject_duplicates: bool,
+    /// keep rejected records instead of only counting them
+    pub dead_letters: Option<DeadLetterConfig>,
+    /// HTTP listener for `/metrics` and the control API, see `ServerHandle::admin_addr`
+    pub admin: Option<SocketAddr>,
+    /// requests to the admin listener must carry it as `Authorization: Bearer`.
+    /// required unless `admin` is a loopback address
+    pub admin_token: Option<String>,
+    /// time `BrokerServer::run` gives the consumers to drain once one is requested
+    /// through the admin API, see `ServerHandle::shutdown_with_drain`
+    pub drain_timeout: Duration,
+    /// `None` accepts anyone who can connect
+    pub auth: Option<AuthConfig>,
+    /// `None` lets every connection publish and subscribe to every topic
+    pub acl: Option<AclConfig>,
+    /// TLS on the TCP listeners, unix sockets stay plaintext. a client certificate,
+    /// when the config asks for one, names the connection's principal and stands in
+    /// for the `auth` handshake. see `net::tls::server_config`
+    pub tls: Option<Arc<rustls::ServerConfig>>,
+    /// send every client a `Frame::Heartbeat` this often
+    pub heartbeat_interval: Option<Duration>,
+    /// close connections that sent nothing, heartbeats included, for this long
+    pub idle_timeout: Option<Duration>,
+    /// TCP keepalive on accepted connections
+    pub keepalive: Option<Keepalive>,
+    /// most bytes one connection may buffer for incoming frames. buffers start
+    /// small and grow up to this for large frames, larger ones are rejected
+    pub connection_memory: usize,
+    /// publish rate of each client: the connections of one principal, or from one
+    /// address without authentication, share it. topics have their own in
+    /// `TopicConfig::quota`
+    pub client_quota: Option<Quota>,
+    /// whether publishes over a client or topic quota wait or fail
+    pub quota_action: QuotaAction,
+    /// most bytes all connections together may buffer. connections that would go
+    /// over it are refused with an `OVERLOADED` error
+    pub memory_limit: usize,
+    /// accept TCP connections on several threads, see `ShardConfig`
+    pub shards: Option<ShardConfig>,
+    /// read plaintext connections through io_uring instead of epoll
+    #[cfg(feature = "io-uring")]
+    pub io_uring: Option<UringConfig>,
+}
+
+impl ServerConfig {
+    /// listen on `port` on all IPv4 interfaces
+    pub fn new(port: u16) -> Self {
+        Self::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)))
+    }
+
+    pub fn bind(addr: SocketAddr) -> Self {
+        Self {
+            listen: vec![addr],
+            ..Default::default()
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen: vec![SocketAddr::from((Ipv4Addr::UNSPECIFIED, DEFAULT_PORT))],
+            unix_listen: Vec::new(),
+            connection_mode: ConnectionMode::Concurrent,
+            topic_defaults: TopicConfig::default(),
+            auto_create_topics: true,
+            max_topics: 64,
+            ring_memory: 4 * 1024 * 1024 * 1024,
+            error_policy: ErrorPolicy::default(),
+            producer_window: 16 * 1024 * 1024,
+            reject_duplicates: false,
+            dead_letters: None,
+            admin: None,
+            admin_token: None,
+            drain_timeout: Duration::from_secs(30),
+            auth: None,
+            acl: None,
+            tls: None,
+            heartbeat_interval: None,
+            idle_timeout: None,
+            keepalive: None,
+            connection_memory: 16 * 1024 * 1024,
+            memory_limit: 1024 * 1024 * 1024,
+            client_quota: None,
+            quota_action: QuotaAction::default(),
+            shards: None,
+            #[cfg(feature = "io-uring")]
+            io_uring: None,
+        }
+    }
+}
+
+/// the io_uring reader. every connection keeps one multishot receive armed, which
+/// fills buffers from a pool registered with the kernel
+#[cfg(feature = "io-uring")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UringConfig {
+    /// submission queue entries
+    pub entries: u32,
+    /// buffers in the pool, a power of two up to 32768
+    pub buffers: u16,
+    /// bytes per buffer. one receive fills at most one
+    pub buffer_size: u32,
+    /// most buffers one connection may hold before its receive is paused until it
+    /// caught up, so a slow reader can't starve the others
+    pub connection_buffers: usize,
+}
+
+#[cfg(feature = "io-uring")]
+impl Default for UringConfig {
+    fn default() -> Self {
+        Self {
+            entries: 256,
+            buffers: 512,
+            buffer_size: 16 * 1024,
+            connection_buffers: 64,
+        }
+    }
+}
+
+/// listener shards: every TCP address is bound once per shard with SO_REUSEPORT,
+/// so the kernel spreads new connections over them. each shard is a thread with a
+/// single threaded runtime that runs the connections it accepted, and publishes
+/// into rings of its own. a merge point per topic moves those into the topic's ring
+/// in publish order, which is what consumers and subscribers read. unix sockets
+/// and the admin API stay on the runtime `BrokerServer::start` is called on.
+/// producer credit covers records from the shard's ring until the consumers are
+/// done with them in the topic's
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShardConfig {
+    pub count: usize,
+    /// pin shard `i` to core `i`, wrapping around when there are more shards
+    pub pin_cores: bool,
+    /// bytes of each shard's ring per topic, a power of two. a message has to fit
+    /// into a quarter of it
+    pub ring_capacity: usize,
+}
+
+impl ShardConfig {
+    pub fn new(count: usize) -> Self {
+        Self {
+            count,
+            pin_cores: true,
+            ring_capacity: 4 * 1024 * 1024,
+        }
+    }
+}
+
+impl Default for ShardConfig {
+    /// one shard per core
+    fn default() -> Self {
+        Self::new(std::thread::available_parallelism().map_or(1, |n| n.get()))
+    }
+}
+
+/// TCP keepalive probing, for peers that vanish without closing the connection.
+/// the kernel gives up after `idle + interval * retries` of silence
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keepalive {
+    /// quiet time before the first probe
+    pub idle: Duration,
+    /// between unanswered probes
+    pub interval: Duration,
+    /// unanswered probes before the connection is dropped
+    pub retries: u32,
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Self {
+            idle: Duration::from_secs(60),
+            interval: Duration::from_secs(10),
+            retries: 6,
+        }
+    }
+}
@@ -0,0 +1,245 @@
+use crate::dead_letter::{DeadLetter, DeadLetterLog};
+use crate::error::BrokerError;
+use crate::handler::{BatchError, ConsumeError, ErrorPolicy, Handler, Message};
+use crate::net::message::PayloadHeader;
+use crate::staleness::{now_nanos, ExpiredAction};
+use crate::stats::{BrokerStats, LatencyBatch};
+use crate::topic::{Cursor, Topic};
+use crate::{BATCH_SIZE, BUFFER_CHUNK};
+use bytes::BytesMut;
+use std::io;
+use std::ops::Range;
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, warn};
+
+/// the per-topic consumer stage: reads the ring in batches, validates every
+/// record and passes the valid ones to the topic's handler
+pub(crate) struct Consumer {
+    pub(crate) topic: Arc<Topic>,
+    pub(crate) handler: Handler,
+    pub(crate) policy: ErrorPolicy,
+    pub(crate) stats: Arc<BrokerStats>,
+    pub(crate) dead_letters: Option<Arc<DeadLetterLog>>,
+    /// exit once caught up
+    pub(crate) drain: CancellationToken,
+    /// exit now
+    pub(crate) abort: CancellationToken,
+}
+
+impl Consumer {
+    /// consume from `cursor` until drained or aborted. returns how many bytes were
+    /// left unread
+    pub(crate) async fn run(self, cursor: Cursor) -> u64 {
+        let topic = self.topic.clone();
+        debug!("consumer started");
+        let mut buf = BytesMut::with_capacity(BUFFER_CHUNK);
+        let mut records: Vec<(u64, Range<usize>)> = Vec::with_capacity(BATCH_SIZE);
+        let mut offset = cursor.position();
+        let mut messages_consumed = 0;
+        let mut messages_processed = 0;
+        let mut processing_errors = 0;
+
+        while !self.abort.is_cancelled() {
+            if topic.is_paused() && !self.drain.is_cancelled() {
+                debug!(offset, "consumer paused");
+                tokio::select! {
+                    _ = topic.wait_resumed() => debug!("consumer resumed"),
+                    _ = self.drain.cancelled() => {}
+                    _ = self.abort.cancelled() => {}
+                }
+                continue;
+            }
+
+            buf.clear();
+            records.clear();
+            let mut next = offset;
+
+            while records.len() < BATCH_SIZE && buf.len() < BUFFER_CHUNK {
+                let start = buf.len();
+                match cursor.read(next, &mut buf) {
+                    Ok(after) => {
+                        records.push((next, start..buf.len()));
+                        next = after;
+                    }
+                    Err(BrokerError::BufferEmpty) => break,
+                    Err(e) => {
+                        error!(offset = next, error = %e, "ring buffer read failed");
+                        break;
+                    }
+                }
+            }
+
+            if records.is_empty() {
+                // caught up, and with the connections gone nothing new is coming
+                if self.drain.is_cancelled() {
+                    break;
+                }
+                tokio::select! {
+                    _ = topic.wait_for(next) => {}
+                    _ = self.drain.cancelled() => {}
+                    _ = self.abort.cancelled() => {}
+                }
+                continue;
+            }
+
+            let staleness = topic.staleness();
+            let now = now_nanos();
+            let mut latency = LatencyBatch::default();
+            let mut batch = Vec::with_capacity(records.len());
+            for (offset, range) in &records {
+                match PayloadHeader::verify(&buf[range.clone()]) {
+                    Ok((header, body)) => {
+                        latency.observe(now.saturating_sub(header.timestamp));
+                        let expired = staleness.filter(|s| s.is_expired(header.timestamp, now));
+                        if let Some(staleness) = expired {
+                            BrokerStats::add(&self.stats.messages_expired, 1);
+                            match staleness.action {
+                                ExpiredAction::Drop => continue,
+                                ExpiredAction::DeadLetter => {
+                                    self.dead_letter(*offset, "expired", &buf[range.clone()])
+                                        .await;
+                                    continue;
+                                }
+                                ExpiredAction::Deliver => {}
+                            }
+                        }
+                        batch.push(Message {
+                            topic: topic.name(),
+                            offset: *offset,
+                            timestamp: header.timestamp,
+                            sequence: header.sequence,
+                            expired: expired.is_some(),
+                            body,
+                        })
+                    }
+                    Err(reason) => {
+                        processing_errors += 1;
+                        BrokerStats::add(&self.stats.processing_errors, 1);
+                        self.dead_letter(*offset, reason, &buf[range.clone()]).await;
+                        self.handler.on_error(
+                            topic.name(),
+                            *offset,
+                            &ConsumeError::Invalid(reason),
+                        );
+                    }
+                }
+            }
+
+            self.stats.latency.record(&latency);
+
+            let (handled, stopped_at) = tokio::select! {
+                handled = self.handle(&batch) => handled,
+                // a handler that never returns doesn't hold the shutdown up past its
+                // deadline. the batch stays unconsumed
+                _ = self.abort.cancelled() => break,
+            };
+            messages_processed += handled;
+            BrokerStats::add(&self.stats.messages_processed, handled);
+
+            if let Some(failed) = stopped_at {
+                // keep the failed message and everything after it in the ring
+                let consumed = records.iter().take_while(|(o, _)| *o < failed).count();
+                BrokerStats::add(&self.stats.messages_consumed, consumed as u64);
+                offset = failed;
+                cursor.advance(offset);
+                warn!(offset, "consumer stopped after a handler error");
+                tokio::select! {
+                    _ = self.drain.cancelled() => {}
+                    _ = self.abort.cancelled() => {}
+                }
+                break;
+            }
+
+            offset = next;
+            cursor.advance(offset);
+            messages_consumed += records.len() as u64;
+            BrokerStats::add(&self.stats.messages_consumed, records.len() as u64);
+
+            if messages_consumed % 1_000_000 < records.len() as u64 && messages_consumed > 0 {
+                debug!(
+                    consumed = messages_consumed,
+                    processed = messages_processed,
+                    errors = processing_errors,
+                    "consumer progress"
+                );
+            }
+        }
+
+        self.handler.on_shutdown(topic.name()).await;
+        let undrained = topic.ring().head() - offset;
+        debug!(undrained, "consumer stopped");
+        undrained
+    }
+
+    /// keep a rejected record, if there is a dead letter log
+    async fn dead_letter(&self, offset: u64, reason: &str, payload: &[u8]) {
+        let Some(log) = self.dead_letters.clone() else {
+            return;
+        };
+        let publisher = self.topic.publisher_at(offset);
+        let letter = DeadLetter {
+            captured_at: DeadLetter::now(),
+            topic: self.topic.name().to_string(),
+            offset,
+            connection: publisher.as_ref().map(|p| p.connection),
+            peer: publisher.map(|p| p.peer.clone()),
+            reason: reason.to_string(),
+            payload: payload.to_vec(),
+        };
+        // file writes and rotation block, they don't belong on the runtime's threads
+        let appended = tokio::task::spawn_blocking(move || log.append(&letter))
+            .await
+            .unwrap_or_else(|e| Err(io::Error::other(e)));
+        match appended {
+            Ok(()) => BrokerStats::add(&self.stats.dead_letters, 1),
+            Err(e) => error!(offset, error = %e, "failed to write dead letter"),
+        }
+    }
+
+    /// run the handler over `batch`, applying the error policy to every failure.
+    /// returns how many messages were handled, and the offset the consumer stopped
+    /// at under `ErrorPolicy::Stop`
+    async fn handle(&self, batch: &[Message<'_>]) -> (u64, Option<u64>) {
+        let mut handled = 0;
+        let mut start = 0;
+
+        while start < batch.len() {
+            let rest = &batch[start..];
+            let (index, error) = match self.handler.on_batch(rest).await {
+                Ok(()) => return (handled + rest.len() as u64, None),
+                Err(BatchError { index, error }) => (index.min(rest.len() - 1), error),
+            };
+            handled += index as u64;
+            let failed = &rest[index];
+            start += index + 1;
+
+            let mut error = ConsumeError::Handler(error);
+            let mut recovered = false;
+            if let ErrorPolicy::Retry { attempts, backoff } = self.policy {
+                for _ in 0..attempts {
+                    BrokerStats::add(&self.stats.handler_retries, 1);
+                    tokio::time::sleep(backoff).await;
+                    match self.handler.on_message(failed).await {
+                        Ok(()) => {
+                            recovered = true;
+                            break;
+                        }
+                        Err(e) => error = ConsumeError::Handler(e),
+                    }
+                }
+            }
+            if recovered {
+                handled += 1;
+                continue;
+            }
+
+            BrokerStats::add(&self.stats.handler_errors, 1);
+            self.handler.on_error(failed.topic, failed.offset, &error);
+            if self.policy == ErrorPolicy::Stop {
+                return (handled, Some(failed.offset));
+            }
+        }
+        (handled, None)
+    }
+}
@@ -0,0 +1,213 @@
+use crate::config::DeadLetterConfig;
+use parking_lot::Mutex;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// longest payload the log keeps, far past any record a default broker takes.
+/// longer ones are refused rather than written
+pub const MAX_PAYLOAD_LEN: usize = 64 * 1024 * 1024;
+
+/// longest record `DeadLetterReader` accepts: every field at its longest and the
+/// longest payload. a longer length prefix can only come from a corrupt file
+pub const MAX_RECORD_LEN: usize = 3 * 8 + 3 * (2 + u16::MAX as usize) + MAX_PAYLOAD_LEN;
+
+/// a record the consumer rejected because it failed validation or expired,
+/// with why and where it came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeadLetter {
+    /// unix time in milliseconds when it was rejected
+    pub captured_at: u64,
+    pub topic: String,
+    pub offset: u64,
+    /// id of the publishing connection, counting from 1 in accept order
+    pub connection: Option<u64>,
+    /// address of the publishing connection
+    pub peer: Option<String>,
+    pub reason: String,
+    /// the record as published
+    pub payload: Vec<u8>,
+}
+
+impl DeadLetter {
+    pub(crate) fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64)
+    }
+
+    /// append as one log record, little endian:
+    ///
+    /// ```text
+    /// len u32 | captured_at u64 | offset u64 | connection u64 | topic_len u16 | topic
+    ///         | peer_len u16 | peer | reason_len u16 | reason | payload
+    /// ```
+    ///
+    /// `len` counts everything after itself, the payload runs to the end of the
+    /// record. connection 0 means the publisher was not known
+    pub fn encode(&self, dst: &mut Vec<u8>) {
+        let start = dst.len();
+        dst.extend_from_slice(&0u32.to_le_bytes());
+        dst.extend_from_slice(&self.captured_at.to_le_bytes());
+        dst.extend_from_slice(&self.offset.to_le_bytes());
+        dst.extend_from_slice(&self.connection.unwrap_or(0).to_le_bytes());
+        for field in [
+            &self.topic,
+            self.peer.as_deref().unwrap_or(""),
+            &self.reason,
+        ] {
+            let field = &field.as_bytes()[..field.len().min(u16::MAX as usize)];
+            dst.extend_from_slice(&(field.len() as u16).to_le_bytes());
+            dst.extend_from_slice(field);
+        }
+        dst.extend_from_slice(&self.payload);
+        let len = (dst.len() - start - 4) as u32;
+        dst[start..start + 4].copy_from_slice(&len.to_le_bytes());
+    }
+
+    /// decode one record without its length prefix
+    pub fn decode(mut src: &[u8]) -> io::Result<Self> {
+        let captured_at = take_u64(&mut src)?;
+        let offset = take_u64(&mut src)?;
+        let connection = Some(take_u64(&mut src)?).filter(|c| *c != 0);
+        let topic = take_str(&mut src)?;
+        let peer = Some(take_str(&mut src)?).filter(|p| !p.is_empty());
+        let reason = take_str(&mut src)?;
+        Ok(Self {
+            captured_at,
+            topic,
+            offset,
+            connection,
+            peer,
+            reason,
+            payload: src.to_vec(),
+        })
+    }
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "truncated dead letter")
+}
+
+fn take<'a>(src: &mut &'a [u8], len: usize) -> io::Result<&'a [u8]> {
+    if src.len() < len {
+        return Err(truncated());
+    }
+    let (head, rest) = src.split_at(len);
+    *src = rest;
+    Ok(head)
+}
+
+fn take_u64(src: &mut &[u8]) -> io::Result<u64> {
+    Ok(u64::from_le_bytes(take(src, 8)?.try_into().unwrap()))
+}
+
+fn take_str(src: &mut &[u8]) -> io::Result<String> {
+    let len = u16::from_le_bytes(take(src, 2)?.try_into().unwrap());
+    let bytes = take(src, len as usize)?;
+    String::from_utf8(bytes.to_vec()).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// reads the records of a dead letter log in the order they were captured
+pub struct DeadLetterReader<R> {
+    reader: R,
+}
+
+impl DeadLetterReader<BufReader<File>> {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::new(BufReader::new(File::open(path)?)))
+    }
+}
+
+impl<R: Read> DeadLetterReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self { reader }
+    }
+}
+
+impl<R: Read> Iterator for DeadLetterReader<R> {
+    type Item = io::Result<DeadLetter>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut len = [0; 4];
+        match self.reader.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return None,
+            Err(e) => return Some(Err(e)),
+        }
+        let len = u32::from_le_bytes(len) as usize;
+        if len > MAX_RECORD_LEN {
+            return Some(Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("corrupt dead letter of {} bytes", len),
+            )));
+        }
+        let mut record = vec![0; len];
+        if let Err(e) = self.reader.read_exact(&mut record) {
+            // the broker went down halfway through the record
+            if e.kind() == io::ErrorKind::UnexpectedEof {
+                return Some(Err(truncated()));
+            }
+            return Some(Err(e));
+        }
+        Some(DeadLetter::decode(&record))
+    }
+}
+
+/// the broker's side of the log: appends, and once `max_bytes` are reached
+/// moves the file to `<path>.1` and starts over, so at most two files are kept
+pub(crate) struct DeadLetterLog {
+    path: PathBuf,
+    max_bytes: u64,
+    /// the current file and how much was written to it
+    file: Mutex<(File, u64)>,
+}
+
+impl DeadLetterLog {
+    pub(crate) fn open(config: &DeadLetterConfig) -> io::Result<Self> {
+        let file = append(&config.path)?;
+        let written = file.metadata()?.len();
+        Ok(Self {
+            path: config.path.clone(),
+            max_bytes: config.max_bytes,
+            file: Mutex::new((file, written)),
+        })
+    }
+
+    /// writes straight through and may rotate the file, so it blocks: the consumer
+    /// calls it through `spawn_blocking`
+    pub(crate) fn append(&self, letter: &DeadLetter) -> io::Result<()> {
+        if letter.payload.len() > MAX_PAYLOAD_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "a payload of {} bytes is too long for the dead letter log",
+                    letter.payload.len()
+                ),
+            ));
+        }
+        let mut record = Vec::with_capacity(letter.payload.len() + 64);
+        letter.encode(&mut record);
+
+        let mut file = self.file.lock();
+        if file.1 > 0 && file.1 + record.len() as u64 > self.max_bytes {
+            fs::rename(&self.path, rotated(&self.path))?;
+            *file = (append(&self.path)?, 0);
+        }
+        file.0.write_all(&record)?;
+        file.1 += record.len() as u64;
+        Ok(())
+    }
+}
+
+fn append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// where the previous log goes when the current one is full
+pub fn rotated(path: &Path) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(".1");
+    PathBuf::from(rotated)
+}
@@ -1,3 +1,6 @@
+use crate::acl::Operation;
+use crate::net::message::PROTOCOL_VERSION;
+use std::time::Duration;
 use thiserror::Error;
 use tokio::task::JoinError;
 
@@ -17,6 +20,40 @@ pub enum BrokerError {
 
     #[error("message size too large")]
     MessageTooLarge,
+
+    #[error("ring capacity {0} is not a power of two")]
+    InvalidCapacity(usize),
+
+    #[error("unknown topic: {0}")]
+    UnknownTopic(String),
+
+    #[error("invalid topic name: {0:?}")]
+    InvalidTopicName(String),
+
+    #[error("topic limit of {0} reached")]
+    TooManyTopics(usize),
+
+    #[error("ring of {requested} bytes doesn't fit, {available} bytes of ring memory left")]
+    RingMemoryExhausted { requested: usize, available: usize },
+
+    #[error("offset {0} is not retained")]
+    OffsetOutOfRange(u64),
+
+    #[error("offset {0} is not the start of a record")]
+    InvalidOffset(u64),
+
+    #[error("publish exceeds the granted credit")]
+    CreditExceeded,
+
+    #[error("publish rate over the {0} quota")]
+    QuotaExceeded(&'static str),
+
+    #[error("{principal} may not {operation} to {topic}")]
+    Forbidden {
+        principal: String,
+        operation: Operation,
+        topic: String,
+    },
 }
 
 #[derive(Error, Debug)]
@@ -29,4 +66,31 @@ pub enum NetworkError {
 
     #[error("Join error {0}")]
     Join(#[from] JoinError),
+
+    #[error("frame exceeds maximum length of {max} bytes")]
+    FrameTooLarge { max: usize },
+
+    #[error("invalid frame: {0}")]
+    InvalidFrame(&'static str),
+
+    #[error("unknown frame kind {0:#04x}")]
+    UnknownFrameKind(u8),
+
+    #[error("unsupported protocol version {0}, expected {PROTOCOL_VERSION}")]
+    UnsupportedVersion(u8),
+
+    #[error("broker rejected request ({code}): {message}")]
+    Remote { code: u16, message: String },
+
+    #[error("connection closed")]
+    Closed,
+
+    #[error("out of credit, the broker is backed up")]
+    WouldBlock,
+
+    #[error("broker is over its memory limit")]
+    MemoryLimit,
+
+    #[error("nothing heard from the peer for {0:?}")]
+    IdleTimeout(Duration),
 }
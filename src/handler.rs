@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::hint::black_box;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use thiserror::Error;
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// a consumed record that passed validation
+#[derive(Debug, Clone, Copy)]
+pub struct Message<'a> {
+    pub topic: &'a str,
+    pub offset: u64,
+    pub timestamp: u64,
+    pub sequence: u64,
+    /// older than the topic's TTL, only set under `ExpiredAction::Deliver`
+    pub expired: bool,
+    /// payload after the payload header
+    pub body: &'a [u8],
+}
+
+/// `on_batch` failed at `batch[index]`, everything before it was handled
+#[derive(Debug)]
+pub struct BatchError {
+    pub index: usize,
+    pub error: BoxError,
+}
+
+/// why a record was not handled, passed to `on_error`
+#[derive(Error, Debug)]
+pub enum ConsumeError {
+    #[error("invalid record: {0}")]
+    Invalid(&'static str),
+    #[error("handler failed: {0}")]
+    Handler(BoxError),
+}
+
+/// what the consumer does when a handler fails on a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ErrorPolicy {
+    /// count it, report it to `on_error` and go on with the next message
+    #[default]
+    Skip,
+    /// call `on_message` up to `attempts` more times, `backoff` apart, then skip
+    Retry { attempts: u32, backoff: Duration },
+    /// stop consuming the topic. the ring keeps everything from the failed
+    /// message on, so producers stall once it is full
+    Stop,
+}
+
+/// business logic run by the consumer of a topic, see `BrokerServer::set_handler`.
+///
+/// runs on the consumer task: a slow handler slows the topic down, and one that
+/// blocks should be an `AsyncMessageHandler` instead
+pub trait MessageHandler: Send + Sync + 'static {
+    fn on_message(&self, msg: &Message<'_>) -> Result<(), BoxError>;
+
+    /// up to one ring batch at a time, in offset order
+    fn on_batch(&self, batch: &[Message<'_>]) -> Result<(), BatchError> {
+        for (index, msg) in batch.iter().enumerate() {
+            self.on_message(msg)
+                .map_err(|error| BatchError { index, error })?;
+        }
+        Ok(())
+    }
+
+    /// a record failed validation or was given up on, as decided by the `ErrorPolicy`
+    fn on_error(&self, _topic: &str, _offset: u64, _error: &ConsumeError) {}
+
+    /// the consumer of `topic` stopped, nothing else is delivered for it
+    fn on_shutdown(&self, _topic: &str) {}
+}
+
+/// `MessageHandler` for handlers that need to await
+pub trait AsyncMessageHandler: Send + Sync + 'static {
+    fn on_message<'a>(&'a self, msg: &'a Message<'a>) -> BoxFuture<'a, Result<(), BoxError>>;
+
+    fn on_batch<'a>(&'a self, batch: &'a [Message<'a>]) -> BoxFuture<'a, Result<(), BatchError>> {
+        Box::pin(async move {
+            for (index, msg) in batch.iter().enumerate() {
+                self.on_message(msg)
+                    .await
+                    .map_err(|error| BatchError { index, error })?;
+            }
+            Ok(())
+        })
+    }
+
+    fn on_error(&self, _topic: &str, _offset: u64, _error: &ConsumeError) {}
+
+    fn on_shutdown<'a>(&'a self, _topic: &'a str) -> BoxFuture<'a, ()> {
+        Box::pin(async {})
+    }
+}
+
+/// used for topics without a handler: validates and drops
+pub struct DiscardHandler;
+
+impl MessageHandler for DiscardHandler {
+    #[inline]
+    fn on_message(&self, msg: &Message<'_>) -> Result<(), BoxError> {
+        black_box(msg);
+        Ok(())
+    }
+
+    #[inline]
+    fn on_batch(&self, batch: &[Message<'_>]) -> Result<(), BatchError> {
+        black_box(batch);
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub(crate) enum Handler {
+    Sync(Arc<dyn MessageHandler>),
+    Async(Arc<dyn AsyncMessageHandler>),
+}
+
+impl Handler {
+    #[inline]
+    pub(crate) async fn on_message(&self, msg: &Message<'_>) -> Result<(), BoxError> {
+        match self {
+            Handler::Sync(handler) => handler.on_message(msg),
+            Handler::Async(handler) => handler.on_message(msg).await,
+        }
+    }
+
+    #[inline]
+    pub(crate) async fn on_batch(&self, batch: &[Message<'_>]) -> Result<(), BatchError> {
+        match self {
+            Handler::Sync(handler) => handler.on_batch(batch),
+            Handler::Async(handler) => handler.on_batch(batch).await,
+        }
+    }
+
+    pub(crate) fn on_error(&self, topic: &str, offset: u64, error: &ConsumeError) {
+        match self {
+            Handler::Sync(handler) => handler.on_error(topic, offset, error),
+            Handler::Async(handler) => handler.on_error(topic, offset, error),
+        }
+    }
+
+    pub(crate) async fn on_shutdown(&self, topic: &str) {
+        match self {
+            Handler::Sync(handler) => handler.on_shutdown(topic),
+            Handler::Async(handler) => handler.on_shutdown(topic).await,
+        }
+    }
+}
+
+/// handlers registered on the server, looked up when a topic's consumer starts
+#[derive(Default)]
+pub(crate) struct Handlers {
+    global: Option<Handler>,
+    topics: HashMap<String, Handler>,
+}
+
+impl Handlers {
+    pub(crate) fn set(&mut self, topic: Option<&str>, handler: Handler) {
+        match topic {
+            Some(topic) => {
+                self.topics.insert(topic.to_string(), handler);
+            }
+            None => self.global = Some(handler),
+        }
+    }
+
+    pub(crate) fn for_topic(&self, topic: &str) -> Handler {
+        self.topics
+            .get(topic)
+            .or(self.global.as_ref())
+            .cloned()
+            .unwrap_or_else(|| Handler::Sync(Arc::new(DiscardHandler)))
+    }
+}
@@ -1,12 +1,39 @@
+pub mod acl;
+pub mod auth;
 mod buffer;
+mod config;
+mod consumer;
+pub mod dead_letter;
 mod error;
+pub mod handler;
 mod metrics;
 pub mod net;
+mod quota;
+mod staleness;
+mod stats;
+pub mod topic;
 
+pub use auth::{Credential, Credentials};
 pub use buffer::RingBuffer;
+#[cfg(feature = "io-uring")]
+pub use config::UringConfig;
+pub use config::{
+    AclConfig, AuthConfig, ConnectionMode, DeadLetterConfig, Keepalive, ServerConfig, ShardConfig,
+    UnixListenConfig, DEFAULT_ADMIN_PORT, DEFAULT_PORT, DEFAULT_SOCKET_MODE,
+};
 pub use error::{BrokerError, NetworkError};
+pub use handler::{
+    AsyncMessageHandler, BatchError, BoxError, ConsumeError, ErrorPolicy, Message, MessageHandler,
+};
 pub use metrics::Metrics;
-pub use net::{BrokerClient, BrokerServer};
+pub use net::{
+    Backpressure, BrokerClient, BrokerCodec, BrokerServer, BrokerSubscriber, ClientOptions,
+    ClientTls, Frame, Record, ServerHandle, ShutdownReport, SubscribeOptions,
+};
+pub use quota::{Quota, QuotaAction};
+pub use staleness::{ExpiredAction, Staleness, DEFAULT_CLOCK_SKEW};
+pub use stats::StatsSnapshot;
+pub use topic::{StartPosition, TopicConfig, TopicInfo, TopicRef};
 
 pub(crate) const CACHE_LINE_SIZE: usize = 64;
 pub(crate) const RING_BUFFER_SIZE: usize = 256 * 1024 * 1024;
@@ -1,90 +1,473 @@
-use crate::error::NetworkError;
-use crate::net::message::MessageHeader;
+use crate::auth::{self, Credential};
+use crate::config::Keepalive;
+use crate::error::{BrokerError, NetworkError};
+use crate::net::codec::{Batch, BrokerCodec, Frame, Mechanism};
+use crate::net::tls::ClientTls;
+use crate::net::transport::{set_keepalive, Stream};
+use crate::topic::{TopicId, TopicInfo, TopicRef, DEFAULT_TOPIC};
 use crate::{BATCH_SIZE, BUFFER_CHUNK};
-use tokio::io::{AsyncWriteExt, BufWriter};
-use tokio::net::TcpStream;
+use bytes::{Bytes, BytesMut};
+use futures_util::{FutureExt, Sink, SinkExt, StreamExt};
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::net::{TcpStream, ToSocketAddrs, UnixStream};
+use tokio::sync::Mutex;
+use tokio::time::MissedTickBehavior;
+use tokio_util::codec::Framed;
+use tracing::{debug, trace};
+
+/// a batch is sent once it holds this many bytes, well within the broker's
+/// default `ServerConfig::connection_memory`
+const MAX_BATCH_BYTES: usize = 1024 * 1024;
+
+/// what `send` does when the broker granted no more credit, see `enable_flow_control`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backpressure {
+    /// wait for the broker to grant more
+    Wait,
+    /// fail with `NetworkError::WouldBlock` right away, without queueing the message
+    Fail,
+}
+
+/// connection settings for `BrokerClient::connect_with_options`
+#[derive(Debug, Clone, Default)]
+pub struct ClientOptions {
+    /// authenticate right after connecting, for brokers with `ServerConfig::auth`
+    pub credential: Option<Credential>,
+    /// connect over TLS, for brokers with `ServerConfig::tls`
+    pub tls: Option<ClientTls>,
+    /// TCP keepalive probing of the broker
+    pub keepalive: Option<Keepalive>,
+    /// give up on a response or on credit the broker hasn't sent for this long,
+    /// with `NetworkError::IdleTimeout`. heartbeats count as hearing from it
+    pub idle_timeout: Option<Duration>,
+    /// send the broker a heartbeat this often in the background, so a producer
+    /// with nothing to publish stays within the broker's `ServerConfig::idle_timeout`.
+    /// the broker's own heartbeats are taken in meanwhile
+    pub heartbeat_interval: Option<Duration>,
+}
+
+struct Credit {
+    window: u32,
+    available: u64,
+    backpressure: Backpressure,
+}
+
+/// the connection, shared with the task sending heartbeats
+struct Link {
+    framed: Framed<Stream, BrokerCodec>,
+    /// what the heartbeat task read that wasn't a heartbeat, `None` for the
+    /// connection closing
+    unread: VecDeque<Option<Result<Frame, NetworkError>>>,
+}
+
+impl Link {
+    /// a frame that already arrived, without waiting for one
+    fn ready(&mut self) -> Option<Option<Result<Frame, NetworkError>>> {
+        self.unread
+            .pop_front()
+            .or_else(|| self.framed.next().now_or_never())
+    }
+
+    async fn next(&mut self) -> Option<Result<Frame, NetworkError>> {
+        match self.unread.pop_front() {
+            Some(frame) => frame,
+            None => self.framed.next().await,
+        }
+    }
+}
 
 pub struct BrokerClient {
-    writer: BufWriter<TcpStream>,
-    batch: Vec<u8>,
+    link: Arc<Mutex<Link>>,
+    batch: BytesMut,
+    batch_topic: TopicRef,
+    batch_msg_size: usize,
     batch_count: u32,
     total_sent: u64,
+    /// `None` until flow control is enabled
+    credit: Option<Credit>,
+    idle_timeout: Option<Duration>,
 }
 
 impl BrokerClient {
-    pub async fn connect(addr: &str) -> Result<Self, NetworkError> {
-        eprintln!("DEBUG: Connecting to {}", addr);
+    pub async fn connect(addr: impl ToSocketAddrs) -> Result<Self, NetworkError> {
+        Self::connect_with_options(addr, ClientOptions::default()).await
+    }
+
+    pub async fn connect_with_options(
+        addr: impl ToSocketAddrs,
+        options: ClientOptions,
+    ) -> Result<Self, NetworkError> {
         let stream = TcpStream::connect(addr).await?;
         stream.set_nodelay(true)?;
-        eprintln!("SUCCESS: Connected to {}", addr);
+        if let Some(keepalive) = options.keepalive {
+            set_keepalive(&stream, keepalive)?;
+        }
+        debug!(peer = %stream.peer_addr()?, tls = options.tls.is_some(), "connected");
+        let stream = match &options.tls {
+            Some(tls) => tls.connect(stream).await?,
+            None => Stream::Tcp(stream),
+        };
+        let mut client = Self::new(stream);
+        client.idle_timeout = options.idle_timeout;
+        if let Some(credential) = &options.credential {
+            client.authenticate(credential).await?;
+        }
+        if let Some(period) = options.heartbeat_interval {
+            tokio::spawn(heartbeats(Arc::downgrade(&client.link), period));
+        }
+        Ok(client)
+    }
+
+    /// prove who this client is. returns the principal the broker accepted. brokers
+    /// with `ServerConfig::auth` take nothing else before this
+    pub async fn authenticate(&mut self, credential: &Credential) -> Result<String, NetworkError> {
+        self.flush().await?;
+        authenticate(&mut self.link.lock().await.framed, credential).await
+    }
 
-        Ok(Self {
-            writer: BufWriter::with_capacity(BUFFER_CHUNK * 4, stream),
-            batch: Vec::with_capacity(BUFFER_CHUNK * BATCH_SIZE),
+    /// connect over a unix socket the server listens on, see `ServerConfig::unix_listen`
+    pub async fn connect_unix(path: impl AsRef<Path>) -> Result<Self, NetworkError> {
+        let stream = UnixStream::connect(path.as_ref()).await?;
+        debug!(path = %path.as_ref().display(), "connected");
+        Ok(Self::new(Stream::Unix(stream)))
+    }
+
+    fn new(stream: Stream) -> Self {
+        let framed = Framed::with_capacity(stream, BrokerCodec::new(), BUFFER_CHUNK * 4);
+        Self {
+            link: Arc::new(Mutex::new(Link {
+                framed,
+                unread: VecDeque::new(),
+            })),
+            batch: BytesMut::with_capacity(BUFFER_CHUNK),
+            batch_topic: TopicRef::Name(DEFAULT_TOPIC.to_string()),
+            batch_msg_size: 0,
             batch_count: 0,
             total_sent: 0,
-        })
+            credit: None,
+            idle_timeout: None,
+        }
+    }
+
+    /// tell the broker this connection is alive. producers quiet for longer than the
+    /// broker's idle timeout call this in between, unless they set
+    /// `ClientOptions::heartbeat_interval`. it also takes in the heartbeats and
+    /// grants the broker sent meanwhile
+    pub async fn heartbeat(&mut self) -> Result<(), NetworkError> {
+        self.take_ready().await?;
+        self.send_frame(Frame::Heartbeat).await
+    }
+
+    async fn send_frame(&self, frame: Frame) -> Result<(), NetworkError> {
+        self.link.lock().await.framed.send(frame).await
+    }
+
+    /// handle the grants and heartbeats that already arrived, without waiting
+    async fn take_ready(&mut self) -> Result<(), NetworkError> {
+        loop {
+            let frame = self.link.lock().await.ready();
+            match frame {
+                Some(frame) => self.on_grant(frame)?,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// the next frame from the broker that isn't a heartbeat
+    async fn next_frame(&mut self) -> Option<Result<Frame, NetworkError>> {
+        let mut link = self.link.lock().await;
+        loop {
+            let frame = match self.idle_timeout {
+                Some(timeout) => match tokio::time::timeout(timeout, link.next()).await {
+                    Ok(frame) => frame,
+                    Err(_) => return Some(Err(NetworkError::IdleTimeout(timeout))),
+                },
+                None => link.next().await,
+            };
+            if !matches!(frame, Some(Ok(Frame::Heartbeat))) {
+                return frame;
+            }
+            // answered like `BrokerSubscriber::recv` does, a producer waiting on credit
+            // longer than the broker's idle timeout isn't taken for dead
+            if let Err(e) = link.framed.send(Frame::Heartbeat).await {
+                return Some(Err(e));
+            }
+        }
     }
 
+    /// publish against credit granted by the broker instead of until the socket
+    /// stalls. the broker grants a window of message bytes up front and more as
+    /// the published messages leave its rings. returns the window
+    pub async fn enable_flow_control(
+        &mut self,
+        backpressure: Backpressure,
+    ) -> Result<u32, NetworkError> {
+        if let Some(credit) = self.credit.as_mut() {
+            credit.backpressure = backpressure;
+            return Ok(credit.window);
+        }
+
+        self.flush().await?;
+        self.send_frame(Frame::FlowControl).await?;
+        match self.next_frame().await {
+            Some(Ok(Frame::Grant { bytes })) => {
+                self.credit = Some(Credit {
+                    window: bytes,
+                    available: bytes as u64,
+                    backpressure,
+                });
+                Ok(bytes)
+            }
+            Some(Ok(Frame::Error { code, message })) => Err(NetworkError::Remote { code, message }),
+            Some(Ok(_)) => Err(NetworkError::InvalidFrame("unexpected response frame")),
+            Some(Err(e)) => Err(e),
+            None => Err(NetworkError::Closed),
+        }
+    }
+
+    /// message bytes that can be sent before the broker has to grant more,
+    /// `None` without flow control
+    pub fn credit(&self) -> Option<u64> {
+        self.credit.as_ref().map(|credit| credit.available)
+    }
+
+    /// publish to the default topic
     #[inline]
     pub async fn send(&mut self, data: &[u8]) -> Result<(), NetworkError> {
+        self.send_to(DEFAULT_TOPIC, data).await
+    }
+
+    /// publish to the topic called `topic`
+    #[inline]
+    pub async fn send_to(&mut self, topic: &str, data: &[u8]) -> Result<(), NetworkError> {
+        if !matches!(&self.batch_topic, TopicRef::Name(name) if name == topic) {
+            self.flush().await?;
+            self.batch_topic = TopicRef::Name(topic.to_string());
+        }
+        self.push(data).await
+    }
+
+    /// publish to a topic by the id the broker assigned it, see `declare_topic`
+    #[inline]
+    pub async fn send_to_id(&mut self, topic: TopicId, data: &[u8]) -> Result<(), NetworkError> {
+        if self.batch_topic != TopicRef::Id(topic) {
+            self.flush().await?;
+            self.batch_topic = TopicRef::Id(topic);
+        }
+        self.push(data).await
+    }
+
+    #[inline]
+    async fn push(&mut self, data: &[u8]) -> Result<(), NetworkError> {
+        // a frame carries messages of one size only
+        if self.batch_count > 0 && data.len() != self.batch_msg_size {
+            self.flush().await?;
+        }
+        if self.credit.is_some() {
+            self.reserve(data.len() as u64).await?;
+        }
+
         self.batch.extend_from_slice(data);
+        self.batch_msg_size = data.len();
         self.batch_count += 1;
         self.total_sent += 1;
 
-        if self.batch_count >= BATCH_SIZE as u32 {
-            let header = MessageHeader {
-                size: data.len() as u32,
-                batch_size: self.batch_count,
-            };
+        if self.batch_count >= BATCH_SIZE as u32 || self.batch.len() >= MAX_BATCH_BYTES {
+            self.flush().await?;
 
-            let header_bytes = unsafe {
-                std::slice::from_raw_parts(
-                    &header as *const _ as *const u8,
-                    std::mem::size_of::<MessageHeader>(),
-                )
-            };
+            if self.total_sent.is_multiple_of(1_000_000) {
+                trace!(sent = self.total_sent, "client progress");
+            }
+        }
 
-            self.writer.write_all(header_bytes).await?;
-            self.writer.write_all(&self.batch).await?;
-            self.writer.flush().await?;
+        Ok(())
+    }
 
-            self.batch.clear();
-            self.batch_count = 0;
+    /// pay for a message of `len` bytes out of the granted credit
+    async fn reserve(&mut self, len: u64) -> Result<(), NetworkError> {
+        let Some(credit) = self.credit.as_ref() else {
+            return Ok(());
+        };
+        if len > credit.window as u64 {
+            return Err(BrokerError::MessageTooLarge.into());
+        }
 
-            if self.total_sent % 1_000_000 == 0 {
-                eprintln!(
-                    "DEBUG: Client sent {} million messages",
-                    self.total_sent / 1_000_000
-                );
+        if credit.available < len {
+            // the batch is paid for already, and the broker can't give credit
+            // back for messages it never got
+            self.flush().await?;
+            self.take_ready().await?;
+        }
+        while let Some(credit) = self.credit.as_ref().filter(|c| c.available < len) {
+            if credit.backpressure == Backpressure::Fail {
+                return Err(NetworkError::WouldBlock);
             }
+            let frame = self.next_frame().await;
+            self.on_grant(frame)?;
         }
 
+        if let Some(credit) = self.credit.as_mut() {
+            credit.available -= len;
+        }
         Ok(())
     }
 
+    /// the broker only ever sends producers grants and heartbeats, or an error before closing
+    fn on_grant(&mut self, frame: Option<Result<Frame, NetworkError>>) -> Result<(), NetworkError> {
+        match frame {
+            Some(Ok(Frame::Heartbeat)) => Ok(()),
+            Some(Ok(Frame::Grant { bytes })) => {
+                if let Some(credit) = self.credit.as_mut() {
+                    credit.available += bytes as u64;
+                }
+                Ok(())
+            }
+            Some(Ok(Frame::Error { code, message })) => Err(NetworkError::Remote { code, message }),
+            Some(Ok(_)) => Err(NetworkError::InvalidFrame("unexpected frame")),
+            Some(Err(e)) => Err(e),
+            None => Err(NetworkError::Closed),
+        }
+    }
+
     pub async fn flush(&mut self) -> Result<(), NetworkError> {
         if self.batch_count > 0 {
-            let msg_size = self.batch.len() / self.batch_count as usize;
-            let header = MessageHeader {
-                size: msg_size as u32,
-                batch_size: self.batch_count,
+            let payload = self.batch.split().freeze();
+            let topic = self.batch_topic.clone();
+            let sent = match Batch::new(
+                self.batch_msg_size as u32,
+                self.batch_count,
+                payload.clone(),
+            ) {
+                Ok(batch) => self.send_frame(Frame::Publish { topic, batch }).await,
+                Err(e) => Err(e),
             };
+            if let Err(e) = sent {
+                // still batched, for the next flush to try again
+                self.batch.extend_from_slice(&payload);
+                return Err(e);
+            }
+            self.batch_count = 0;
+        }
+        Ok(())
+    }
 
-            let header_bytes = unsafe {
-                std::slice::from_raw_parts(
-                    &header as *const _ as *const u8,
-                    std::mem::size_of::<MessageHeader>(),
-                )
-            };
+    /// create `topic` on the broker if it does not exist yet
+    pub async fn declare_topic(&mut self, topic: &str) -> Result<TopicInfo, NetworkError> {
+        let mut topics = self
+            .request(Frame::Declare {
+                topic: topic.to_string(),
+            })
+            .await?;
+        topics
+            .pop()
+            .ok_or(NetworkError::InvalidFrame("empty declare response"))
+    }
 
-            self.writer.write_all(header_bytes).await?;
-            self.writer.write_all(&self.batch).await?;
-            self.writer.flush().await?;
+    pub async fn list_topics(&mut self) -> Result<Vec<TopicInfo>, NetworkError> {
+        self.request(Frame::ListTopics).await
+    }
 
-            self.batch.clear();
-            self.batch_count = 0;
+    async fn request(&mut self, frame: Frame) -> Result<Vec<TopicInfo>, NetworkError> {
+        // keep ordering with anything still batched
+        self.flush().await?;
+        self.send_frame(frame).await?;
+
+        loop {
+            match self.next_frame().await {
+                Some(Ok(Frame::Topics(topics))) => return Ok(topics),
+                grant @ Some(Ok(Frame::Grant { .. })) => self.on_grant(grant)?,
+                Some(Ok(Frame::Error { code, message })) => {
+                    return Err(NetworkError::Remote { code, message })
+                }
+                Some(Ok(_)) => return Err(NetworkError::InvalidFrame("unexpected response frame")),
+                Some(Err(e)) => return Err(e),
+                None => return Err(NetworkError::Closed),
+            }
+        }
+    }
+}
+
+/// send a heartbeat every `period` while the client isn't using the connection
+/// itself, taking in the broker's. ends with the client or the connection
+async fn heartbeats(link: Weak<Mutex<Link>>, period: Duration) {
+    let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticks.tick().await;
+        let Some(link) = link.upgrade() else {
+            return;
+        };
+        // a client in the middle of a call is talking to the broker already
+        let Ok(mut link) = link.try_lock() else {
+            continue;
+        };
+        while let Some(frame) = link.framed.next().now_or_never() {
+            match frame {
+                Some(Ok(Frame::Heartbeat)) => {}
+                Some(Ok(frame)) => link.unread.push_back(Some(Ok(frame))),
+                // left for the client to find
+                closed => {
+                    link.unread.push_back(closed);
+                    return;
+                }
+            }
+        }
+        if link.framed.send(Frame::Heartbeat).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// the client side of the authentication handshake, see `Frame::Auth`
+pub(crate) async fn authenticate<F>(
+    framed: &mut F,
+    credential: &Credential,
+) -> Result<String, NetworkError>
+where
+    F: futures_util::Stream<Item = Result<Frame, NetworkError>>
+        + Sink<Frame, Error = NetworkError>
+        + Unpin,
+{
+    let proof = match credential {
+        Credential::Token { token, .. } => Bytes::copy_from_slice(token.as_bytes()),
+        Credential::Hmac { .. } => Bytes::new(),
+    };
+    framed
+        .send(Frame::Auth {
+            mechanism: credential.mechanism(),
+            principal: credential.principal().to_string(),
+            proof,
+        })
+        .await?;
+
+    loop {
+        match framed.next().await {
+            Some(Ok(Frame::Authenticated { principal })) => {
+                debug!(%principal, "authenticated");
+                return Ok(principal);
+            }
+            Some(Ok(Frame::Challenge { nonce })) => {
+                let Credential::Hmac { principal, secret } = credential else {
+                    return Err(NetworkError::InvalidFrame("challenge for a token"));
+                };
+                framed
+                    .send(Frame::Auth {
+                        mechanism: Mechanism::Hmac,
+                        principal: principal.clone(),
+                        proof: auth::sign(secret, &nonce).into(),
+                    })
+                    .await?;
+            }
+            Some(Ok(Frame::Heartbeat)) => {}
+            Some(Ok(Frame::Error { code, message })) => {
+                return Err(NetworkError::Remote { code, message })
+            }
+            Some(Ok(_)) => return Err(NetworkError::InvalidFrame("unexpected response frame")),
+            Some(Err(e)) => return Err(e),
+            None => return Err(NetworkError::Closed),
         }
-        Ok(())
     }
 }
@@ -0,0 +1,697 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::error::NetworkError;
+use crate::net::message::{FrameHeader, MessageHeader, PROTOCOL_VERSION};
+use crate::topic::{StartPosition, TopicId, TopicInfo, TopicRef, MAX_TOPIC_NAME_LEN};
+use crate::{BATCH_SIZE, BUFFER_CHUNK};
+
+/// frame kind tags, the first byte of every `FrameHeader`
+pub mod kind {
+    pub const PUBLISH: u8 = 0x01;
+    pub const DECLARE: u8 = 0x02;
+    pub const LIST_TOPICS: u8 = 0x03;
+    pub const SUBSCRIBE: u8 = 0x04;
+    pub const CREDIT: u8 = 0x05;
+    pub const ACK: u8 = 0x06;
+    pub const FLOW_CONTROL: u8 = 0x07;
+    pub const HEARTBEAT: u8 = 0x08;
+    pub const AUTH: u8 = 0x09;
+    pub const TOPICS: u8 = 0x81;
+    pub const SUBSCRIBED: u8 = 0x82;
+    pub const DELIVER: u8 = 0x83;
+    pub const GRANT: u8 = 0x84;
+    pub const CHALLENGE: u8 = 0x85;
+    pub const AUTHENTICATED: u8 = 0x86;
+    pub const ERROR: u8 = 0xff;
+}
+
+/// codes carried by `Frame::Error`
+pub mod error_code {
+    pub const INVALID_FRAME: u16 = 1;
+    pub const UNKNOWN_TOPIC: u16 = 2;
+    pub const INVALID_TOPIC: u16 = 3;
+    pub const TOO_MANY_TOPICS: u16 = 4;
+    pub const MESSAGE_TOO_LARGE: u16 = 5;
+    pub const OFFSET_OUT_OF_RANGE: u16 = 6;
+    pub const CREDIT_EXCEEDED: u16 = 7;
+    pub const OVERLOADED: u16 = 8;
+    pub const QUOTA_EXCEEDED: u16 = 9;
+    pub const UNAUTHENTICATED: u16 = 10;
+    pub const FORBIDDEN: u16 = 11;
+    pub const UNSUPPORTED_VERSION: u16 = 12;
+    pub const INVALID_OFFSET: u16 = 13;
+    pub const INTERNAL: u16 = 0xffff;
+}
+
+const TOPIC_BY_ID: u8 = 0;
+const TOPIC_BY_NAME: u8 = 1;
+
+const START_EARLIEST: u8 = 0;
+const START_LATEST: u8 = 1;
+const START_OFFSET: u8 = 2;
+
+const MECHANISM_TOKEN: u8 = 0;
+const MECHANISM_HMAC: u8 = 1;
+
+const ACK_THROUGH: u8 = 0;
+const ACK_OFFSETS: u8 = 1;
+
+/// `offset: u64 | len: u32` in front of every record of a delivery
+const RECORD_PREFIX_LEN: usize = 12;
+
+/// a batch of equally sized messages, as carried by a single frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Batch {
+    msg_size: u32,
+    count: u32,
+    payload: Bytes,
+}
+
+impl Batch {
+    /// `payload` must hold exactly `count` messages of `msg_size` bytes each
+    pub fn new(msg_size: u32, count: u32, payload: Bytes) -> Result<Self, NetworkError> {
+        let expected = msg_size as usize * count as usize;
+        if payload.len() != expected {
+            return Err(NetworkError::InvalidFrame("batch payload length mismatch"));
+        }
+        Ok(Self {
+            msg_size,
+            count,
+            payload,
+        })
+    }
+
+    #[inline]
+    pub fn msg_size(&self) -> u32 {
+        self.msg_size
+    }
+
+    #[inline]
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    #[inline]
+    pub fn payload(&self) -> &Bytes {
+        &self.payload
+    }
+
+    /// iterate over the individual messages in the batch
+    #[inline]
+    pub fn messages(&self) -> impl Iterator<Item = &[u8]> {
+        // chunks_exact panics on 0, and a zero sized message has nothing to yield anyway
+        let size = (self.msg_size as usize).max(1);
+        self.payload.chunks_exact(size).take(self.count as usize)
+    }
+}
+
+/// a message delivered to a subscriber, with the offset to resume or ack from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub offset: u64,
+    pub payload: Bytes,
+}
+
+/// a run of records from one topic, as carried by a single frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delivery {
+    topic: TopicId,
+    count: u32,
+    records: Bytes,
+}
+
+impl Delivery {
+    #[inline]
+    pub fn topic(&self) -> TopicId {
+        self.topic
+    }
+
+    #[inline]
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    /// iterate over the records, sharing the frame buffer
+    pub fn records(&self) -> impl Iterator<Item = Record> + '_ {
+        let mut rest = self.records.clone();
+        (0..self.count).map(move |_| {
+            let offset = rest.get_u64_le();
+            let len = rest.get_u32_le() as usize;
+            Record {
+                offset,
+                payload: rest.split_to(len),
+            }
+        })
+    }
+}
+
+/// builds a `Delivery` one record at a time
+pub struct DeliveryBuilder {
+    topic: TopicId,
+    count: u32,
+    records: BytesMut,
+}
+
+impl DeliveryBuilder {
+    pub fn new(topic: TopicId) -> Self {
+        Self {
+            topic,
+            count: 0,
+            records: BytesMut::new(),
+        }
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    /// bytes buffered so far
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// `fill` appends the payload of the record at `offset` and returns its result
+    pub fn push_with<T, E>(
+        &mut self,
+        offset: u64,
+        fill: impl FnOnce(&mut BytesMut) -> Result<T, E>,
+    ) -> Result<T, E> {
+        let start = self.records.len();
+        self.records.put_u64_le(offset);
+        self.records.put_u32_le(0);
+        match fill(&mut self.records) {
+            Ok(value) => {
+                let len = (self.records.len() - start - RECORD_PREFIX_LEN) as u32;
+                self.records[start + 8..start + RECORD_PREFIX_LEN]
+                    .copy_from_slice(&len.to_le_bytes());
+                self.count += 1;
+                Ok(value)
+            }
+            Err(e) => {
+                self.records.truncate(start);
+                Err(e)
+            }
+        }
+    }
+
+    pub fn push(&mut self, offset: u64, payload: &[u8]) {
+        let _ = self.push_with(offset, |dst| {
+            dst.put_slice(payload);
+            Ok::<_, ()>(())
+        });
+    }
+
+    pub fn finish(self) -> Delivery {
+        Delivery {
+            topic: self.topic,
+            count: self.count,
+            records: self.records.freeze(),
+        }
+    }
+}
+
+/// how a client proves who it is, see `Frame::Auth`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mechanism {
+    /// the proof is the principal's pre-shared token
+    Token,
+    /// the broker answers with a `Challenge`, the proof is HMAC-SHA256 of its
+    /// nonce keyed with the principal's secret
+    Hmac,
+}
+
+/// which delivered records a subscriber is done with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Ack {
+    /// cumulative: every record at or below the offset
+    Through(u64),
+    /// individual records by offset
+    Offsets(Vec<u64>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    /// producer -> broker: append a batch to a topic
+    Publish { topic: TopicRef, batch: Batch },
+    /// producer -> broker: create a topic if missing, answered with `Topics`
+    Declare { topic: String },
+    /// client -> broker: answered with `Topics`
+    ListTopics,
+    /// subscriber -> broker: stream `topic` from `start`, at most `credit` messages ahead.
+    /// answered with `Subscribed`, then `Deliver` frames.
+    ///
+    /// with a non-zero `ack_timeout_ms` records must be acked, are redelivered when the
+    /// timeout passes first, and at most `max_in_flight` are unacked at a time.
+    /// with zero, records count as acked once sent
+    Subscribe {
+        topic: TopicRef,
+        start: StartPosition,
+        credit: u32,
+        ack_timeout_ms: u32,
+        max_in_flight: u32,
+    },
+    /// subscriber -> broker: allow `messages` more deliveries
+    Credit { messages: u32 },
+    /// subscriber -> broker
+    Ack(Ack),
+    /// producer -> broker: only publish against credit from now on. answered
+    /// with a `Grant` of the whole window, later grants follow as the rings drain
+    FlowControl,
+    /// client -> broker: the first frame on a broker that requires authentication.
+    /// answered with `Authenticated`, or an error before the broker closes. for
+    /// `Mechanism::Hmac` the first `Auth` has an empty proof and gets a `Challenge`,
+    /// a second one carries the proof
+    Auth {
+        mechanism: Mechanism,
+        principal: String,
+        proof: Bytes,
+    },
+    /// either way: the sender is alive, see `ServerConfig::heartbeat_interval`
+    Heartbeat,
+    /// broker -> client
+    Topics(Vec<TopicInfo>),
+    /// broker -> subscriber: the subscription starts at `offset`
+    Subscribed { topic: TopicId, offset: u64 },
+    /// broker -> subscriber
+    Deliver(Delivery),
+    /// broker -> producer: `bytes` more message bytes may be published
+    Grant { bytes: u32 },
+    /// broker -> client: sign `nonce` to authenticate
+    Challenge { nonce: Bytes },
+    /// broker -> client: the connection acts as `principal` from now on
+    Authenticated { principal: String },
+    /// broker -> client: the request was rejected
+    Error { code: u16, message: String },
+}
+
+impl Frame {
+    pub fn kind(&self) -> u8 {
+        match self {
+            Frame::Publish { .. } => kind::PUBLISH,
+            Frame::Declare { .. } => kind::DECLARE,
+            Frame::ListTopics => kind::LIST_TOPICS,
+            Frame::Subscribe { .. } => kind::SUBSCRIBE,
+            Frame::Credit { .. } => kind::CREDIT,
+            Frame::Ack(_) => kind::ACK,
+            Frame::FlowControl => kind::FLOW_CONTROL,
+            Frame::Heartbeat => kind::HEARTBEAT,
+            Frame::Auth { .. } => kind::AUTH,
+            Frame::Challenge { .. } => kind::CHALLENGE,
+            Frame::Authenticated { .. } => kind::AUTHENTICATED,
+            Frame::Topics(_) => kind::TOPICS,
+            Frame::Subscribed { .. } => kind::SUBSCRIBED,
+            Frame::Deliver(_) => kind::DELIVER,
+            Frame::Grant { .. } => kind::GRANT,
+            Frame::Error { .. } => kind::ERROR,
+        }
+    }
+}
+
+/// `Encoder`/`Decoder` for the broker wire format.
+///
+/// Wrap any `AsyncRead`/`AsyncWrite` (tcp, unix, `tokio::io::duplex`) in a
+/// `tokio_util::codec::Framed` to get typed frames.
+#[derive(Debug, Clone)]
+pub struct BrokerCodec {
+    max_frame_len: usize,
+}
+
+impl BrokerCodec {
+    pub fn new() -> Self {
+        Self {
+            max_frame_len: BUFFER_CHUNK * BATCH_SIZE,
+        }
+    }
+
+    /// largest frame body (in bytes, header excluded) the decoder will accept
+    pub fn with_max_frame_len(max_frame_len: usize) -> Self {
+        Self { max_frame_len }
+    }
+
+    pub fn max_frame_len(&self) -> usize {
+        self.max_frame_len
+    }
+}
+
+impl Default for BrokerCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder for BrokerCodec {
+    type Item = Frame;
+    type Error = NetworkError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, NetworkError> {
+        if src.len() < FrameHeader::LEN {
+            return Ok(None);
+        }
+
+        let header = FrameHeader::from_le_bytes(src[..FrameHeader::LEN].try_into().unwrap());
+        if header.version != PROTOCOL_VERSION {
+            return Err(NetworkError::UnsupportedVersion(header.version));
+        }
+        let body_len = header.len as usize;
+        if body_len > self.max_frame_len {
+            return Err(NetworkError::FrameTooLarge {
+                max: self.max_frame_len,
+            });
+        }
+
+        let frame_len = FrameHeader::LEN + body_len;
+        if src.len() < frame_len {
+            src.reserve(frame_len - src.len());
+            return Ok(None);
+        }
+
+        src.advance(FrameHeader::LEN);
+        let body = src.split_to(body_len).freeze();
+        decode_body(header.kind, body).map(Some)
+    }
+}
+
+impl Encoder<Frame> for BrokerCodec {
+    type Error = NetworkError;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> Result<(), NetworkError> {
+        let start = dst.len();
+        dst.put_slice(&[0u8; FrameHeader::LEN]);
+
+        if let Err(e) = encode_body(&frame, dst) {
+            dst.truncate(start);
+            return Err(e);
+        }
+
+        let body_len = dst.len() - start - FrameHeader::LEN;
+        if body_len > self.max_frame_len {
+            dst.truncate(start);
+            return Err(NetworkError::FrameTooLarge {
+                max: self.max_frame_len,
+            });
+        }
+
+        let header = FrameHeader {
+            kind: frame.kind(),
+            version: PROTOCOL_VERSION,
+            len: body_len as u32,
+        };
+        dst[start..start + FrameHeader::LEN].copy_from_slice(&header.to_le_bytes());
+        Ok(())
+    }
+}
+
+fn encode_body(frame: &Frame, dst: &mut BytesMut) -> Result<(), NetworkError> {
+    match frame {
+        Frame::Publish { topic, batch } => {
+            put_topic_ref(topic, dst)?;
+            let header = MessageHeader {
+                size: batch.msg_size,
+                batch_size: batch.count,
+            };
+            dst.reserve(MessageHeader::LEN + batch.payload.len());
+            dst.put_slice(&header.to_le_bytes());
+            dst.put_slice(&batch.payload);
+        }
+        Frame::Declare { topic } => put_short_str(topic, dst)?,
+        Frame::ListTopics => {}
+        Frame::Subscribe {
+            topic,
+            start,
+            credit,
+            ack_timeout_ms,
+            max_in_flight,
+        } => {
+            put_topic_ref(topic, dst)?;
+            let (tag, offset) = match start {
+                StartPosition::Earliest => (START_EARLIEST, 0),
+                StartPosition::Latest => (START_LATEST, 0),
+                StartPosition::Offset(offset) => (START_OFFSET, *offset),
+            };
+            dst.put_u8(tag);
+            dst.put_u64_le(offset);
+            dst.put_u32_le(*credit);
+            dst.put_u32_le(*ack_timeout_ms);
+            dst.put_u32_le(*max_in_flight);
+        }
+        Frame::Credit { messages } => dst.put_u32_le(*messages),
+        Frame::Ack(Ack::Through(offset)) => {
+            dst.put_u8(ACK_THROUGH);
+            dst.put_u32_le(1);
+            dst.put_u64_le(*offset);
+        }
+        Frame::Ack(Ack::Offsets(offsets)) => {
+            dst.put_u8(ACK_OFFSETS);
+            dst.put_u32_le(offsets.len() as u32);
+            for offset in offsets {
+                dst.put_u64_le(*offset);
+            }
+        }
+        Frame::FlowControl | Frame::Heartbeat => {}
+        Frame::Auth {
+            mechanism,
+            principal,
+            proof,
+        } => {
+            dst.put_u8(match mechanism {
+                Mechanism::Token => MECHANISM_TOKEN,
+                Mechanism::Hmac => MECHANISM_HMAC,
+            });
+            put_short_str(principal, dst)?;
+            dst.put_slice(proof);
+        }
+        Frame::Challenge { nonce } => dst.put_slice(nonce),
+        Frame::Authenticated { principal } => put_short_str(principal, dst)?,
+        Frame::Grant { bytes } => dst.put_u32_le(*bytes),
+        Frame::Subscribed { topic, offset } => {
+            dst.put_u32_le(*topic);
+            dst.put_u64_le(*offset);
+        }
+        Frame::Deliver(delivery) => {
+            dst.reserve(8 + delivery.records.len());
+            dst.put_u32_le(delivery.topic);
+            dst.put_u32_le(delivery.count);
+            dst.put_slice(&delivery.records);
+        }
+        Frame::Topics(topics) => {
+            dst.put_u32_le(topics.len() as u32);
+            for info in topics {
+                dst.put_u32_le(info.id);
+                dst.put_u64_le(info.capacity);
+                dst.put_u64_le(info.used);
+                put_short_str(&info.name, dst)?;
+            }
+        }
+        Frame::Error { code, message } => {
+            dst.put_u16_le(*code);
+            let message = &message.as_bytes()[..message.len().min(u16::MAX as usize)];
+            dst.put_u16_le(message.len() as u16);
+            dst.put_slice(message);
+        }
+    }
+    Ok(())
+}
+
+fn decode_body(kind: u8, mut body: Bytes) -> Result<Frame, NetworkError> {
+    let frame = match kind {
+        kind::PUBLISH => {
+            let topic = get_topic_ref(&mut body)?;
+            ensure(&body, MessageHeader::LEN)?;
+            let header = MessageHeader::from_le_bytes(
+                body.split_to(MessageHeader::LEN)[..].try_into().unwrap(),
+            );
+            Frame::Publish {
+                topic,
+                batch: Batch::new(header.size, header.batch_size, body.split_off(0))?,
+            }
+        }
+        kind::DECLARE => Frame::Declare {
+            topic: get_short_str(&mut body)?,
+        },
+        kind::LIST_TOPICS => Frame::ListTopics,
+        kind::SUBSCRIBE => {
+            let topic = get_topic_ref(&mut body)?;
+            ensure(&body, 21)?;
+            let tag = body.get_u8();
+            let offset = body.get_u64_le();
+            let start = match tag {
+                START_EARLIEST => StartPosition::Earliest,
+                START_LATEST => StartPosition::Latest,
+                START_OFFSET => StartPosition::Offset(offset),
+                _ => return Err(NetworkError::InvalidFrame("unknown start position tag")),
+            };
+            Frame::Subscribe {
+                topic,
+                start,
+                credit: body.get_u32_le(),
+                ack_timeout_ms: body.get_u32_le(),
+                max_in_flight: body.get_u32_le(),
+            }
+        }
+        kind::ACK => {
+            ensure(&body, 5)?;
+            let tag = body.get_u8();
+            let count = body.get_u32_le() as usize;
+            ensure(&body, count.saturating_mul(8))?;
+            let offsets: Vec<u64> = (0..count).map(|_| body.get_u64_le()).collect();
+            match tag {
+                ACK_THROUGH if count == 1 => Frame::Ack(Ack::Through(offsets[0])),
+                ACK_OFFSETS => Frame::Ack(Ack::Offsets(offsets)),
+                _ => return Err(NetworkError::InvalidFrame("malformed ack")),
+            }
+        }
+        kind::CREDIT => {
+            ensure(&body, 4)?;
+            Frame::Credit {
+                messages: body.get_u32_le(),
+            }
+        }
+        kind::FLOW_CONTROL => Frame::FlowControl,
+        kind::HEARTBEAT => Frame::Heartbeat,
+        kind::AUTH => {
+            ensure(&body, 1)?;
+            let mechanism = match body.get_u8() {
+                MECHANISM_TOKEN => Mechanism::Token,
+                MECHANISM_HMAC => Mechanism::Hmac,
+                _ => return Err(NetworkError::InvalidFrame("unknown auth mechanism")),
+            };
+            Frame::Auth {
+                mechanism,
+                principal: get_short_str(&mut body)?,
+                proof: body.split_off(0),
+            }
+        }
+        kind::CHALLENGE => Frame::Challenge {
+            nonce: body.split_off(0),
+        },
+        kind::AUTHENTICATED => Frame::Authenticated {
+            principal: get_short_str(&mut body)?,
+        },
+        kind::GRANT => {
+            ensure(&body, 4)?;
+            Frame::Grant {
+                bytes: body.get_u32_le(),
+            }
+        }
+        kind::SUBSCRIBED => {
+            ensure(&body, 12)?;
+            Frame::Subscribed {
+                topic: body.get_u32_le(),
+                offset: body.get_u64_le(),
+            }
+        }
+        kind::DELIVER => {
+            ensure(&body, 8)?;
+            let topic = body.get_u32_le();
+            let count = body.get_u32_le();
+            // validate the record layout once, so `Delivery::records` can't read out of bounds
+            let mut rest = &body[..];
+            for _ in 0..count {
+                if rest.len() < RECORD_PREFIX_LEN {
+                    return Err(NetworkError::InvalidFrame("truncated delivery record"));
+                }
+                let len = u32::from_le_bytes(rest[8..12].try_into().unwrap()) as usize;
+                rest = rest
+                    .get(RECORD_PREFIX_LEN + len..)
+                    .ok_or(NetworkError::InvalidFrame("truncated delivery record"))?;
+            }
+            let records_len = body.len() - rest.len();
+            Frame::Deliver(Delivery {
+                topic,
+                count,
+                records: body.split_to(records_len),
+            })
+        }
+        kind::TOPICS => {
+            ensure(&body, 4)?;
+            let count = body.get_u32_le() as usize;
+            let mut topics = Vec::with_capacity(count.min(body.len() / 21));
+            for _ in 0..count {
+                ensure(&body, 20)?;
+                let id = body.get_u32_le();
+                let capacity = body.get_u64_le();
+                let used = body.get_u64_le();
+                let name = get_short_str(&mut body)?;
+                topics.push(TopicInfo {
+                    id,
+                    name,
+                    capacity,
+                    used,
+                });
+            }
+            Frame::Topics(topics)
+        }
+        kind::ERROR => {
+            ensure(&body, 4)?;
+            let code = body.get_u16_le();
+            let len = body.get_u16_le() as usize;
+            ensure(&body, len)?;
+            let message = String::from_utf8_lossy(&body.split_to(len)).into_owned();
+            Frame::Error { code, message }
+        }
+        other => return Err(NetworkError::UnknownFrameKind(other)),
+    };
+
+    if !body.is_empty() {
+        return Err(NetworkError::InvalidFrame(
+            "trailing bytes after frame body",
+        ));
+    }
+    Ok(frame)
+}
+
+#[inline]
+fn ensure(body: &Bytes, len: usize) -> Result<(), NetworkError> {
+    if body.len() < len {
+        return Err(NetworkError::InvalidFrame("truncated frame body"));
+    }
+    Ok(())
+}
+
+fn put_short_str(s: &str, dst: &mut BytesMut) -> Result<(), NetworkError> {
+    if s.len() > MAX_TOPIC_NAME_LEN {
+        return Err(NetworkError::InvalidFrame("string longer than 255 bytes"));
+    }
+    dst.put_u8(s.len() as u8);
+    dst.put_slice(s.as_bytes());
+    Ok(())
+}
+
+fn get_short_str(body: &mut Bytes) -> Result<String, NetworkError> {
+    ensure(body, 1)?;
+    let len = body.get_u8() as usize;
+    ensure(body, len)?;
+    String::from_utf8(body.split_to(len).to_vec())
+        .map_err(|_| NetworkError::InvalidFrame("string is not utf-8"))
+}
+
+fn put_topic_ref(topic: &TopicRef, dst: &mut BytesMut) -> Result<(), NetworkError> {
+    match topic {
+        TopicRef::Id(id) => {
+            dst.put_u8(TOPIC_BY_ID);
+            dst.put_u32_le(*id);
+        }
+        TopicRef::Name(name) => {
+            dst.put_u8(TOPIC_BY_NAME);
+            put_short_str(name, dst)?;
+        }
+    }
+    Ok(())
+}
+
+fn get_topic_ref(body: &mut Bytes) -> Result<TopicRef, NetworkError> {
+    ensure(body, 1)?;
+    match body.get_u8() {
+        TOPIC_BY_ID => {
+            ensure(body, 4)?;
+            Ok(TopicRef::Id(body.get_u32_le()))
+        }
+        TOPIC_BY_NAME => Ok(TopicRef::Name(get_short_str(body)?)),
+        _ => Err(NetworkError::InvalidFrame("unknown topic reference tag")),
+    }
+}
@@ -0,0 +1,143 @@
+use crate::error::BrokerError;
+use crate::topic::Topic;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+/// flow control state of a producer connection.
+///
+/// published bytes are paid for with credit, and the credit comes back once the
+/// records were released from their ring, i.e. every consumer is past them
+pub(crate) struct ProducerCredit {
+    /// granted and not yet published against
+    available: u64,
+    /// published batches still in a ring: topic, where the batch ends, bytes
+    outstanding: VecDeque<(Arc<Topic>, Position, u64)>,
+}
+
+/// where a published batch ends
+pub(crate) enum Position {
+    /// in the topic's ring, at this head
+    Ring(u64),
+    /// in a shard's ring, until the merge moved every record stamped below `stamp`
+    /// into the topic's ring
+    Merging { merged: Arc<Merged>, stamp: u64 },
+}
+
+/// how far the merge point of a sharded topic got, see `ShardConfig`
+#[derive(Default)]
+pub(crate) struct Merged {
+    /// records stamped below this are in the topic's ring
+    stamps: AtomicU64,
+    progressed: Notify,
+}
+
+impl Merged {
+    /// every record stamped below `stamps` is in the topic's ring
+    pub(crate) fn advance(&self, stamps: u64) {
+        self.stamps.store(stamps, Ordering::Release);
+    }
+
+    /// wake the credit waiting on `advance`, once per merged batch
+    pub(crate) fn notify(&self) {
+        self.progressed.notify_waiters();
+    }
+
+    fn past(&self, stamp: u64) -> bool {
+        self.stamps.load(Ordering::Acquire) >= stamp
+    }
+
+    async fn wait_past(&self, stamp: u64) {
+        loop {
+            let progressed = self.progressed.notified();
+            tokio::pin!(progressed);
+            progressed.as_mut().enable();
+            if self.past(stamp) {
+                return;
+            }
+            progressed.await;
+        }
+    }
+}
+
+impl ProducerCredit {
+    pub(crate) fn new(window: u32) -> Self {
+        Self {
+            available: window as u64,
+            outstanding: VecDeque::new(),
+        }
+    }
+
+    /// pay for a batch of `bytes` before it is published
+    pub(crate) fn spend(&mut self, bytes: u64) -> Result<(), BrokerError> {
+        if bytes > self.available {
+            return Err(BrokerError::CreditExceeded);
+        }
+        self.available -= bytes;
+        Ok(())
+    }
+
+    /// a batch paid for with `spend` was published, up to `position`
+    pub(crate) fn published(&mut self, topic: Arc<Topic>, position: Position, bytes: u64) {
+        // consecutive batches to one topic come back together
+        if let Some((last, last_position, last_bytes)) = self.outstanding.back_mut() {
+            let joined = Arc::ptr_eq(last, &topic)
+                && match (last_position, &position) {
+                    (Position::Ring(last_head), Position::Ring(head)) => {
+                        *last_head = *head;
+                        true
+                    }
+                    (
+                        Position::Merging {
+                            stamp: last_stamp, ..
+                        },
+                        Position::Merging { stamp, .. },
+                    ) => {
+                        *last_stamp = *stamp;
+                        true
+                    }
+                    _ => false,
+                };
+            if joined {
+                *last_bytes += bytes;
+                return;
+            }
+        }
+        self.outstanding.push_back((topic, position, bytes));
+    }
+
+    /// resolves when the oldest outstanding batch left its ring or was merged into
+    /// the topic's, never if there is none
+    pub(crate) async fn released(credit: &Option<ProducerCredit>) {
+        match credit.as_ref().and_then(|c| c.outstanding.front()) {
+            Some((topic, Position::Ring(head), _)) => topic.wait_released(*head).await,
+            Some((_, Position::Merging { merged, stamp }, _)) => merged.wait_past(*stamp).await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// take back the credit of every batch released so far, to be granted again
+    pub(crate) fn reclaim(&mut self) -> u64 {
+        let mut reclaimed = 0;
+        self.outstanding.retain_mut(|(topic, position, bytes)| {
+            let head = match position {
+                Position::Ring(head) => *head,
+                Position::Merging { merged, stamp } if merged.past(*stamp) => {
+                    // somewhere below the head now, which will do
+                    let head = topic.ring().head();
+                    *position = Position::Ring(head);
+                    head
+                }
+                Position::Merging { .. } => return true,
+            };
+            let released = topic.ring().tail() >= head;
+            if released {
+                reclaimed += *bytes;
+            }
+            !released
+        });
+        self.available += reclaimed;
+        reclaimed
+    }
+}
@@ -0,0 +1,141 @@
+use crate::error::NetworkError;
+use crate::net::codec::{BrokerCodec, Frame};
+use crate::net::message::FrameHeader;
+use bytes::{BufMut, BytesMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio_util::codec::Decoder;
+
+/// read buffer every connection starts with, and shrinks back to after a large frame
+pub(crate) const INITIAL_READ_BUFFER: usize = 64 * 1024;
+
+/// the read buffer a connection with a budget of `limit` starts with
+pub(crate) fn initial_read_buffer(limit: usize) -> usize {
+    INITIAL_READ_BUFFER.min(limit)
+}
+
+/// the broker wide limit on connection buffers, see `ServerConfig::memory_limit`
+#[derive(Debug)]
+pub(crate) struct MemoryLimit {
+    limit: usize,
+    used: AtomicUsize,
+}
+
+impl MemoryLimit {
+    pub(crate) fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    /// bytes held by connections right now
+    pub(crate) fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    fn try_reserve(&self, bytes: usize) -> bool {
+        self.used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                used.checked_add(bytes).filter(|total| *total <= self.limit)
+            })
+            .is_ok()
+    }
+
+    fn release(&self, bytes: usize) {
+        self.used.fetch_sub(bytes, Ordering::AcqRel);
+    }
+}
+
+/// what one connection may buffer, reserved from the broker wide `MemoryLimit`
+/// as its read buffer grows and given back as it shrinks or the connection closes
+#[derive(Debug)]
+pub(crate) struct Budget {
+    global: Arc<MemoryLimit>,
+    /// `ServerConfig::connection_memory`
+    limit: usize,
+    reserved: usize,
+}
+
+impl Budget {
+    /// reserve the initial read buffer of a new connection. fails if the broker is
+    /// over its limit already
+    pub(crate) fn new(global: Arc<MemoryLimit>, limit: usize) -> Result<Self, NetworkError> {
+        let reserved = initial_read_buffer(limit);
+        if !global.try_reserve(reserved) {
+            return Err(NetworkError::MemoryLimit);
+        }
+        Ok(Self {
+            global,
+            limit,
+            reserved,
+        })
+    }
+
+    /// hold exactly `bytes` from now on
+    fn resize(&mut self, bytes: usize) -> Result<(), NetworkError> {
+        if bytes > self.limit {
+            return Err(NetworkError::FrameTooLarge {
+                max: self.limit.saturating_sub(FrameHeader::LEN),
+            });
+        }
+        if bytes > self.reserved {
+            if !self.global.try_reserve(bytes - self.reserved) {
+                return Err(NetworkError::MemoryLimit);
+            }
+        } else {
+            self.global.release(self.reserved - bytes);
+        }
+        self.reserved = bytes;
+        Ok(())
+    }
+}
+
+impl Drop for Budget {
+    fn drop(&mut self) {
+        self.global.release(self.reserved);
+    }
+}
+
+/// `BrokerCodec` for the server side of a connection: frames only get the buffer
+/// room they need once the budget covers it
+pub(crate) struct BudgetedCodec {
+    codec: BrokerCodec,
+    budget: Budget,
+}
+
+impl BudgetedCodec {
+    pub(crate) fn new(budget: Budget) -> Self {
+        let max_frame_len = budget.limit.saturating_sub(FrameHeader::LEN);
+        Self {
+            codec: BrokerCodec::with_max_frame_len(max_frame_len),
+            budget,
+        }
+    }
+}
+
+impl Decoder for BudgetedCodec {
+    type Item = Frame;
+    type Error = NetworkError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, NetworkError> {
+        if src.len() >= FrameHeader::LEN {
+            let header = FrameHeader::from_le_bytes(src[..FrameHeader::LEN].try_into().unwrap());
+            let frame_len = FrameHeader::LEN + header.len as usize;
+            if frame_len > self.budget.reserved {
+                self.budget.resize(frame_len)?;
+            }
+        }
+
+        let frame = self.codec.decode(src)?;
+        let initial = initial_read_buffer(self.budget.limit);
+        if frame.is_some() && self.budget.reserved > initial && src.len() <= initial {
+            // done with the large frame, the next one may well be small again
+            let mut smaller = BytesMut::with_capacity(initial);
+            smaller.put_slice(src);
+            *src = smaller;
+            self.budget.resize(initial)?;
+        }
+        Ok(frame)
+    }
+}
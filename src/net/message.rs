@@ -2,52 +2,103 @@ use std::collections::hash_map::DefaultHasher;
 use std::hash::Hasher;
 use std::hint::black_box;
 
-pub struct ProcessedMessage {
+use crate::staleness::{now_nanos, ExpiredAction, Staleness};
+
+/// per message header written by producers ahead of the payload.
+///
+/// wire layout (little-endian):
+/// `timestamp: u64 | sequence: u64 | checksum: u32`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PayloadHeader {
     pub timestamp: u64,
     pub sequence: u64,
     pub checksum: u32,
-    pub payload: Vec<u8>,
 }
 
-impl ProcessedMessage {
-    pub fn from_bytes(data: &[u8]) -> Option<Self> {
-        if data.len() < 20 {
-            return None;
+impl PayloadHeader {
+    pub const LEN: usize = 20;
+
+    /// header for `body`, with the checksum filled in
+    pub fn for_body(timestamp: u64, sequence: u64, body: &[u8]) -> Self {
+        Self {
+            timestamp,
+            sequence,
+            checksum: checksum(timestamp, sequence, body),
         }
+    }
+
+    #[inline]
+    pub fn to_le_bytes(&self) -> [u8; Self::LEN] {
+        let mut out = [0u8; Self::LEN];
+        out[0..8].copy_from_slice(&self.timestamp.to_le_bytes());
+        out[8..16].copy_from_slice(&self.sequence.to_le_bytes());
+        out[16..20].copy_from_slice(&self.checksum.to_le_bytes());
+        out
+    }
 
-        let timestamp = u64::from_le_bytes(data[0..8].try_into().ok()?);
-        let sequence = u64::from_le_bytes(data[8..16].try_into().ok()?);
-        let checksum = u32::from_le_bytes(data[16..20].try_into().ok()?);
+    #[inline]
+    pub fn from_le_bytes(bytes: [u8; Self::LEN]) -> Self {
+        Self {
+            timestamp: u64::from_le_bytes(bytes[0..8].try_into().unwrap()),
+            sequence: u64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+            checksum: u32::from_le_bytes(bytes[16..20].try_into().unwrap()),
+        }
+    }
 
-        // validate checksum
-        let mut hasher = DefaultHasher::new();
-        hasher.write_u64(timestamp);
-        hasher.write_u64(sequence);
-        hasher.write(&data[20..]);
-        let computed_hash = hasher.finish() as u32;
+    /// `None` if `data` is shorter than the header
+    #[inline]
+    pub fn read(data: &[u8]) -> Option<Self> {
+        let bytes: [u8; Self::LEN] = data.get(..Self::LEN)?.try_into().ok()?;
+        Some(Self::from_le_bytes(bytes))
+    }
 
-        if computed_hash != checksum {
-            return None;
+    /// split `data` into header and body, checking the checksum
+    pub fn verify(data: &[u8]) -> Result<(Self, &[u8]), &'static str> {
+        let header = Self::read(data).ok_or("shorter than the payload header")?;
+        let body = &data[Self::LEN..];
+        if checksum(header.timestamp, header.sequence, body) != header.checksum {
+            return Err("checksum mismatch");
         }
+        Ok((header, body))
+    }
+}
+
+/// checksum over the header fields and the message body
+pub fn checksum(timestamp: u64, sequence: u64, body: &[u8]) -> u32 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write_u64(timestamp);
+    hasher.write_u64(sequence);
+    hasher.write(body);
+    hasher.finish() as u32
+}
+
+pub struct ProcessedMessage {
+    pub timestamp: u64,
+    pub sequence: u64,
+    pub checksum: u32,
+    pub payload: Vec<u8>,
+}
+
+impl ProcessedMessage {
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        let (header, body) = PayloadHeader::verify(data).ok()?;
 
         Some(ProcessedMessage {
-            timestamp,
-            sequence,
-            checksum,
-            payload: data[20..].to_vec(),
+            timestamp: header.timestamp,
+            sequence: header.sequence,
+            checksum: header.checksum,
+            payload: body.to_vec(),
         })
     }
 
-    pub fn process(&self) -> bool {
-        // verify message is recent (~1s)
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_nanos() as u64;
-
-        let age_nanos = now - self.timestamp;
-        if age_nanos > 1_000_000_000 {
-            return false;
+    /// false if the message expired under `staleness` and isn't to be delivered anyway
+    pub fn process(&self, staleness: Option<Staleness>) -> bool {
+        if let Some(staleness) = staleness {
+            if staleness.action != ExpiredAction::Deliver
+                && staleness.is_expired(self.timestamp, now_nanos())
+            {
+                return false;
+            }
         }
 
         // simulate payload processing
@@ -63,26 +114,70 @@ impl ProcessedMessage {
     }
 }
 
-#[repr(C, packed)]
+/// frame header preceding every batch on the wire.
+///
+/// wire layout (little-endian):
+/// `size: u32 | batch_size: u32`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct MessageHeader {
     pub size: u32,
     pub batch_size: u32,
 }
 
 impl MessageHeader {
+    pub const LEN: usize = 8;
+
     #[inline(always)]
-    pub fn size(&self) -> u32 {
-        unsafe {
-            let ptr = (self as *const MessageHeader).cast::<u8>();
-            std::ptr::read_unaligned(ptr.cast::<u32>())
+    pub fn to_le_bytes(&self) -> [u8; Self::LEN] {
+        let mut out = [0u8; Self::LEN];
+        out[0..4].copy_from_slice(&self.size.to_le_bytes());
+        out[4..8].copy_from_slice(&self.batch_size.to_le_bytes());
+        out
+    }
+
+    #[inline(always)]
+    pub fn from_le_bytes(bytes: [u8; Self::LEN]) -> Self {
+        Self {
+            size: u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+            batch_size: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
         }
     }
+}
+
+/// version of the frame layout, carried by every `FrameHeader`. peers with
+/// another version are turned away instead of misread
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// envelope in front of every frame.
+///
+/// wire layout (little-endian):
+/// `kind: u8 | version: u8 | reserved: [u8; 2] | len: u32`, where `len` is the
+/// body length
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub kind: u8,
+    pub version: u8,
+    pub len: u32,
+}
+
+impl FrameHeader {
+    pub const LEN: usize = 8;
+
+    #[inline(always)]
+    pub fn to_le_bytes(&self) -> [u8; Self::LEN] {
+        let mut out = [0u8; Self::LEN];
+        out[0] = self.kind;
+        out[1] = self.version;
+        out[4..8].copy_from_slice(&self.len.to_le_bytes());
+        out
+    }
 
     #[inline(always)]
-    pub fn batch_size(&self) -> u32 {
-        unsafe {
-            let ptr = (self as *const MessageHeader).cast::<u8>();
-            std::ptr::read_unaligned(ptr.add(4).cast::<u32>())
+    pub fn from_le_bytes(bytes: [u8; Self::LEN]) -> Self {
+        Self {
+            kind: bytes[0],
+            version: bytes[1],
+            len: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
         }
     }
 }
@@ -1,6 +1,20 @@
 pub mod client;
+pub mod codec;
+mod credit;
+mod memory;
 pub mod message;
+mod sequence;
 pub mod server;
+pub mod subscriber;
+mod subscription;
+pub mod tls;
+mod transport;
+#[cfg(feature = "io-uring")]
+mod uring;
 
-pub use client::BrokerClient;
-pub use server::BrokerServer;
+pub use client::{Backpressure, BrokerClient, ClientOptions};
+pub use codec::{Ack, Batch, BrokerCodec, Delivery, Frame, Mechanism, Record};
+pub use server::{BrokerServer, ServerHandle, ShutdownReport};
+pub use subscriber::{BrokerSubscriber, SubscribeOptions};
+pub use tls::ClientTls;
+pub use tokio_rustls::rustls;
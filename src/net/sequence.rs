@@ -0,0 +1,136 @@
+use crate::net::message::PayloadHeader;
+use crate::stats::BrokerStats;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::ops::Range;
+
+/// missing ranges remembered to tell late messages from duplicates, the oldest
+/// are forgotten first
+const MAX_GAPS: usize = 64;
+
+/// how a sequence number compares to what the producer sent before
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Sequenced {
+    /// the next one, or the first of the session
+    InOrder,
+    /// this many sequences were skipped
+    Gap(u64),
+    /// a sequence skipped earlier, arriving late
+    Reordered,
+    /// seen before
+    Duplicate,
+    /// below the gaps still remembered: late or seen before, there is no telling
+    Unknown,
+}
+
+/// the sequence numbers of one producer: a principal across its connections,
+/// or a single connection without authentication
+#[derive(Debug, Default)]
+pub(crate) struct SequenceTracker {
+    /// highest sequence seen
+    last: Option<u64>,
+    /// skipped sequences below `last` that may still arrive
+    missing: VecDeque<Range<u64>>,
+    /// end of the newest gap forgotten for `MAX_GAPS`
+    forgotten: u64,
+}
+
+impl SequenceTracker {
+    /// check the sequence in `msg`'s payload header. messages too short to carry
+    /// one are left to the consumer to reject
+    #[inline]
+    pub(crate) fn track(&mut self, msg: &[u8]) -> Option<Sequenced> {
+        let header = PayloadHeader::read(msg)?;
+        Some(self.next(header.sequence))
+    }
+
+    pub(crate) fn next(&mut self, sequence: u64) -> Sequenced {
+        let Some(last) = self.last else {
+            self.last = Some(sequence);
+            return Sequenced::InOrder;
+        };
+
+        if sequence > last {
+            self.last = Some(sequence);
+            if sequence == last + 1 {
+                return Sequenced::InOrder;
+            }
+            if self.missing.len() == MAX_GAPS {
+                self.forget_oldest();
+            }
+            self.missing.push_back(last + 1..sequence);
+            return Sequenced::Gap(sequence - last - 1);
+        }
+
+        let Some(index) = self.missing.iter().position(|r| r.contains(&sequence)) else {
+            if sequence < self.forgotten {
+                return Sequenced::Unknown;
+            }
+            return Sequenced::Duplicate;
+        };
+        let range = self.missing[index].clone();
+        if range.start == sequence {
+            self.missing[index].start += 1;
+        } else if range.end == sequence + 1 {
+            self.missing[index].end -= 1;
+        } else {
+            // split around the late sequence
+            self.missing[index].end = sequence;
+            self.missing.insert(index + 1, sequence + 1..range.end);
+            if self.missing.len() > MAX_GAPS {
+                self.forget_oldest();
+            }
+        }
+        self.missing.retain(|r| !r.is_empty());
+        Sequenced::Reordered
+    }
+
+    fn forget_oldest(&mut self) {
+        if let Some(range) = self.missing.pop_front() {
+            self.forgotten = self.forgotten.max(range.end);
+        }
+    }
+}
+
+/// the messages of a publish batch that get into the ring, counting what
+/// `SequenceTracker` finds along the way
+pub(crate) struct Admit<'a, I> {
+    pub(crate) messages: I,
+    /// locked per message, the batch may wait for ring space halfway through
+    pub(crate) sequences: &'a Mutex<SequenceTracker>,
+    pub(crate) stats: &'a BrokerStats,
+    pub(crate) reject_duplicates: bool,
+    /// duplicates dropped so far
+    pub(crate) rejected: u64,
+}
+
+impl<'a, 'm, I> Iterator for Admit<'a, I>
+where
+    I: Iterator<Item = &'m [u8]>,
+{
+    type Item = &'m [u8];
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let msg = self.messages.next()?;
+            let tracked = self.sequences.lock().track(msg);
+            match tracked {
+                Some(Sequenced::Gap(skipped)) => {
+                    BrokerStats::add(&self.stats.sequence_gaps, skipped)
+                }
+                Some(Sequenced::Reordered) => BrokerStats::add(&self.stats.sequence_reordered, 1),
+                Some(Sequenced::Unknown) => BrokerStats::add(&self.stats.sequence_unknown, 1),
+                Some(Sequenced::Duplicate) => {
+                    BrokerStats::add(&self.stats.sequence_duplicates, 1);
+                    if self.reject_duplicates {
+                        self.rejected += 1;
+                        continue;
+                    }
+                }
+                Some(Sequenced::InOrder) | None => {}
+            }
+            return Some(msg);
+        }
+    }
+}
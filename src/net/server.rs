@@ -1,175 +1,1463 @@
+use crate::acl::{AccessControl, Denial, Operation, ANONYMOUS};
+use crate::auth::{self, Credentials};
+use crate::config::{ConnectionMode, ServerConfig};
+use crate::consumer::Consumer;
+use crate::dead_letter::{DeadLetter, DeadLetterLog};
 use crate::error::{BrokerError, NetworkError};
-use crate::net::message::{MessageHeader, ProcessedMessage};
-use crate::RingBuffer;
-use crate::{BATCH_SIZE, BUFFER_CHUNK};
-use std::hint::black_box;
-use std::sync::Arc;
-use tokio::io::AsyncReadExt;
-use tokio::net::{TcpListener, TcpStream};
+use crate::handler::{AsyncMessageHandler, Handler, Handlers, MessageHandler};
+use crate::net::codec::{error_code, BrokerCodec, Frame, Mechanism};
+use crate::net::credit::{Position, ProducerCredit};
+use crate::net::memory::{initial_read_buffer, Budget, BudgetedCodec, MemoryLimit};
+use crate::net::sequence::{Admit, SequenceTracker};
+use crate::net::subscription::Subscription;
+use crate::net::tls;
+use crate::net::transport::{self, Listener, Stream};
+#[cfg(feature = "io-uring")]
+use crate::net::uring::{self, Uring};
+use crate::quota::{ClientQuotas, QuotaAction, RateLimiter};
+use crate::stats::{BrokerStats, StatsSnapshot};
+use crate::topic::{
+    Publisher, StartPosition, Topic, TopicConfig, TopicInfo, TopicRef, TopicRegistry, DEFAULT_TOPIC,
+};
+use futures_util::{SinkExt, StreamExt};
+use parking_lot::{Mutex, RwLock};
+use shard::{Merge, Shards};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::ops::ControlFlow;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf, ReadHalf, WriteHalf};
+use tokio::net::TcpListener;
+use tokio::runtime::Handle;
 use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio::time::{Instant, Interval, MissedTickBehavior, Sleep};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::codec::{FramedRead, FramedWrite};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{debug, error, info, info_span, warn, Instrument};
+
+mod admin;
+mod shard;
+
+/// a stopping connection closes after the client sent nothing for this long
+const CLOSE_LINGER: Duration = Duration::from_millis(50);
+/// how long `ServerHandle::shutdown` waits for connections to finish
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+/// time a new connection gets to finish the TLS handshake
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// state shared by the acceptor, the connections and the topic consumers
+struct Shared {
+    config: ServerConfig,
+    topics: TopicRegistry,
+    stats: Arc<BrokerStats>,
+    handlers: RwLock<Handlers>,
+    /// stop accepting, connections finish the frames they already have and close
+    stop: CancellationToken,
+    /// consumers exit once they caught up with their ring
+    drain: CancellationToken,
+    /// everyone exits as soon as possible
+    abort: CancellationToken,
+    connections: TaskTracker,
+    /// closes the previous connections in `ConnectionMode::Replace`
+    handler_shutdown: Mutex<watch::Sender<bool>>,
+    /// each consumer returns how many bytes it left unread
+    consumers: Mutex<JoinSet<u64>>,
+    /// opened by `start` when configured
+    dead_letters: OnceLock<Arc<DeadLetterLog>>,
+    /// open connections by id, for the admin API
+    sessions: Mutex<HashMap<u64, Arc<Session>>>,
+    /// an operator asked for a drain through the admin API, see `ServerHandle::wait`
+    drain_requested: CancellationToken,
+    /// what the connections buffer, against `ServerConfig::memory_limit`
+    memory: Arc<MemoryLimit>,
+    /// loaded by `start` when `ServerConfig::auth` is set
+    credentials: OnceLock<Credentials>,
+    /// `ServerConfig::tls`
+    tls: Option<TlsAcceptor>,
+    /// loaded by `start` when `ServerConfig::acl` is set
+    acl: OnceLock<AccessControl>,
+    /// started by `start` when `ServerConfig::io_uring` is set
+    #[cfg(feature = "io-uring")]
+    uring: OnceLock<Uring>,
+    /// set up by `start` when `ServerConfig::shards` is set
+    shards: OnceLock<Shards>,
+    /// `ServerConfig::client_quota`
+    client_quotas: Option<ClientQuotas>,
+    /// sequence trackers of authenticated producers, by principal
+    sequences: Mutex<HashMap<String, Arc<Mutex<SequenceTracker>>>>,
+}
 
 pub struct BrokerServer {
-    ring: Arc<RingBuffer>,
-    port: u16,
-    consumer_shutdown: watch::Sender<bool>,
+    shared: Arc<Shared>,
 }
 
 impl BrokerServer {
     pub fn new(port: u16) -> Self {
-        eprintln!("DEBUG: Creating new BrokerServer on port {}", port);
-        let ring = Arc::new(RingBuffer::new().expect("Failed to create ring buffer"));
-        let (shutdown_tx, _) = watch::channel(false);
+        Self::with_config(ServerConfig::new(port))
+    }
+
+    /// listen on exactly `addr`, e.g. loopback only, IPv6 or port 0 for a free port
+    pub fn bind(addr: SocketAddr) -> Self {
+        Self::with_config(ServerConfig::bind(addr))
+    }
+
+    pub fn with_config(config: ServerConfig) -> Self {
+        debug!(listen = ?config.listen, "creating broker server");
+        let topics = TopicRegistry::new(
+            config.topic_defaults,
+            config.auto_create_topics,
+            config.max_topics,
+            config.ring_memory,
+        );
+        topics
+            .declare(DEFAULT_TOPIC, config.topic_defaults)
+            .expect("Failed to create default topic");
 
         Self {
-            ring,
-            port,
-            consumer_shutdown: shutdown_tx,
+            shared: Arc::new(Shared {
+                topics,
+                stats: Arc::default(),
+                handlers: RwLock::new(Handlers::default()),
+                stop: CancellationToken::new(),
+                drain: CancellationToken::new(),
+                abort: CancellationToken::new(),
+                connections: TaskTracker::new(),
+                handler_shutdown: Mutex::new(watch::channel(false).0),
+                consumers: Mutex::new(JoinSet::new()),
+                dead_letters: OnceLock::new(),
+                sessions: Mutex::new(HashMap::new()),
+                drain_requested: CancellationToken::new(),
+                memory: Arc::new(MemoryLimit::new(config.memory_limit)),
+                credentials: OnceLock::new(),
+                tls: config.tls.clone().map(TlsAcceptor::from),
+                acl: OnceLock::new(),
+                #[cfg(feature = "io-uring")]
+                uring: OnceLock::new(),
+                shards: OnceLock::new(),
+                client_quotas: config.client_quota.map(ClientQuotas::new),
+                sequences: Mutex::new(HashMap::new()),
+                config,
+            }),
+        }
+    }
+
+    /// create a topic ahead of time, e.g. with a larger or smaller ring than the defaults
+    pub fn declare_topic(&self, name: &str, config: TopicConfig) -> Result<TopicInfo, BrokerError> {
+        Ok(self.shared.topics.declare(name, config)?.info())
+    }
+
+    pub fn topics(&self) -> Vec<TopicInfo> {
+        self.shared.topics.list()
+    }
+
+    pub fn stats(&self) -> StatsSnapshot {
+        self.shared.stats.snapshot()
+    }
+
+    /// handle the records of every topic without a handler of its own
+    pub fn set_handler(&self, handler: impl MessageHandler) {
+        self.shared
+            .handlers
+            .write()
+            .set(None, Handler::Sync(Arc::new(handler)));
+    }
+
+    pub fn set_async_handler(&self, handler: impl AsyncMessageHandler) {
+        self.shared
+            .handlers
+            .write()
+            .set(None, Handler::Async(Arc::new(handler)));
+    }
+
+    /// handle the records of `topic`, instead of the handler set with `set_handler`
+    pub fn set_topic_handler(&self, topic: &str, handler: impl MessageHandler) {
+        self.shared
+            .handlers
+            .write()
+            .set(Some(topic), Handler::Sync(Arc::new(handler)));
+    }
+
+    pub fn set_async_topic_handler(&self, topic: &str, handler: impl AsyncMessageHandler) {
+        self.shared
+            .handlers
+            .write()
+            .set(Some(topic), Handler::Async(Arc::new(handler)));
+    }
+
+    /// bind every listen address and unix socket and serve in the background.
+    /// the returned handle has the bound addresses and stops the server again
+    pub async fn start(self) -> Result<ServerHandle, NetworkError> {
+        let config = &self.shared.config;
+        if config.listen.is_empty() && config.unix_listen.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "no listen address").into());
+        }
+        // the control API can disconnect clients and drain the broker
+        if let (Some(addr), None) = (config.admin, &config.admin_token) {
+            if !addr.ip().is_loopback() {
+                let e = io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("admin address {} isn't loopback but has no token", addr),
+                );
+                return Err(e.into());
+            }
+        }
+
+        // bind everything before serving anything, so a bad address fails the whole start
+        let mut listeners = Vec::with_capacity(config.listen.len() + config.unix_listen.len());
+        let mut local_addrs = Vec::with_capacity(config.listen.len());
+        let mut sharded = Vec::new();
+        if let Some(shards) = config.shards {
+            let shards = Shards::new(shards)?;
+            (sharded, local_addrs) = shards.bind(&config.listen)?;
+            for addr in &local_addrs {
+                info!(address = %addr, shards = sharded.len(), "listening");
+            }
+            let _ = self.shared.shards.set(shards);
+        } else {
+            for addr in &config.listen {
+                let listener = Listener::bind_tcp(*addr).await?;
+                if let Listener::Tcp(tcp) = &listener {
+                    local_addrs.push(tcp.local_addr()?);
+                }
+                listeners.push(listener);
+            }
+        }
+        for unix in &config.unix_listen {
+            listeners.push(Listener::bind_unix(unix)?);
+        }
+        for listener in &listeners {
+            info!(address = %listener.describe(), "listening");
+        }
+        let admin = match config.admin {
+            Some(addr) => Some(TcpListener::bind(addr).await?),
+            None => None,
+        };
+        let admin_addr = admin.as_ref().map(TcpListener::local_addr).transpose()?;
+        if let Some(addr) = admin_addr {
+            info!(address = %addr, "admin listening");
+        }
+
+        if let Some(dead_letters) = &config.dead_letters {
+            let log = DeadLetterLog::open(dead_letters)?;
+            let _ = self.shared.dead_letters.set(Arc::new(log));
+        }
+        if let Some(auth) = &config.auth {
+            let credentials = Credentials::load(&auth.path)?;
+            info!(principals = credentials.len(), "authentication required");
+            let _ = self.shared.credentials.set(credentials);
+        }
+        if let Some(acl) = &config.acl {
+            let acl = AccessControl::open(acl)?;
+            info!(rules = acl.len(), "access control enabled");
+            let _ = self.shared.acl.set(acl);
+        }
+        #[cfg(feature = "io-uring")]
+        if let Some(config) = &config.io_uring {
+            let uring = Uring::start(config)?;
+            info!(
+                buffers = config.buffers,
+                buffer_size = config.buffer_size,
+                "reading connections through io_uring"
+            );
+            let _ = self.shared.uring.set(uring);
+        }
+
+        for topic in self.shared.topics.all() {
+            spawn_consumer(&self.shared, topic);
+        }
+
+        let mut acceptors = JoinSet::new();
+        for listener in listeners {
+            acceptors.spawn(accept(listener, self.shared.clone(), None));
+        }
+        if let Some(shards) = self.shared.shards.get() {
+            for (index, listeners) in sharded.into_iter().enumerate() {
+                for stopped in shards.spawn(index, listeners, self.shared.clone())? {
+                    acceptors.spawn(async move {
+                        stopped
+                            .await
+                            .unwrap_or_else(|_| Err(io::Error::other("shard exited").into()))
+                    });
+                }
+            }
+        }
+        if let Some(admin) = admin {
+            acceptors.spawn(admin::accept(admin, self.shared.clone()));
+        }
+        if let Some(interval) = config.acl.as_ref().and_then(|acl| acl.reload_interval) {
+            acceptors.spawn(watch_acl(interval, self.shared.clone()));
+        }
+        Ok(ServerHandle {
+            shared: self.shared,
+            local_addrs,
+            admin_addr,
+            acceptors,
+        })
+    }
+
+    /// serve until accepting fails, or until a drain is requested through the admin
+    /// API, which drains for up to `ServerConfig::drain_timeout` before returning
+    pub async fn run(self) -> Result<(), NetworkError> {
+        let timeout = self.shared.config.drain_timeout;
+        let mut handle = self.start().await?;
+        handle.wait().await?;
+        handle.shutdown_with_drain(timeout).await?;
+        Ok(())
+    }
+}
+
+/// final counters of a stopped server
+#[derive(Debug, Clone)]
+pub struct ShutdownReport {
+    pub stats: StatsSnapshot,
+    /// bytes the topic consumers didn't get to
+    pub undrained: u64,
+    /// the drain timeout passed and the rest was cut short
+    pub timed_out: bool,
+}
+
+impl ShutdownReport {
+    pub fn is_drained(&self) -> bool {
+        self.undrained == 0 && !self.timed_out
+    }
+}
+
+/// a running server, returned by `BrokerServer::start`
+pub struct ServerHandle {
+    shared: Arc<Shared>,
+    local_addrs: Vec<SocketAddr>,
+    admin_addr: Option<SocketAddr>,
+    acceptors: JoinSet<Result<(), NetworkError>>,
+}
+
+impl ServerHandle {
+    /// the address of the first TCP listener, with the actual port if it was bound to port 0.
+    /// panics if the server only listens on unix sockets
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addrs[0]
+    }
+
+    /// bound TCP addresses, in the order of `ServerConfig::listen`
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    /// the bound admin HTTP address, if `ServerConfig::admin` is set
+    pub fn admin_addr(&self) -> Option<SocketAddr> {
+        self.admin_addr
+    }
+
+    pub fn stats(&self) -> StatsSnapshot {
+        self.shared.stats.snapshot()
+    }
+
+    pub fn topics(&self) -> Vec<TopicInfo> {
+        self.shared.topics.list()
+    }
+
+    /// bytes the open connections reserved for their read buffers, see
+    /// `ServerConfig::memory_limit`
+    pub fn memory_used(&self) -> usize {
+        self.shared.memory.used()
+    }
+
+    /// read `ServerConfig::acl` again and apply it to every connection, ending the
+    /// subscriptions it no longer allows. returns the number of rules. the current
+    /// rules stay in place if the file is invalid
+    pub fn reload_acl(&self) -> Result<usize, NetworkError> {
+        let Some(acl) = self.shared.acl.get() else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "no ACL configured").into());
+        };
+        let rules = acl.reload()?;
+        info!(rules, "ACL reloaded");
+        Ok(rules)
+    }
+
+    /// resolves when a listener stops accepting on its own, which only happens on
+    /// errors, or when a drain was requested through the admin API. the server keeps
+    /// running either way, follow up with `shutdown_with_drain`
+    pub async fn wait(&mut self) -> Result<(), NetworkError> {
+        tokio::select! {
+            result = self.acceptors.join_next() => match result {
+                Some(result) => result?,
+                None => std::future::pending().await,
+            },
+            _ = self.shared.drain_requested.cancelled() => Ok(()),
         }
     }
 
-    pub async fn run(&mut self) -> Result<(), NetworkError> {
-        let addr = format!("0.0.0.0:{}", self.port);
-        let listener = TcpListener::bind(&addr).await?;
-        println!("Server listening on {}", addr);
+    /// stop accepting and close every connection once the frames it already sent
+    /// are handled. whatever the consumers haven't read yet is dropped
+    pub async fn shutdown(self) -> Result<ShutdownReport, NetworkError> {
+        self.stop(Instant::now() + CLOSE_TIMEOUT, false).await
+    }
+
+    /// like `shutdown`, but the topic consumers first work through everything left in
+    /// their rings. after `timeout` the rest is dropped and the report says so
+    pub async fn shutdown_with_drain(
+        self,
+        timeout: Duration,
+    ) -> Result<ShutdownReport, NetworkError> {
+        self.stop(Instant::now() + timeout, true).await
+    }
+
+    async fn stop(
+        mut self,
+        deadline: Instant,
+        drain: bool,
+    ) -> Result<ShutdownReport, NetworkError> {
+        let shared = self.shared.clone();
+        info!(drain, "shutting down");
+        shared.stop.cancel();
+        while let Some(result) = self.acceptors.join_next().await {
+            // an accept error already ended it, that doesn't matter anymore
+            let _ = result?;
+        }
+
+        shared.connections.close();
+        let mut timed_out = tokio::time::timeout_at(deadline, shared.connections.wait())
+            .await
+            .is_err();
+        if timed_out {
+            // connections still reading or stuck on a full ring give up once aborted
+            shared.abort.cancel();
+            shared.connections.wait().await;
+        }
+
+        if !drain || timed_out {
+            shared.abort.cancel();
+        }
+        let mut undrained = 0;
+        if let Some(shards) = shared.shards.get() {
+            // the consumers only drain once everything is merged into their rings
+            let (left, late) = shards.close(deadline, &shared.abort).await;
+            undrained += left;
+            timed_out |= late;
+        }
+        if !shared.abort.is_cancelled() {
+            shared.drain.cancel();
+        }
 
+        let mut consumers = std::mem::take(&mut *shared.consumers.lock());
         loop {
-            let (socket, addr) = listener.accept().await?;
-            println!("New connection from {}", addr);
+            let next = if shared.abort.is_cancelled() {
+                consumers.join_next().await
+            } else {
+                match tokio::time::timeout_at(deadline, consumers.join_next()).await {
+                    Ok(next) => next,
+                    Err(_) => {
+                        timed_out = true;
+                        shared.abort.cancel();
+                        continue;
+                    }
+                }
+            };
+            match next {
+                Some(left) => undrained += left?,
+                None => break,
+            }
+        }
+
+        Ok(ShutdownReport {
+            stats: shared.stats.snapshot(),
+            undrained,
+            timed_out,
+        })
+    }
+}
+
+/// `shard` is the index of the listener shard accepting, if any
+async fn accept(
+    listener: Listener,
+    shared: Arc<Shared>,
+    shard: Option<usize>,
+) -> Result<(), NetworkError> {
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept(shared.config.keepalive) => accepted,
+            _ = shared.stop.cancelled() => break,
+        };
+        match accepted {
+            Ok((socket, addr)) => serve(socket, addr, &shared, shard),
+            Err(e) => accept_failed(e, &listener.describe()).await?,
+        }
+    }
 
-            // Stop previous consumer
-            let _ = self.consumer_shutdown.send(true);
+    // clients that connected before the stop may have sent frames already,
+    // take what is queued up but stop listening shortly after
+    let deadline = Instant::now() + CLOSE_LINGER;
+    while let Ok(accepted) =
+        tokio::time::timeout_at(deadline, listener.accept(shared.config.keepalive)).await
+    {
+        match accepted {
+            Ok((socket, addr)) => serve(socket, addr, &shared, shard),
+            Err(e) => accept_failed(e, &listener.describe()).await?,
+        }
+    }
+    Ok(())
+}
+
+/// a failed accept that only concerns one connection, or a passing lack of
+/// descriptors, is logged and the listener keeps going. `Err` if it is broken
+async fn accept_failed(e: io::Error, listener: &str) -> io::Result<()> {
+    let Some(backoff) = transport::accept_backoff(&e) else {
+        return Err(e);
+    };
+    warn!(listener, error = %e, "accept failed, still listening");
+    if !backoff.is_zero() {
+        tokio::time::sleep(backoff).await;
+    }
+    Ok(())
+}
+
+fn serve(socket: Stream, addr: String, shared: &Arc<Shared>, shard: Option<usize>) {
+    let id = shared.stats.connections.fetch_add(1, Ordering::Relaxed) + 1;
+    let span = info_span!(
+        "connection",
+        id,
+        peer = %addr,
+        shard,
+        principal = tracing::field::Empty
+    );
+    debug!(parent: &span, "accepted");
+    let budget = match Budget::new(shared.memory.clone(), shared.config.connection_memory) {
+        Ok(budget) => budget,
+        Err(e) => {
+            BrokerStats::add(&shared.stats.connections_refused, 1);
+            warn!(parent: &span, used = shared.memory.used(), "refused, {}", e);
+            shared.connections.spawn(refuse(socket, e).instrument(span));
+            return;
+        }
+    };
+    let session = Arc::new(Session {
+        publisher: Arc::new(Publisher {
+            connection: id,
+            peer: addr,
+            principal: OnceLock::new(),
+        }),
+        connected_at: DeadLetter::now(),
+        frames_in: AtomicU64::new(0),
+        messages_in: AtomicU64::new(0),
+        bytes_in: AtomicU64::new(0),
+        quota_hits: AtomicU64::new(0),
+        close: CancellationToken::new(),
+    });
+    shared.sessions.lock().insert(id, session.clone());
+
+    let shutdown_rx = match shared.config.connection_mode {
+        ConnectionMode::Concurrent => shared.handler_shutdown.lock().subscribe(),
+        ConnectionMode::Replace => {
+            let mut handler_shutdown = shared.handler_shutdown.lock();
+            // Stop previous handlers
+            let _ = handler_shutdown.send(true);
 
             // Create new shutdown channel
             let (shutdown_tx, shutdown_rx) = watch::channel(false);
-            self.consumer_shutdown = shutdown_tx;
+            *handler_shutdown = shutdown_tx;
+            shutdown_rx
+        }
+    };
 
-            // Start new consumer
-            let ring = self.ring.clone();
-            tokio::spawn(async move {
-                let result = handle_connection(socket, ring, shutdown_rx).await;
-                if let Err(e) = result {
-                    eprintln!("Connection error: {:?}", e);
+    // Start new handler
+    let shared = shared.clone();
+    BrokerStats::add(&shared.stats.open_connections, 1);
+    shared.clone().connections.spawn(
+        async move {
+            let result = match secure(socket, &session, &shared).await {
+                Ok(Some(socket)) => {
+                    #[cfg(feature = "io-uring")]
+                    let uring = uring_reader(&socket, &shared);
+                    let connection =
+                        Connection::new(socket, budget, session, shared.clone(), shard);
+                    #[cfg(feature = "io-uring")]
+                    let connection = match uring {
+                        Some(reader) => connection.read_through(reader),
+                        None => connection,
+                    };
+                    connection.run(shutdown_rx).await
                 }
-            });
+                Ok(None) => Ok(()),
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(()) => debug!("closed"),
+                Err(e) => warn!(error = %e, "connection failed"),
+            }
+            shared.sessions.lock().remove(&id);
+            shared
+                .stats
+                .open_connections
+                .fetch_sub(1, Ordering::Relaxed);
+        }
+        .instrument(span),
+    );
+}
+
+/// reload the ACL whenever its file changes
+async fn watch_acl(interval: Duration, shared: Arc<Shared>) -> Result<(), NetworkError> {
+    let Some(acl) = shared.acl.get() else {
+        return Ok(());
+    };
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            _ = shared.stop.cancelled() => return Ok(()),
+        }
+        if !acl.changed() {
+            continue;
+        }
+        match acl.reload() {
+            Ok(rules) => info!(rules, "ACL file changed, reloaded"),
+            Err(e) => warn!(error = %e, "ACL reload failed, keeping the previous rules"),
         }
     }
 }
 
-async fn handle_connection(
-    mut socket: TcpStream,
-    ring: Arc<RingBuffer>,
-    mut shutdown: watch::Receiver<bool>,
-) -> Result<(), NetworkError> {
-    socket.set_nodelay(true)?;
-
-    // Spawn consumer task
-    let consumer_ring = ring.clone();
-    let consumer_shutdown = shutdown.clone();
-
-    let consumer = tokio::spawn(async move {
-        println!("DEBUG: Starting consumer task");
-        let mut msg_bufs = [
-            vec![0u8; BUFFER_CHUNK * BATCH_SIZE],
-            vec![0u8; BUFFER_CHUNK * BATCH_SIZE],
-        ];
-        let mut current_buf = 0;
-        let mut messages_consumed = 0;
-        let mut messages_processed = 0;
-        let mut processing_errors = 0;
+/// the TLS handshake, for TCP connections with `ServerConfig::tls`. `None` if the
+/// connection was closed or the server stopped meanwhile
+async fn secure(
+    socket: Stream,
+    session: &Session,
+    shared: &Shared,
+) -> Result<Option<Stream>, NetworkError> {
+    let (tcp, acceptor) = match (socket, &shared.tls) {
+        (Stream::Tcp(tcp), Some(acceptor)) => (tcp, acceptor),
+        (socket, _) => return Ok(Some(socket)),
+    };
+    let accepted = tokio::select! {
+        accepted = tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, tls::accept(acceptor, tcp)) => accepted,
+        _ = session.close.cancelled() => return Ok(None),
+        _ = shared.stop.cancelled() => return Ok(None),
+    };
+    let (socket, identity) = match accepted {
+        Ok(Ok(accepted)) => accepted,
+        Ok(Err(e)) => {
+            BrokerStats::add(&shared.stats.tls_failures, 1);
+            return Err(e.into());
+        }
+        Err(_) => {
+            BrokerStats::add(&shared.stats.tls_failures, 1);
+            return Err(NetworkError::IdleTimeout(TLS_HANDSHAKE_TIMEOUT));
+        }
+    };
+    if let Some(identity) = identity {
+        tracing::Span::current().record("principal", identity.as_str());
+        debug!("client certificate accepted");
+        let _ = session.publisher.principal.set(identity);
+    }
+    Ok(Some(socket))
+}
+
+/// an io_uring reader for a plaintext connection, with `ServerConfig::io_uring`.
+/// connections it can't take are read through epoll as usual
+#[cfg(feature = "io-uring")]
+fn uring_reader(socket: &Stream, shared: &Shared) -> Option<uring::Reader> {
+    let fd = socket.plain_fd()?;
+    match shared.uring.get()?.reader(fd) {
+        Ok(reader) => Some(reader),
+        Err(e) => {
+            warn!(error = %e, "io_uring unavailable, reading through epoll");
+            None
+        }
+    }
+}
+
+/// tell a client why it can't stay, then close
+async fn refuse<S: AsyncWrite + Unpin>(socket: S, e: NetworkError) {
+    let mut sink = FramedWrite::new(socket, BrokerCodec::new());
+    let frame = Frame::Error {
+        code: error_code::OVERLOADED,
+        message: e.to_string(),
+    };
+    let _ = tokio::time::timeout(CLOSE_TIMEOUT, async {
+        sink.send(frame).await?;
+        sink.close().await
+    })
+    .await;
+}
+
+/// one consumer per topic, started the first time the topic is seen
+fn spawn_consumer(shared: &Arc<Shared>, topic: Arc<Topic>) {
+    if !topic.claim_consumer() {
+        return;
+    }
+    // opened before the task first runs: a ring without cursors retains nothing, so
+    // publishes filling it in the meantime would overwrite what the consumer hasn't read
+    let cursor = match topic.open_cursor(StartPosition::Earliest) {
+        Ok(cursor) => cursor,
+        Err(e) => {
+            error!(topic = %topic.name(), error = %e, "consumer failed to start");
+            return;
+        }
+    };
+
+    let consumer = Consumer {
+        handler: shared.handlers.read().for_topic(topic.name()),
+        topic,
+        policy: shared.config.error_policy,
+        stats: shared.stats.clone(),
+        dead_letters: shared.dead_letters.get().cloned(),
+        drain: shared.drain.clone(),
+        abort: shared.abort.clone(),
+    };
+    let span = info_span!("consumer", topic = %consumer.topic.name());
+    // a shard may see the topic first, the consumer still belongs to the server's runtime
+    let runtime = shared
+        .shards
+        .get()
+        .map_or_else(Handle::current, |shards| shards.runtime().clone());
+    shared
+        .consumers
+        .lock()
+        .spawn_on(consumer.run(cursor).instrument(span), &runtime);
+}
+
+/// what the admin API sees of a connection
+struct Session {
+    /// recorded with everything the connection publishes
+    publisher: Arc<Publisher>,
+    /// unix time in milliseconds
+    connected_at: u64,
+    frames_in: AtomicU64,
+    messages_in: AtomicU64,
+    bytes_in: AtomicU64,
+    /// publish frames over the client's quota
+    quota_hits: AtomicU64,
+    /// closes the connection right away
+    close: CancellationToken,
+}
+
+/// where a connection's frames come from
+enum Reader<S> {
+    Socket(ReadHalf<S>),
+    #[cfg(feature = "io-uring")]
+    Uring(uring::Reader),
+}
+
+impl<S: AsyncRead> AsyncRead for Reader<S> {
+    #[inline]
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Reader::Socket(reader) => Pin::new(reader).poll_read(cx, buf),
+            #[cfg(feature = "io-uring")]
+            Reader::Uring(reader) => Pin::new(reader).poll_read(cx, buf),
+        }
+    }
+}
+
+/// a client connection, publishing, subscribing or managing topics
+struct Connection<S> {
+    frames: FramedRead<Reader<S>, BudgetedCodec>,
+    sink: FramedWrite<WriteHalf<S>, BrokerCodec>,
+    shared: Arc<Shared>,
+    session: Arc<Session>,
+    /// publishers usually stick to one topic, skip the registry lookup for it
+    last_topic: Option<(TopicRef, Arc<Topic>)>,
+    subscription: Option<Subscription>,
+    /// set once the producer asked for flow control
+    credit: Option<ProducerCredit>,
+    /// looked up on the first publish, see `Connection::sequences`
+    sequences: Option<Arc<Mutex<SequenceTracker>>>,
+    /// the client's share of `ServerConfig::client_quota`, looked up on the
+    /// first publish once the principal is known
+    quota: Option<Arc<Mutex<RateLimiter>>>,
+    /// the `AccessControl::generation` `last_topic` was authorized under
+    acl_generation: u64,
+    /// `AccessControl::reloads`, the subscription is checked again on every one
+    acl_reloads: Option<watch::Receiver<u64>>,
+    /// ticks every `ServerConfig::heartbeat_interval`
+    heartbeat: Option<Interval>,
+    /// fires once the client was quiet for `ServerConfig::idle_timeout`
+    idle: Option<Pin<Box<Sleep>>>,
+    /// the listener shard that accepted the connection, it publishes through its lanes
+    shard: Option<usize>,
+}
+
+impl<S> Connection<S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    fn new(
+        socket: S,
+        budget: Budget,
+        session: Arc<Session>,
+        shared: Arc<Shared>,
+        shard: Option<usize>,
+    ) -> Self {
+        let (reader, writer) = tokio::io::split(socket);
+        let read_buffer = initial_read_buffer(shared.config.connection_memory);
+        let heartbeat = shared.config.heartbeat_interval.map(|period| {
+            let mut interval = tokio::time::interval_at(Instant::now() + period, period);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            interval
+        });
+        let idle = shared
+            .config
+            .idle_timeout
+            .map(|timeout| Box::pin(tokio::time::sleep(timeout)));
+        let acl_reloads = shared.acl.get().map(AccessControl::reloads);
+        Self {
+            frames: FramedRead::with_capacity(
+                Reader::Socket(reader),
+                BudgetedCodec::new(budget),
+                read_buffer,
+            ),
+            sink: FramedWrite::new(writer, BrokerCodec::new()),
+            shared,
+            session,
+            last_topic: None,
+            subscription: None,
+            credit: None,
+            sequences: None,
+            quota: None,
+            acl_generation: 0,
+            acl_reloads,
+            heartbeat,
+            idle,
+            shard,
+        }
+    }
+
+    /// read through io_uring instead, the socket half is dropped unread
+    #[cfg(feature = "io-uring")]
+    fn read_through(mut self, reader: uring::Reader) -> Self {
+        *self.frames.get_mut() = Reader::Uring(reader);
+        self
+    }
+
+    async fn run(mut self, mut shutdown: watch::Receiver<bool>) -> Result<(), NetworkError> {
+        if self.handshake().await?.is_break() {
+            return Ok(());
+        }
 
         loop {
-            if *consumer_shutdown.borrow() {
-                println!("DEBUG: Consumer shutting down");
-                break;
+            if *shutdown.borrow() {
+                debug!("replaced by a newer connection");
+                return Ok(());
             }
 
-            let mut batch_size = 0;
-            let buf = &mut msg_bufs[current_buf];
-
-            for _ in 0..BATCH_SIZE {
-                match consumer_ring.try_read(&mut buf[batch_size..]) {
-                    Ok(size) => {
-                        match ProcessedMessage::from_bytes(&buf[batch_size..batch_size + size]) {
-                            Some(msg) => {
-                                messages_processed += 1;
-                                black_box(msg);
-                            }
-                            None => {
-                                processing_errors += 1;
-                            }
-                        }
-                        batch_size += size;
-                        messages_consumed += 1;
+            let frame = tokio::select! {
+                frame = self.frames.next() => frame,
+                _ = Subscription::ready(&self.subscription) => {
+                    if let Some(sub) = self.subscription.as_mut() {
+                        sub.deliver(&mut self.sink).await?;
                     }
-                    Err(BrokerError::BufferEmpty) => {
-                        if batch_size > 0 {
-                            break;
-                        }
-                        tokio::task::yield_now().await;
-                        continue;
+                    continue;
+                }
+                _ = Subscription::redelivery_due(&self.subscription) => {
+                    if let Some(sub) = self.subscription.as_mut() {
+                        sub.redeliver(&mut self.sink).await?;
                     }
-                    Err(e) => {
-                        eprintln!("ring buffer read error: {:?}", e);
-                        break;
+                    continue;
+                }
+                _ = acl_reloaded(&mut self.acl_reloads), if self.subscription.is_some() => {
+                    self.recheck_subscription().await?;
+                    continue;
+                }
+                _ = ProducerCredit::released(&self.credit) => {
+                    if let Some(credit) = self.credit.as_mut() {
+                        let bytes = credit.reclaim();
+                        if bytes > 0 {
+                            self.sink.send(Frame::Grant { bytes: bytes as u32 }).await?;
+                        }
                     }
+                    continue;
+                }
+                // the sender goes away with the server, that alone isn't a replacement
+                Ok(()) = shutdown.changed() => {
+                    debug!("replaced by a newer connection");
+                    return Ok(());
                 }
+                _ = heartbeat_due(&mut self.heartbeat) => {
+                    self.send_heartbeat().await?;
+                    continue;
+                }
+                _ = idle_expired(&mut self.idle) => {
+                    BrokerStats::add(&self.shared.stats.idle_timeouts, 1);
+                    debug!("idle timeout, closing");
+                    return Ok(());
+                }
+                _ = self.session.close.cancelled() => {
+                    debug!("disconnected through the admin API");
+                    return Ok(());
+                }
+                _ = self.shared.stop.cancelled() => return self.finish().await,
+            };
+
+            if let (Some(idle), Some(timeout)) = (&mut self.idle, self.shared.config.idle_timeout) {
+                idle.as_mut().reset(Instant::now() + timeout);
+            }
+            if self.dispatch(frame).await?.is_break() {
+                return Ok(());
             }
+        }
+    }
 
-            if messages_consumed % 1_000_000 == 0 && messages_consumed > 0 {
-                println!(
-                    "Stats: consumed={}, processed={}, errors={}",
-                    messages_consumed, messages_processed, processing_errors
-                );
+    /// with `ServerConfig::auth`, nothing but a successful handshake or a client
+    /// certificate gets a client in
+    async fn handshake(&mut self) -> Result<ControlFlow<()>, NetworkError> {
+        let shared = self.shared.clone();
+        let (Some(credentials), Some(auth)) = (shared.credentials.get(), &shared.config.auth)
+        else {
+            return Ok(ControlFlow::Continue(()));
+        };
+        if self.session.publisher.principal.get().is_some() {
+            // a client certificate said who this is already
+            return Ok(ControlFlow::Continue(()));
+        }
+        let close = self.session.close.clone();
+        let authenticated = tokio::select! {
+            authenticated = tokio::time::timeout(auth.timeout, self.authenticate(credentials)) => {
+                authenticated.unwrap_or(Ok(None))?
             }
-            current_buf = 1 - current_buf;
+            _ = close.cancelled() => return Ok(ControlFlow::Break(())),
+            _ = shared.stop.cancelled() => return Ok(ControlFlow::Break(())),
+        };
+        let Some(principal) = authenticated else {
+            BrokerStats::add(&shared.stats.auth_failures, 1);
+            warn!("authentication failed");
+            self.sink
+                .send(Frame::Error {
+                    code: error_code::UNAUTHENTICATED,
+                    message: "authentication failed".to_string(),
+                })
+                .await?;
+            return Ok(ControlFlow::Break(()));
+        };
+        tracing::Span::current().record("principal", principal.as_str());
+        debug!("authenticated");
+        self.sink
+            .send(Frame::Authenticated {
+                principal: principal.clone(),
+            })
+            .await?;
+        let _ = self.session.publisher.principal.set(principal);
+        Ok(ControlFlow::Continue(()))
+    }
+
+    /// the server side of the handshake, see `Frame::Auth`. `None` if the client
+    /// didn't prove who it is, hung up or sent anything else first
+    async fn authenticate(
+        &mut self,
+        credentials: &Credentials,
+    ) -> Result<Option<String>, NetworkError> {
+        let (mechanism, principal, proof) = match self.frames.next().await {
+            Some(Ok(Frame::Auth {
+                mechanism,
+                principal,
+                proof,
+            })) => (mechanism, principal, proof),
+            _ => return Ok(None),
+        };
+        if credentials.mechanism(&principal) != Some(mechanism) {
+            return Ok(None);
         }
-    });
 
-    let mut header_buf = [0u8; std::mem::size_of::<MessageHeader>()];
-    let mut msg_buf = vec![0u8; BUFFER_CHUNK * BATCH_SIZE];
+        let verified = match mechanism {
+            Mechanism::Token => credentials.verify(&principal, mechanism, &[], &proof),
+            Mechanism::Hmac => {
+                let nonce = auth::nonce()?;
+                self.sink
+                    .send(Frame::Challenge {
+                        nonce: nonce.clone(),
+                    })
+                    .await?;
+                match self.frames.next().await {
+                    Some(Ok(Frame::Auth {
+                        mechanism: Mechanism::Hmac,
+                        principal: answered,
+                        proof,
+                    })) if answered == principal => {
+                        credentials.verify(&principal, mechanism, &nonce, &proof)
+                    }
+                    _ => false,
+                }
+            }
+        };
+        Ok(verified.then_some(principal))
+    }
 
-    loop {
-        if *shutdown.borrow() {
-            println!("DEBUG: Connection handler shutting down");
-            break;
+    /// a peer that hasn't read its heartbeats for a whole interval is not coming
+    /// back, don't wait on its socket buffer forever
+    async fn send_heartbeat(&mut self) -> Result<(), NetworkError> {
+        let period = self
+            .shared
+            .config
+            .heartbeat_interval
+            .unwrap_or(CLOSE_TIMEOUT);
+        match tokio::time::timeout(period, self.sink.send(Frame::Heartbeat)).await {
+            Ok(sent) => sent,
+            Err(_) => Err(NetworkError::IdleTimeout(period)),
         }
+    }
 
-        tokio::select! {
-            result = socket.read_exact(&mut header_buf) => {
-                match result {
-                    Ok(_) => {
-                        let header = unsafe {
-                            std::ptr::read_unaligned(header_buf.as_ptr() as *const MessageHeader)
-                        };
-
-                        let total_size = header.size() as usize * header.batch_size() as usize;
-                        if msg_buf.len() < total_size {
-                            msg_buf.resize(total_size, 0);
+    /// the server is stopping: handle what the client already sent, which may
+    /// still be in socket buffers, and close once it goes quiet
+    async fn finish(mut self) -> Result<(), NetworkError> {
+        loop {
+            let frame = tokio::select! {
+                biased;
+                frame = self.frames.next() => frame,
+                _ = tokio::time::sleep(CLOSE_LINGER) => return Ok(()),
+                _ = self.shared.abort.cancelled() => return Ok(()),
+            };
+            if self.dispatch(frame).await?.is_break() {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn dispatch(
+        &mut self,
+        frame: Option<Result<Frame, NetworkError>>,
+    ) -> Result<ControlFlow<()>, NetworkError> {
+        if let Some(Ok(_)) = &frame {
+            BrokerStats::add(&self.shared.stats.frames_in, 1);
+            BrokerStats::add(&self.session.frames_in, 1);
+        }
+
+        match frame {
+            Some(Ok(Frame::Publish { topic, batch })) => {
+                if self.acl_changed() {
+                    self.last_topic = None;
+                }
+                let target = match &self.last_topic {
+                    Some((cached, target)) if *cached == topic => target.clone(),
+                    _ => match self
+                        .authorize(Operation::Publish, &topic)
+                        .and_then(|()| self.resolve(&topic))
+                    {
+                        Ok(target) => {
+                            self.last_topic = Some((topic, target.clone()));
+                            target
+                        }
+                        Err(e @ BrokerError::Forbidden { .. }) => {
+                            // like a rejected quota, the connection may go on with
+                            // topics it is allowed to publish to
+                            self.refund(batch.payload().len() as u64).await?;
+                            self.sink.send(error_frame(&e)).await?;
+                            return Ok(ControlFlow::Continue(()));
                         }
+                        Err(e) => {
+                            // publishes are not acknowledged, so the producer only
+                            // learns about this on its next read: close after telling it
+                            self.sink.send(error_frame(&e)).await?;
+                            return Err(e.into());
+                        }
+                    },
+                };
 
-                        socket.read_exact(&mut msg_buf[..total_size]).await?;
+                let bytes = batch.payload().len() as u64;
+                if !self
+                    .within_quota(&target, batch.count() as u64, bytes)
+                    .await?
+                {
+                    return Ok(ControlFlow::Continue(()));
+                }
+                if let Some(credit) = self.credit.as_mut() {
+                    if let Err(e) = credit.spend(bytes) {
+                        self.sink.send(error_frame(&e)).await?;
+                        return Err(e.into());
+                    }
+                }
 
-                        let mut offset = 0;
-                        for _ in 0..header.batch_size() {
-                            let msg_size = header.size() as usize;
-                            while ring.try_write(&msg_buf[offset..offset + msg_size]).is_err() {
-                                tokio::task::yield_now().await;
+                let merge = match self.merge(&target) {
+                    Ok(merge) => merge,
+                    Err(e) => {
+                        self.sink.send(error_frame(&e)).await?;
+                        return Err(e.into());
+                    }
+                };
+                let sequences = self.sequences();
+                let mut admit = Admit {
+                    messages: batch.messages(),
+                    sequences: &sequences,
+                    stats: &self.shared.stats,
+                    reject_duplicates: self.shared.config.reject_duplicates,
+                    rejected: 0,
+                };
+                let mut messages = admit.by_ref().peekable();
+                let mut stalled = false;
+                loop {
+                    let publisher = &self.session.publisher;
+                    let pushed = match (&merge, self.shard) {
+                        (Some(merge), Some(shard)) => {
+                            merge.push_many(shard, publisher, &mut messages)
+                        }
+                        _ => target.push_many(publisher, &mut messages),
+                    };
+                    match pushed {
+                        Ok(()) => break,
+                        Err(BrokerError::BufferFull) => {
+                            if !stalled {
+                                stalled = true;
+                                target.stalled();
+                            }
+                            // let the consumers in on what fit so far
+                            notify_published(&target, merge.as_deref());
+                            if self.shared.abort.is_cancelled() {
+                                return Ok(ControlFlow::Break(()));
                             }
-                            offset += msg_size;
+                            tokio::task::yield_now().await;
+                        }
+                        Err(e) => {
+                            self.sink.send(error_frame(&e)).await?;
+                            return Err(e.into());
                         }
                     }
-                    Err(_) => break,
+                }
+                notify_published(&target, merge.as_deref());
+                let duplicates = admit.rejected;
+                if let Some(credit) = self.credit.as_mut() {
+                    let position = match (&merge, self.shard) {
+                        (Some(merge), Some(_)) => merge.position(),
+                        _ => Position::Ring(target.ring().head()),
+                    };
+                    credit.published(target.clone(), position, bytes);
+                }
+                let published = batch.count() as u64 - duplicates;
+                let published_bytes = published * batch.msg_size() as u64;
+                BrokerStats::add(&self.shared.stats.messages_in, published);
+                BrokerStats::add(&self.shared.stats.bytes_in, published_bytes);
+                BrokerStats::add(&self.session.messages_in, published);
+                BrokerStats::add(&self.session.bytes_in, published_bytes);
+            }
+            Some(Ok(Frame::Declare { topic })) => {
+                let topic = TopicRef::Name(topic);
+                let reply = match self
+                    .authorize(Operation::Publish, &topic)
+                    .and_then(|()| self.resolve(&topic))
+                {
+                    Ok(topic) => Frame::Topics(vec![topic.info()]),
+                    Err(e) => error_frame(&e),
+                };
+                self.sink.send(reply).await?;
+            }
+            Some(Ok(Frame::ListTopics)) => {
+                self.sink
+                    .send(Frame::Topics(self.shared.topics.list()))
+                    .await?;
+            }
+            Some(Ok(Frame::Subscribe {
+                topic,
+                start,
+                credit,
+                ack_timeout_ms,
+                max_in_flight,
+            })) if self.subscription.is_none() => {
+                let cursor = self
+                    .authorize(Operation::Subscribe, &topic)
+                    .and_then(|()| self.resolve(&topic))
+                    .and_then(|topic| topic.open_cursor(start));
+                match cursor {
+                    Ok(cursor) => {
+                        self.sink
+                            .send(Frame::Subscribed {
+                                topic: cursor.topic().id(),
+                                offset: cursor.position(),
+                            })
+                            .await?;
+                        let ack_timeout = Duration::from_millis(ack_timeout_ms as u64);
+                        self.subscription = Some(Subscription::new(
+                            cursor,
+                            credit,
+                            ack_timeout,
+                            max_in_flight,
+                        ));
+                    }
+                    Err(e) => self.sink.send(error_frame(&e)).await?,
                 }
             }
-            _ = shutdown.changed() => {
-                println!("DEBUG: handler received shutdown signal");
+            Some(Ok(Frame::Credit { messages })) if self.subscription.is_some() => {
+                if let Some(sub) = self.subscription.as_mut() {
+                    sub.add_credit(messages);
+                }
+            }
+            Some(Ok(Frame::Ack(ack))) if self.subscription.is_some() => {
+                if let Some(sub) = self.subscription.as_mut() {
+                    sub.ack(ack);
+                }
+            }
+            Some(Ok(Frame::Heartbeat)) => {}
+            Some(Ok(Frame::FlowControl)) if self.credit.is_none() => {
+                let window = self.shared.config.producer_window;
+                self.credit = Some(ProducerCredit::new(window));
+                self.sink.send(Frame::Grant { bytes: window }).await?;
+            }
+            Some(Ok(_)) => {
+                self.sink
+                    .send(Frame::Error {
+                        code: error_code::INVALID_FRAME,
+                        message: "unexpected frame from client".to_string(),
+                    })
+                    .await?;
+                return Err(NetworkError::InvalidFrame("unexpected frame from client"));
+            }
+            Some(Err(NetworkError::Io(_))) | None => return Ok(ControlFlow::Break(())),
+            Some(Err(
+                e @ (NetworkError::FrameTooLarge { .. }
+                | NetworkError::MemoryLimit
+                | NetworkError::UnsupportedVersion(_)),
+            )) => {
+                let code = match e {
+                    NetworkError::MemoryLimit => error_code::OVERLOADED,
+                    NetworkError::UnsupportedVersion(_) => error_code::UNSUPPORTED_VERSION,
+                    _ => error_code::MESSAGE_TOO_LARGE,
+                };
+                self.sink
+                    .send(Frame::Error {
+                        code,
+                        message: e.to_string(),
+                    })
+                    .await?;
+                return Err(e);
+            }
+            Some(Err(e)) => return Err(e),
+        }
+        Ok(ControlFlow::Continue(()))
+    }
+
+    /// the sequence tracker of the producer behind the connection. an authenticated
+    /// one keeps its own across connections, so a reconnect doesn't look like a
+    /// new producer starting over
+    fn sequences(&mut self) -> Arc<Mutex<SequenceTracker>> {
+        if let Some(sequences) = &self.sequences {
+            return sequences.clone();
+        }
+        let sequences = match self.session.publisher.principal.get() {
+            Some(principal) => self
+                .shared
+                .sequences
+                .lock()
+                .entry(principal.clone())
+                .or_default()
+                .clone(),
+            None => Arc::default(),
+        };
+        self.sequences = Some(sequences.clone());
+        sequences
+    }
+
+    /// the producer paid for a batch that was turned away before publishing.
+    /// under flow control that credit has to come back or it is lost for good
+    async fn refund(&mut self, bytes: u64) -> Result<(), NetworkError> {
+        if self.credit.is_some() && bytes > 0 {
+            self.sink
+                .send(Frame::Grant {
+                    bytes: bytes as u32,
+                })
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// hold a publish back until the client's and the topic's quotas allow it, or
+    /// reject it. false if the frame is to be dropped
+    async fn within_quota(
+        &mut self,
+        topic: &Topic,
+        messages: u64,
+        bytes: u64,
+    ) -> Result<bool, NetworkError> {
+        if let (None, Some(quotas)) = (&self.quota, &self.shared.client_quotas) {
+            self.quota = Some(quotas.limiter(&self.session.publisher.client()));
+        }
+        let mut hit = false;
+        loop {
+            let now = Instant::now();
+            let client = self
+                .quota
+                .as_ref()
+                .map_or(Duration::ZERO, |q| q.lock().wait(now));
+            let shared = topic
+                .limiter()
+                .map_or(Duration::ZERO, |q| q.lock().wait(now));
+            if client.is_zero() && shared.is_zero() {
                 break;
             }
+
+            if !hit {
+                hit = true;
+                if !client.is_zero() {
+                    BrokerStats::add(&self.session.quota_hits, 1);
+                }
+                if !shared.is_zero() {
+                    topic.quota_hit();
+                }
+            }
+            match self.shared.config.quota_action {
+                QuotaAction::Reject => {
+                    BrokerStats::add(&self.shared.stats.quota_rejected, 1);
+                    let which = if client.is_zero() { "topic" } else { "client" };
+                    let e = BrokerError::QuotaExceeded(which);
+                    self.refund(bytes).await?;
+                    self.sink.send(error_frame(&e)).await?;
+                    return Ok(false);
+                }
+                QuotaAction::Delay => {
+                    tokio::select! {
+                        _ = tokio::time::sleep(client.max(shared)) => {}
+                        _ = self.shared.abort.cancelled() => return Ok(false),
+                    }
+                }
+            }
+        }
+
+        // a delayed frame counts once, however often it had to wait
+        if hit {
+            BrokerStats::add(&self.shared.stats.quota_delayed, 1);
+        }
+        if let Some(quota) = self.quota.as_ref() {
+            quota.lock().take(messages, bytes);
+        }
+        if let Some(quota) = topic.limiter() {
+            quota.lock().take(messages, bytes);
         }
+        Ok(true)
     }
 
-    // instead of abort
-    // let the consumer exit nicely before starting another
-    consumer.await?;
-    Ok(())
+    /// whether the ACL changed since `last_topic` was authorized
+    fn acl_changed(&mut self) -> bool {
+        let Some(acl) = self.shared.acl.get() else {
+            return false;
+        };
+        let generation = acl.generation();
+        let changed = generation != self.acl_generation;
+        self.acl_generation = generation;
+        changed
+    }
+
+    /// end the subscription with an error frame if the reloaded ACL no longer allows it.
+    /// the connection stays open for whatever else it may do
+    async fn recheck_subscription(&mut self) -> Result<(), NetworkError> {
+        let Some(sub) = &self.subscription else {
+            return Ok(());
+        };
+        let topic = TopicRef::Name(sub.topic().to_string());
+        if let Err(e) = self.authorize(Operation::Subscribe, &topic) {
+            debug!(topic = %topic, "subscription no longer allowed, ending it");
+            self.subscription = None;
+            self.sink.send(error_frame(&e)).await?;
+        }
+        Ok(())
+    }
+
+    /// check `operation` on `topic` against `ServerConfig::acl`, auditing denials.
+    /// unknown topic ids are left for `resolve` to reject
+    fn authorize(&self, operation: Operation, topic: &TopicRef) -> Result<(), BrokerError> {
+        let Some(acl) = self.shared.acl.get() else {
+            return Ok(());
+        };
+        let name = match topic {
+            TopicRef::Name(name) => name.clone(),
+            TopicRef::Id(_) => match self.shared.topics.get(topic) {
+                Some(topic) => topic.name().to_string(),
+                None => return Ok(()),
+            },
+        };
+        let publisher = &self.session.publisher;
+        let principal = publisher.principal.get().map(String::as_str);
+        if acl.allows(principal, operation, &name) {
+            return Ok(());
+        }
+
+        BrokerStats::add(&self.shared.stats.acl_denials, 1);
+        acl.audit(Denial {
+            at: DeadLetter::now(),
+            connection: publisher.connection,
+            peer: &publisher.peer,
+            principal,
+            operation: operation.as_str(),
+            topic: &name,
+            repeated: 0,
+        });
+        Err(BrokerError::Forbidden {
+            principal: principal.unwrap_or(ANONYMOUS).to_string(),
+            operation,
+            topic: name,
+        })
+    }
+
+    /// the merge point a sharded connection publishes to `topic` through
+    fn merge(&self, topic: &Arc<Topic>) -> Result<Option<Arc<Merge>>, BrokerError> {
+        match (self.shard, self.shared.shards.get()) {
+            (Some(_), Some(shards)) => shards.merge(topic, &self.shared.abort).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    fn resolve(&self, topic: &TopicRef) -> Result<Arc<Topic>, BrokerError> {
+        let topic = self.shared.topics.resolve(topic)?;
+        spawn_consumer(&self.shared, topic.clone());
+        Ok(topic)
+    }
+}
+
+/// wake whoever reads what a publish just pushed: the consumers, or the merger
+/// when it went into a shard's lane
+#[inline]
+fn notify_published(topic: &Topic, merge: Option<&Merge>) {
+    match merge {
+        Some(merge) => merge.notify_published(),
+        None => topic.notify_published(),
+    }
+}
+
+/// ticks with the heartbeat interval, never without one
+async fn heartbeat_due(heartbeat: &mut Option<Interval>) {
+    match heartbeat {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// resolves when the ACL was reloaded, never without an ACL
+async fn acl_reloaded(reloads: &mut Option<watch::Receiver<u64>>) {
+    let Some(reloads) = reloads else {
+        return std::future::pending().await;
+    };
+    // the sender lives as long as the connection's `Shared`
+    let _ = reloads.changed().await;
+}
+
+/// resolves when the idle timer runs out, never without an idle timeout
+async fn idle_expired(idle: &mut Option<Pin<Box<Sleep>>>) {
+    match idle {
+        Some(sleep) => sleep.as_mut().await,
+        None => std::future::pending().await,
+    }
+}
+
+fn error_frame(e: &BrokerError) -> Frame {
+    let code = match e {
+        BrokerError::UnknownTopic(_) => error_code::UNKNOWN_TOPIC,
+        BrokerError::InvalidTopicName(_) => error_code::INVALID_TOPIC,
+        BrokerError::TooManyTopics(_) => error_code::TOO_MANY_TOPICS,
+        BrokerError::RingMemoryExhausted { .. } => error_code::OVERLOADED,
+        BrokerError::MessageTooLarge => error_code::MESSAGE_TOO_LARGE,
+        BrokerError::OffsetOutOfRange(_) => error_code::OFFSET_OUT_OF_RANGE,
+        BrokerError::InvalidOffset(_) => error_code::INVALID_OFFSET,
+        BrokerError::CreditExceeded => error_code::CREDIT_EXCEEDED,
+        BrokerError::QuotaExceeded(_) => error_code::QUOTA_EXCEEDED,
+        BrokerError::Forbidden { .. } => error_code::FORBIDDEN,
+        _ => error_code::INTERNAL,
+    };
+    Frame::Error {
+        code,
+        message: e.to_string(),
+    }
 }
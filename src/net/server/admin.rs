@@ -0,0 +1,515 @@
+use super::Shared;
+use crate::error::NetworkError;
+use crate::stats::LATENCY_BUCKETS;
+use crate::topic::{Topic, TopicRef};
+use serde::Serialize;
+use std::fmt::Write as _;
+use std::io;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use subtle::ConstantTimeEq;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::debug;
+
+/// longest request head the admin listener reads
+const MAX_REQUEST: usize = 8 * 1024;
+/// slow or idle admin clients are dropped after this
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+const PROMETHEUS: &str = "text/plain; version=0.0.4";
+const JSON: &str = "application/json";
+
+/// serve admin HTTP requests, one per connection, until the server stops
+pub(super) async fn accept(listener: TcpListener, shared: Arc<Shared>) -> Result<(), NetworkError> {
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shared.stop.cancelled() => return Ok(()),
+        };
+        let (socket, peer) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                super::accept_failed(e, "admin").await?;
+                continue;
+            }
+        };
+        let shared = shared.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(REQUEST_TIMEOUT, respond(socket, &shared)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => debug!(%peer, error = %e, "admin request failed"),
+                Err(_) => debug!(%peer, "admin request timed out"),
+            }
+        });
+    }
+}
+
+struct Request {
+    method: String,
+    /// without the query string
+    path: String,
+    /// the `Authorization` header
+    authorization: Option<String>,
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn ok(content_type: &'static str, body: String) -> Self {
+        Self {
+            status: "200 OK",
+            content_type,
+            body,
+        }
+    }
+
+    fn json(status: &'static str, body: &impl Serialize) -> Self {
+        Self {
+            status,
+            content_type: JSON,
+            body: serde_json::to_string(body).unwrap_or_default(),
+        }
+    }
+
+    fn error(status: &'static str) -> Self {
+        Self::json(status, &serde_json::json!({ "error": status }))
+    }
+}
+
+/// a connection as `GET /connections` lists it
+#[derive(Serialize)]
+struct ConnectionView<'a> {
+    id: u64,
+    peer: &'a str,
+    /// `None` until the connection authenticated, or without `ServerConfig::auth`
+    principal: Option<&'a str>,
+    /// unix time in milliseconds
+    connected_at: u64,
+    frames_in: u64,
+    messages_in: u64,
+    bytes_in: u64,
+    quota_hits: u64,
+}
+
+/// a topic as `GET /topics` lists it
+#[derive(Serialize)]
+struct TopicView<'a> {
+    id: u32,
+    name: &'a str,
+    capacity: u64,
+    used: u64,
+    head: u64,
+    tail: u64,
+    stalls: u64,
+    drops: u64,
+    quota_hits: u64,
+    consumer: bool,
+    paused: bool,
+}
+
+async fn respond(mut socket: TcpStream, shared: &Shared) -> io::Result<()> {
+    let response = match read_request(&mut socket).await? {
+        Some(request) => route(&request, shared),
+        None => Response::error("400 Bad Request"),
+    };
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.content_type,
+        response.body.len()
+    );
+    socket.write_all(head.as_bytes()).await?;
+    socket.write_all(response.body.as_bytes()).await?;
+    socket.shutdown().await
+}
+
+fn route(request: &Request, shared: &Shared) -> Response {
+    if let Some(token) = &shared.config.admin_token {
+        let presented = request
+            .authorization
+            .as_deref()
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .unwrap_or_default();
+        if !bool::from(presented.as_bytes().ct_eq(token.as_bytes())) {
+            return Response::error("401 Unauthorized");
+        }
+    }
+    let segments: Vec<&str> = request.path.split('/').filter(|s| !s.is_empty()).collect();
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["metrics"]) => Response::ok(PROMETHEUS, metrics(shared)),
+        ("GET", ["connections"]) => connections(shared),
+        ("DELETE", ["connections", id]) => disconnect(shared, id),
+        ("GET", ["topics"]) => topics(shared),
+        ("POST", ["topics", name, action @ ("pause" | "resume")]) => pause(shared, name, action),
+        ("POST", ["drain"]) => {
+            shared.drain_requested.cancel();
+            Response::json("202 Accepted", &serde_json::json!({ "draining": true }))
+        }
+        (
+            _,
+            ["metrics"]
+            | ["connections"]
+            | ["connections", _]
+            | ["topics"]
+            | ["topics", _, "pause" | "resume"]
+            | ["drain"],
+        ) => Response::error("405 Method Not Allowed"),
+        _ => Response::error("404 Not Found"),
+    }
+}
+
+fn connections(shared: &Shared) -> Response {
+    let mut sessions: Vec<_> = shared.sessions.lock().values().cloned().collect();
+    sessions.sort_by_key(|s| s.publisher.connection);
+    let views: Vec<_> = sessions
+        .iter()
+        .map(|s| ConnectionView {
+            id: s.publisher.connection,
+            peer: &s.publisher.peer,
+            principal: s.publisher.principal.get().map(String::as_str),
+            connected_at: s.connected_at,
+            frames_in: s.frames_in.load(Ordering::Relaxed),
+            messages_in: s.messages_in.load(Ordering::Relaxed),
+            bytes_in: s.bytes_in.load(Ordering::Relaxed),
+            quota_hits: s.quota_hits.load(Ordering::Relaxed),
+        })
+        .collect();
+    Response::json("200 OK", &views)
+}
+
+fn disconnect(shared: &Shared, id: &str) -> Response {
+    let session = id
+        .parse::<u64>()
+        .ok()
+        .and_then(|id| shared.sessions.lock().get(&id).cloned());
+    match session {
+        Some(session) => {
+            session.close.cancel();
+            Response::json(
+                "200 OK",
+                &serde_json::json!({ "disconnected": session.publisher.connection }),
+            )
+        }
+        None => Response::error("404 Not Found"),
+    }
+}
+
+fn topics(shared: &Shared) -> Response {
+    let topics = shared.topics.all();
+    let views: Vec<_> = topics
+        .iter()
+        .map(|t| TopicView {
+            id: t.id(),
+            name: t.name(),
+            capacity: t.ring().capacity() as u64,
+            used: t.ring().len() as u64,
+            head: t.ring().head(),
+            tail: t.ring().tail(),
+            stalls: t.stalls(),
+            drops: t.drops(),
+            quota_hits: t.quota_hits(),
+            consumer: t.has_consumer(),
+            paused: t.is_paused(),
+        })
+        .collect();
+    Response::json("200 OK", &views)
+}
+
+fn pause(shared: &Shared, name: &str, action: &str) -> Response {
+    let Some(topic) = shared.topics.get(&TopicRef::from(name)) else {
+        return Response::error("404 Not Found");
+    };
+    if action == "pause" {
+        topic.pause();
+    } else {
+        topic.resume();
+    }
+    debug!(
+        topic = name,
+        action, "consumer control through the admin API"
+    );
+    Response::json(
+        "200 OK",
+        &serde_json::json!({ "topic": name, "paused": topic.is_paused() }),
+    )
+}
+
+/// read up to the end of the request head. `None` if it isn't HTTP
+async fn read_request(socket: &mut TcpStream) -> io::Result<Option<Request>> {
+    let mut buf = Vec::with_capacity(1024);
+    loop {
+        if buf.windows(4).any(|w| w == b"\r\n\r\n") {
+            break;
+        }
+        if buf.len() >= MAX_REQUEST {
+            return Ok(None);
+        }
+        let mut chunk = [0; 1024];
+        let n = socket.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+
+    let head = String::from_utf8_lossy(&buf);
+    let mut request_line = head.lines().next().unwrap_or("").split(' ');
+    let (Some(method), Some(target), Some(version)) = (
+        request_line.next(),
+        request_line.next(),
+        request_line.next(),
+    ) else {
+        return Ok(None);
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Ok(None);
+    }
+    let path = target.split('?').next().unwrap_or(target);
+    let authorization = head.lines().skip(1).find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.eq_ignore_ascii_case("authorization")
+            .then(|| value.trim().to_string())
+    });
+    Ok(Some(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        authorization,
+    }))
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    metric(out, name, "counter", help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    metric(out, name, "gauge", help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// name, type, help and value of a metric with a sample per topic
+type TopicMetric = (&'static str, &'static str, &'static str, fn(&Topic) -> u64);
+
+/// a label value, escaped as the text format wants it
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// everything in the Prometheus text exposition format
+fn metrics(shared: &Shared) -> String {
+    let stats = shared.stats.snapshot();
+    let mut out = String::with_capacity(8 * 1024);
+
+    gauge(
+        &mut out,
+        "broker_connections_open",
+        "Connections currently open.",
+        stats.open_connections,
+    );
+    gauge(
+        &mut out,
+        "broker_connection_memory_bytes",
+        "Read buffer bytes reserved by open connections.",
+        shared.memory.used() as u64,
+    );
+    let counters = [
+        (
+            "broker_connections_total",
+            "Connections accepted.",
+            stats.connections,
+        ),
+        (
+            "broker_connections_refused_total",
+            "Connections refused for lack of memory.",
+            stats.connections_refused,
+        ),
+        (
+            "broker_frames_in_total",
+            "Frames read from clients.",
+            stats.frames_in,
+        ),
+        (
+            "broker_messages_in_total",
+            "Messages published into topic rings.",
+            stats.messages_in,
+        ),
+        (
+            "broker_bytes_in_total",
+            "Published message bytes.",
+            stats.bytes_in,
+        ),
+        (
+            "broker_messages_consumed_total",
+            "Records read by the topic consumers.",
+            stats.messages_consumed,
+        ),
+        (
+            "broker_messages_processed_total",
+            "Records a handler took without error.",
+            stats.messages_processed,
+        ),
+        (
+            "broker_processing_errors_total",
+            "Records that failed validation.",
+            stats.processing_errors,
+        ),
+        (
+            "broker_handler_errors_total",
+            "Messages given up on after the handler failed.",
+            stats.handler_errors,
+        ),
+        (
+            "broker_handler_retries_total",
+            "Handler calls repeated by the retry policy.",
+            stats.handler_retries,
+        ),
+        (
+            "broker_dead_letters_total",
+            "Records written to the dead letter log.",
+            stats.dead_letters,
+        ),
+        (
+            "broker_messages_expired_total",
+            "Messages past their topic's TTL.",
+            stats.messages_expired,
+        ),
+        (
+            "broker_sequence_gaps_total",
+            "Sequence numbers producers skipped.",
+            stats.sequence_gaps,
+        ),
+        (
+            "broker_sequence_duplicates_total",
+            "Messages with a sequence number seen before.",
+            stats.sequence_duplicates,
+        ),
+        (
+            "broker_sequence_reordered_total",
+            "Skipped sequence numbers that arrived late.",
+            stats.sequence_reordered,
+        ),
+        (
+            "broker_sequence_unknown_total",
+            "Sequence numbers too far behind to tell late from duplicate.",
+            stats.sequence_unknown,
+        ),
+        (
+            "broker_quota_delayed_total",
+            "Publish frames held back by a client or topic quota.",
+            stats.quota_delayed,
+        ),
+        (
+            "broker_quota_rejected_total",
+            "Publish frames rejected for being over a quota.",
+            stats.quota_rejected,
+        ),
+        (
+            "broker_idle_timeouts_total",
+            "Connections closed for going quiet past the idle timeout.",
+            stats.idle_timeouts,
+        ),
+        (
+            "broker_auth_failures_total",
+            "Connections closed for failing authentication.",
+            stats.auth_failures,
+        ),
+        (
+            "broker_tls_failures_total",
+            "TLS handshakes that failed or timed out.",
+            stats.tls_failures,
+        ),
+        (
+            "broker_acl_denials_total",
+            "Publishes, declares and subscribes denied by the ACL.",
+            stats.acl_denials,
+        ),
+    ];
+    for (name, help, value) in counters {
+        counter(&mut out, name, help, value);
+    }
+
+    let topics = shared.topics.all();
+    let per_topic: [TopicMetric; 5] = [
+        (
+            "broker_ring_used_bytes",
+            "gauge",
+            "Bytes held in the topic's ring.",
+            |t| t.ring().len() as u64,
+        ),
+        (
+            "broker_ring_capacity_bytes",
+            "gauge",
+            "Size of the topic's ring.",
+            |t| t.ring().capacity() as u64,
+        ),
+        (
+            "broker_publish_stalls_total",
+            "counter",
+            "Publish batches that waited for room in the ring.",
+            Topic::stalls,
+        ),
+        (
+            "broker_subscriber_drops_total",
+            "counter",
+            "Records at-most-once subscribers lost to a full ring.",
+            Topic::drops,
+        ),
+        (
+            "broker_topic_quota_hits_total",
+            "counter",
+            "Publish frames over the topic's quota.",
+            Topic::quota_hits,
+        ),
+    ];
+    for (name, kind, help, value) in per_topic {
+        metric(&mut out, name, kind, help);
+        for topic in &topics {
+            let _ = writeln!(
+                out,
+                "{}{{topic=\"{}\"}} {}",
+                name,
+                label(topic.name()),
+                value(topic)
+            );
+        }
+    }
+
+    let name = "broker_message_latency_seconds";
+    metric(
+        &mut out,
+        name,
+        "histogram",
+        "Age of messages when their topic consumer reads them, from the producer timestamp.",
+    );
+    let (buckets, sum) = shared.stats.latency.load();
+    let mut count = 0;
+    for (bound, n) in LATENCY_BUCKETS.iter().zip(buckets) {
+        count += n;
+        let _ = writeln!(
+            out,
+            "{}_bucket{{le=\"{}\"}} {}",
+            name,
+            *bound as f64 / 1e9,
+            count
+        );
+    }
+    count += buckets[LATENCY_BUCKETS.len()];
+    let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+    let _ = writeln!(out, "{}_sum {}", name, sum as f64 / 1e9);
+    let _ = writeln!(out, "{}_count {}", name, count);
+    out
+}
@@ -0,0 +1,401 @@
+use super::{accept, Shared};
+use crate::buffer::{RECORD_HEADER_LEN, STAMP_LEN};
+use crate::config::ShardConfig;
+use crate::error::{BrokerError, NetworkError};
+use crate::net::credit::{Merged, Position};
+use crate::net::transport::{self, Listener};
+use crate::topic::{Publisher, Topic, TopicId};
+use crate::{RingBuffer, BATCH_SIZE};
+use bytes::BytesMut;
+use parking_lot::{Mutex, RwLock};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::iter::{self, Peekable};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use tokio::runtime::{self, Handle, Runtime};
+use tokio::sync::{oneshot, Notify};
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info_span, warn, Instrument};
+
+/// the listener shards of a server, see `ShardConfig`
+pub(super) struct Shards {
+    config: ShardConfig,
+    /// the runtime `BrokerServer::start` ran on, where mergers and consumers live
+    runtime: Handle,
+    merges: RwLock<HashMap<TopicId, Arc<Merge>>>,
+    /// each merger returns how many bytes it left in the shard rings
+    mergers: Mutex<JoinSet<u64>>,
+    /// the connections are gone, mergers move what is left and exit
+    closed: CancellationToken,
+    threads: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Shards {
+    pub(super) fn new(config: ShardConfig) -> io::Result<Self> {
+        if config.count == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "shard count must be at least 1",
+            ));
+        }
+        // fail here rather than on the first publish
+        RingBuffer::with_capacity(config.ring_capacity).map_err(io::Error::other)?;
+        Ok(Self {
+            config,
+            runtime: Handle::current(),
+            merges: RwLock::new(HashMap::new()),
+            mergers: Mutex::new(JoinSet::new()),
+            closed: CancellationToken::new(),
+            threads: Mutex::new(Vec::new()),
+        })
+    }
+
+    #[inline]
+    pub(super) fn runtime(&self) -> &Handle {
+        &self.runtime
+    }
+
+    /// bind every address once per shard. the result has a listener per address for
+    /// each shard, and the bound addresses
+    pub(super) fn bind(
+        &self,
+        addrs: &[SocketAddr],
+    ) -> io::Result<(Vec<Vec<std::net::TcpListener>>, Vec<SocketAddr>)> {
+        let mut shards: Vec<Vec<_>> = (0..self.config.count).map(|_| Vec::new()).collect();
+        let mut local_addrs = Vec::with_capacity(addrs.len());
+        for addr in addrs {
+            let listeners = transport::bind_reuseport(*addr, self.config.count)?;
+            local_addrs.push(listeners[0].local_addr()?);
+            for (shard, listener) in shards.iter_mut().zip(listeners) {
+                shard.push(listener);
+            }
+        }
+        Ok((shards, local_addrs))
+    }
+
+    /// run shard `index` on a thread of its own, accepting on `listeners`. every
+    /// returned receiver resolves when the accept loop of one listener ends
+    pub(super) fn spawn(
+        &self,
+        index: usize,
+        listeners: Vec<std::net::TcpListener>,
+        shared: Arc<Shared>,
+    ) -> io::Result<Vec<oneshot::Receiver<Result<(), NetworkError>>>> {
+        let runtime = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let listeners = {
+            let _runtime = runtime.enter();
+            listeners
+                .into_iter()
+                .map(Listener::from_std)
+                .collect::<io::Result<Vec<_>>>()?
+        };
+        let (stopped, receivers): (Vec<_>, Vec<_>) =
+            listeners.iter().map(|_| oneshot::channel()).unzip();
+
+        let core = self.config.pin_cores.then(|| {
+            core_affinity::get_core_ids()
+                .filter(|cores| !cores.is_empty())
+                .map(|cores| cores[index % cores.len()])
+        });
+        let thread = thread::Builder::new()
+            .name(format!("broker-shard-{}", index))
+            .spawn(move || {
+                if let Some(Some(core)) = core {
+                    core_affinity::set_for_current(core);
+                }
+                serve(runtime, index, listeners, stopped, shared);
+            })?;
+        self.threads.lock().push(thread);
+        Ok(receivers)
+    }
+
+    /// the merge point of `topic`, started on first use
+    pub(super) fn merge(
+        &self,
+        topic: &Arc<Topic>,
+        abort: &CancellationToken,
+    ) -> Result<Arc<Merge>, BrokerError> {
+        if let Some(merge) = self.merges.read().get(&topic.id()) {
+            return Ok(merge.clone());
+        }
+
+        let mut merges = self.merges.write();
+        if let Some(merge) = merges.get(&topic.id()) {
+            return Ok(merge.clone());
+        }
+        let lanes = (0..self.config.count)
+            .map(|_| Lane::new(self.config.ring_capacity))
+            .collect::<Result<_, _>>()?;
+        let merge = Arc::new(Merge {
+            topic: topic.clone(),
+            lanes,
+            stamps: AtomicU64::new(0),
+            merged: Arc::default(),
+            published: Notify::new(),
+        });
+        merges.insert(topic.id(), merge.clone());
+
+        let span = info_span!("merger", topic = %topic.name());
+        let run = merge.clone().run(self.closed.clone(), abort.clone());
+        self.mergers
+            .lock()
+            .spawn_on(run.instrument(span), &self.runtime);
+        Ok(merge)
+    }
+
+    /// stop the mergers once the connections are gone and wait for the shard threads.
+    /// with `abort` cancelled the mergers drop what is left in the shard rings,
+    /// otherwise they move it into the topics first, until `deadline`. returns the
+    /// bytes dropped and whether the deadline passed
+    pub(super) async fn close(&self, deadline: Instant, abort: &CancellationToken) -> (u64, bool) {
+        self.closed.cancel();
+        let mut mergers = std::mem::take(&mut *self.mergers.lock());
+        let mut left = 0;
+        let mut timed_out = false;
+        loop {
+            let next = match tokio::time::timeout_at(deadline, mergers.join_next()).await {
+                Ok(next) => next,
+                Err(_) => {
+                    timed_out = true;
+                    abort.cancel();
+                    mergers.join_next().await
+                }
+            };
+            match next {
+                Some(Ok(bytes)) => left += bytes,
+                Some(Err(e)) => warn!(error = %e, "merger failed"),
+                None => break,
+            }
+        }
+
+        let threads = std::mem::take(&mut *self.threads.lock());
+        let _ = tokio::task::spawn_blocking(move || {
+            for thread in threads {
+                let _ = thread.join();
+            }
+        })
+        .await;
+        (left, timed_out)
+    }
+}
+
+/// the body of a shard thread: accept on every listener, then keep the accepted
+/// connections running until the server closed them
+fn serve(
+    runtime: Runtime,
+    index: usize,
+    listeners: Vec<Listener>,
+    stopped: Vec<oneshot::Sender<Result<(), NetworkError>>>,
+    shared: Arc<Shared>,
+) {
+    let span = info_span!("shard", index);
+    runtime.block_on(
+        async move {
+            debug!("shard started");
+            let mut acceptors = JoinSet::new();
+            for (listener, stopped) in listeners.into_iter().zip(stopped) {
+                let shared = shared.clone();
+                acceptors.spawn(async move {
+                    let _ = stopped.send(accept(listener, shared, Some(index)).await);
+                });
+            }
+            while acceptors.join_next().await.is_some() {}
+            shared.connections.wait().await;
+            debug!("shard stopped");
+        }
+        .instrument(span),
+    );
+}
+
+/// what one shard published to a topic, waiting to be merged
+struct Lane {
+    /// records behind their order number
+    ring: RingBuffer,
+    /// who published from which offset on, for records still in the ring
+    publishers: Mutex<VecDeque<(u64, Arc<Publisher>)>>,
+}
+
+impl Lane {
+    fn new(capacity: usize) -> Result<Self, BrokerError> {
+        Ok(Self {
+            ring: RingBuffer::with_capacity(capacity)?,
+            publishers: Mutex::new(VecDeque::new()),
+        })
+    }
+
+    /// the oldest record, without its order number
+    fn front(&self) -> Option<Front> {
+        let offset = self.ring.tail();
+        let mut record = BytesMut::new();
+        let next = self.ring.read_record(offset, &mut record).ok()?;
+        let stamp = u64::from_le_bytes(record.split_to(STAMP_LEN)[..].try_into().ok()?);
+        let publishers = self.publishers.lock();
+        let after = publishers.partition_point(|(start, _)| *start <= offset);
+        let publisher = publishers[after.checked_sub(1)?].1.clone();
+        Some(Front {
+            stamp,
+            record,
+            next,
+            publisher,
+        })
+    }
+
+    /// the record before `next` was merged
+    fn release(&self, next: u64) {
+        self.ring.release(next);
+        let mut publishers = self.publishers.lock();
+        while publishers.len() > 1 && publishers[1].0 <= next {
+            publishers.pop_front();
+        }
+    }
+}
+
+/// the oldest record of a lane, read but not merged yet
+struct Front {
+    stamp: u64,
+    record: BytesMut,
+    /// offset after the record
+    next: u64,
+    publisher: Arc<Publisher>,
+}
+
+/// where the shards' records for one topic come together. every record takes the
+/// next order number when it is published, and the merger moves them into the
+/// topic's ring in exactly that order
+pub(super) struct Merge {
+    topic: Arc<Topic>,
+    /// one per shard
+    lanes: Vec<Lane>,
+    stamps: AtomicU64,
+    merged: Arc<Merged>,
+    published: Notify,
+}
+
+impl Merge {
+    /// like `Topic::push_many`, into the lane of `shard`. messages the topic's ring
+    /// could never take are refused here, nobody would be told after the merge
+    pub(super) fn push_many<'a, I>(
+        &self,
+        shard: usize,
+        publisher: &Arc<Publisher>,
+        messages: &mut Peekable<I>,
+    ) -> Result<(), BrokerError>
+    where
+        I: Iterator<Item = &'a [u8]>,
+    {
+        let lane = &self.lanes[shard];
+        {
+            let mut publishers = lane.publishers.lock();
+            if !publishers
+                .back()
+                .is_some_and(|(_, last)| Arc::ptr_eq(last, publisher))
+            {
+                publishers.push_back((lane.ring.head(), publisher.clone()));
+            }
+        }
+        let largest = self.topic.ring().capacity() / 4 - RECORD_HEADER_LEN;
+        while let Some(msg) = messages.peek() {
+            if msg.len() > largest {
+                return Err(BrokerError::MessageTooLarge);
+            }
+            lane.ring.try_push_stamped(&self.stamps, msg)?;
+            messages.next();
+        }
+        Ok(())
+    }
+
+    /// wake the merger, once per published batch
+    #[inline]
+    pub(super) fn notify_published(&self) {
+        self.published.notify_one();
+    }
+
+    /// where what was pushed so far ends, for the credit of the producer that pushed
+    /// it last. records other shards stamped meanwhile are waited for as well
+    pub(super) fn position(&self) -> Position {
+        Position::Merging {
+            merged: self.merged.clone(),
+            stamp: self.stamps.load(Ordering::Acquire),
+        }
+    }
+
+    /// the consumers and the producers' credit learn about merged records
+    fn notify_merged(&self) {
+        self.topic.notify_published();
+        self.merged.notify();
+    }
+
+    /// merge until closed or aborted. returns how many bytes were left in the lanes
+    async fn run(self: Arc<Self>, closed: CancellationToken, abort: CancellationToken) -> u64 {
+        debug!(lanes = self.lanes.len(), "merger started");
+        let mut fronts: Vec<Option<Front>> = self.lanes.iter().map(|_| None).collect();
+        let mut next_stamp = 0;
+        let mut merged = 0;
+        loop {
+            if abort.is_cancelled() {
+                return self.lanes.iter().map(|lane| lane.ring.len() as u64).sum();
+            }
+            for (lane, front) in self.lanes.iter().zip(fronts.iter_mut()) {
+                if front.is_none() {
+                    *front = lane.front();
+                }
+            }
+
+            let due = fronts.iter_mut().enumerate().find_map(|(index, slot)| {
+                slot.take_if(|front| front.stamp == next_stamp)
+                    .map(|front| (index, front))
+            });
+            if let Some((index, front)) = due {
+                let mut record = iter::once(&front.record[..]).peekable();
+                match self.topic.push_many(&front.publisher, &mut record) {
+                    Ok(()) => {}
+                    Err(BrokerError::BufferFull) => {
+                        self.topic.stalled();
+                        self.notify_merged();
+                        fronts[index] = Some(front);
+                        merged = 0;
+                        tokio::task::yield_now().await;
+                        continue;
+                    }
+                    Err(e) => warn!(error = %e, "merged record dropped"),
+                }
+                self.lanes[index].release(front.next);
+                next_stamp += 1;
+                self.merged.advance(next_stamp);
+                merged += 1;
+                if merged == BATCH_SIZE {
+                    self.notify_merged();
+                    merged = 0;
+                    tokio::task::yield_now().await;
+                }
+                continue;
+            }
+
+            if merged > 0 {
+                self.notify_merged();
+                merged = 0;
+            }
+            if fronts.iter().any(Option::is_some) {
+                // the next number was taken, its record is still being written
+                tokio::task::yield_now().await;
+                continue;
+            }
+            if closed.is_cancelled() {
+                debug!(merged = next_stamp, "merger finished");
+                return 0;
+            }
+            tokio::select! {
+                _ = self.published.notified() => {}
+                _ = closed.cancelled() => {}
+                _ = abort.cancelled() => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,205 @@
+use crate::auth::Credential;
+use crate::config::Keepalive;
+use crate::error::NetworkError;
+use crate::net::client::authenticate;
+use crate::net::codec::{Ack, BrokerCodec, Frame, Record};
+use crate::net::tls::ClientTls;
+use crate::net::transport::{set_keepalive, Stream};
+use crate::topic::{StartPosition, TopicId, TopicRef};
+use crate::{BATCH_SIZE, BUFFER_CHUNK};
+use futures_util::{SinkExt, StreamExt};
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio_util::codec::Framed;
+
+/// messages the broker may send ahead of what the application has consumed
+pub const DEFAULT_CREDIT_WINDOW: u32 = (BATCH_SIZE * 4) as u32;
+
+/// individual acks are sent in batches of this many
+const ACK_BATCH: usize = 256;
+
+#[derive(Debug, Clone)]
+pub struct SubscribeOptions {
+    /// bounds how many messages are buffered between the broker and `recv`
+    pub window: u32,
+    /// `None` for at-most-once: the broker forgets records as soon as they are sent,
+    /// and skips those it couldn't send yet when the topic's ring is full.
+    /// with a timeout every record must be acked, and is redelivered if it isn't in time
+    pub ack_timeout: Option<Duration>,
+    /// most records unacked at once, only used with `ack_timeout`
+    pub max_in_flight: u32,
+    /// connect over TLS, for brokers with `ServerConfig::tls`
+    pub tls: Option<ClientTls>,
+    /// TCP keepalive probing of the broker
+    pub keepalive: Option<Keepalive>,
+    /// `recv` fails with `NetworkError::IdleTimeout` when the broker sent nothing,
+    /// heartbeats included, for this long. only useful if the broker sends heartbeats
+    pub idle_timeout: Option<Duration>,
+    /// authenticate before subscribing, for brokers with `ServerConfig::auth`
+    pub credential: Option<Credential>,
+}
+
+impl Default for SubscribeOptions {
+    fn default() -> Self {
+        Self {
+            window: DEFAULT_CREDIT_WINDOW,
+            ack_timeout: None,
+            max_in_flight: DEFAULT_CREDIT_WINDOW,
+            tls: None,
+            keepalive: None,
+            idle_timeout: None,
+            credential: None,
+        }
+    }
+}
+
+/// reads a topic from the broker, one record at a time
+pub struct BrokerSubscriber {
+    framed: Framed<Stream, BrokerCodec>,
+    topic: TopicId,
+    start_offset: u64,
+    pending: VecDeque<Record>,
+    window: u32,
+    /// consumed since credit was last topped up
+    consumed: u32,
+    acks: Vec<u64>,
+    idle_timeout: Option<Duration>,
+}
+
+impl BrokerSubscriber {
+    pub async fn connect(
+        addr: impl ToSocketAddrs,
+        topic: &str,
+        start: StartPosition,
+    ) -> Result<Self, NetworkError> {
+        Self::connect_with_options(addr, topic, start, SubscribeOptions::default()).await
+    }
+
+    pub async fn connect_with_options(
+        addr: impl ToSocketAddrs,
+        topic: &str,
+        start: StartPosition,
+        options: SubscribeOptions,
+    ) -> Result<Self, NetworkError> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        if let Some(keepalive) = options.keepalive {
+            set_keepalive(&stream, keepalive)?;
+        }
+        let stream = match &options.tls {
+            Some(tls) => tls.connect(stream).await?,
+            None => Stream::Tcp(stream),
+        };
+        let mut framed = Framed::with_capacity(stream, BrokerCodec::new(), BUFFER_CHUNK * 4);
+        if let Some(credential) = &options.credential {
+            authenticate(&mut framed, credential).await?;
+        }
+
+        let window = options.window.max(1);
+        let ack_timeout_ms = options
+            .ack_timeout
+            .map(|t| (t.as_millis() as u32).max(1))
+            .unwrap_or(0);
+        framed
+            .send(Frame::Subscribe {
+                topic: TopicRef::from(topic),
+                start,
+                credit: window,
+                ack_timeout_ms,
+                max_in_flight: options.max_in_flight,
+            })
+            .await?;
+
+        let (topic, start_offset) = match framed.next().await {
+            Some(Ok(Frame::Subscribed { topic, offset })) => (topic, offset),
+            Some(Ok(Frame::Error { code, message })) => {
+                return Err(NetworkError::Remote { code, message })
+            }
+            Some(Ok(_)) => return Err(NetworkError::InvalidFrame("unexpected response frame")),
+            Some(Err(e)) => return Err(e),
+            None => return Err(NetworkError::Closed),
+        };
+
+        Ok(Self {
+            framed,
+            topic,
+            start_offset,
+            pending: VecDeque::new(),
+            window,
+            consumed: 0,
+            acks: Vec::new(),
+            idle_timeout: options.idle_timeout,
+        })
+    }
+
+    /// id the broker assigned to the subscribed topic
+    pub fn topic(&self) -> TopicId {
+        self.topic
+    }
+
+    /// offset of the first record of the subscription
+    pub fn start_offset(&self) -> u64 {
+        self.start_offset
+    }
+
+    /// next record, or `None` once the broker closed the connection.
+    ///
+    /// with acks enabled a record may show up again if it wasn't acked in time
+    pub async fn recv(&mut self) -> Result<Option<Record>, NetworkError> {
+        loop {
+            if let Some(record) = self.pending.pop_front() {
+                self.consumed += 1;
+                if self.consumed >= self.window / 2 {
+                    let messages = std::mem::take(&mut self.consumed);
+                    self.framed.send(Frame::Credit { messages }).await?;
+                }
+                return Ok(Some(record));
+            }
+
+            // about to wait on the broker, don't leave acks sitting here meanwhile
+            self.flush_acks().await?;
+
+            let frame = match self.idle_timeout {
+                Some(timeout) => tokio::time::timeout(timeout, self.framed.next())
+                    .await
+                    .map_err(|_| NetworkError::IdleTimeout(timeout))?,
+                None => self.framed.next().await,
+            };
+            match frame {
+                Some(Ok(Frame::Deliver(delivery))) => self.pending.extend(delivery.records()),
+                // answered, so a subscriber waiting on a quiet topic isn't taken for dead
+                Some(Ok(Frame::Heartbeat)) => self.framed.send(Frame::Heartbeat).await?,
+                Some(Ok(Frame::Error { code, message })) => {
+                    return Err(NetworkError::Remote { code, message })
+                }
+                Some(Ok(_)) => return Err(NetworkError::InvalidFrame("unexpected frame")),
+                Some(Err(e)) => return Err(e),
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// mark one record as processed. acks are batched, see `flush_acks`
+    pub async fn ack(&mut self, offset: u64) -> Result<(), NetworkError> {
+        self.acks.push(offset);
+        if self.acks.len() >= ACK_BATCH {
+            self.flush_acks().await?;
+        }
+        Ok(())
+    }
+
+    /// mark every record up to and including `offset` as processed, sent right away
+    pub async fn ack_through(&mut self, offset: u64) -> Result<(), NetworkError> {
+        self.acks.retain(|acked| *acked > offset);
+        self.framed.send(Frame::Ack(Ack::Through(offset))).await
+    }
+
+    pub async fn flush_acks(&mut self) -> Result<(), NetworkError> {
+        if self.acks.is_empty() {
+            return Ok(());
+        }
+        let offsets = std::mem::take(&mut self.acks);
+        self.framed.send(Frame::Ack(Ack::Offsets(offsets))).await
+    }
+}
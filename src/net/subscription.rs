@@ -0,0 +1,197 @@
+use crate::error::{BrokerError, NetworkError};
+use crate::net::codec::{Ack, BrokerCodec, DeliveryBuilder, Frame};
+use crate::topic::Cursor;
+use crate::{BATCH_SIZE, BUFFER_CHUNK};
+use futures_util::SinkExt;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio::io::AsyncWrite;
+use tokio::time::Instant;
+use tokio_util::codec::FramedWrite;
+
+/// unacked records of an at-least-once subscription
+struct InFlight {
+    timeout: Duration,
+    max: u32,
+    /// offset -> when it was last sent
+    sent: BTreeMap<u64, Instant>,
+}
+
+/// a connection streaming a topic back to a `BrokerSubscriber`.
+///
+/// `cursor` is the retention point: the lowest unacked offset, or `next` when
+/// nothing is in flight. the ring keeps everything from there on, except that
+/// at-most-once subscribers are skipped forward when the ring is full
+pub(crate) struct Subscription {
+    cursor: Cursor,
+    next: u64,
+    credit: u32,
+    in_flight: Option<InFlight>,
+}
+
+impl Subscription {
+    /// `ack_timeout` of zero means records count as acked once sent
+    pub(crate) fn new(
+        cursor: Cursor,
+        credit: u32,
+        ack_timeout: Duration,
+        max_in_flight: u32,
+    ) -> Self {
+        let in_flight = (!ack_timeout.is_zero()).then(|| InFlight {
+            timeout: ack_timeout,
+            max: max_in_flight.max(1),
+            sent: BTreeMap::new(),
+        });
+        if in_flight.is_none() {
+            cursor.skip_when_full();
+        }
+        Self {
+            next: cursor.position(),
+            cursor,
+            credit,
+            in_flight,
+        }
+    }
+
+    pub(crate) fn topic(&self) -> &str {
+        self.cursor.topic().name()
+    }
+
+    pub(crate) fn add_credit(&mut self, messages: u32) {
+        self.credit = self.credit.saturating_add(messages);
+    }
+
+    /// how many more records may be sent right now
+    fn window(&self) -> u32 {
+        let unacked_room = match &self.in_flight {
+            Some(in_flight) => in_flight.max.saturating_sub(in_flight.sent.len() as u32),
+            None => u32::MAX,
+        };
+        self.credit.min(unacked_room).min(BATCH_SIZE as u32)
+    }
+
+    /// resolves when there is something to deliver, never if the subscriber has no window left
+    pub(crate) async fn ready(subscription: &Option<Subscription>) {
+        match subscription {
+            Some(sub) if sub.window() > 0 => sub.cursor.topic().wait_for(sub.next).await,
+            _ => std::future::pending().await,
+        }
+    }
+
+    /// resolves when the oldest unacked record is due for redelivery, never while
+    /// the subscriber has no credit for it
+    pub(crate) async fn redelivery_due(subscription: &Option<Subscription>) {
+        let due = subscription
+            .as_ref()
+            .filter(|sub| sub.credit > 0)
+            .and_then(|sub| sub.in_flight.as_ref())
+            .and_then(|in_flight| {
+                let oldest = in_flight.sent.values().min()?;
+                Some(*oldest + in_flight.timeout)
+            });
+        match due {
+            Some(due) => tokio::time::sleep_until(due).await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// send up to one batch worth of new records, bounded by credit and the in-flight window
+    pub(crate) async fn deliver<W>(
+        &mut self,
+        sink: &mut FramedWrite<W, BrokerCodec>,
+    ) -> Result<(), NetworkError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut delivery = DeliveryBuilder::new(self.cursor.topic().id());
+        let limit = self.window();
+        let now = Instant::now();
+        let held = self.in_flight.is_none().then(|| self.cursor.hold());
+        // a full ring may have skipped the cursor past what was never sent
+        let mut offset = self.next.max(self.cursor.position());
+
+        while delivery.count() < limit && delivery.len() < BUFFER_CHUNK {
+            match delivery.push_with(offset, |dst| self.cursor.read(offset, dst)) {
+                Ok(next) => {
+                    if let Some(in_flight) = self.in_flight.as_mut() {
+                        in_flight.sent.insert(offset, now);
+                    }
+                    offset = next;
+                }
+                Err(BrokerError::BufferEmpty) => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        drop(held);
+        self.next = offset;
+        if delivery.is_empty() {
+            return Ok(());
+        }
+
+        self.credit -= delivery.count();
+        self.release();
+        sink.send(Frame::Deliver(delivery.finish())).await
+    }
+
+    /// resend the records whose ack timeout has passed, as far as credit goes.
+    /// the subscriber can't tell them from new ones, so they cost the same
+    pub(crate) async fn redeliver<W>(
+        &mut self,
+        sink: &mut FramedWrite<W, BrokerCodec>,
+    ) -> Result<(), NetworkError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let Some(in_flight) = self.in_flight.as_mut() else {
+            return Ok(());
+        };
+
+        let now = Instant::now();
+        let limit = self.credit.min(BATCH_SIZE as u32);
+        let mut delivery = DeliveryBuilder::new(self.cursor.topic().id());
+        for (offset, sent) in in_flight.sent.iter_mut() {
+            if delivery.count() >= limit || delivery.len() >= BUFFER_CHUNK {
+                break;
+            }
+            if *sent + in_flight.timeout > now {
+                continue;
+            }
+            // still retained: the cursor never moves past an unacked offset
+            delivery.push_with(*offset, |dst| self.cursor.read(*offset, dst))?;
+            *sent = now;
+        }
+        if delivery.is_empty() {
+            return Ok(());
+        }
+
+        self.credit -= delivery.count();
+        sink.send(Frame::Deliver(delivery.finish())).await
+    }
+
+    pub(crate) fn ack(&mut self, ack: Ack) {
+        let Some(in_flight) = self.in_flight.as_mut() else {
+            return;
+        };
+        match ack {
+            Ack::Through(offset) => {
+                in_flight.sent = in_flight.sent.split_off(&offset.saturating_add(1));
+            }
+            Ack::Offsets(offsets) => {
+                for offset in offsets {
+                    in_flight.sent.remove(&offset);
+                }
+            }
+        }
+        self.release();
+    }
+
+    /// let the ring reclaim everything below the lowest unacked offset
+    fn release(&self) {
+        let lowest = self
+            .in_flight
+            .as_ref()
+            .and_then(|in_flight| in_flight.sent.keys().next().copied())
+            .unwrap_or(self.next);
+        self.cursor.advance(lowest);
+    }
+}
@@ -0,0 +1,151 @@
+use crate::net::transport::Stream;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{self, RootCertStore};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+/// TLS for `BrokerClient` and `BrokerSubscriber`, see `ClientOptions::tls`
+#[derive(Debug, Clone)]
+pub struct ClientTls {
+    pub config: Arc<rustls::ClientConfig>,
+    /// the name the broker's certificate has to be valid for
+    pub server_name: ServerName<'static>,
+}
+
+impl ClientTls {
+    pub fn new(config: Arc<rustls::ClientConfig>, server_name: &str) -> io::Result<Self> {
+        let server_name = ServerName::try_from(server_name.to_string())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        Ok(Self {
+            config,
+            server_name,
+        })
+    }
+
+    pub(crate) async fn connect(&self, stream: TcpStream) -> io::Result<Stream> {
+        let connector = TlsConnector::from(self.config.clone());
+        let stream = connector.connect(self.server_name.clone(), stream).await?;
+        Ok(Stream::Tls(Box::new(stream.into())))
+    }
+}
+
+/// a server config from a PEM certificate chain and key. with `client_ca`, clients
+/// need a certificate issued by it: mutual TLS, see `ServerConfig::tls`
+pub fn server_config(
+    cert: impl AsRef<Path>,
+    key: impl AsRef<Path>,
+    client_ca: Option<&Path>,
+) -> io::Result<Arc<rustls::ServerConfig>> {
+    let builder = rustls::ServerConfig::builder();
+    let builder = match client_ca {
+        Some(ca) => {
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots(ca)?))
+                .build()
+                .map_err(io::Error::other)?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder
+        .with_single_cert(certs(cert.as_ref())?, private_key(key.as_ref())?)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(Arc::new(config))
+}
+
+/// a client config trusting the PEM certificates in `ca`. `identity` is a
+/// certificate chain and key to present to brokers that require mutual TLS
+pub fn client_config(
+    ca: impl AsRef<Path>,
+    identity: Option<(&Path, &Path)>,
+) -> io::Result<Arc<rustls::ClientConfig>> {
+    let builder = rustls::ClientConfig::builder().with_root_certificates(roots(ca.as_ref())?);
+    let config = match identity {
+        Some((cert, key)) => builder
+            .with_client_auth_cert(certs(cert)?, private_key(key)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+fn certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| pem_error(path, e))?;
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: no certificates", path.display()),
+        ));
+    }
+    Ok(certs)
+}
+
+fn private_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path).map_err(|e| pem_error(path, e))
+}
+
+fn roots(path: &Path) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in certs(path)? {
+        roots
+            .add(cert)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    }
+    Ok(roots)
+}
+
+fn pem_error(path: &Path, e: rustls::pki_types::pem::Error) -> io::Error {
+    match e {
+        rustls::pki_types::pem::Error::Io(e) => {
+            io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+        }
+        e => io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {:?}", path.display(), e),
+        ),
+    }
+}
+
+/// the server side of a TLS connection, and who the client certificate says the
+/// client is if it sent one
+pub(crate) async fn accept(
+    acceptor: &TlsAcceptor,
+    stream: TcpStream,
+) -> io::Result<(Stream, Option<String>)> {
+    let stream = acceptor.accept(stream).await?;
+    let identity = stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .and_then(|cert| identity(cert));
+    Ok((Stream::Tls(Box::new(stream.into())), identity))
+}
+
+/// the subject common name of a certificate, or its first DNS name without one
+fn identity(cert: &CertificateDer<'_>) -> Option<String> {
+    let (_, cert) = X509Certificate::from_der(cert).ok()?;
+    if let Some(name) = cert
+        .subject()
+        .iter_common_name()
+        .find_map(|cn| cn.as_str().ok())
+    {
+        return Some(name.to_string());
+    }
+    let names = cert.subject_alternative_name().ok()??;
+    names
+        .value
+        .general_names
+        .iter()
+        .find_map(|name| match name {
+            GeneralName::DNSName(name) => Some(name.to_string()),
+            _ => None,
+        })
+}
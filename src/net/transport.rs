@@ -0,0 +1,296 @@
+use crate::config::{Keepalive, UnixListenConfig};
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use std::fs::{self, Permissions};
+use std::io;
+use std::net::SocketAddr;
+#[cfg(feature = "io-uring")]
+use std::os::fd::{AsFd, BorrowedFd};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio_rustls::TlsStream;
+use tracing::warn;
+
+/// pause after an accept failed for lack of descriptors or memory, so the loop
+/// doesn't spin while nothing was freed
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// a connection to or from the broker. same framing whatever is underneath
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    /// boxed, it is many times the size of a socket
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Stream {
+    /// the socket of a plaintext connection, TLS has to read through rustls
+    #[cfg(feature = "io-uring")]
+    pub(crate) fn plain_fd(&self) -> Option<BorrowedFd<'_>> {
+        match self {
+            Stream::Tcp(stream) => Some(stream.as_fd()),
+            Stream::Unix(stream) => Some(stream.as_fd()),
+            Stream::Tls(_) => None,
+        }
+    }
+}
+
+impl AsyncRead for Stream {
+    #[inline]
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    #[inline]
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    #[inline]
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            Stream::Unix(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            Stream::Tls(stream) => Pin::new(stream.as_mut()).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Stream::Tcp(stream) => stream.is_write_vectored(),
+            Stream::Unix(stream) => stream.is_write_vectored(),
+            Stream::Tls(stream) => stream.is_write_vectored(),
+        }
+    }
+
+    #[inline]
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Tls(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Tls(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+/// a bound server socket. a unix socket file is removed again when this is dropped
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    Unix {
+        listener: UnixListener,
+        path: PathBuf,
+    },
+}
+
+impl Listener {
+    pub(crate) async fn bind_tcp(addr: SocketAddr) -> io::Result<Self> {
+        Ok(Listener::Tcp(TcpListener::bind(addr).await?))
+    }
+
+    /// a listener bound with `bind_reuseport`, on the runtime of the calling thread
+    pub(crate) fn from_std(listener: std::net::TcpListener) -> io::Result<Self> {
+        Ok(Listener::Tcp(TcpListener::from_std(listener)?))
+    }
+
+    pub(crate) fn bind_unix(config: &UnixListenConfig) -> io::Result<Self> {
+        remove_stale_socket(&config.path)?;
+        // bound where nobody else can reach it, and only linked into place once it
+        // has its mode: there is never a moment it takes connections under the umask
+        let private = private_dir(&config.path)?;
+        let bound = private.join("socket");
+        let bind = || -> io::Result<UnixListener> {
+            let listener = UnixListener::bind(&bound)?;
+            fs::set_permissions(&bound, Permissions::from_mode(config.mode))?;
+            // a rename would replace a socket another server bound at `path` in the
+            // meantime, a link fails instead
+            fs::hard_link(&bound, &config.path).map_err(|e| match e.kind() {
+                io::ErrorKind::AlreadyExists => io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("{} was bound by another server", config.path.display()),
+                ),
+                _ => e,
+            })?;
+            Ok(listener)
+        };
+        let listener = bind();
+        let _ = fs::remove_dir_all(&private);
+        Ok(Listener::Unix {
+            listener: listener?,
+            path: config.path.clone(),
+        })
+    }
+
+    /// what the listener is bound to, for logs
+    pub(crate) fn describe(&self) -> String {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => addr.to_string(),
+                Err(_) => "tcp".to_string(),
+            },
+            Listener::Unix { path, .. } => format!("unix:{}", path.display()),
+        }
+    }
+
+    /// the next connection and who it is from
+    pub(crate) async fn accept(
+        &self,
+        keepalive: Option<Keepalive>,
+    ) -> io::Result<(Stream, String)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (socket, addr) = listener.accept().await?;
+                // only costs latency, no reason to turn the client away
+                if let Err(e) = socket.set_nodelay(true) {
+                    warn!(%addr, error = %e, "setting TCP_NODELAY failed");
+                }
+                if let Some(keepalive) = keepalive {
+                    // the connection still works, it just won't notice a vanished peer as soon
+                    if let Err(e) = set_keepalive(&socket, keepalive) {
+                        warn!(%addr, error = %e, "setting TCP keepalive failed");
+                    }
+                }
+                Ok((Stream::Tcp(socket), addr.to_string()))
+            }
+            Listener::Unix { listener, path } => {
+                let (socket, _) = listener.accept().await?;
+                Ok((Stream::Unix(socket), format!("unix:{}", path.display())))
+            }
+        }
+    }
+}
+
+/// how long to wait before accepting again after `e`, `None` if the listener
+/// itself is broken. a connection that died before it was accepted doesn't
+/// concern the others, running out of descriptors or memory passes again
+pub(crate) fn accept_backoff(e: &io::Error) -> Option<Duration> {
+    match e.kind() {
+        io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionRefused
+        | io::ErrorKind::Interrupted
+        | io::ErrorKind::TimedOut => return Some(Duration::ZERO),
+        _ => {}
+    }
+    match e.raw_os_error()? {
+        libc::EPROTO | libc::EPERM | libc::ENETDOWN | libc::EHOSTUNREACH => Some(Duration::ZERO),
+        libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM => Some(ACCEPT_BACKOFF),
+        _ => None,
+    }
+}
+
+/// `count` nonblocking listeners on `addr` with SO_REUSEPORT, the kernel balances
+/// new connections between them. with port 0 they all share the port the first got
+pub(crate) fn bind_reuseport(
+    addr: SocketAddr,
+    count: usize,
+) -> io::Result<Vec<std::net::TcpListener>> {
+    let mut addr = addr;
+    let mut listeners = Vec::with_capacity(count);
+    for _ in 0..count {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_reuse_address(true)?;
+        socket.set_reuse_port(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(1024)?;
+        let listener = std::net::TcpListener::from(socket);
+        addr = listener.local_addr()?;
+        listeners.push(listener);
+    }
+    Ok(listeners)
+}
+
+/// turn on keepalive probing for `socket`
+pub(crate) fn set_keepalive(socket: &TcpStream, keepalive: Keepalive) -> io::Result<()> {
+    let params = TcpKeepalive::new()
+        .with_time(keepalive.idle)
+        .with_interval(keepalive.interval)
+        .with_retries(keepalive.retries);
+    SockRef::from(socket).set_tcp_keepalive(&params)
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix { path, .. } = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// a fresh 0700 directory next to `path`
+fn private_dir(path: &Path) -> io::Result<PathBuf> {
+    let name = path.file_name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a socket path", path.display()),
+        )
+    })?;
+    let mut private = name.to_os_string();
+    private.push(format!(".{}.bind", std::process::id()));
+    let private = path.with_file_name(private);
+    // left behind by a server that died binding
+    if fs::symlink_metadata(&private).is_ok() {
+        fs::remove_dir_all(&private)?;
+    }
+    fs::DirBuilder::new().mode(0o700).create(&private)?;
+    Ok(private)
+}
+
+/// a socket file nobody accepts on is left over from a server that didn't stop cleanly.
+/// anything else at `path` is an error, a live socket included
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        ));
+    }
+
+    match std::os::unix::net::UnixStream::connect(path) {
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("{} is in use by another server", path.display()),
+        )),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path),
+        Err(e) => Err(e),
+    }
+}
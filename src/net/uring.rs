@@ -0,0 +1,653 @@
+use crate::config::UringConfig;
+use io_uring::types::{BufRingEntry, Fd};
+use io_uring::{cqueue, opcode, squeue, IoUring};
+use parking_lot::Mutex;
+use std::alloc::{self, Layout};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd};
+use std::pin::Pin;
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::task::{Context, Poll, Waker};
+use std::thread::{self, JoinHandle};
+use tokio::io::{AsyncRead, ReadBuf};
+use tracing::{debug, warn};
+
+/// the buffer group every receive picks its buffers from
+const BUFFER_GROUP: u16 = 0;
+/// `user_data` of the eventfd poll that wakes the driver, connections count from 1
+const WAKE: u64 = 0;
+/// `user_data` of cancellations, their completions carry nothing
+const CANCEL: u64 = u64::MAX;
+const PAGE: usize = 4096;
+
+/// reads connections through io_uring on a thread of its own. every connection has
+/// one multishot receive armed, which keeps filling buffers the kernel picks from a
+/// registered pool, so a busy connection costs no syscall per read at all
+pub(crate) struct Uring {
+    inner: Arc<Inner>,
+    next_key: AtomicU64,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Uring {
+    pub(crate) fn start(config: &UringConfig) -> io::Result<Self> {
+        let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidInput, what.to_string());
+        if !config.buffers.is_power_of_two() || config.buffers > 32768 {
+            return Err(invalid(
+                "io_uring buffers must be a power of two up to 32768",
+            ));
+        }
+        if config.buffer_size == 0 || config.connection_buffers == 0 {
+            return Err(invalid("io_uring buffers can't be empty"));
+        }
+
+        // SAFETY: eventfd returns a new descriptor or -1
+        let eventfd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if eventfd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let buffer_size = config.buffer_size as usize;
+        let inner = Arc::new(Inner {
+            pool: Region::new(config.buffers as usize * buffer_size)?,
+            buffer_size,
+            connection_buffers: config.connection_buffers,
+            commands: Mutex::new(Vec::new()),
+            released: Mutex::new(Vec::new()),
+            starving: AtomicBool::new(false),
+            woken: AtomicBool::new(false),
+            stopped: AtomicBool::new(false),
+            // SAFETY: just opened and owned by nobody else
+            eventfd: unsafe { OwnedFd::from_raw_fd(eventfd) },
+        });
+
+        // the ring is set up on the thread that submits to it, it is single issuer
+        let (ready_tx, ready_rx) = mpsc::sync_channel(1);
+        let config = *config;
+        let driver_inner = inner.clone();
+        let thread = thread::Builder::new()
+            .name("broker-uring".to_string())
+            .spawn(move || match Driver::new(&config, driver_inner) {
+                Ok(driver) => {
+                    let _ = ready_tx.send(Ok(()));
+                    driver.run();
+                }
+                Err(e) => {
+                    let _ = ready_tx.send(Err(e));
+                }
+            })?;
+        ready_rx
+            .recv()
+            .map_err(|_| io::Error::other("io_uring thread exited"))??;
+        Ok(Self {
+            inner,
+            next_key: AtomicU64::new(1),
+            thread: Some(thread),
+        })
+    }
+
+    /// start receiving on `fd`, a connected TCP or unix socket. the driver reads
+    /// through a duplicate, the socket stays open until the reader is dropped
+    pub(crate) fn reader(&self, fd: BorrowedFd<'_>) -> io::Result<Reader> {
+        if self.inner.stopped.load(Ordering::Acquire) {
+            return Err(io::Error::other("io_uring reader stopped"));
+        }
+        let fd = fd.try_clone_to_owned()?;
+        let key = self.next_key.fetch_add(1, Ordering::Relaxed);
+        let inbox = Arc::new(Inbox::default());
+        self.inner.send(Command::Register {
+            key,
+            fd,
+            inbox: inbox.clone(),
+        });
+        Ok(Reader {
+            key,
+            inbox,
+            inner: self.inner.clone(),
+            done: Vec::new(),
+        })
+    }
+}
+
+impl Drop for Uring {
+    fn drop(&mut self) {
+        self.inner.send(Command::Stop);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// the read half of a connection, fed by the driver
+pub(crate) struct Reader {
+    key: u64,
+    inbox: Arc<Inbox>,
+    inner: Arc<Inner>,
+    /// buffers read to the end, handed back after the inbox is unlocked
+    done: Vec<u16>,
+}
+
+impl AsyncRead for Reader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let mut state = this.inbox.state.lock();
+        let start = buf.remaining();
+        while buf.remaining() > 0 {
+            let Some(&(bid, len)) = state.chunks.front() else {
+                break;
+            };
+            let n = (len - state.offset).min(buf.remaining());
+            // SAFETY: the kernel filled `len` bytes of the buffer and won't touch it
+            // again until it is released
+            let data = unsafe { this.inner.buffer(bid, len) };
+            buf.put_slice(&data[state.offset..state.offset + n]);
+            state.offset += n;
+            if state.offset == len {
+                state.chunks.pop_front();
+                state.offset = 0;
+                this.done.push(bid);
+            }
+        }
+        let resume = state.paused && state.chunks.len() <= this.inner.connection_buffers / 2;
+        if resume {
+            state.paused = false;
+        }
+        let result = if buf.remaining() < start {
+            Poll::Ready(Ok(()))
+        } else if let Some(end) = &mut state.end {
+            // an error is reported once, reads after it see the end of the stream
+            Poll::Ready(mem::replace(end, Ok(())))
+        } else {
+            if !state
+                .waker
+                .as_ref()
+                .is_some_and(|w| w.will_wake(cx.waker()))
+            {
+                state.waker = Some(cx.waker().clone());
+            }
+            Poll::Pending
+        };
+        drop(state);
+
+        if !this.done.is_empty() {
+            this.inner.release(&mut this.done);
+        }
+        if resume {
+            this.inner.send(Command::Resume(this.key));
+        }
+        result
+    }
+}
+
+impl Drop for Reader {
+    fn drop(&mut self) {
+        self.inner.send(Command::Close(self.key));
+    }
+}
+
+/// what the driver received for one connection and the reader didn't take yet
+#[derive(Default)]
+struct Inbox {
+    state: Mutex<InboxState>,
+}
+
+#[derive(Default)]
+struct InboxState {
+    /// buffer ids and how much the kernel put in them, oldest first
+    chunks: VecDeque<(u16, usize)>,
+    /// read from the first chunk already
+    offset: usize,
+    /// the receive is over, the peer closed the connection or it failed
+    end: Option<io::Result<()>>,
+    /// the driver stopped receiving until the reader catches up
+    paused: bool,
+    waker: Option<Waker>,
+}
+
+impl InboxState {
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+enum Command {
+    Register {
+        key: u64,
+        fd: OwnedFd,
+        inbox: Arc<Inbox>,
+    },
+    /// the reader caught up with a paused receive
+    Resume(u64),
+    /// the reader is gone
+    Close(u64),
+    Stop,
+}
+
+/// shared by the driver and the readers
+struct Inner {
+    /// buffer `bid` is at `bid * buffer_size`
+    pool: Region,
+    buffer_size: usize,
+    /// `UringConfig::connection_buffers`
+    connection_buffers: usize,
+    commands: Mutex<Vec<Command>>,
+    /// buffers readers are done with, the driver gives them back to the kernel
+    released: Mutex<Vec<u16>>,
+    /// a receive ran out of buffers, releases have to wake the driver
+    starving: AtomicBool,
+    /// the eventfd was written and the driver didn't see it yet
+    woken: AtomicBool,
+    /// the driver thread exited
+    stopped: AtomicBool,
+    eventfd: OwnedFd,
+}
+
+impl Inner {
+    fn send(&self, command: Command) {
+        self.commands.lock().push(command);
+        self.wake();
+    }
+
+    fn wake(&self) {
+        if self.woken.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let one = 1u64;
+        // SAFETY: writes 8 bytes from a u64 to an eventfd we own
+        unsafe {
+            libc::write(
+                self.eventfd.as_raw_fd(),
+                &one as *const u64 as *const libc::c_void,
+                mem::size_of::<u64>(),
+            );
+        }
+    }
+
+    /// hand buffers back. the driver only needs waking when a receive is waiting
+    /// for them, otherwise it picks them up on its next round
+    fn release(&self, bids: &mut Vec<u16>) {
+        self.released.lock().append(bids);
+        if self.starving.load(Ordering::SeqCst) {
+            self.wake();
+        }
+    }
+
+    /// # Safety
+    /// the buffer must hold `len` bytes from a completed receive and not be released yet
+    unsafe fn buffer(&self, bid: u16, len: usize) -> &[u8] {
+        let start = bid as usize * self.buffer_size;
+        std::slice::from_raw_parts(self.pool.ptr.as_ptr().add(start), len)
+    }
+}
+
+/// page aligned memory the kernel reads or writes
+struct Region {
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+// SAFETY: plain memory, who writes where is up to the driver and the buffer ids
+unsafe impl Send for Region {}
+unsafe impl Sync for Region {}
+
+impl Region {
+    fn new(len: usize) -> io::Result<Self> {
+        let layout = Layout::from_size_align(len.max(1), PAGE)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        // SAFETY: the layout isn't zero sized
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        match NonNull::new(ptr) {
+            Some(ptr) => Ok(Self { ptr, layout }),
+            None => Err(io::ErrorKind::OutOfMemory.into()),
+        }
+    }
+}
+
+impl Drop for Region {
+    fn drop(&mut self) {
+        // SAFETY: allocated in `new` with this layout
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), self.layout) }
+    }
+}
+
+/// a connection's receive, as the driver sees it
+struct Receive {
+    /// a duplicate of the socket, closed once the kernel is done with it
+    fd: OwnedFd,
+    inbox: Arc<Inbox>,
+    /// a receive is in flight, the kernel may still complete into it
+    armed: bool,
+    /// the reader fell behind, see `UringConfig::connection_buffers`
+    paused: bool,
+    /// the reader was dropped
+    closing: bool,
+    /// the peer closed the connection or it failed
+    ended: bool,
+}
+
+struct Driver {
+    ring: IoUring,
+    inner: Arc<Inner>,
+    /// the ring of buffers the kernel picks from, shared with it
+    entries: Region,
+    mask: u16,
+    tail: u16,
+    receives: HashMap<u64, Receive>,
+    /// receives that ran out of buffers, armed again once some come back
+    starved: Vec<u64>,
+    /// buffers the driver recycles itself, e.g. data for a closed connection
+    returned: Vec<u16>,
+    /// entries that didn't fit in the submission queue
+    backlog: VecDeque<squeue::Entry>,
+    stopping: bool,
+}
+
+impl Driver {
+    fn new(config: &UringConfig, inner: Arc<Inner>) -> io::Result<Self> {
+        let ring = IoUring::builder()
+            .setup_single_issuer()
+            .setup_coop_taskrun()
+            .setup_cqsize(config.entries * 4)
+            .build(config.entries)?;
+        let entries = Region::new(config.buffers as usize * mem::size_of::<BufRingEntry>())?;
+        // SAFETY: the ring memory is page aligned, zeroed and outlives the io_uring
+        unsafe {
+            ring.submitter().register_buf_ring_with_flags(
+                entries.ptr.as_ptr() as u64,
+                config.buffers,
+                BUFFER_GROUP,
+                0,
+            )?;
+        }
+        let mut driver = Self {
+            ring,
+            inner,
+            entries,
+            mask: config.buffers - 1,
+            tail: 0,
+            receives: HashMap::new(),
+            starved: Vec::new(),
+            returned: Vec::new(),
+            backlog: VecDeque::new(),
+            stopping: false,
+        };
+        for bid in 0..config.buffers {
+            driver.provide(bid);
+        }
+        driver.publish();
+        driver.arm_wake();
+        debug!(
+            buffers = config.buffers,
+            buffer_size = config.buffer_size,
+            "io_uring reader started"
+        );
+        Ok(driver)
+    }
+
+    fn run(mut self) {
+        let mut completions = Vec::new();
+        loop {
+            while let Some(entry) = self.backlog.pop_front() {
+                // SAFETY: entries only point at memory that outlives the ring
+                if unsafe { self.ring.submission().push(&entry) }.is_err() {
+                    self.backlog.push_front(entry);
+                    break;
+                }
+            }
+            match self.ring.submit_and_wait(1) {
+                Ok(_) => {}
+                Err(e) if e.raw_os_error() == Some(libc::EINTR) => continue,
+                // the completion queue overflowed, make room below
+                Err(e) if e.raw_os_error() == Some(libc::EBUSY) => {}
+                Err(e) => {
+                    warn!(error = %e, "io_uring failed, its connections are closed");
+                    self.fail(e);
+                    return;
+                }
+            }
+
+            completions.extend(
+                self.ring
+                    .completion()
+                    .map(|cqe| (cqe.user_data(), cqe.result(), cqe.flags())),
+            );
+            for (key, result, flags) in completions.drain(..) {
+                self.complete(key, result, flags);
+            }
+            let commands = mem::take(&mut *self.inner.commands.lock());
+            for command in commands {
+                self.command(command);
+            }
+            self.recycle();
+
+            if self.stopping && self.receives.is_empty() {
+                self.inner.stopped.store(true, Ordering::Release);
+                debug!("io_uring reader stopped");
+                return;
+            }
+        }
+    }
+
+    fn complete(&mut self, key: u64, result: i32, flags: u32) {
+        match key {
+            WAKE => {
+                let mut count = 0u64;
+                // SAFETY: reads 8 bytes into a u64 from our nonblocking eventfd
+                unsafe {
+                    libc::read(
+                        self.inner.eventfd.as_raw_fd(),
+                        &mut count as *mut u64 as *mut libc::c_void,
+                        mem::size_of::<u64>(),
+                    );
+                }
+                self.inner.woken.store(false, Ordering::SeqCst);
+                if !cqueue::more(flags) {
+                    self.arm_wake();
+                }
+                return;
+            }
+            CANCEL => return,
+            _ => {}
+        }
+
+        let bid = cqueue::buffer_select(flags);
+        let Some(receive) = self.receives.get_mut(&key) else {
+            self.returned.extend(bid);
+            return;
+        };
+        let mut pause = false;
+        if let Some(bid) = bid {
+            if result > 0 && !receive.closing {
+                let mut state = receive.inbox.state.lock();
+                state.chunks.push_back((bid, result as usize));
+                if state.chunks.len() >= self.inner.connection_buffers && !receive.paused {
+                    receive.paused = true;
+                    state.paused = true;
+                    pause = receive.armed && cqueue::more(flags);
+                }
+                state.wake();
+            } else {
+                self.returned.push(bid);
+            }
+        }
+        if result <= 0 && result != -libc::ENOBUFS && result != -libc::ECANCELED {
+            receive.ended = true;
+            let mut state = receive.inbox.state.lock();
+            state.end = Some(match result {
+                0 => Ok(()),
+                e => Err(io::Error::from_raw_os_error(-e)),
+            });
+            state.wake();
+        }
+
+        if !cqueue::more(flags) {
+            receive.armed = false;
+            if receive.closing {
+                self.receives.remove(&key);
+            } else if result == -libc::ENOBUFS {
+                self.starved.push(key);
+            } else if !receive.ended && !receive.paused {
+                self.arm(key);
+            }
+        } else if pause {
+            self.push(opcode::AsyncCancel::new(key).build().user_data(CANCEL));
+        }
+    }
+
+    fn command(&mut self, command: Command) {
+        match command {
+            Command::Register { key, fd, inbox } => {
+                if self.stopping {
+                    inbox.state.lock().end = Some(Err(io::Error::other("io_uring reader stopped")));
+                    return;
+                }
+                self.receives.insert(
+                    key,
+                    Receive {
+                        fd,
+                        inbox,
+                        armed: false,
+                        paused: false,
+                        closing: false,
+                        ended: false,
+                    },
+                );
+                self.arm(key);
+            }
+            Command::Resume(key) => {
+                let starved = self.starved.contains(&key);
+                let Some(receive) = self.receives.get_mut(&key) else {
+                    return;
+                };
+                receive.paused = false;
+                if !receive.armed && !receive.ended && !receive.closing && !starved {
+                    self.arm(key);
+                }
+            }
+            Command::Close(key) => self.close(key),
+            Command::Stop => {
+                self.stopping = true;
+                let keys: Vec<u64> = self.receives.keys().copied().collect();
+                for key in keys {
+                    if let Some(receive) = self.receives.get(&key) {
+                        let mut state = receive.inbox.state.lock();
+                        state.end = Some(Err(io::Error::other("io_uring reader stopped")));
+                        state.wake();
+                    }
+                    self.close(key);
+                }
+            }
+        }
+    }
+
+    fn close(&mut self, key: u64) {
+        let Some(receive) = self.receives.get_mut(&key) else {
+            return;
+        };
+        receive.closing = true;
+        let chunks = mem::take(&mut receive.inbox.state.lock().chunks);
+        self.returned.extend(chunks.into_iter().map(|(bid, _)| bid));
+        if receive.armed {
+            self.push(opcode::AsyncCancel::new(key).build().user_data(CANCEL));
+        } else {
+            self.receives.remove(&key);
+            self.starved.retain(|&k| k != key);
+        }
+    }
+
+    /// give released buffers back to the kernel, and the receives waiting for them
+    /// another go
+    fn recycle(&mut self) {
+        if !self.starved.is_empty() {
+            // before looking at the releases, a reader releasing after that wakes us
+            self.inner.starving.store(true, Ordering::SeqCst);
+        }
+        let mut released = mem::take(&mut *self.inner.released.lock());
+        released.append(&mut self.returned);
+        if released.is_empty() {
+            return;
+        }
+        for bid in released {
+            self.provide(bid);
+        }
+        self.publish();
+
+        for key in mem::take(&mut self.starved) {
+            if self
+                .receives
+                .get(&key)
+                .is_some_and(|r| !r.paused && !r.closing && !r.ended)
+            {
+                self.arm(key);
+            }
+        }
+        self.inner.starving.store(false, Ordering::SeqCst);
+    }
+
+    fn arm(&mut self, key: u64) {
+        let Some(receive) = self.receives.get_mut(&key) else {
+            return;
+        };
+        receive.armed = true;
+        let entry = opcode::RecvMulti::new(Fd(receive.fd.as_raw_fd()), BUFFER_GROUP)
+            .build()
+            .user_data(key);
+        self.push(entry);
+    }
+
+    fn arm_wake(&mut self) {
+        let entry = opcode::PollAdd::new(Fd(self.inner.eventfd.as_raw_fd()), libc::POLLIN as u32)
+            .multi(true)
+            .build()
+            .user_data(WAKE);
+        self.push(entry);
+    }
+
+    fn push(&mut self, entry: squeue::Entry) {
+        // SAFETY: entries only point at memory that outlives the ring
+        if !self.backlog.is_empty() || unsafe { self.ring.submission().push(&entry) }.is_err() {
+            self.backlog.push_back(entry);
+        }
+    }
+
+    /// put a buffer at the tail of the ring, the kernel sees it after `publish`
+    fn provide(&mut self, bid: u16) {
+        let base = self.entries.ptr.as_ptr() as *mut BufRingEntry;
+        // SAFETY: the index is masked to the ring size
+        let entry = unsafe { &mut *base.add((self.tail & self.mask) as usize) };
+        let addr = self.inner.pool.ptr.as_ptr() as u64 + bid as u64 * self.inner.buffer_size as u64;
+        entry.set_addr(addr);
+        entry.set_len(self.inner.buffer_size as u32);
+        entry.set_bid(bid);
+        self.tail = self.tail.wrapping_add(1);
+    }
+
+    fn publish(&self) {
+        let base = self.entries.ptr.as_ptr() as *const BufRingEntry;
+        // SAFETY: the tail is an aligned u16 in the ring header the kernel reads
+        // atomically
+        unsafe {
+            let tail = &*(BufRingEntry::tail(base) as *const AtomicU16);
+            tail.store(self.tail, Ordering::Release);
+        }
+    }
+
+    /// the ring itself failed, nothing can be received anymore
+    fn fail(&mut self, e: io::Error) {
+        self.inner.stopped.store(true, Ordering::Release);
+        for receive in self.receives.values() {
+            let mut state = receive.inbox.state.lock();
+            state.end = Some(Err(io::Error::new(e.kind(), e.to_string())));
+            state.wake();
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// what a connection does with a publish frame over quota, see `ServerConfig::quota_action`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QuotaAction {
+    /// stop reading from the client until the quota allows the frame
+    #[default]
+    Delay,
+    /// drop the frame and answer with a `QUOTA_EXCEEDED` error, the connection stays open
+    Reject,
+}
+
+/// publish rates for one client or one topic, enforced with token buckets that
+/// hold up to a second's worth. either limit may be left out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Quota {
+    pub messages_per_sec: Option<u64>,
+    pub bytes_per_sec: Option<u64>,
+}
+
+impl Quota {
+    pub fn messages(per_sec: u64) -> Self {
+        Self {
+            messages_per_sec: Some(per_sec),
+            bytes_per_sec: None,
+        }
+    }
+
+    pub fn bytes(per_sec: u64) -> Self {
+        Self {
+            messages_per_sec: None,
+            bytes_per_sec: Some(per_sec),
+        }
+    }
+}
+
+/// tokens refill at `rate` per second up to `rate`. a frame may take more than is
+/// left, which puts the bucket in debt: whole batches always fit eventually and the
+/// next frame waits for the debt to be paid off
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64, now: Instant) -> Self {
+        Self {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last: now,
+        }
+    }
+
+    /// how long until the bucket is out of debt
+    fn wait(&mut self, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last = now;
+        if self.tokens >= 0.0 || self.rate == 0.0 {
+            // a zero rate is no limit at all rather than a wall
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(-self.tokens / self.rate)
+    }
+
+    fn take(&mut self, tokens: u64) {
+        if self.rate > 0.0 {
+            self.tokens -= tokens as f64;
+        }
+    }
+
+    /// refilled up to its rate, as good as a new bucket
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.wait(now);
+        self.tokens >= self.rate
+    }
+}
+
+/// the buckets of one `Quota`
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    messages: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(quota: Quota) -> Self {
+        let now = Instant::now();
+        Self {
+            messages: quota
+                .messages_per_sec
+                .map(|rate| TokenBucket::new(rate, now)),
+            bytes: quota.bytes_per_sec.map(|rate| TokenBucket::new(rate, now)),
+        }
+    }
+
+    /// how long a publish has to wait, zero if it may go ahead now
+    pub(crate) fn wait(&mut self, now: Instant) -> Duration {
+        let messages = self
+            .messages
+            .as_mut()
+            .map_or(Duration::ZERO, |b| b.wait(now));
+        let bytes = self.bytes.as_mut().map_or(Duration::ZERO, |b| b.wait(now));
+        messages.max(bytes)
+    }
+
+    /// pay for a publish that was let through
+    pub(crate) fn take(&mut self, messages: u64, bytes: u64) {
+        if let Some(bucket) = self.messages.as_mut() {
+            bucket.take(messages);
+        }
+        if let Some(bucket) = self.bytes.as_mut() {
+            bucket.take(bytes);
+        }
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        let messages = self.messages.as_mut().is_none_or(|b| b.is_full(now));
+        let bytes = self.bytes.as_mut().is_none_or(|b| b.is_full(now));
+        messages && bytes
+    }
+}
+
+/// the limiters of `ServerConfig::client_quota`, one per client however many
+/// connections it opens
+#[derive(Debug)]
+pub(crate) struct ClientQuotas {
+    quota: Quota,
+    limiters: Mutex<HashMap<String, Arc<Mutex<RateLimiter>>>>,
+}
+
+impl ClientQuotas {
+    pub(crate) fn new(quota: Quota) -> Self {
+        Self {
+            quota,
+            limiters: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// the limiter shared by every connection of `client`
+    pub(crate) fn limiter(&self, client: &str) -> Arc<Mutex<RateLimiter>> {
+        let now = Instant::now();
+        let mut limiters = self.limiters.lock();
+        // no connection left and refilled: forgetting it loses nothing
+        limiters
+            .retain(|_, limiter| Arc::strong_count(limiter) > 1 || !limiter.lock().is_full(now));
+        limiters
+            .entry(client.to_string())
+            .or_insert_with(|| Arc::new(Mutex::new(RateLimiter::new(self.quota))))
+            .clone()
+    }
+}
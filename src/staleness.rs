@@ -0,0 +1,57 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// timestamps this far ahead of the broker's clock still count as current
+pub const DEFAULT_CLOCK_SKEW: Duration = Duration::from_millis(100);
+
+/// what the consumer does with a message older than its topic's TTL
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExpiredAction {
+    /// count it and move on, the handler never sees it
+    #[default]
+    Drop,
+    /// write it to the dead letter log, or drop it if there is none
+    DeadLetter,
+    /// hand it to the handler with `Message::expired` set
+    Deliver,
+}
+
+/// how old a message may get before its topic's consumer reaches it, see `TopicConfig::staleness`.
+///
+/// ages come from the payload header timestamp, nanoseconds since the unix epoch
+/// on the producer's clock
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Staleness {
+    pub ttl: Duration,
+    pub action: ExpiredAction,
+    /// how far producer clocks may run ahead of the broker's. timestamps further
+    /// in the future can't be trusted and count as expired
+    pub clock_skew: Duration,
+}
+
+impl Staleness {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            action: ExpiredAction::default(),
+            clock_skew: DEFAULT_CLOCK_SKEW,
+        }
+    }
+
+    /// whether a message stamped `timestamp` is expired at `now`, both in
+    /// nanoseconds since the unix epoch
+    #[inline]
+    pub fn is_expired(&self, timestamp: u64, now: u64) -> bool {
+        match now.checked_sub(timestamp) {
+            Some(age) => age > self.ttl.as_nanos() as u64,
+            None => timestamp - now > self.clock_skew.as_nanos() as u64,
+        }
+    }
+}
+
+/// the broker's clock in payload header units
+#[inline]
+pub fn now_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64)
+}
@@ -0,0 +1,179 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// upper bounds of the latency histogram buckets, in nanoseconds. there is a
+/// last, unbounded bucket on top
+pub(crate) const LATENCY_BUCKETS: [u64; 14] = [
+    100_000,
+    250_000,
+    500_000,
+    1_000_000,
+    2_500_000,
+    5_000_000,
+    10_000_000,
+    25_000_000,
+    50_000_000,
+    100_000_000,
+    250_000_000,
+    500_000_000,
+    1_000_000_000,
+    5_000_000_000,
+];
+
+/// ages beyond this go into the sum as this, so a few bogus timestamps don't overflow it
+const MAX_LATENCY: u64 = 3600 * 1_000_000_000;
+
+/// latencies collected by one consumer batch, added to the shared histogram in one go
+#[derive(Debug, Default)]
+pub(crate) struct LatencyBatch {
+    buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    sum: u64,
+}
+
+impl LatencyBatch {
+    #[inline]
+    pub(crate) fn observe(&mut self, nanos: u64) {
+        let bucket = LATENCY_BUCKETS.partition_point(|bound| *bound < nanos);
+        self.buckets[bucket] += 1;
+        self.sum += nanos.min(MAX_LATENCY);
+    }
+}
+
+/// message latency from the producer's timestamp to the topic consumer
+#[derive(Debug, Default)]
+pub(crate) struct Histogram {
+    /// not cumulative, unlike the exposition format
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    pub(crate) fn record(&self, batch: &LatencyBatch) {
+        for (bucket, n) in self.buckets.iter().zip(batch.buckets) {
+            if n > 0 {
+                bucket.fetch_add(n, Ordering::Relaxed);
+            }
+        }
+        self.sum.fetch_add(batch.sum, Ordering::Relaxed);
+    }
+
+    /// per bucket counts and the sum in nanoseconds
+    pub(crate) fn load(&self) -> ([u64; LATENCY_BUCKETS.len() + 1], u64) {
+        let buckets = std::array::from_fn(|i| self.buckets[i].load(Ordering::Relaxed));
+        (buckets, self.sum.load(Ordering::Relaxed))
+    }
+}
+
+/// broker wide counters, bumped by connections and topic consumers
+#[derive(Debug, Default)]
+pub(crate) struct BrokerStats {
+    pub(crate) connections: AtomicU64,
+    pub(crate) open_connections: AtomicU64,
+    pub(crate) connections_refused: AtomicU64,
+    pub(crate) frames_in: AtomicU64,
+    pub(crate) messages_in: AtomicU64,
+    pub(crate) bytes_in: AtomicU64,
+    pub(crate) messages_consumed: AtomicU64,
+    pub(crate) messages_processed: AtomicU64,
+    pub(crate) processing_errors: AtomicU64,
+    pub(crate) handler_errors: AtomicU64,
+    pub(crate) handler_retries: AtomicU64,
+    pub(crate) dead_letters: AtomicU64,
+    pub(crate) sequence_gaps: AtomicU64,
+    pub(crate) sequence_duplicates: AtomicU64,
+    pub(crate) sequence_reordered: AtomicU64,
+    pub(crate) sequence_unknown: AtomicU64,
+    pub(crate) messages_expired: AtomicU64,
+    pub(crate) idle_timeouts: AtomicU64,
+    pub(crate) quota_delayed: AtomicU64,
+    pub(crate) quota_rejected: AtomicU64,
+    pub(crate) auth_failures: AtomicU64,
+    pub(crate) tls_failures: AtomicU64,
+    pub(crate) acl_denials: AtomicU64,
+    pub(crate) latency: Histogram,
+}
+
+impl BrokerStats {
+    #[inline]
+    pub(crate) fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            connections: self.connections.load(Ordering::Relaxed),
+            connections_refused: self.connections_refused.load(Ordering::Relaxed),
+            open_connections: self.open_connections.load(Ordering::Relaxed),
+            frames_in: self.frames_in.load(Ordering::Relaxed),
+            messages_in: self.messages_in.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            messages_consumed: self.messages_consumed.load(Ordering::Relaxed),
+            messages_processed: self.messages_processed.load(Ordering::Relaxed),
+            processing_errors: self.processing_errors.load(Ordering::Relaxed),
+            handler_errors: self.handler_errors.load(Ordering::Relaxed),
+            handler_retries: self.handler_retries.load(Ordering::Relaxed),
+            dead_letters: self.dead_letters.load(Ordering::Relaxed),
+            sequence_gaps: self.sequence_gaps.load(Ordering::Relaxed),
+            sequence_duplicates: self.sequence_duplicates.load(Ordering::Relaxed),
+            sequence_reordered: self.sequence_reordered.load(Ordering::Relaxed),
+            sequence_unknown: self.sequence_unknown.load(Ordering::Relaxed),
+            messages_expired: self.messages_expired.load(Ordering::Relaxed),
+            idle_timeouts: self.idle_timeouts.load(Ordering::Relaxed),
+            quota_delayed: self.quota_delayed.load(Ordering::Relaxed),
+            quota_rejected: self.quota_rejected.load(Ordering::Relaxed),
+            auth_failures: self.auth_failures.load(Ordering::Relaxed),
+            tls_failures: self.tls_failures.load(Ordering::Relaxed),
+            acl_denials: self.acl_denials.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// counters at one point in time
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    /// connections accepted
+    pub connections: u64,
+    /// connections currently open
+    pub open_connections: u64,
+    /// connections turned away because the broker was over its memory limit
+    pub connections_refused: u64,
+    /// frames read from clients
+    pub frames_in: u64,
+    /// messages published into topic rings
+    pub messages_in: u64,
+    /// published payload bytes
+    pub bytes_in: u64,
+    /// records read by the topic consumers
+    pub messages_consumed: u64,
+    /// records a handler took without error
+    pub messages_processed: u64,
+    /// records that failed validation
+    pub processing_errors: u64,
+    /// messages given up on after the handler failed
+    pub handler_errors: u64,
+    /// handler calls repeated by `ErrorPolicy::Retry`
+    pub handler_retries: u64,
+    /// rejected records written to the dead letter log
+    pub dead_letters: u64,
+    /// sequence numbers a producer skipped, some may still arrive as reordered
+    pub sequence_gaps: u64,
+    /// messages with a sequence number their producer already sent
+    pub sequence_duplicates: u64,
+    /// skipped sequence numbers that arrived late
+    pub sequence_reordered: u64,
+    /// sequence numbers below the gaps still remembered, delivered as they may be late
+    pub sequence_unknown: u64,
+    /// messages past their topic's TTL, whatever the `ExpiredAction`
+    pub messages_expired: u64,
+    /// connections closed after `ServerConfig::idle_timeout` without a frame
+    pub idle_timeouts: u64,
+    /// publish frames held back until a client or topic quota allowed them
+    pub quota_delayed: u64,
+    /// publish frames dropped for being over a quota, with `QuotaAction::Reject`
+    pub quota_rejected: u64,
+    /// connections closed for failing or not finishing the authentication handshake
+    pub auth_failures: u64,
+    /// TLS handshakes that failed or didn't finish in time
+    pub tls_failures: u64,
+    /// publishes, declares and subscribes the ACL turned down
+    pub acl_denials: u64,
+}
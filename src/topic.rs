@@ -0,0 +1,605 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::iter::Peekable;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+
+use bytes::BytesMut;
+use parking_lot::{Mutex, RwLock};
+use tokio::sync::Notify;
+
+use crate::error::BrokerError;
+use crate::quota::{Quota, RateLimiter};
+use crate::staleness::Staleness;
+use crate::RingBuffer;
+use crate::RING_BUFFER_SIZE;
+
+pub type TopicId = u32;
+
+/// topic every plain `BrokerClient::send` publishes to
+pub const DEFAULT_TOPIC: &str = "default";
+pub const MAX_TOPIC_NAME_LEN: usize = 255;
+
+/// how a publish frame addresses its topic
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum TopicRef {
+    Id(TopicId),
+    Name(String),
+}
+
+impl From<TopicId> for TopicRef {
+    fn from(id: TopicId) -> Self {
+        TopicRef::Id(id)
+    }
+}
+
+impl From<&str> for TopicRef {
+    fn from(name: &str) -> Self {
+        TopicRef::Name(name.to_string())
+    }
+}
+
+impl From<String> for TopicRef {
+    fn from(name: String) -> Self {
+        TopicRef::Name(name)
+    }
+}
+
+impl fmt::Display for TopicRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TopicRef::Id(id) => write!(f, "#{}", id),
+            TopicRef::Name(name) => f.write_str(name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TopicConfig {
+    /// ring size in bytes, a power of two. this is the memory limit of the topic
+    pub capacity: usize,
+    /// expire messages the consumer gets to late. `None` keeps them current forever
+    pub staleness: Option<Staleness>,
+    /// publish rate of all producers together, see `ServerConfig::quota_action`
+    pub quota: Option<Quota>,
+}
+
+impl Default for TopicConfig {
+    fn default() -> Self {
+        Self {
+            capacity: RING_BUFFER_SIZE,
+            staleness: None,
+            quota: None,
+        }
+    }
+}
+
+/// snapshot of a topic, as returned by listings
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopicInfo {
+    pub id: TopicId,
+    pub name: String,
+    pub capacity: u64,
+    pub used: u64,
+}
+
+/// where a new cursor starts reading
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StartPosition {
+    /// oldest retained record
+    Earliest,
+    /// only records published from now on
+    Latest,
+    /// a record offset previously handed out by the broker
+    Offset(u64),
+}
+
+/// the connection a record came from
+#[derive(Debug)]
+pub(crate) struct Publisher {
+    pub(crate) connection: u64,
+    pub(crate) peer: String,
+    /// who the connection authenticated as, see `ServerConfig::auth`
+    pub(crate) principal: OnceLock<String>,
+}
+
+impl Publisher {
+    /// the client behind the connection across reconnects: its principal, or
+    /// the address it connects from without authentication
+    pub(crate) fn client(&self) -> String {
+        match (self.principal.get(), self.peer.parse::<SocketAddr>()) {
+            (Some(principal), _) => format!("principal {}", principal),
+            (None, Ok(addr)) => format!("peer {}", addr.ip()),
+            (None, Err(_)) => format!("peer {}", self.peer),
+        }
+    }
+}
+
+pub struct Topic {
+    id: TopicId,
+    name: String,
+    ring: RingBuffer,
+    staleness: Option<Staleness>,
+    consumer_started: AtomicBool,
+    producer: Mutex<()>,
+    /// read positions of everyone consuming the ring, the ring is retained up to the
+    /// lowest. flagged ones are skipped forward when the ring is full, see `skip_tail`
+    cursors: Mutex<Vec<(Arc<AtomicU64>, bool)>>,
+    published: Notify,
+    /// the tail moved, see `wait_released`
+    released: Notify,
+    /// who published from which offset on, for records still in the ring
+    publishers: Mutex<VecDeque<(u64, Arc<Publisher>)>>,
+    /// publish batches that found the ring full and had to wait
+    stalls: AtomicU64,
+    /// records at-most-once subscribers were skipped past to make room
+    drops: AtomicU64,
+    /// the consumer holds off until `resume`, publishers keep filling the ring
+    paused: AtomicBool,
+    resumed: Notify,
+    /// `TopicConfig::quota`
+    limiter: Option<Mutex<RateLimiter>>,
+    /// publish frames that were over the topic's quota
+    quota_hits: AtomicU64,
+}
+
+impl Topic {
+    #[inline]
+    pub fn id(&self) -> TopicId {
+        self.id
+    }
+
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[inline]
+    pub fn ring(&self) -> &RingBuffer {
+        &self.ring
+    }
+
+    #[inline]
+    pub fn staleness(&self) -> Option<Staleness> {
+        self.staleness
+    }
+
+    /// how often publishers had to wait for the consumers to make room
+    pub fn stalls(&self) -> u64 {
+        self.stalls.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn stalled(&self) {
+        self.stalls.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// how many records at-most-once subscribers lost to a full ring
+    pub fn drops(&self) -> u64 {
+        self.drops.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn limiter(&self) -> Option<&Mutex<RateLimiter>> {
+        self.limiter.as_ref()
+    }
+
+    /// how often publishers ran into the topic's quota
+    pub fn quota_hits(&self) -> u64 {
+        self.quota_hits.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn quota_hit(&self) {
+        self.quota_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// stop the topic's consumer after its current batch. a drain still empties the ring
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::Release);
+    }
+
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::Release);
+        self.resumed.notify_waiters();
+    }
+
+    #[inline]
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Acquire)
+    }
+
+    /// whether a consumer was spawned for the topic
+    #[inline]
+    pub fn has_consumer(&self) -> bool {
+        self.consumer_started.load(Ordering::Acquire)
+    }
+
+    /// resolves once the topic is not paused
+    pub(crate) async fn wait_resumed(&self) {
+        loop {
+            let resumed = self.resumed.notified();
+            tokio::pin!(resumed);
+            resumed.as_mut().enable();
+            if !self.is_paused() {
+                return;
+            }
+            resumed.await;
+        }
+    }
+
+    pub fn info(&self) -> TopicInfo {
+        TopicInfo {
+            id: self.id,
+            name: self.name.clone(),
+            capacity: self.ring.capacity() as u64,
+            used: self.ring.len() as u64,
+        }
+    }
+
+    /// true the first time it is called, so exactly one consumer gets spawned
+    pub(crate) fn claim_consumer(&self) -> bool {
+        !self.consumer_started.swap(true, Ordering::AcqRel)
+    }
+
+    /// append records until `messages` runs dry or the ring is full, in which case
+    /// `BufferFull` is returned and the message that didn't fit stays in `messages`.
+    ///
+    /// the ring has a single write position, so concurrent publishers take turns
+    /// here, one batch at a time. readers are woken separately by `notify_published`
+    pub(crate) fn push_many<'a, I>(
+        &self,
+        publisher: &Arc<Publisher>,
+        messages: &mut Peekable<I>,
+    ) -> Result<(), BrokerError>
+    where
+        I: Iterator<Item = &'a [u8]>,
+    {
+        let _producer = self.producer.lock();
+        {
+            let mut publishers = self.publishers.lock();
+            if !publishers
+                .back()
+                .is_some_and(|(_, last)| Arc::ptr_eq(last, publisher))
+            {
+                publishers.push_back((self.ring.head(), publisher.clone()));
+            }
+        }
+        while let Some(msg) = messages.peek() {
+            self.try_push(msg)?;
+            messages.next();
+        }
+        Ok(())
+    }
+
+    /// callers hold the producer lock
+    #[inline(always)]
+    fn try_push(&self, record: &[u8]) -> Result<u64, BrokerError> {
+        match self.ring.try_push(record) {
+            Err(BrokerError::BufferFull) => {
+                let cursors = self.cursors.lock();
+                loop {
+                    // nobody reading means nobody to wait for, make room instead of stalling
+                    if !cursors.is_empty() && !self.skip_tail(&cursors) {
+                        return Err(BrokerError::BufferFull);
+                    }
+                    self.release(&cursors);
+                    match self.ring.try_push(record) {
+                        Err(BrokerError::BufferFull) => continue,
+                        result => return result,
+                    }
+                }
+            }
+            result => result,
+        }
+    }
+
+    /// wake readers waiting for new records, once per published batch
+    #[inline]
+    pub(crate) fn notify_published(&self) {
+        self.published.notify_waiters();
+    }
+
+    /// resolves once there is a record at or after `offset`
+    pub(crate) async fn wait_for(&self, offset: u64) {
+        loop {
+            let published = self.published.notified();
+            tokio::pin!(published);
+            published.as_mut().enable();
+            if self.ring.head() > offset {
+                return;
+            }
+            published.await;
+        }
+    }
+
+    /// resolves once everything below `offset` was released by every cursor
+    pub(crate) async fn wait_released(&self, offset: u64) {
+        loop {
+            let released = self.released.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+            if self.ring.tail() >= offset {
+                return;
+            }
+            released.await;
+        }
+    }
+
+    /// the connection that published the record at `offset`
+    pub(crate) fn publisher_at(&self, offset: u64) -> Option<Arc<Publisher>> {
+        let publishers = self.publishers.lock();
+        let after = publishers.partition_point(|(start, _)| *start <= offset);
+        after
+            .checked_sub(1)
+            .map(|index| publishers[index].1.clone())
+    }
+
+    /// register a reader. the ring keeps everything from the cursor position onwards
+    pub fn open_cursor(self: &Arc<Self>, start: StartPosition) -> Result<Cursor, BrokerError> {
+        let mut cursors = self.cursors.lock();
+        let tail = self.ring.tail();
+        let head = self.ring.head();
+        let position = match start {
+            StartPosition::Earliest => tail,
+            StartPosition::Latest => head,
+            StartPosition::Offset(offset) if !(tail..=head).contains(&offset) => {
+                return Err(BrokerError::OffsetOutOfRange(offset))
+            }
+            // the cursors lock keeps the tail where it is while the records are walked
+            StartPosition::Offset(offset) if !self.ring.is_record_boundary(offset) => {
+                return Err(BrokerError::InvalidOffset(offset))
+            }
+            StartPosition::Offset(offset) => offset,
+        };
+
+        let position = Arc::new(AtomicU64::new(position));
+        cursors.push((position.clone(), false));
+        Ok(Cursor {
+            topic: self.clone(),
+            position,
+        })
+    }
+
+    /// move the flagged cursors at the tail past the tail record, counting a drop for
+    /// each. false if a cursor that can't be skipped is at the tail as well
+    fn skip_tail(&self, cursors: &[(Arc<AtomicU64>, bool)]) -> bool {
+        let tail = self.ring.tail();
+        let at_tail = || {
+            cursors
+                .iter()
+                .filter(|(c, _)| c.load(Ordering::Acquire) == tail)
+        };
+        if tail == self.ring.head() || at_tail().any(|(_, skippable)| !skippable) {
+            return false;
+        }
+        let next = self.ring.next_record(tail);
+        for (position, _) in at_tail() {
+            position.store(next, Ordering::Release);
+            self.drops.fetch_add(1, Ordering::Relaxed);
+        }
+        true
+    }
+
+    /// move the tail up to the slowest cursor. with no cursors nothing is retained
+    fn release(&self, cursors: &[(Arc<AtomicU64>, bool)]) {
+        let lowest = cursors
+            .iter()
+            .map(|(c, _)| c.load(Ordering::Acquire))
+            .min()
+            .unwrap_or_else(|| self.ring.head());
+        if lowest > self.ring.tail() {
+            self.ring.release(lowest);
+            self.released.notify_waiters();
+
+            // keep the publisher of the new tail record
+            let mut publishers = self.publishers.lock();
+            while publishers.len() > 1 && publishers[1].0 <= lowest {
+                publishers.pop_front();
+            }
+        }
+    }
+}
+
+/// a registered read position on a topic, deregistered on drop
+pub struct Cursor {
+    topic: Arc<Topic>,
+    position: Arc<AtomicU64>,
+}
+
+impl Cursor {
+    #[inline]
+    pub fn topic(&self) -> &Arc<Topic> {
+        &self.topic
+    }
+
+    #[inline]
+    pub fn position(&self) -> u64 {
+        self.position.load(Ordering::Acquire)
+    }
+
+    /// records available past the cursor
+    #[inline]
+    pub fn has_next(&self) -> bool {
+        self.topic.ring.head() > self.position()
+    }
+
+    /// append the record at `offset` to `dst` and return the next offset. doesn't move the cursor
+    #[inline]
+    pub fn read(&self, offset: u64, dst: &mut BytesMut) -> Result<u64, BrokerError> {
+        self.topic.ring.read_record(offset, dst)
+    }
+
+    /// let a full ring skip the cursor past records it hasn't read instead of making
+    /// publishers wait, for at-most-once subscribers. see `Topic::drops`
+    pub(crate) fn skip_when_full(&self) {
+        let mut cursors = self.topic.cursors.lock();
+        if let Some((_, skippable)) = cursors
+            .iter_mut()
+            .find(|(c, _)| Arc::ptr_eq(c, &self.position))
+        {
+            *skippable = true;
+        }
+    }
+
+    /// keep a full ring from skipping the cursor while records are read past it
+    pub(crate) fn hold(&self) -> impl Sized + '_ {
+        self.topic.cursors.lock()
+    }
+
+    /// move the cursor to `offset`, letting the ring reclaim what no cursor needs anymore
+    pub fn advance(&self, offset: u64) {
+        let cursors = self.topic.cursors.lock();
+        self.position.store(offset, Ordering::Release);
+        self.topic.release(&cursors);
+    }
+}
+
+impl Drop for Cursor {
+    fn drop(&mut self) {
+        let mut cursors = self.topic.cursors.lock();
+        cursors.retain(|(c, _)| !Arc::ptr_eq(c, &self.position));
+        self.topic.release(&cursors);
+    }
+}
+
+#[derive(Default)]
+struct Topics {
+    by_name: HashMap<String, Arc<Topic>>,
+    by_id: Vec<Arc<Topic>>,
+    /// ring bytes of the topics, and of those being allocated
+    ring_memory: usize,
+}
+
+/// one ring per topic, created on demand or declared ahead of time
+pub struct TopicRegistry {
+    topics: RwLock<Topics>,
+    defaults: TopicConfig,
+    auto_create: bool,
+    max_topics: usize,
+    /// most bytes all rings together may take
+    max_ring_memory: usize,
+}
+
+impl TopicRegistry {
+    pub fn new(
+        defaults: TopicConfig,
+        auto_create: bool,
+        max_topics: usize,
+        max_ring_memory: usize,
+    ) -> Self {
+        Self {
+            topics: RwLock::new(Topics::default()),
+            defaults,
+            auto_create,
+            max_topics,
+            max_ring_memory,
+        }
+    }
+
+    /// create `name` with `config`, or return the existing topic of that name
+    pub fn declare(&self, name: &str, config: TopicConfig) -> Result<Arc<Topic>, BrokerError> {
+        validate_name(name)?;
+
+        // room for the ring is taken under the lock, the ring is allocated and
+        // zeroed without it so lookups don't wait on a large allocation
+        {
+            let mut topics = self.topics.write();
+            if let Some(topic) = topics.by_name.get(name) {
+                return Ok(topic.clone());
+            }
+            if topics.by_id.len() >= self.max_topics {
+                return Err(BrokerError::TooManyTopics(self.max_topics));
+            }
+            let available = self.max_ring_memory.saturating_sub(topics.ring_memory);
+            if config.capacity > available {
+                return Err(BrokerError::RingMemoryExhausted {
+                    requested: config.capacity,
+                    available,
+                });
+            }
+            topics.ring_memory += config.capacity;
+        }
+        let ring = RingBuffer::with_capacity(config.capacity);
+
+        let mut topics = self.topics.write();
+        let ring = match ring {
+            Ok(ring) => ring,
+            Err(e) => {
+                topics.ring_memory -= config.capacity;
+                return Err(e);
+            }
+        };
+        // declared by someone else meanwhile
+        if let Some(topic) = topics.by_name.get(name) {
+            let topic = topic.clone();
+            topics.ring_memory -= config.capacity;
+            return Ok(topic);
+        }
+        if topics.by_id.len() >= self.max_topics {
+            topics.ring_memory -= config.capacity;
+            return Err(BrokerError::TooManyTopics(self.max_topics));
+        }
+
+        let topic = Arc::new(Topic {
+            id: topics.by_id.len() as TopicId,
+            name: name.to_string(),
+            ring,
+            staleness: config.staleness,
+            consumer_started: AtomicBool::new(false),
+            producer: Mutex::new(()),
+            cursors: Mutex::new(Vec::new()),
+            published: Notify::new(),
+            released: Notify::new(),
+            publishers: Mutex::new(VecDeque::new()),
+            stalls: AtomicU64::new(0),
+            drops: AtomicU64::new(0),
+            paused: AtomicBool::new(false),
+            resumed: Notify::new(),
+            limiter: config
+                .quota
+                .map(|quota| Mutex::new(RateLimiter::new(quota))),
+            quota_hits: AtomicU64::new(0),
+        });
+        topics.by_name.insert(topic.name.clone(), topic.clone());
+        topics.by_id.push(topic.clone());
+        Ok(topic)
+    }
+
+    pub fn get(&self, topic: &TopicRef) -> Option<Arc<Topic>> {
+        let topics = self.topics.read();
+        match topic {
+            TopicRef::Id(id) => topics.by_id.get(*id as usize).cloned(),
+            TopicRef::Name(name) => topics.by_name.get(name).cloned(),
+        }
+    }
+
+    /// look `topic` up, creating it with the default config if auto-create is on
+    pub fn resolve(&self, topic: &TopicRef) -> Result<Arc<Topic>, BrokerError> {
+        if let Some(found) = self.get(topic) {
+            return Ok(found);
+        }
+        match topic {
+            TopicRef::Name(name) if self.auto_create => self.declare(name, self.defaults),
+            _ => Err(BrokerError::UnknownTopic(topic.to_string())),
+        }
+    }
+
+    pub fn all(&self) -> Vec<Arc<Topic>> {
+        self.topics.read().by_id.clone()
+    }
+
+    pub fn list(&self) -> Vec<TopicInfo> {
+        self.topics.read().by_id.iter().map(|t| t.info()).collect()
+    }
+}
+
+fn validate_name(name: &str) -> Result<(), BrokerError> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_TOPIC_NAME_LEN
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'));
+    if !valid {
+        return Err(BrokerError::InvalidTopicName(name.to_string()));
+    }
+    Ok(())
+}
@@ -0,0 +1,292 @@
+//! topic ACLs: who may publish and subscribe where, denials, audits and reloads
+
+mod common;
+
+use broker::acl::{Acl, Operation};
+use broker::net::codec::error_code;
+use broker::topic::StartPosition;
+use broker::{
+    AclConfig, AuthConfig, Backpressure, BrokerClient, BrokerServer, BrokerSubscriber,
+    ClientOptions, Credential, NetworkError, ServerConfig, SubscribeOptions,
+};
+use common::{message, start, temp_path, wait_until, LOOPBACK, MESSAGE_LEN};
+use serde_json::Value;
+use std::time::Duration;
+
+fn token(principal: &str) -> Option<Credential> {
+    Some(Credential::Token {
+        principal: principal.to_string(),
+        token: format!("{}-token", principal),
+    })
+}
+
+fn forbidden(result: Result<impl Sized, NetworkError>) -> bool {
+    matches!(result, Err(NetworkError::Remote { code, .. }) if code == error_code::FORBIDDEN)
+}
+
+#[test]
+fn rules_match_principals_operations_and_topics() {
+    let acl = Acl::parse(
+        "
+        # principal  operations         topic
+        ingest       publish            orders.*
+        analytics    subscribe          *
+        *            publish,subscribe  scratch
+        team-*       all                team.*.events
+        ",
+    )
+    .unwrap();
+    assert_eq!(acl.len(), 4);
+
+    assert!(acl.allows(Some("ingest"), Operation::Publish, "orders.eu"));
+    assert!(!acl.allows(Some("ingest"), Operation::Publish, "orders"));
+    assert!(!acl.allows(Some("ingest"), Operation::Subscribe, "orders.eu"));
+    assert!(acl.allows(Some("analytics"), Operation::Subscribe, "payments"));
+    assert!(!acl.allows(Some("analytics"), Operation::Publish, "payments"));
+    assert!(acl.allows(None, Operation::Publish, "scratch"));
+    assert!(!acl.allows(None, Operation::Publish, "scratchpad"));
+    assert!(acl.allows(Some("team-a"), Operation::Subscribe, "team.a.events"));
+    assert!(!acl.allows(Some("team-a"), Operation::Subscribe, "team.a.logs"));
+
+    // no rules, no access
+    assert!(!Acl::default().allows(Some("ingest"), Operation::Publish, "orders"));
+
+    for (text, error) in [
+        (
+            "ingest publish",
+            "line 1: expected a principal, operations and a topic",
+        ),
+        (
+            "\ningest write orders",
+            "line 2: unknown operation \"write\"",
+        ),
+        ("ingest publish, orders", "line 1: unknown operation \"\""),
+    ] {
+        let err = Acl::parse(text).err().unwrap();
+        assert_eq!(err.to_string(), error);
+    }
+}
+
+#[tokio::test]
+async fn denials_are_answered_and_audited() {
+    let credentials = temp_path("acl.credentials");
+    std::fs::write(
+        &credentials,
+        "ingest token ingest-token\nanalytics token analytics-token\n",
+    )
+    .unwrap();
+    let rules = temp_path("denials.acl");
+    std::fs::write(
+        &rules,
+        "ingest publish orders.*\nanalytics subscribe orders.*\n",
+    )
+    .unwrap();
+    let audit_log = temp_path("denials.audit");
+    let _ = std::fs::remove_file(&audit_log);
+    let server = BrokerServer::with_config(ServerConfig {
+        auth: Some(AuthConfig::new(&credentials)),
+        acl: Some(AclConfig {
+            audit_log: Some(audit_log.clone()),
+            ..AclConfig::new(&rules)
+        }),
+        ..ServerConfig::bind(LOOPBACK)
+    });
+    let handle = server.start().await.unwrap();
+
+    let options = ClientOptions {
+        credential: token("ingest"),
+        ..ClientOptions::default()
+    };
+    let mut ingest = BrokerClient::connect_with_options(handle.local_addr(), options)
+        .await
+        .unwrap();
+
+    let subscribe = |principal| SubscribeOptions {
+        credential: token(principal),
+        ..SubscribeOptions::default()
+    };
+    let addr = handle.local_addr();
+    let denied = BrokerSubscriber::connect_with_options(
+        addr,
+        "orders.eu",
+        StartPosition::Earliest,
+        subscribe("ingest"),
+    )
+    .await;
+    assert!(forbidden(denied));
+    let mut analytics = BrokerSubscriber::connect_with_options(
+        addr,
+        "orders.eu",
+        StartPosition::Earliest,
+        subscribe("analytics"),
+    )
+    .await
+    .unwrap();
+
+    ingest.send_to("payments", &message(0)).await.unwrap();
+    ingest.flush().await.unwrap();
+    assert!(forbidden(ingest.list_topics().await));
+    // the same denial again right away is counted, not logged
+    ingest.send_to("payments", &message(0)).await.unwrap();
+    ingest.flush().await.unwrap();
+    wait_until(|| handle.stats().acl_denials == 3).await;
+    // the connection stays usable for what it may do
+    ingest.send_to("orders.eu", &message(1)).await.unwrap();
+    ingest.flush().await.unwrap();
+    wait_until(|| handle.stats().messages_in == 1).await;
+    assert!(!handle.topics().iter().any(|t| t.name == "payments"));
+
+    let record = analytics.recv().await.unwrap().unwrap();
+    assert_eq!(record.payload, message(1));
+    ingest.send_to("refunds", &message(2)).await.unwrap();
+    ingest.flush().await.unwrap();
+    wait_until(|| handle.stats().acl_denials == 4).await;
+
+    // written behind the connections' backs
+    let entries = || -> Vec<Value> {
+        let audit = std::fs::read_to_string(&audit_log).unwrap_or_default();
+        audit
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    };
+    wait_until(|| entries().len() == 3).await;
+    let entries = entries();
+    assert_eq!(entries[0]["principal"], "ingest");
+    assert_eq!(entries[0]["operation"], "subscribe");
+    assert_eq!(entries[0]["topic"], "orders.eu");
+    assert_eq!(entries[1]["operation"], "publish");
+    assert_eq!(entries[1]["topic"], "payments");
+    assert_eq!(entries[2]["topic"], "refunds");
+    assert!(entries.iter().all(|entry| entry["repeated"] == 0));
+
+    drop((ingest, analytics));
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn rules_are_reloaded() {
+    let rules = temp_path("reload.acl");
+    std::fs::write(&rules, "anonymous publish first\n").unwrap();
+    let server = BrokerServer::with_config(ServerConfig {
+        acl: Some(AclConfig {
+            reload_interval: Some(Duration::from_millis(20)),
+            ..AclConfig::new(&rules)
+        }),
+        ..ServerConfig::bind(LOOPBACK)
+    });
+    let handle = server.start().await.unwrap();
+    let mut client = BrokerClient::connect(handle.local_addr()).await.unwrap();
+    client.send_to("first", &message(0)).await.unwrap();
+    client.flush().await.unwrap();
+    wait_until(|| handle.stats().messages_in == 1).await;
+    assert!(forbidden(client.declare_topic("second").await));
+
+    // the file is watched
+    std::fs::write(&rules, "anonymous publish second\n").unwrap();
+    let mut allowed = false;
+    for _ in 0..500 {
+        if client.declare_topic("second").await.is_ok() {
+            allowed = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(allowed);
+    // the topic this connection published to before is checked again
+    client.send_to("first", &message(1)).await.unwrap();
+    client.flush().await.unwrap();
+    assert!(forbidden(client.list_topics().await));
+
+    // an invalid file leaves the rules as they were
+    std::fs::write(&rules, "anonymous everything\n").unwrap();
+    assert!(handle.reload_acl().is_err());
+    client.declare_topic("second").await.unwrap();
+
+    drop(client);
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn revoked_subscriptions_are_ended() {
+    let rules = temp_path("revoke.acl");
+    std::fs::write(&rules, "anonymous publish,subscribe orders\n").unwrap();
+    let handle = start(ServerConfig {
+        acl: Some(AclConfig::new(&rules)),
+        ..ServerConfig::bind(LOOPBACK)
+    })
+    .await;
+    let addr = handle.local_addr();
+    let mut subscriber = BrokerSubscriber::connect(addr, "orders", StartPosition::Earliest)
+        .await
+        .unwrap();
+    let mut client = BrokerClient::connect(addr).await.unwrap();
+    client.send_to("orders", &message(0)).await.unwrap();
+    client.flush().await.unwrap();
+    let record = subscriber.recv().await.unwrap().unwrap();
+    assert_eq!(&record.payload[..], &message(0)[..]);
+
+    std::fs::write(&rules, "anonymous publish orders\n").unwrap();
+    handle.reload_acl().unwrap();
+    let ended = tokio::time::timeout(Duration::from_secs(5), subscriber.recv())
+        .await
+        .expect("the subscription outlived its rule");
+    assert!(forbidden(ended));
+    assert_eq!(handle.stats().acl_denials, 1);
+
+    drop((client, subscriber));
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn denied_publishes_give_their_credit_back() {
+    let rules = temp_path("credit.acl");
+    std::fs::write(&rules, "anonymous publish,subscribe allowed\n").unwrap();
+    let window = 4 * MESSAGE_LEN as u32;
+    let handle = start(ServerConfig {
+        acl: Some(AclConfig::new(&rules)),
+        producer_window: window,
+        ..ServerConfig::bind(LOOPBACK)
+    })
+    .await;
+    let addr = handle.local_addr();
+    let mut client = BrokerClient::connect(addr).await.unwrap();
+    assert_eq!(
+        client
+            .enable_flow_control(Backpressure::Wait)
+            .await
+            .unwrap(),
+        window
+    );
+
+    // several windows worth, each batch denied
+    let denied = async {
+        for sequence in 0..16 {
+            let sent = client.send_to("secret", &message(sequence)).await;
+            assert!(sent.is_ok() || forbidden(sent));
+            let flushed = client.flush().await;
+            assert!(flushed.is_ok() || forbidden(flushed));
+        }
+        // read up to the last denial
+        while forbidden(client.list_topics().await) {}
+    };
+    tokio::time::timeout(Duration::from_secs(5), denied)
+        .await
+        .expect("credit of denied batches was never granted back");
+    assert_eq!(client.credit(), Some(window as u64));
+
+    let mut subscriber = BrokerSubscriber::connect(addr, "allowed", StartPosition::Earliest)
+        .await
+        .unwrap();
+    client.send_to("allowed", &message(16)).await.unwrap();
+    client.flush().await.unwrap();
+    let record = tokio::time::timeout(Duration::from_secs(5), subscriber.recv())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(&record.payload[..], &message(16)[..]);
+
+    drop((client, subscriber));
+    handle.shutdown().await.unwrap();
+}
@@ -0,0 +1,230 @@
+//! the admin listener's control API: connections, topics, pausing consumers, drains
+//! and the token guarding them
+
+mod common;
+
+use broker::{
+    BoxError, BrokerClient, BrokerServer, Message, MessageHandler, ServerConfig, TopicConfig,
+    UnixListenConfig,
+};
+use common::{admin, http, message, temp_path, wait_until, LOOPBACK};
+use serde_json::Value;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+fn server() -> BrokerServer {
+    BrokerServer::with_config(ServerConfig {
+        admin: Some(LOOPBACK),
+        ..ServerConfig::bind(LOOPBACK)
+    })
+}
+
+#[tokio::test]
+async fn connections_and_topics_are_listed() {
+    let handle = server().start().await.unwrap();
+    let mut client = BrokerClient::connect(handle.local_addr()).await.unwrap();
+    for sequence in 0..10 {
+        client.send_to("orders", &message(sequence)).await.unwrap();
+    }
+    client.flush().await.unwrap();
+    wait_until(|| handle.stats().messages_processed == 10).await;
+
+    let (status, connections) = admin(&handle, "GET", "/connections").await;
+    assert_eq!(status, 200);
+    let connections = connections.as_array().unwrap();
+    assert_eq!(connections.len(), 1);
+    assert_eq!(connections[0]["id"], 1);
+    assert_eq!(connections[0]["messages_in"], 10);
+    assert_eq!(connections[0]["bytes_in"], 480);
+    assert!(connections[0]["frames_in"].as_u64().unwrap() >= 1);
+
+    let (status, topics) = admin(&handle, "GET", "/topics").await;
+    assert_eq!(status, 200);
+    let orders = topics
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|t| t["name"] == "orders")
+        .unwrap();
+    assert_eq!(orders["consumer"], true);
+    assert_eq!(orders["paused"], false);
+    assert_eq!(orders["head"], orders["tail"]);
+
+    drop(client);
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn clients_can_be_disconnected() {
+    let handle = server().start().await.unwrap();
+    let _client = BrokerClient::connect(handle.local_addr()).await.unwrap();
+    wait_until(|| handle.stats().open_connections == 1).await;
+
+    let (status, _) = admin(&handle, "DELETE", "/connections/1").await;
+    assert_eq!(status, 200);
+    wait_until(|| handle.stats().open_connections == 0).await;
+    let (_, connections) = admin(&handle, "GET", "/connections").await;
+    assert_eq!(connections, Value::Array(vec![]));
+
+    let (status, body) = admin(&handle, "DELETE", "/connections/1").await;
+    assert_eq!(status, 404);
+    assert_eq!(body["error"], "404 Not Found");
+    let (status, _) = admin(&handle, "GET", "/connections/1").await;
+    assert_eq!(status, 405);
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn paused_consumers_hold_messages() {
+    let server = server();
+    server
+        .declare_topic("orders", TopicConfig::default())
+        .unwrap();
+    let handle = server.start().await.unwrap();
+
+    let (status, body) = admin(&handle, "POST", "/topics/orders/pause").await;
+    assert_eq!(status, 200);
+    assert_eq!(body["paused"], true);
+    let mut client = BrokerClient::connect(handle.local_addr()).await.unwrap();
+    for sequence in 0..10 {
+        client.send_to("orders", &message(sequence)).await.unwrap();
+    }
+    client.flush().await.unwrap();
+    wait_until(|| handle.stats().messages_in == 10).await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(handle.stats().messages_processed, 0);
+
+    let (status, body) = admin(&handle, "POST", "/topics/orders/resume").await;
+    assert_eq!(status, 200);
+    assert_eq!(body["paused"], false);
+    wait_until(|| handle.stats().messages_processed == 10).await;
+
+    let (status, _) = admin(&handle, "POST", "/topics/nope/pause").await;
+    assert_eq!(status, 404);
+    drop(client);
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn drains_can_be_requested() {
+    let server = server();
+    server
+        .declare_topic("orders", TopicConfig::default())
+        .unwrap();
+    let mut handle = server.start().await.unwrap();
+    admin(&handle, "POST", "/topics/orders/pause").await;
+    let mut client = BrokerClient::connect(handle.local_addr()).await.unwrap();
+    for sequence in 0..10 {
+        client.send_to("orders", &message(sequence)).await.unwrap();
+    }
+    client.flush().await.unwrap();
+    drop(client);
+
+    let (status, _) = admin(&handle, "POST", "/drain").await;
+    assert_eq!(status, 202);
+    tokio::time::timeout(Duration::from_secs(5), handle.wait())
+        .await
+        .unwrap()
+        .unwrap();
+    // a drain delivers what paused consumers held back
+    let report = handle
+        .shutdown_with_drain(Duration::from_secs(10))
+        .await
+        .unwrap();
+    assert_eq!(report.stats.messages_processed, 10);
+}
+
+/// counts the messages it is handed
+#[derive(Clone, Default)]
+struct Counter(Arc<AtomicU64>);
+
+impl MessageHandler for Counter {
+    fn on_message(&self, _msg: &Message<'_>) -> Result<(), BoxError> {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn run_drains_when_asked_to() {
+    // `run` hides the bound addresses, so the test picks them
+    let admin_addr = std::net::TcpListener::bind(LOOPBACK)
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let path = temp_path("run-drain.sock");
+    let server = BrokerServer::with_config(ServerConfig {
+        listen: Vec::new(),
+        unix_listen: vec![UnixListenConfig::new(&path)],
+        admin: Some(admin_addr),
+        ..Default::default()
+    });
+    server
+        .declare_topic("orders", TopicConfig::default())
+        .unwrap();
+    let counter = Counter::default();
+    server.set_topic_handler("orders", counter.clone());
+    let run = tokio::spawn(server.run());
+
+    let request =
+        |method: &str, path: &str| format!("{} {} HTTP/1.1\r\nHost: broker\r\n\r\n", method, path);
+    // the admin listener is bound last
+    wait_until(|| std::net::TcpStream::connect(admin_addr).is_ok()).await;
+    let (status, _) = http(admin_addr, &request("POST", "/topics/orders/pause")).await;
+    assert!(status.contains("200"));
+    let mut client = BrokerClient::connect_unix(&path).await.unwrap();
+    for sequence in 0..10 {
+        client.send_to("orders", &message(sequence)).await.unwrap();
+    }
+    client.flush().await.unwrap();
+    drop(client);
+
+    let (status, _) = http(admin_addr, &request("POST", "/drain")).await;
+    assert!(status.contains("202"));
+    tokio::time::timeout(Duration::from_secs(10), run)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    // what the paused consumer held back was handled before `run` returned
+    assert_eq!(counter.0.load(Ordering::Relaxed), 10);
+}
+
+#[tokio::test]
+async fn tokens_guard_the_admin_api() {
+    // anywhere but loopback the API needs a token
+    let open = BrokerServer::with_config(ServerConfig {
+        admin: Some("0.0.0.0:0".parse().unwrap()),
+        ..ServerConfig::bind(LOOPBACK)
+    });
+    assert!(open.start().await.is_err());
+
+    let handle = BrokerServer::with_config(ServerConfig {
+        admin: Some(LOOPBACK),
+        admin_token: Some("s3cret".to_string()),
+        ..ServerConfig::bind(LOOPBACK)
+    })
+    .start()
+    .await
+    .unwrap();
+    assert_eq!(admin(&handle, "POST", "/drain").await.0, 401);
+
+    let addr = handle.admin_addr().unwrap();
+    let get = |authorization: &str| {
+        format!(
+            "GET /topics HTTP/1.1\r\nHost: broker\r\n{}\r\n\r\n",
+            authorization
+        )
+    };
+    let (status, _) = http(addr, &get("Authorization: Bearer secret")).await;
+    assert!(status.contains("401"), "{}", status);
+    let (status, _) = http(addr, &get("Authorization: s3cret")).await;
+    assert!(status.contains("401"), "{}", status);
+    let (status, body) = http(addr, &get("authorization: Bearer s3cret")).await;
+    assert!(status.contains("200"), "{}", status);
+    let topics: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(topics[0]["name"], "default");
+
+    handle.shutdown().await.unwrap();
+}
@@ -0,0 +1,218 @@
+//! the authentication handshake: tokens, HMAC challenges and who a connection is
+
+mod common;
+
+use broker::auth::sign;
+use broker::net::codec::{error_code, BrokerCodec, Frame, Mechanism};
+use broker::{
+    AuthConfig, BrokerClient, BrokerSubscriber, ClientOptions, Credential, Credentials,
+    NetworkError, ServerConfig, ServerHandle, SubscribeOptions,
+};
+use bytes::Bytes;
+use common::{connections, message, start, temp_path, LOOPBACK};
+use futures_util::{SinkExt, StreamExt};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_util::codec::Framed;
+
+const CREDENTIALS: &str = "
+# principal  mechanism  secret
+ingest       token      s3cret-token
+analytics    hmac       hmac key with spaces
+";
+
+fn credentials_file(name: &str) -> PathBuf {
+    let path = temp_path(&format!("{}.credentials", name));
+    std::fs::write(&path, CREDENTIALS).unwrap();
+    path
+}
+
+fn authenticated(name: &str) -> ServerConfig {
+    ServerConfig {
+        admin: Some(LOOPBACK),
+        auth: Some(AuthConfig {
+            timeout: Duration::from_millis(500),
+            ..AuthConfig::new(credentials_file(name))
+        }),
+        ..ServerConfig::bind(LOOPBACK)
+    }
+}
+
+fn token(token: &str) -> Credential {
+    Credential::Token {
+        principal: "ingest".to_string(),
+        token: token.to_string(),
+    }
+}
+
+fn hmac(secret: &str) -> Credential {
+    Credential::Hmac {
+        principal: "analytics".to_string(),
+        secret: secret.to_string(),
+    }
+}
+
+async fn connect(
+    handle: &ServerHandle,
+    credential: Credential,
+) -> Result<BrokerClient, NetworkError> {
+    let options = ClientOptions {
+        credential: Some(credential),
+        ..ClientOptions::default()
+    };
+    BrokerClient::connect_with_options(handle.local_addr(), options).await
+}
+
+fn unauthenticated(err: &NetworkError) -> bool {
+    matches!(err, NetworkError::Remote { code, .. } if *code == error_code::UNAUTHENTICATED)
+}
+
+#[tokio::test]
+async fn tokens_authenticate_producers() {
+    let handle = start(authenticated("token")).await;
+    let mut client = connect(&handle, token("s3cret-token")).await.unwrap();
+    client.send_to("orders", &message(0)).await.unwrap();
+    let topics = client.list_topics().await.unwrap();
+    assert!(topics.iter().any(|t| t.name == "orders"));
+    assert_eq!(handle.stats().messages_in, 1);
+    assert_eq!(handle.stats().auth_failures, 0);
+    assert_eq!(connections(&handle).await[0]["principal"], "ingest");
+
+    let err = connect(&handle, token("guess")).await.err().unwrap();
+    assert!(unauthenticated(&err));
+    assert_eq!(handle.stats().auth_failures, 1);
+
+    drop(client);
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn hmac_proves_the_secret_without_sending_it() {
+    let handle = start(authenticated("hmac")).await;
+    let mut client = connect(&handle, hmac("hmac key with spaces"))
+        .await
+        .unwrap();
+    assert!(!client.list_topics().await.unwrap().is_empty());
+
+    let err = connect(&handle, hmac("hmac key")).await.err().unwrap();
+    assert!(unauthenticated(&err));
+
+    // a principal has to use the mechanism it is listed with
+    let wrong_mechanism = Credential::Token {
+        principal: "analytics".to_string(),
+        token: "hmac key with spaces".to_string(),
+    };
+    let err = connect(&handle, wrong_mechanism).await.err().unwrap();
+    assert!(unauthenticated(&err));
+    assert_eq!(handle.stats().auth_failures, 2);
+
+    drop(client);
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn the_handshake_comes_first() {
+    let handle = start(authenticated("first")).await;
+
+    let mut client = BrokerClient::connect(handle.local_addr()).await.unwrap();
+    let err = client.list_topics().await.unwrap_err();
+    assert!(unauthenticated(&err), "{}", err);
+
+    // a nonce answered for another principal proves nothing
+    let socket = TcpStream::connect(handle.local_addr()).await.unwrap();
+    let mut framed = Framed::new(socket, BrokerCodec::new());
+    framed
+        .send(Frame::Auth {
+            mechanism: Mechanism::Hmac,
+            principal: "analytics".to_string(),
+            proof: Bytes::new(),
+        })
+        .await
+        .unwrap();
+    let Some(Ok(Frame::Challenge { nonce })) = framed.next().await else {
+        panic!("no challenge");
+    };
+    assert_eq!(nonce.len(), broker::auth::NONCE_LEN);
+    framed
+        .send(Frame::Auth {
+            mechanism: Mechanism::Hmac,
+            principal: "ingest".to_string(),
+            proof: sign("hmac key with spaces", &nonce).into(),
+        })
+        .await
+        .unwrap();
+    assert!(matches!(
+        framed.next().await,
+        Some(Ok(Frame::Error { code, .. })) if code == error_code::UNAUTHENTICATED
+    ));
+
+    // saying nothing runs out the handshake timeout
+    let silent = TcpStream::connect(handle.local_addr()).await.unwrap();
+    let mut framed = Framed::new(silent, BrokerCodec::new());
+    let frame = tokio::time::timeout(Duration::from_secs(5), framed.next())
+        .await
+        .unwrap();
+    assert!(matches!(
+        frame,
+        Some(Ok(Frame::Error { code, .. })) if code == error_code::UNAUTHENTICATED
+    ));
+    assert_eq!(handle.stats().auth_failures, 3);
+    assert_eq!(handle.stats().messages_in, 0);
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn subscribers_authenticate_too() {
+    let handle = start(authenticated("subscriber")).await;
+    let options = SubscribeOptions {
+        credential: Some(hmac("hmac key with spaces")),
+        ..SubscribeOptions::default()
+    };
+    let mut subscriber = BrokerSubscriber::connect_with_options(
+        handle.local_addr(),
+        "orders",
+        broker::StartPosition::Earliest,
+        options,
+    )
+    .await
+    .unwrap();
+
+    let mut client = connect(&handle, token("s3cret-token")).await.unwrap();
+    client.send_to("orders", &message(0)).await.unwrap();
+    client.flush().await.unwrap();
+    let record = subscriber.recv().await.unwrap().unwrap();
+    assert_eq!(record.payload, message(0));
+
+    drop((client, subscriber));
+    handle.shutdown().await.unwrap();
+}
+
+#[test]
+fn credentials_files_are_checked() {
+    let credentials = Credentials::parse(CREDENTIALS).unwrap();
+    assert_eq!(credentials.len(), 2);
+    assert_eq!(credentials.mechanism("ingest"), Some(Mechanism::Token));
+    assert_eq!(credentials.mechanism("analytics"), Some(Mechanism::Hmac));
+    assert_eq!(credentials.mechanism("nobody"), None);
+
+    for (text, error) in [
+        (
+            "ingest token a\ningest hmac b",
+            "line 2: ingest is listed twice",
+        ),
+        (
+            "ingest password a",
+            "line 1: unknown mechanism \"password\"",
+        ),
+        ("ingest token", "line 1: missing secret"),
+        ("ingest", "line 1: missing mechanism"),
+    ] {
+        let err = Credentials::parse(text).err().unwrap();
+        assert_eq!(err.to_string(), error);
+    }
+
+    // secrets never end up in logs
+    let debug = format!("{:?}", token("s3cret-token"));
+    assert!(!debug.contains("s3cret"), "{}", debug);
+}
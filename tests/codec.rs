@@ -0,0 +1,230 @@
+//! the codec on a byte stream: frames split at any point, several per read, and
+//! the errors it must report instead of producing garbage
+
+mod common;
+
+use broker::net::codec::{Ack, Batch, BrokerCodec, DeliveryBuilder, Frame, Mechanism};
+use broker::net::message::{FrameHeader, PROTOCOL_VERSION};
+use broker::topic::{StartPosition, TopicInfo, TopicRef};
+use broker::{BrokerClient, NetworkError};
+use bytes::{Bytes, BytesMut};
+use common::{message, LOOPBACK};
+use futures_util::{SinkExt, StreamExt};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio_util::codec::{Decoder, Encoder, FramedRead, FramedWrite};
+
+/// one frame of every kind
+fn frames() -> Vec<Frame> {
+    let mut delivery = DeliveryBuilder::new(3);
+    delivery.push(0, b"first");
+    delivery.push(40, b"second");
+    vec![
+        Frame::Publish {
+            topic: TopicRef::Name("orders".to_string()),
+            batch: Batch::new(4, 3, Bytes::from_static(b"aaaabbbbcccc")).unwrap(),
+        },
+        Frame::Publish {
+            topic: TopicRef::Id(9),
+            batch: Batch::new(2, 0, Bytes::new()).unwrap(),
+        },
+        Frame::Declare {
+            topic: "orders".to_string(),
+        },
+        Frame::ListTopics,
+        Frame::Subscribe {
+            topic: TopicRef::Name("orders".to_string()),
+            start: StartPosition::Offset(1 << 40),
+            credit: 128,
+            ack_timeout_ms: 500,
+            max_in_flight: 64,
+        },
+        Frame::Subscribe {
+            topic: TopicRef::Id(2),
+            start: StartPosition::Latest,
+            credit: 1,
+            ack_timeout_ms: 0,
+            max_in_flight: 0,
+        },
+        Frame::Credit { messages: 17 },
+        Frame::Ack(Ack::Through(99)),
+        Frame::Ack(Ack::Offsets(vec![3, 1, u64::MAX])),
+        Frame::FlowControl,
+        Frame::Auth {
+            mechanism: Mechanism::Hmac,
+            principal: "alice".to_string(),
+            proof: Bytes::from_static(&[0xde, 0xad]),
+        },
+        Frame::Heartbeat,
+        Frame::Topics(vec![TopicInfo {
+            id: 1,
+            name: "orders".to_string(),
+            capacity: 1 << 20,
+            used: 96,
+        }]),
+        Frame::Subscribed {
+            topic: 3,
+            offset: 40,
+        },
+        Frame::Deliver(delivery.finish()),
+        Frame::Grant { bytes: 65_536 },
+        Frame::Challenge {
+            nonce: Bytes::from_static(&[7; 32]),
+        },
+        Frame::Authenticated {
+            principal: "alice".to_string(),
+        },
+        Frame::Error {
+            code: 11,
+            message: "not allowed".to_string(),
+        },
+    ]
+}
+
+fn encode_all(frames: &[Frame]) -> BytesMut {
+    let mut codec = BrokerCodec::new();
+    let mut dst = BytesMut::new();
+    for frame in frames {
+        codec.encode(frame.clone(), &mut dst).unwrap();
+    }
+    dst
+}
+
+#[test]
+fn every_frame_round_trips() {
+    for frame in frames() {
+        let mut src = encode_all(std::slice::from_ref(&frame));
+        assert_eq!(src[0], frame.kind());
+        let decoded = BrokerCodec::new().decode(&mut src).unwrap();
+        assert_eq!(decoded, Some(frame));
+        assert!(src.is_empty());
+    }
+}
+
+#[test]
+fn frames_decode_from_any_split() {
+    let frames = frames();
+    let stream = encode_all(&frames);
+
+    for chunk in [1, 2, 3, 7, FrameHeader::LEN, 13, 64, stream.len()] {
+        let mut codec = BrokerCodec::new();
+        let mut src = BytesMut::new();
+        let mut decoded = Vec::new();
+        for piece in stream.chunks(chunk) {
+            src.extend_from_slice(piece);
+            while let Some(frame) = codec.decode(&mut src).unwrap() {
+                decoded.push(frame);
+            }
+        }
+        assert_eq!(decoded, frames, "chunks of {}", chunk);
+        assert!(src.is_empty());
+    }
+}
+
+#[tokio::test]
+async fn frames_survive_a_stream() {
+    let (client, server) = tokio::io::duplex(64);
+    let frames = frames();
+
+    let sent = frames.clone();
+    let writer = tokio::spawn(async move {
+        let mut sink = FramedWrite::new(client, BrokerCodec::new());
+        for frame in sent {
+            sink.send(frame).await.unwrap();
+        }
+    });
+
+    let mut received = FramedRead::new(server, BrokerCodec::new());
+    for frame in frames {
+        assert_eq!(received.next().await.unwrap().unwrap(), frame);
+    }
+    writer.await.unwrap();
+    assert!(received.next().await.is_none());
+}
+
+#[test]
+fn oversized_frame_is_not_encoded() {
+    let mut dst = BytesMut::from(&b"queued"[..]);
+    let frame = Frame::Publish {
+        topic: TopicRef::Id(1),
+        batch: Batch::new(64, 4, Bytes::from(vec![0; 256])).unwrap(),
+    };
+    let encoded = BrokerCodec::with_max_frame_len(128).encode(frame, &mut dst);
+    assert!(matches!(
+        encoded,
+        Err(NetworkError::FrameTooLarge { max: 128 })
+    ));
+    // nothing half written after what was already queued
+    assert_eq!(&dst[..], b"queued");
+}
+
+#[test]
+fn long_topic_name_is_not_encoded() {
+    let mut dst = BytesMut::new();
+    let frame = Frame::Declare {
+        topic: "t".repeat(256),
+    };
+    assert!(BrokerCodec::new().encode(frame, &mut dst).is_err());
+    assert!(dst.is_empty());
+}
+
+#[test]
+fn batch_length_must_match() {
+    assert!(Batch::new(4, 3, Bytes::from_static(b"aaaabbbb")).is_err());
+    assert!(Batch::new(4, 1, Bytes::from_static(b"aaaabbbb")).is_err());
+}
+
+#[test]
+fn garbage_body_is_rejected() {
+    // a declare whose name length runs past the body
+    let mut src = BytesMut::from(&[0x02, 1, 0, 0, 2, 0, 0, 0, 9, b'a'][..]);
+    assert!(BrokerCodec::new().decode(&mut src).is_err());
+
+    // a declare whose name is not UTF-8
+    let mut src = BytesMut::from(&[0x02, 1, 0, 0, 3, 0, 0, 0, 2, 0xff, 0xfe][..]);
+    assert!(BrokerCodec::new().decode(&mut src).is_err());
+}
+
+#[test]
+fn oversized_header_is_rejected_before_the_body_arrives() {
+    let mut codec = BrokerCodec::with_max_frame_len(1024);
+    let mut src = BytesMut::new();
+    src.extend_from_slice(
+        &FrameHeader {
+            kind: 0x01,
+            version: PROTOCOL_VERSION,
+            len: 1025,
+        }
+        .to_le_bytes(),
+    );
+    assert!(matches!(
+        codec.decode(&mut src),
+        Err(NetworkError::FrameTooLarge { max: 1024 })
+    ));
+}
+
+#[tokio::test]
+async fn failed_flushes_keep_the_batch() {
+    let listener = TcpListener::bind(LOOPBACK).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let accepted = tokio::spawn(async move { drop(listener.accept().await.unwrap()) });
+    let mut client = BrokerClient::connect(addr).await.unwrap();
+    accepted.await.unwrap();
+
+    // the first writes after the peer is gone may still be buffered
+    let mut failed = None;
+    for sequence in 0..100 {
+        client.send(&message(sequence)).await.unwrap();
+        if let Err(e) = client.flush().await {
+            failed = Some(e);
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(matches!(failed, Some(NetworkError::Io(_))), "{:?}", failed);
+
+    // the batch is whole, so trying again fails on the socket only
+    client.send(&message(100)).await.unwrap();
+    let err = client.flush().await.unwrap_err();
+    assert!(matches!(err, NetworkError::Io(_)), "{}", err);
+}
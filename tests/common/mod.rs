@@ -0,0 +1,94 @@
+//! fixtures shared by the integration tests. every server binds port 0 or a path of
+//! its own, so the tests run in parallel
+
+// every test file uses a different part of this
+#![allow(dead_code)]
+
+use broker::net::message::PayloadHeader;
+use broker::{BrokerServer, ServerConfig, ServerHandle};
+use serde_json::Value;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// port 0 on the IPv4 loopback
+pub const LOOPBACK: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+/// length of the records `message` builds
+pub const MESSAGE_LEN: usize = 48;
+
+pub async fn start(config: ServerConfig) -> ServerHandle {
+    BrokerServer::with_config(config).start().await.unwrap()
+}
+
+/// a valid record, its body filled with `sequence as u8`
+pub fn message(sequence: u64) -> Vec<u8> {
+    record(1, sequence, sequence as u8, MESSAGE_LEN)
+}
+
+/// `message` at `len` bytes
+pub fn sized_message(sequence: u64, len: usize) -> Vec<u8> {
+    record(1, sequence, sequence as u8, len)
+}
+
+/// `message` published at `timestamp`, for latencies and staleness
+pub fn timed_message(timestamp: u64, sequence: u64) -> Vec<u8> {
+    record(timestamp, sequence, sequence as u8, MESSAGE_LEN)
+}
+
+/// a valid record of `len` bytes whose body is filled with `fill`
+pub fn record(timestamp: u64, sequence: u64, fill: u8, len: usize) -> Vec<u8> {
+    let mut msg = vec![fill; len];
+    let header = PayloadHeader::for_body(timestamp, sequence, &msg[PayloadHeader::LEN..]);
+    msg[..PayloadHeader::LEN].copy_from_slice(&header.to_le_bytes());
+    msg
+}
+
+/// unix time in nanoseconds, the clock of payload timestamps
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos() as u64
+}
+
+/// a path in the temp directory no other test process uses
+pub fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("broker-{}-{}", std::process::id(), name))
+}
+
+/// poll `done` for up to ten seconds
+pub async fn wait_until(mut done: impl FnMut() -> bool) {
+    for _ in 0..1000 {
+        if done() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("timed out");
+}
+
+/// send a raw HTTP `request` to `addr`, returning the status line and the body
+pub async fn http(addr: SocketAddr, request: &str) -> (String, String) {
+    let mut socket = TcpStream::connect(addr).await.unwrap();
+    socket.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    socket.read_to_string(&mut response).await.unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    (head.lines().next().unwrap().to_string(), body.to_string())
+}
+
+/// `method path` against the admin listener: status code and JSON body
+pub async fn admin(handle: &ServerHandle, method: &str, path: &str) -> (u16, Value) {
+    let request = format!("{} {} HTTP/1.1\r\nHost: broker\r\n\r\n", method, path);
+    let (status, body) = http(handle.admin_addr().unwrap(), &request).await;
+    let code = status.split(' ').nth(1).unwrap().parse().unwrap();
+    (code, serde_json::from_str(&body).unwrap())
+}
+
+/// `GET /connections` from the admin listener
+pub async fn connections(handle: &ServerHandle) -> Value {
+    admin(handle, "GET", "/connections").await.1
+}
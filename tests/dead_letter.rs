@@ -0,0 +1,135 @@
+//! records that fail validation end up in the dead letter log, with where they came from
+
+mod common;
+
+use broker::dead_letter::{rotated, DeadLetter, DeadLetterReader, MAX_RECORD_LEN};
+use broker::{BrokerClient, BrokerServer, DeadLetterConfig, ServerConfig};
+use common::{message, temp_path, LOOPBACK};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+fn log_path(name: &str) -> PathBuf {
+    temp_path(&format!("{}.dlq", name))
+}
+
+fn read_log(path: &PathBuf) -> Vec<DeadLetter> {
+    DeadLetterReader::open(path)
+        .unwrap()
+        .collect::<io::Result<_>>()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn rejected_records_are_captured_with_their_publisher() {
+    let path = log_path("capture");
+    let _ = fs::remove_file(&path);
+    let server = BrokerServer::with_config(ServerConfig {
+        dead_letters: Some(DeadLetterConfig::new(&path)),
+        ..ServerConfig::bind(LOOPBACK)
+    });
+    let handle = server.start().await.unwrap();
+
+    let mut good = BrokerClient::connect(handle.local_addr()).await.unwrap();
+    good.send_to("orders", &message(0)).await.unwrap();
+    good.flush().await.unwrap();
+
+    let mut bad = BrokerClient::connect(handle.local_addr()).await.unwrap();
+    let mut corrupt = message(1);
+    corrupt[30] ^= 0xff;
+    bad.send_to("orders", &corrupt).await.unwrap();
+    bad.send_to("orders", &[7; 8]).await.unwrap();
+    bad.flush().await.unwrap();
+
+    let report = handle
+        .shutdown_with_drain(Duration::from_secs(10))
+        .await
+        .unwrap();
+    assert_eq!(report.stats.processing_errors, 2);
+    assert_eq!(report.stats.dead_letters, 2);
+
+    let letters = read_log(&path);
+    assert_eq!(letters.len(), 2);
+    assert_eq!(letters[0].topic, "orders");
+    assert_eq!(letters[0].reason, "checksum mismatch");
+    assert_eq!(letters[0].payload, corrupt);
+    assert_eq!(letters[1].reason, "shorter than the payload header");
+    assert_eq!(letters[1].payload, [7; 8]);
+    for letter in &letters {
+        // the good producer connected first
+        assert_eq!(letter.connection, Some(2));
+        assert!(letter.peer.as_deref().unwrap().starts_with("127.0.0.1:"));
+    }
+    fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn full_log_is_rotated() {
+    let path = log_path("rotate");
+    let _ = fs::remove_file(&path);
+    let _ = fs::remove_file(rotated(&path));
+    let server = BrokerServer::with_config(ServerConfig {
+        dead_letters: Some(DeadLetterConfig {
+            path: path.clone(),
+            max_bytes: 200,
+        }),
+        ..ServerConfig::bind(LOOPBACK)
+    });
+    let handle = server.start().await.unwrap();
+    let mut client = BrokerClient::connect(handle.local_addr()).await.unwrap();
+    for sequence in 0..5 {
+        let mut corrupt = message(sequence);
+        corrupt[40] ^= 0xff;
+        client.send_to("orders", &corrupt).await.unwrap();
+    }
+    client.flush().await.unwrap();
+    let report = handle
+        .shutdown_with_drain(Duration::from_secs(10))
+        .await
+        .unwrap();
+    assert_eq!(report.stats.dead_letters, 5);
+
+    // each record is ~110 bytes, so every log holds one
+    let current = read_log(&path);
+    let previous = read_log(&rotated(&path));
+    assert_eq!(current.len(), 1);
+    assert_eq!(previous.len(), 1);
+    assert_eq!(previous[0].offset + 52, current[0].offset);
+    fs::remove_file(&path).unwrap();
+    fs::remove_file(rotated(&path)).unwrap();
+}
+
+#[test]
+fn torn_record_is_reported() {
+    let letter = DeadLetter {
+        captured_at: 1,
+        topic: "orders".to_string(),
+        offset: 2,
+        connection: None,
+        peer: None,
+        reason: "checksum mismatch".to_string(),
+        payload: vec![1, 2, 3],
+    };
+    let mut log = Vec::new();
+    letter.encode(&mut log);
+    letter.encode(&mut log);
+    log.truncate(log.len() - 1);
+
+    let mut reader = DeadLetterReader::new(log.as_slice());
+    assert_eq!(reader.next().unwrap().unwrap(), letter);
+    let torn = reader.next().unwrap().unwrap_err();
+    assert_eq!(torn.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn corrupt_length_is_reported_without_allocating_it() {
+    for len in [MAX_RECORD_LEN as u32 + 1, u32::MAX] {
+        let mut log = len.to_le_bytes().to_vec();
+        log.extend_from_slice(&[0; 64]);
+        let mut reader = DeadLetterReader::new(log.as_slice());
+        let corrupt = reader.next().unwrap().unwrap_err();
+        assert_eq!(corrupt.kind(), io::ErrorKind::InvalidData);
+        assert!(corrupt.to_string().contains("corrupt"));
+    }
+}
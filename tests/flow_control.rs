@@ -0,0 +1,129 @@
+//! producer flow control: credit granted up front and handed back as the rings drain
+
+mod common;
+
+use broker::handler::BoxFuture;
+use broker::{
+    AsyncMessageHandler, Backpressure, BoxError, BrokerClient, BrokerServer, Message, NetworkError,
+    ServerConfig,
+};
+use common::{message, LOOPBACK, MESSAGE_LEN};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+
+/// handles one message per permit
+struct Gate(Arc<Semaphore>);
+
+impl AsyncMessageHandler for Gate {
+    fn on_message<'a>(&'a self, _msg: &'a Message<'a>) -> BoxFuture<'a, Result<(), BoxError>> {
+        Box::pin(async move {
+            self.0.acquire().await?.forget();
+            Ok(())
+        })
+    }
+}
+
+/// a server with room for `window` messages per producer, whose "gated" topic
+/// only drains as permits are added to the returned semaphore
+fn gated_server(window: usize) -> (BrokerServer, Arc<Semaphore>) {
+    let server = BrokerServer::with_config(ServerConfig {
+        producer_window: (window * MESSAGE_LEN) as u32,
+        ..ServerConfig::bind(LOOPBACK)
+    });
+    let gate = Arc::new(Semaphore::new(0));
+    server.set_async_topic_handler("gated", Gate(gate.clone()));
+    (server, gate)
+}
+
+#[tokio::test]
+async fn fail_mode_reports_would_block() {
+    let (server, gate) = gated_server(4);
+    let handle = server.start().await.unwrap();
+    let mut client = BrokerClient::connect(handle.local_addr()).await.unwrap();
+    let window = client
+        .enable_flow_control(Backpressure::Fail)
+        .await
+        .unwrap();
+    assert_eq!(window as usize, 4 * MESSAGE_LEN);
+
+    for sequence in 0..4 {
+        client.send_to("gated", &message(sequence)).await.unwrap();
+    }
+    assert_eq!(client.credit(), Some(0));
+    let blocked = client.send_to("gated", &message(4)).await;
+    assert!(matches!(blocked, Err(NetworkError::WouldBlock)));
+
+    // credit comes back once the consumer is past the messages
+    gate.add_permits(4);
+    let mut sent = false;
+    for _ in 0..200 {
+        match client.send_to("gated", &message(4)).await {
+            Ok(()) => {
+                sent = true;
+                break;
+            }
+            Err(NetworkError::WouldBlock) => tokio::time::sleep(Duration::from_millis(10)).await,
+            Err(e) => panic!("unexpected error: {e}"),
+        }
+    }
+    assert!(sent, "no credit granted after the ring drained");
+    client.flush().await.unwrap();
+
+    gate.add_permits(1);
+    let report = handle
+        .shutdown_with_drain(Duration::from_secs(10))
+        .await
+        .unwrap();
+    assert!(report.is_drained());
+    assert_eq!(report.stats.messages_processed, 5);
+}
+
+#[tokio::test]
+async fn wait_mode_paces_the_producer() {
+    let (server, gate) = gated_server(4);
+    let handle = server.start().await.unwrap();
+    let mut client = BrokerClient::connect(handle.local_addr()).await.unwrap();
+    client
+        .enable_flow_control(Backpressure::Wait)
+        .await
+        .unwrap();
+
+    let producer = tokio::spawn(async move {
+        for sequence in 0..20 {
+            client.send_to("gated", &message(sequence)).await.unwrap();
+        }
+        client.flush().await.unwrap();
+    });
+
+    // the producer can't get further than its window ahead of the handler
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(!producer.is_finished());
+    assert_eq!(handle.stats().messages_in, 4);
+
+    gate.add_permits(20);
+    tokio::time::timeout(Duration::from_secs(10), producer)
+        .await
+        .unwrap()
+        .unwrap();
+    let report = handle
+        .shutdown_with_drain(Duration::from_secs(10))
+        .await
+        .unwrap();
+    assert_eq!(report.stats.messages_processed, 20);
+}
+
+#[tokio::test]
+async fn oversized_message_never_fits_the_window() {
+    let (server, _gate) = gated_server(1);
+    let handle = server.start().await.unwrap();
+    let mut client = BrokerClient::connect(handle.local_addr()).await.unwrap();
+    client
+        .enable_flow_control(Backpressure::Wait)
+        .await
+        .unwrap();
+
+    let result = client.send_to("gated", &[0; MESSAGE_LEN + 1]).await;
+    assert!(matches!(result, Err(NetworkError::Broker(_))));
+    handle.shutdown().await.unwrap();
+}
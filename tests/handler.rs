@@ -0,0 +1,211 @@
+//! consumer stage hooks: what handlers see and how their errors are routed
+
+mod common;
+
+use broker::handler::BoxFuture;
+use broker::{
+    AsyncMessageHandler, BoxError, BrokerClient, BrokerServer, ConsumeError, ErrorPolicy, Message,
+    MessageHandler, ServerConfig, ShutdownReport,
+};
+use common::{message, LOOPBACK};
+use parking_lot::Mutex;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Default)]
+struct Seen {
+    sequences: Vec<u64>,
+    errors: Vec<(u64, String)>,
+    shutdowns: Vec<String>,
+}
+
+/// records everything, fails on the sequences in `fail_on` until they were tried `failures` times
+#[derive(Clone, Default)]
+struct Recorder {
+    seen: Arc<Mutex<Seen>>,
+    fail_on: Vec<u64>,
+    failures: u32,
+    attempts: Arc<Mutex<Vec<u64>>>,
+}
+
+impl Recorder {
+    fn failing(fail_on: Vec<u64>, failures: u32) -> Self {
+        Self {
+            fail_on,
+            failures,
+            ..Default::default()
+        }
+    }
+
+    fn take(&self, msg: &Message<'_>) -> Result<(), BoxError> {
+        if self.fail_on.contains(&msg.sequence) {
+            let mut attempts = self.attempts.lock();
+            attempts.push(msg.sequence);
+            let tries = attempts.iter().filter(|s| **s == msg.sequence).count();
+            if tries <= self.failures as usize {
+                return Err(format!("refusing {}", msg.sequence).into());
+            }
+        }
+        self.seen.lock().sequences.push(msg.sequence);
+        Ok(())
+    }
+}
+
+impl MessageHandler for Recorder {
+    fn on_message(&self, msg: &Message<'_>) -> Result<(), BoxError> {
+        self.take(msg)
+    }
+
+    fn on_error(&self, _topic: &str, offset: u64, error: &ConsumeError) {
+        self.seen.lock().errors.push((offset, error.to_string()));
+    }
+
+    fn on_shutdown(&self, topic: &str) {
+        self.seen.lock().shutdowns.push(topic.to_string());
+    }
+}
+
+struct AsyncRecorder(Recorder);
+
+impl AsyncMessageHandler for AsyncRecorder {
+    fn on_message<'a>(&'a self, msg: &'a Message<'a>) -> BoxFuture<'a, Result<(), BoxError>> {
+        Box::pin(async move {
+            tokio::task::yield_now().await;
+            self.0.take(msg)
+        })
+    }
+
+    fn on_shutdown<'a>(&'a self, topic: &'a str) -> BoxFuture<'a, ()> {
+        Box::pin(async move { self.0.on_shutdown(topic) })
+    }
+}
+
+fn config(error_policy: ErrorPolicy) -> ServerConfig {
+    ServerConfig {
+        error_policy,
+        ..ServerConfig::bind(LOOPBACK)
+    }
+}
+
+/// publish `count` messages to `topic` and stop the server once they are drained
+async fn publish_and_drain(server: BrokerServer, topic: &str, count: u64) -> ShutdownReport {
+    let handle = server.start().await.unwrap();
+    let mut client = BrokerClient::connect(handle.local_addr()).await.unwrap();
+    for sequence in 0..count {
+        client.send_to(topic, &message(sequence)).await.unwrap();
+    }
+    client.flush().await.unwrap();
+    handle
+        .shutdown_with_drain(Duration::from_secs(10))
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn topic_handler_takes_precedence_over_global() {
+    let server = BrokerServer::with_config(config(ErrorPolicy::Skip));
+    let global = Recorder::default();
+    let orders = Recorder::default();
+    server.set_handler(global.clone());
+    server.set_topic_handler("orders", orders.clone());
+
+    let report = publish_and_drain(server, "orders", 3000).await;
+    assert_eq!(report.stats.messages_processed, 3000);
+
+    let orders = orders.seen.lock();
+    assert_eq!(orders.sequences, (0..3000).collect::<Vec<_>>());
+    assert_eq!(orders.shutdowns, ["orders"]);
+    // the global handler only ever saw the default topic, which stayed empty
+    let global = global.seen.lock();
+    assert!(global.sequences.is_empty());
+    assert_eq!(global.shutdowns, ["default"]);
+}
+
+#[tokio::test]
+async fn async_handler_sees_every_message() {
+    let server = BrokerServer::with_config(config(ErrorPolicy::Skip));
+    let recorder = Recorder::default();
+    server.set_async_topic_handler("events", AsyncRecorder(recorder.clone()));
+
+    let report = publish_and_drain(server, "events", 500).await;
+    assert_eq!(report.stats.messages_processed, 500);
+    let seen = recorder.seen.lock();
+    assert_eq!(seen.sequences, (0..500).collect::<Vec<_>>());
+    assert_eq!(seen.shutdowns, ["events"]);
+}
+
+#[tokio::test]
+async fn skip_reports_and_moves_on() {
+    let server = BrokerServer::with_config(config(ErrorPolicy::Skip));
+    let recorder = Recorder::failing(vec![3, 7], u32::MAX);
+    server.set_topic_handler("jobs", recorder.clone());
+
+    let report = publish_and_drain(server, "jobs", 10).await;
+    assert!(report.is_drained());
+    assert_eq!(report.stats.messages_processed, 8);
+    assert_eq!(report.stats.handler_errors, 2);
+    let seen = recorder.seen.lock();
+    assert_eq!(seen.sequences, [0, 1, 2, 4, 5, 6, 8, 9]);
+    assert_eq!(seen.errors.len(), 2);
+    assert!(seen.errors[0].1.contains("refusing 3"));
+}
+
+#[tokio::test]
+async fn retry_recovers_flaky_handlers() {
+    let policy = ErrorPolicy::Retry {
+        attempts: 2,
+        backoff: Duration::from_millis(1),
+    };
+    let server = BrokerServer::with_config(config(policy));
+    let recorder = Recorder::failing(vec![5], 2);
+    server.set_topic_handler("jobs", recorder.clone());
+
+    let report = publish_and_drain(server, "jobs", 10).await;
+    assert_eq!(report.stats.messages_processed, 10);
+    assert_eq!(report.stats.handler_retries, 2);
+    assert_eq!(report.stats.handler_errors, 0);
+    assert_eq!(recorder.seen.lock().sequences, (0..10).collect::<Vec<_>>());
+}
+
+#[tokio::test]
+async fn stop_keeps_the_failed_message_in_the_ring() {
+    let server = BrokerServer::with_config(config(ErrorPolicy::Stop));
+    let recorder = Recorder::failing(vec![4], u32::MAX);
+    server.set_topic_handler("jobs", recorder.clone());
+
+    let report = publish_and_drain(server, "jobs", 10).await;
+    assert!(!report.is_drained());
+    assert_eq!(report.undrained, 6 * (4 + 48));
+    assert_eq!(report.stats.messages_processed, 4);
+    assert_eq!(report.stats.handler_errors, 1);
+    let seen = recorder.seen.lock();
+    assert_eq!(seen.sequences, [0, 1, 2, 3]);
+    assert_eq!(seen.errors.len(), 1);
+}
+
+#[tokio::test]
+async fn invalid_records_reach_on_error() {
+    let server = BrokerServer::with_config(config(ErrorPolicy::Skip));
+    let recorder = Recorder::default();
+    server.set_topic_handler("raw", recorder.clone());
+
+    let handle = server.start().await.unwrap();
+    let mut client = BrokerClient::connect(handle.local_addr()).await.unwrap();
+    let mut corrupt = message(1);
+    corrupt[30] ^= 0xff;
+    client.send_to("raw", &message(0)).await.unwrap();
+    client.send_to("raw", &corrupt).await.unwrap();
+    client.send_to("raw", &message(2)).await.unwrap();
+    client.flush().await.unwrap();
+    let report = handle
+        .shutdown_with_drain(Duration::from_secs(10))
+        .await
+        .unwrap();
+
+    assert_eq!(report.stats.messages_consumed, 3);
+    assert_eq!(report.stats.processing_errors, 1);
+    let seen = recorder.seen.lock();
+    assert_eq!(seen.sequences, [0, 2]);
+    assert_eq!(seen.errors.len(), 1);
+    assert!(seen.errors[0].1.contains("checksum mismatch"));
+}
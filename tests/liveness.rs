@@ -0,0 +1,128 @@
+//! heartbeats, idle timeouts and keepalive: dead peers don't hold connections forever
+
+mod common;
+
+use broker::topic::StartPosition;
+use broker::{
+    BrokerClient, BrokerSubscriber, ClientOptions, Keepalive, NetworkError, ServerConfig,
+    SubscribeOptions,
+};
+use common::{message, start, LOOPBACK};
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpListener, TcpStream};
+
+fn liveness(heartbeat: Option<u64>, idle_timeout: u64) -> ServerConfig {
+    ServerConfig {
+        heartbeat_interval: heartbeat.map(Duration::from_millis),
+        idle_timeout: Some(Duration::from_millis(idle_timeout)),
+        keepalive: Some(Keepalive::default()),
+        ..ServerConfig::bind(LOOPBACK)
+    }
+}
+
+#[tokio::test]
+async fn idle_connections_are_closed() {
+    let handle = start(liveness(None, 100)).await;
+    let mut silent = TcpStream::connect(handle.local_addr()).await.unwrap();
+
+    let mut buf = [0; 64];
+    let read = tokio::time::timeout(Duration::from_secs(5), silent.read(&mut buf)).await;
+    assert_eq!(read.unwrap().unwrap(), 0);
+    assert_eq!(handle.stats().idle_timeouts, 1);
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn heartbeats_keep_producers_connected() {
+    let handle = start(liveness(Some(50), 300)).await;
+    let options = ClientOptions {
+        keepalive: Some(Keepalive::default()),
+        idle_timeout: Some(Duration::from_secs(5)),
+        ..ClientOptions::default()
+    };
+    let mut client = BrokerClient::connect_with_options(handle.local_addr(), options)
+        .await
+        .unwrap();
+    for _ in 0..10 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        client.heartbeat().await.unwrap();
+    }
+
+    // the broker's own heartbeats are skipped while waiting for the answer
+    let topics = client.list_topics().await.unwrap();
+    assert!(topics.iter().any(|t| t.name == "default"));
+    assert_eq!(handle.stats().idle_timeouts, 0);
+    drop(client);
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn idle_producers_send_heartbeats_on_their_own() {
+    let handle = start(liveness(Some(50), 300)).await;
+    let options = ClientOptions {
+        heartbeat_interval: Some(Duration::from_millis(100)),
+        ..ClientOptions::default()
+    };
+    let mut client = BrokerClient::connect_with_options(handle.local_addr(), options)
+        .await
+        .unwrap();
+
+    // nothing to publish for several idle timeouts
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    client.send(&message(0)).await.unwrap();
+    client.list_topics().await.unwrap();
+    assert_eq!(handle.stats().messages_in, 1);
+    assert_eq!(handle.stats().idle_timeouts, 0);
+    drop(client);
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn subscribers_answer_heartbeats() {
+    let handle = start(liveness(Some(50), 300)).await;
+    let options = SubscribeOptions {
+        idle_timeout: Some(Duration::from_secs(5)),
+        ..SubscribeOptions::default()
+    };
+    let mut subscriber = BrokerSubscriber::connect_with_options(
+        handle.local_addr(),
+        "orders",
+        StartPosition::Earliest,
+        options,
+    )
+    .await
+    .unwrap();
+
+    // nothing to deliver for a while, the subscription survives on heartbeats alone
+    let quiet = tokio::time::timeout(Duration::from_secs(1), subscriber.recv()).await;
+    assert!(quiet.is_err());
+
+    let mut client = BrokerClient::connect(handle.local_addr()).await.unwrap();
+    client.send_to("orders", &message(0)).await.unwrap();
+    client.flush().await.unwrap();
+    let record = subscriber.recv().await.unwrap().unwrap();
+    assert_eq!(record.payload, message(0));
+    assert_eq!(handle.stats().idle_timeouts, 0);
+
+    drop((client, subscriber));
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn clients_give_up_on_a_silent_broker() {
+    let listener = TcpListener::bind(LOOPBACK).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let accepted = tokio::spawn(async move { listener.accept().await.unwrap() });
+
+    let options = ClientOptions {
+        idle_timeout: Some(Duration::from_millis(100)),
+        ..ClientOptions::default()
+    };
+    let mut client = BrokerClient::connect_with_options(addr, options)
+        .await
+        .unwrap();
+    let _socket = accepted.await.unwrap();
+    let err = client.list_topics().await.unwrap_err();
+    assert!(matches!(err, NetworkError::IdleTimeout(_)), "{}", err);
+}
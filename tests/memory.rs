@@ -0,0 +1,86 @@
+//! connection memory budgets: buffers grow on demand, within limits
+
+mod common;
+
+use broker::net::codec::error_code;
+use broker::{BrokerClient, NetworkError, ServerConfig};
+use common::{sized_message, start, wait_until, LOOPBACK};
+
+/// what every connection starts out with
+const INITIAL: usize = 64 * 1024;
+
+fn limits(connection_memory: usize, memory_limit: usize) -> ServerConfig {
+    ServerConfig {
+        connection_memory,
+        memory_limit,
+        ..ServerConfig::bind(LOOPBACK)
+    }
+}
+
+#[tokio::test]
+async fn connections_over_the_limit_are_refused() {
+    let handle = start(limits(1024 * 1024, 2 * INITIAL)).await;
+    let mut first = BrokerClient::connect(handle.local_addr()).await.unwrap();
+    let second = BrokerClient::connect(handle.local_addr()).await.unwrap();
+    first.list_topics().await.unwrap();
+    wait_until(|| handle.stats().open_connections == 2).await;
+    assert_eq!(handle.memory_used(), 2 * INITIAL);
+
+    let mut third = BrokerClient::connect(handle.local_addr()).await.unwrap();
+    match third.list_topics().await {
+        Err(NetworkError::Remote { code, message }) => {
+            assert_eq!(code, error_code::OVERLOADED);
+            assert!(message.contains("memory limit"), "{}", message);
+        }
+        other => panic!("expected a refusal, got {:?}", other),
+    }
+    assert_eq!(handle.stats().connections_refused, 1);
+
+    // room again once someone leaves
+    drop(second);
+    wait_until(|| handle.stats().open_connections == 1).await;
+    let mut fourth = BrokerClient::connect(handle.local_addr()).await.unwrap();
+    fourth.list_topics().await.unwrap();
+
+    drop((first, fourth));
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn frames_over_the_connection_budget_are_rejected() {
+    let handle = start(limits(256 * 1024, 1024 * 1024 * 1024)).await;
+    let mut client = BrokerClient::connect(handle.local_addr()).await.unwrap();
+    client.send(&sized_message(0, 300 * 1024)).await.unwrap();
+    client.flush().await.unwrap();
+    match client.list_topics().await {
+        Err(NetworkError::Remote { code, .. }) => assert_eq!(code, error_code::MESSAGE_TOO_LARGE),
+        other => panic!("expected a rejection, got {:?}", other),
+    }
+    assert_eq!(handle.stats().messages_in, 0);
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn buffers_grow_for_large_frames_and_shrink_after() {
+    let handle = start(limits(4 * 1024 * 1024, 1024 * 1024 * 1024)).await;
+    let mut client = BrokerClient::connect(handle.local_addr()).await.unwrap();
+    client.list_topics().await.unwrap();
+    assert_eq!(handle.memory_used(), INITIAL);
+
+    // two of these make a frame of more than a MiB
+    for sequence in 0..2 {
+        client
+            .send(&sized_message(sequence, 600 * 1024))
+            .await
+            .unwrap();
+    }
+    client.flush().await.unwrap();
+    wait_until(|| handle.stats().messages_in == 2).await;
+    client.list_topics().await.unwrap();
+    assert_eq!(handle.memory_used(), INITIAL);
+
+    drop(client);
+    wait_until(|| handle.stats().open_connections == 0).await;
+    assert_eq!(handle.memory_used(), 0);
+    handle.shutdown().await.unwrap();
+}
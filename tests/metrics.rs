@@ -0,0 +1,98 @@
+//! the admin listener's Prometheus endpoint, scraped with plain HTTP
+
+mod common;
+
+use broker::{BrokerClient, ServerConfig};
+use common::{http, now, start, timed_message, wait_until, LOOPBACK};
+use std::collections::HashMap;
+
+/// samples by name including labels
+fn samples(body: &str) -> HashMap<String, f64> {
+    body.lines()
+        .filter(|line| !line.starts_with('#'))
+        .map(|line| {
+            let (name, value) = line.rsplit_once(' ').unwrap();
+            (name.to_string(), value.parse().unwrap())
+        })
+        .collect()
+}
+
+fn config() -> ServerConfig {
+    ServerConfig {
+        admin: Some(LOOPBACK),
+        ..ServerConfig::bind(LOOPBACK)
+    }
+}
+
+#[tokio::test]
+async fn metrics_cover_the_pipeline() {
+    let handle = start(config()).await;
+    let mut client = BrokerClient::connect(handle.local_addr()).await.unwrap();
+    for sequence in 0..100 {
+        client
+            .send_to("orders", &timed_message(now(), sequence))
+            .await
+            .unwrap();
+    }
+    client.flush().await.unwrap();
+    wait_until(|| handle.stats().messages_processed == 100).await;
+
+    let (status, body) = http(
+        handle.admin_addr().unwrap(),
+        "GET /metrics HTTP/1.1\r\nHost: broker\r\n\r\n",
+    )
+    .await;
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    assert!(body.contains("# TYPE broker_message_latency_seconds histogram"));
+    let samples = samples(&body);
+    assert_eq!(samples["broker_connections_total"], 1.0);
+    assert_eq!(samples["broker_connections_open"], 1.0);
+    assert_eq!(samples["broker_messages_in_total"], 100.0);
+    assert_eq!(samples["broker_bytes_in_total"], 4800.0);
+    assert_eq!(samples["broker_messages_consumed_total"], 100.0);
+    assert_eq!(samples["broker_messages_processed_total"], 100.0);
+    assert_eq!(samples["broker_processing_errors_total"], 0.0);
+    assert_eq!(
+        samples["broker_publish_stalls_total{topic=\"orders\"}"],
+        0.0
+    );
+    assert_eq!(
+        samples["broker_ring_capacity_bytes{topic=\"orders\"}"],
+        handle
+            .topics()
+            .iter()
+            .find(|t| t.name == "orders")
+            .unwrap()
+            .capacity as f64
+    );
+    assert!(samples.contains_key("broker_ring_used_bytes{topic=\"default\"}"));
+    assert_eq!(samples["broker_message_latency_seconds_count"], 100.0);
+    assert_eq!(
+        samples["broker_message_latency_seconds_bucket{le=\"+Inf\"}"],
+        100.0
+    );
+    // a local publish is read well within a second
+    assert_eq!(
+        samples["broker_message_latency_seconds_bucket{le=\"1\"}"],
+        100.0
+    );
+
+    drop(client);
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn unknown_requests_are_rejected() {
+    let handle = start(config()).await;
+    let (status, _) = http(handle.admin_addr().unwrap(), "GET /nope HTTP/1.1\r\n\r\n").await;
+    assert_eq!(status, "HTTP/1.1 404 Not Found");
+    let (status, _) = http(
+        handle.admin_addr().unwrap(),
+        "POST /metrics HTTP/1.1\r\n\r\n",
+    )
+    .await;
+    assert_eq!(status, "HTTP/1.1 405 Method Not Allowed");
+    let (status, _) = http(handle.admin_addr().unwrap(), "hello\r\n\r\n").await;
+    assert_eq!(status, "HTTP/1.1 400 Bad Request");
+    handle.shutdown().await.unwrap();
+}
@@ -0,0 +1,175 @@
+//! client and topic quotas: token buckets that hold publishers back or turn them away
+
+mod common;
+
+use broker::net::codec::error_code;
+use broker::{
+    Backpressure, BrokerClient, BrokerServer, NetworkError, Quota, QuotaAction, ServerConfig,
+    TopicConfig,
+};
+use common::{message, wait_until, LOOPBACK, MESSAGE_LEN};
+use std::time::{Duration, Instant};
+
+fn config() -> ServerConfig {
+    ServerConfig::bind(LOOPBACK)
+}
+
+/// publish `frames` frames of 100 messages each to `topic`
+async fn publish(client: &mut BrokerClient, topic: &str, frames: u64) {
+    for frame in 0..frames {
+        for sequence in frame * 100..(frame + 1) * 100 {
+            client.send_to(topic, &message(sequence)).await.unwrap();
+        }
+        client.flush().await.unwrap();
+    }
+}
+
+fn assert_quota_error(result: Result<Vec<broker::topic::TopicInfo>, NetworkError>) {
+    match result {
+        Err(NetworkError::Remote { code, message }) => {
+            assert_eq!(code, error_code::QUOTA_EXCEEDED);
+            assert!(message.contains("quota"), "{}", message);
+        }
+        other => panic!("expected a quota error, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn client_quotas_delay_publishes() {
+    let server = BrokerServer::with_config(ServerConfig {
+        client_quota: Some(Quota::messages(400)),
+        ..config()
+    });
+    let handle = server.start().await.unwrap();
+    let mut client = BrokerClient::connect(handle.local_addr()).await.unwrap();
+
+    // a second's worth goes through right away, the rest at 400/s
+    let started = Instant::now();
+    publish(&mut client, "orders", 8).await;
+    wait_until(|| handle.stats().messages_in == 800).await;
+    assert!(started.elapsed() >= Duration::from_millis(600));
+    let stats = handle.stats();
+    assert!(stats.quota_delayed > 0);
+    assert_eq!(stats.quota_rejected, 0);
+
+    drop(client);
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn client_quotas_can_reject() {
+    let server = BrokerServer::with_config(ServerConfig {
+        client_quota: Some(Quota::messages(100)),
+        quota_action: QuotaAction::Reject,
+        ..config()
+    });
+    let handle = server.start().await.unwrap();
+    let mut client = BrokerClient::connect(handle.local_addr()).await.unwrap();
+
+    // the first frame empties the bucket, the second goes into debt, the third is over
+    publish(&mut client, "orders", 3).await;
+    assert_quota_error(client.list_topics().await);
+    let stats = handle.stats();
+    assert_eq!(stats.messages_in, 200);
+    assert_eq!(stats.quota_rejected, 1);
+
+    // the connection stays usable
+    client.list_topics().await.unwrap();
+    drop(client);
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn topic_quotas_are_shared_by_producers() {
+    let server = BrokerServer::with_config(ServerConfig {
+        quota_action: QuotaAction::Reject,
+        ..config()
+    });
+    server
+        .declare_topic(
+            "slow",
+            TopicConfig {
+                quota: Some(Quota::bytes(100 * 48)),
+                ..TopicConfig::default()
+            },
+        )
+        .unwrap();
+    let handle = server.start().await.unwrap();
+
+    let mut first = BrokerClient::connect(handle.local_addr()).await.unwrap();
+    publish(&mut first, "slow", 2).await;
+    first.list_topics().await.unwrap();
+    wait_until(|| handle.stats().messages_in == 200).await;
+
+    let mut second = BrokerClient::connect(handle.local_addr()).await.unwrap();
+    publish(&mut second, "slow", 1).await;
+    assert_quota_error(second.list_topics().await);
+    // other topics are not affected
+    publish(&mut second, "fast", 3).await;
+    wait_until(|| handle.stats().messages_in == 500).await;
+
+    let stats = handle.stats();
+    assert_eq!(stats.quota_rejected, 1);
+    drop((first, second));
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn client_quotas_span_connections() {
+    let server = BrokerServer::with_config(ServerConfig {
+        client_quota: Some(Quota::messages(100)),
+        quota_action: QuotaAction::Reject,
+        ..config()
+    });
+    let handle = server.start().await.unwrap();
+
+    let mut first = BrokerClient::connect(handle.local_addr()).await.unwrap();
+    publish(&mut first, "orders", 2).await;
+    first.list_topics().await.unwrap();
+    wait_until(|| handle.stats().messages_in == 200).await;
+
+    // a new connection from the same address doesn't come with a new bucket
+    let mut second = BrokerClient::connect(handle.local_addr()).await.unwrap();
+    publish(&mut second, "orders", 1).await;
+    assert_quota_error(second.list_topics().await);
+    assert_eq!(handle.stats().quota_rejected, 1);
+
+    drop((first, second));
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn rejected_publishes_give_their_credit_back() {
+    let window = 300 * MESSAGE_LEN as u32;
+    let server = BrokerServer::with_config(ServerConfig {
+        client_quota: Some(Quota::messages(100)),
+        quota_action: QuotaAction::Reject,
+        producer_window: window,
+        ..config()
+    });
+    let handle = server.start().await.unwrap();
+    let mut client = BrokerClient::connect(handle.local_addr()).await.unwrap();
+    client
+        .enable_flow_control(Backpressure::Wait)
+        .await
+        .unwrap();
+
+    publish(&mut client, "orders", 3).await;
+    assert_quota_error(client.list_topics().await);
+
+    // the accepted frames come back as the consumer passes them, the rejected
+    // one right away
+    let mut restored = false;
+    for _ in 0..500 {
+        client.list_topics().await.unwrap();
+        if client.credit() == Some(window as u64) {
+            restored = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(restored, "credit stuck at {:?}", client.credit());
+
+    drop(client);
+    handle.shutdown().await.unwrap();
+}
@@ -0,0 +1,129 @@
+//! per producer sequence tracking: gaps, duplicates and late messages
+
+mod common;
+
+use broker::{
+    AuthConfig, BoxError, BrokerClient, BrokerServer, ClientOptions, Credential, Message,
+    MessageHandler, ServerConfig, ShutdownReport,
+};
+use common::{message, temp_path, LOOPBACK};
+use parking_lot::Mutex;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Clone, Default)]
+struct Sequences(Arc<Mutex<Vec<u64>>>);
+
+impl MessageHandler for Sequences {
+    fn on_message(&self, msg: &Message<'_>) -> Result<(), BoxError> {
+        self.0.lock().push(msg.sequence);
+        Ok(())
+    }
+}
+
+/// publish `sequences` from one connection and `0..3` from another, then drain
+async fn publish(reject_duplicates: bool, sequences: &[u64]) -> (ShutdownReport, Vec<u64>) {
+    let server = BrokerServer::with_config(ServerConfig {
+        reject_duplicates,
+        ..ServerConfig::bind(LOOPBACK)
+    });
+    let seen = Sequences::default();
+    server.set_topic_handler("orders", seen.clone());
+    let handle = server.start().await.unwrap();
+
+    let mut client = BrokerClient::connect(handle.local_addr()).await.unwrap();
+    for sequence in sequences {
+        client.send_to("orders", &message(*sequence)).await.unwrap();
+    }
+    client.flush().await.unwrap();
+    // a second session has sequences of its own
+    let mut other = BrokerClient::connect(handle.local_addr()).await.unwrap();
+    for sequence in 0..3 {
+        other.send_to("orders", &message(sequence)).await.unwrap();
+    }
+    other.flush().await.unwrap();
+
+    let report = handle
+        .shutdown_with_drain(Duration::from_secs(10))
+        .await
+        .unwrap();
+    let seen = seen.0.lock().clone();
+    (report, seen)
+}
+
+#[tokio::test]
+async fn gaps_duplicates_and_reordering_are_counted() {
+    let (report, seen) = publish(false, &[0, 1, 2, 5, 3, 3, 6, 1]).await;
+    assert_eq!(report.stats.sequence_gaps, 2);
+    assert_eq!(report.stats.sequence_reordered, 1);
+    assert_eq!(report.stats.sequence_duplicates, 2);
+    assert_eq!(report.stats.messages_in, 11);
+    assert_eq!(seen, [0, 1, 2, 5, 3, 3, 6, 1, 0, 1, 2]);
+}
+
+#[tokio::test]
+async fn duplicates_can_be_rejected() {
+    let (report, seen) = publish(true, &[0, 1, 1, 2, 4, 2, 3, 4]).await;
+    assert_eq!(report.stats.sequence_duplicates, 3);
+    assert_eq!(report.stats.sequence_gaps, 1);
+    assert_eq!(report.stats.sequence_reordered, 1);
+    assert_eq!(report.stats.messages_in, 8);
+    assert_eq!(report.stats.bytes_in, 8 * 48);
+    assert_eq!(seen, [0, 1, 2, 4, 3, 0, 1, 2]);
+}
+
+#[tokio::test]
+async fn forgotten_gaps_are_delivered() {
+    // 65 gaps of one, the oldest is forgotten
+    let mut sequences: Vec<u64> = (0..=130).step_by(2).collect();
+    // forgotten, then remembered and late, then a real duplicate
+    sequences.extend([1, 3, 3]);
+    let (report, seen) = publish(true, &sequences).await;
+    assert_eq!(report.stats.sequence_gaps, 65);
+    assert_eq!(report.stats.sequence_unknown, 1);
+    assert_eq!(report.stats.sequence_reordered, 1);
+    assert_eq!(report.stats.sequence_duplicates, 1);
+    assert_eq!(seen[66..68], [1, 3]);
+    assert_eq!(seen.len(), 66 + 2 + 3);
+}
+
+#[tokio::test]
+async fn principals_keep_their_sequences_across_connections() {
+    let credentials = temp_path("sequence.credentials");
+    std::fs::write(&credentials, "ingest token ingest-token\n").unwrap();
+    let server = BrokerServer::with_config(ServerConfig {
+        reject_duplicates: true,
+        auth: Some(AuthConfig::new(&credentials)),
+        ..ServerConfig::bind(LOOPBACK)
+    });
+    let seen = Sequences::default();
+    server.set_topic_handler("orders", seen.clone());
+    let handle = server.start().await.unwrap();
+
+    let options = ClientOptions {
+        credential: Some(Credential::Token {
+            principal: "ingest".to_string(),
+            token: "ingest-token".to_string(),
+        }),
+        ..ClientOptions::default()
+    };
+    // the producer resends what it isn't sure got through after reconnecting
+    for sequences in [0..3, 2..5] {
+        let mut client = BrokerClient::connect_with_options(handle.local_addr(), options.clone())
+            .await
+            .unwrap();
+        for sequence in sequences {
+            client.send_to("orders", &message(sequence)).await.unwrap();
+        }
+        client.flush().await.unwrap();
+        client.list_topics().await.unwrap();
+    }
+
+    let report = handle
+        .shutdown_with_drain(Duration::from_secs(10))
+        .await
+        .unwrap();
+    assert_eq!(report.stats.sequence_duplicates, 1);
+    assert_eq!(report.stats.sequence_gaps, 0);
+    assert_eq!(*seen.0.lock(), [0, 1, 2, 3, 4]);
+}
@@ -0,0 +1,391 @@
+//! end to end over real sockets. every server binds port 0 or its own socket file,
+//! so these run in parallel
+
+mod common;
+
+use broker::handler::BoxFuture;
+use broker::net::codec::error_code;
+use broker::net::message::PayloadHeader;
+use broker::{
+    AsyncMessageHandler, BoxError, BrokerClient, BrokerCodec, BrokerServer, BrokerSubscriber,
+    ConnectionMode, Frame, Message, MessageHandler, ServerConfig, ServerHandle, StartPosition,
+    UnixListenConfig,
+};
+use common::{message, record, start, temp_path, wait_until, LOOPBACK, MESSAGE_LEN};
+use futures_util::{SinkExt, StreamExt};
+use parking_lot::Mutex;
+use std::net::{Ipv6Addr, SocketAddr};
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::{Barrier, Semaphore};
+use tokio_util::codec::{Framed, FramedRead};
+
+#[tokio::test]
+async fn ephemeral_port_is_reported() {
+    let handle = start(ServerConfig::bind(LOOPBACK)).await;
+    let addr = handle.local_addr();
+    assert!(addr.ip().is_loopback());
+    assert_ne!(addr.port(), 0);
+
+    let mut client = BrokerClient::connect(addr).await.unwrap();
+    let topics = client.list_topics().await.unwrap();
+    assert!(topics.iter().any(|t| t.name == "default"));
+
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn reset_peers_do_not_stop_accepting() {
+    let handle = start(ServerConfig::bind(LOOPBACK)).await;
+    let addr = handle.local_addr();
+
+    // connect and reset right away, often before the broker got to accept
+    for _ in 0..64 {
+        let socket = std::net::TcpStream::connect(addr).unwrap();
+        socket2::SockRef::from(&socket)
+            .set_linger(Some(Duration::ZERO))
+            .unwrap();
+    }
+
+    let mut client = BrokerClient::connect(addr).await.unwrap();
+    client.send(&message(0)).await.unwrap();
+    client.flush().await.unwrap();
+    let topics = client.list_topics().await.unwrap();
+    assert!(topics.iter().any(|t| t.name == "default"));
+
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn other_protocol_versions_are_turned_away() {
+    let handle = start(ServerConfig::bind(LOOPBACK)).await;
+    let mut socket = TcpStream::connect(handle.local_addr()).await.unwrap();
+    // a heartbeat from before the header carried a version
+    socket
+        .write_all(&[0x08, 0, 0, 0, 0, 0, 0, 0])
+        .await
+        .unwrap();
+
+    let mut frames = FramedRead::new(socket, BrokerCodec::new());
+    match frames.next().await {
+        Some(Ok(Frame::Error { code, .. })) => assert_eq!(code, error_code::UNSUPPORTED_VERSION),
+        other => panic!("expected an error frame, got {:?}", other),
+    }
+    assert!(frames.next().await.is_none());
+
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn bind_failure_fails_start() {
+    let first = start(ServerConfig::bind(LOOPBACK)).await;
+    let taken = first.local_addr();
+
+    let second = BrokerServer::with_config(ServerConfig {
+        listen: vec![LOOPBACK, taken],
+        ..Default::default()
+    })
+    .start()
+    .await;
+    assert!(second.is_err());
+
+    first.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn every_listener_serves_the_same_topics() {
+    let ipv6 = SocketAddr::from((Ipv6Addr::LOCALHOST, 0));
+    if std::net::TcpListener::bind(ipv6).is_err() {
+        eprintln!("no IPv6 loopback, skipping");
+        return;
+    }
+
+    let handle = start(ServerConfig {
+        listen: vec![LOOPBACK, ipv6],
+        ..Default::default()
+    })
+    .await;
+    let addrs = handle.local_addrs().to_vec();
+    assert_eq!(addrs.len(), 2);
+    assert!(addrs[0].is_ipv4());
+    assert!(addrs[1].is_ipv6());
+
+    let mut subscriber = BrokerSubscriber::connect(addrs[1], "shared", StartPosition::Earliest)
+        .await
+        .unwrap();
+    for (i, addr) in addrs.iter().enumerate() {
+        let mut client = BrokerClient::connect(addr).await.unwrap();
+        client.send_to("shared", &message(i as u64)).await.unwrap();
+        client.flush().await.unwrap();
+    }
+
+    let mut received = Vec::new();
+    while received.len() < addrs.len() {
+        let record = tokio::time::timeout(Duration::from_secs(5), subscriber.recv())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        received.push(record.payload[PayloadHeader::LEN]);
+    }
+    received.sort();
+    assert_eq!(received, [0, 1]);
+
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn drain_consumes_everything_published() {
+    let handle = start(ServerConfig::bind(LOOPBACK)).await;
+
+    let mut client = BrokerClient::connect(handle.local_addr()).await.unwrap();
+    for sequence in 0..10_000 {
+        client.send(&message(sequence)).await.unwrap();
+    }
+    client.flush().await.unwrap();
+
+    let report = handle
+        .shutdown_with_drain(Duration::from_secs(10))
+        .await
+        .unwrap();
+    assert!(report.is_drained());
+    assert_eq!(report.stats.connections, 1);
+    assert_eq!(report.stats.messages_in, 10_000);
+    assert_eq!(report.stats.messages_consumed, 10_000);
+    assert_eq!(report.stats.messages_processed, 10_000);
+    assert_eq!(report.stats.processing_errors, 0);
+}
+
+/// producer (the first body byte) and sequence of every message handled
+#[derive(Clone, Default)]
+struct Handled(Arc<Mutex<Vec<(u8, u64)>>>);
+
+impl MessageHandler for Handled {
+    fn on_message(&self, msg: &Message<'_>) -> Result<(), BoxError> {
+        self.0.lock().push((msg.body[0], msg.sequence));
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn concurrent_producers_keep_their_order() {
+    const PRODUCERS: u8 = 4;
+    const MESSAGES: u64 = 2_000;
+    let server = BrokerServer::with_config(ServerConfig::bind(LOOPBACK));
+    let handled = Handled::default();
+    server.set_handler(handled.clone());
+    let handle = server.start().await.unwrap();
+
+    // all connected before any of them publishes
+    let connected = Arc::new(Barrier::new(PRODUCERS as usize));
+    let mut producers = Vec::new();
+    for producer in 0..PRODUCERS {
+        let (addr, connected) = (handle.local_addr(), connected.clone());
+        producers.push(tokio::spawn(async move {
+            let mut client = BrokerClient::connect(addr).await.unwrap();
+            connected.wait().await;
+            for sequence in 0..MESSAGES {
+                let msg = record(1, sequence, producer, MESSAGE_LEN);
+                client.send(&msg).await.unwrap();
+            }
+            client.flush().await.unwrap();
+            client
+        }));
+    }
+    let mut clients = Vec::new();
+    for producer in producers {
+        clients.push(producer.await.unwrap());
+    }
+
+    let report = handle
+        .shutdown_with_drain(Duration::from_secs(10))
+        .await
+        .unwrap();
+    assert!(report.is_drained());
+    assert_eq!(report.stats.connections, PRODUCERS as u64);
+    assert_eq!(report.stats.messages_processed, PRODUCERS as u64 * MESSAGES);
+    let handled = handled.0.lock();
+    for producer in 0..PRODUCERS {
+        let sequences: Vec<_> = handled
+            .iter()
+            .filter(|(p, _)| *p == producer)
+            .map(|(_, sequence)| *sequence)
+            .collect();
+        assert!(
+            sequences.iter().copied().eq(0..MESSAGES),
+            "producer {}",
+            producer
+        );
+    }
+    drop(clients);
+}
+
+#[tokio::test]
+async fn replacing_connections_close_the_previous_one() {
+    let handle = start(ServerConfig {
+        connection_mode: ConnectionMode::Replace,
+        ..ServerConfig::bind(LOOPBACK)
+    })
+    .await;
+
+    let socket = TcpStream::connect(handle.local_addr()).await.unwrap();
+    let mut first = Framed::new(socket, BrokerCodec::new());
+    // answered, so it was accepted before the second one
+    first.send(Frame::ListTopics).await.unwrap();
+    let topics = first.next().await.unwrap().unwrap();
+    assert!(matches!(topics, Frame::Topics(_)));
+
+    let mut second = BrokerClient::connect(handle.local_addr()).await.unwrap();
+    let closed = tokio::time::timeout(Duration::from_secs(5), first.next())
+        .await
+        .expect("the first connection was left open");
+    assert!(closed.is_none());
+
+    second.send(&message(0)).await.unwrap();
+    second.flush().await.unwrap();
+    wait_until(|| handle.stats().messages_in == 1).await;
+    assert_eq!(handle.stats().connections, 2);
+
+    drop(second);
+    handle.shutdown().await.unwrap();
+}
+
+/// handles one message per permit
+struct Gate(Arc<Semaphore>);
+
+impl AsyncMessageHandler for Gate {
+    fn on_message<'a>(&'a self, _msg: &'a Message<'a>) -> BoxFuture<'a, Result<(), BoxError>> {
+        Box::pin(async move {
+            self.0.acquire().await?.forget();
+            Ok(())
+        })
+    }
+}
+
+/// a started server whose consumer only gets on as permits are added, with
+/// `count` messages published and read off the connection
+async fn gated(count: u64) -> (ServerHandle, Arc<Semaphore>, BrokerClient) {
+    let server = BrokerServer::with_config(ServerConfig::bind(LOOPBACK));
+    let gate = Arc::new(Semaphore::new(0));
+    server.set_async_handler(Gate(gate.clone()));
+    let handle = server.start().await.unwrap();
+    let mut client = BrokerClient::connect(handle.local_addr()).await.unwrap();
+    for sequence in 0..count {
+        client.send(&message(sequence)).await.unwrap();
+    }
+    client.flush().await.unwrap();
+    wait_until(|| handle.stats().messages_in == count).await;
+    (handle, gate, client)
+}
+
+#[tokio::test]
+async fn drain_waits_for_the_consumer_and_turns_new_clients_away() {
+    let (handle, gate, client) = gated(100).await;
+    let addr = handle.local_addr();
+    let drain = tokio::spawn(handle.shutdown_with_drain(Duration::from_secs(10)));
+
+    // the listener is gone while the ring is still being worked through
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert!(TcpStream::connect(addr).await.is_err());
+    assert!(!drain.is_finished());
+
+    gate.add_permits(100);
+    let report = drain.await.unwrap().unwrap();
+    assert!(report.is_drained());
+    assert_eq!(report.stats.messages_processed, 100);
+    assert_eq!(report.stats.connections, 1);
+    drop(client);
+}
+
+#[tokio::test]
+async fn drain_timeout_reports_what_was_left() {
+    let (handle, _gate, client) = gated(100).await;
+    let report = handle
+        .shutdown_with_drain(Duration::from_millis(200))
+        .await
+        .unwrap();
+    assert!(report.timed_out);
+    assert!(report.undrained >= 99 * (4 + MESSAGE_LEN as u64));
+    assert_eq!(report.stats.messages_processed, 0);
+    drop(client);
+}
+
+fn socket_path(name: &str) -> PathBuf {
+    temp_path(&format!("{}.sock", name))
+}
+
+#[tokio::test]
+async fn unix_socket_serves_publishers() {
+    let path = socket_path("publish");
+    // left behind by a server that didn't stop cleanly
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+
+    let mut unix = UnixListenConfig::new(&path);
+    unix.mode = 0o600;
+    let handle = start(ServerConfig {
+        listen: vec![LOOPBACK],
+        unix_listen: vec![unix],
+        ..Default::default()
+    })
+    .await;
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    // the private directory it was bound in is gone again
+    let name = path.file_name().unwrap().to_str().unwrap();
+    let leftovers = std::fs::read_dir(path.parent().unwrap())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .filter(|entry| entry.to_str().unwrap().starts_with(&format!("{}.", name)))
+        .count();
+    assert_eq!(leftovers, 0);
+
+    let mut subscriber =
+        BrokerSubscriber::connect(handle.local_addr(), "local", StartPosition::Earliest)
+            .await
+            .unwrap();
+    let mut client = BrokerClient::connect_unix(&path).await.unwrap();
+    for sequence in 0..100 {
+        client.send_to("local", &message(sequence)).await.unwrap();
+    }
+    client.flush().await.unwrap();
+
+    for sequence in 0..100u64 {
+        let record = tokio::time::timeout(Duration::from_secs(5), subscriber.recv())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(record.payload[PayloadHeader::LEN], sequence as u8);
+    }
+
+    handle.shutdown().await.unwrap();
+    assert!(!path.exists());
+}
+
+#[tokio::test]
+async fn unix_socket_in_use_fails_start() {
+    let path = socket_path("in-use");
+    let first = start(ServerConfig {
+        listen: Vec::new(),
+        unix_listen: vec![UnixListenConfig::new(&path)],
+        ..Default::default()
+    })
+    .await;
+
+    let second = BrokerServer::with_config(ServerConfig {
+        listen: Vec::new(),
+        unix_listen: vec![UnixListenConfig::new(&path)],
+        ..Default::default()
+    })
+    .start()
+    .await;
+    assert!(second.is_err());
+    assert!(path.exists());
+
+    first.shutdown().await.unwrap();
+    assert!(!path.exists());
+}
@@ -0,0 +1,199 @@
+//! SO_REUSEPORT listener shards: connections spread over shard threads, and every
+//! topic still reads in publish order
+
+mod common;
+
+use broker::handler::BoxFuture;
+use broker::net::message::PayloadHeader;
+use broker::{
+    AsyncMessageHandler, Backpressure, BoxError, BrokerClient, BrokerServer, BrokerSubscriber,
+    Message, ServerConfig, ShardConfig, StartPosition, TopicConfig,
+};
+use common::{record, start, wait_until, LOOPBACK, MESSAGE_LEN};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+
+const PRODUCERS: u8 = 4;
+
+fn sharded(count: usize) -> ServerConfig {
+    ServerConfig {
+        shards: Some(ShardConfig {
+            count,
+            pin_cores: false,
+            ring_capacity: 64 * 1024,
+        }),
+        ..ServerConfig::bind(LOOPBACK)
+    }
+}
+
+async fn publish(addr: SocketAddr, topic: &str, producer: u8, count: u64) {
+    let mut client = BrokerClient::connect(addr).await.unwrap();
+    for sequence in 0..count {
+        client
+            .send_to(topic, &record(1, sequence, producer, MESSAGE_LEN))
+            .await
+            .unwrap();
+    }
+    client.flush().await.unwrap();
+}
+
+#[tokio::test]
+async fn shards_share_one_port() {
+    let handle = start(sharded(3)).await;
+    assert_eq!(handle.local_addrs().len(), 1);
+    assert_ne!(handle.local_addr().port(), 0);
+
+    let mut client = BrokerClient::connect(handle.local_addr()).await.unwrap();
+    let topics = client.list_topics().await.unwrap();
+    assert!(topics.iter().any(|t| t.name == "default"));
+
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn zero_shards_fail_start() {
+    let started = BrokerServer::with_config(sharded(0)).start().await;
+    assert!(started.is_err());
+}
+
+#[tokio::test]
+async fn every_producer_arrives_in_order() {
+    let handle = start(sharded(2)).await;
+    let addr = handle.local_addr();
+    let mut subscriber = BrokerSubscriber::connect(addr, "merged", StartPosition::Earliest)
+        .await
+        .unwrap();
+
+    let producers: Vec<_> = (0..PRODUCERS)
+        .map(|producer| tokio::spawn(publish(addr, "merged", producer, 2_000)))
+        .collect();
+    for producer in producers {
+        producer.await.unwrap();
+    }
+
+    let mut next = [0u64; PRODUCERS as usize];
+    let mut offset = None;
+    for _ in 0..PRODUCERS as u64 * 2_000 {
+        let record = tokio::time::timeout(Duration::from_secs(5), subscriber.recv())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert!(offset < Some(record.offset));
+        offset = Some(record.offset);
+
+        let (header, body) = PayloadHeader::verify(&record.payload).unwrap();
+        let producer = body[0] as usize;
+        assert_eq!(header.sequence, next[producer]);
+        next[producer] += 1;
+    }
+    assert_eq!(next, [2_000; PRODUCERS as usize]);
+
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn drain_merges_everything_published() {
+    let handle = start(sharded(2)).await;
+    let addr = handle.local_addr();
+
+    let producers: Vec<_> = (0..PRODUCERS)
+        .map(|producer| tokio::spawn(publish(addr, "default", producer, 5_000)))
+        .collect();
+    for producer in producers {
+        producer.await.unwrap();
+    }
+
+    let report = handle
+        .shutdown_with_drain(Duration::from_secs(10))
+        .await
+        .unwrap();
+    assert!(report.is_drained());
+    assert_eq!(report.stats.connections, PRODUCERS as u64);
+    assert_eq!(report.stats.messages_in, PRODUCERS as u64 * 5_000);
+    assert_eq!(report.stats.messages_processed, PRODUCERS as u64 * 5_000);
+    assert_eq!(report.stats.processing_errors, 0);
+}
+
+/// handles one message per permit
+struct Gate(Arc<Semaphore>);
+
+impl AsyncMessageHandler for Gate {
+    fn on_message<'a>(&'a self, _msg: &'a Message<'a>) -> BoxFuture<'a, Result<(), BoxError>> {
+        Box::pin(async move {
+            self.0.acquire().await?.forget();
+            Ok(())
+        })
+    }
+}
+
+#[tokio::test]
+async fn credit_comes_back_after_the_merge_and_the_consumer() {
+    let server = BrokerServer::with_config(ServerConfig {
+        producer_window: (4 * MESSAGE_LEN) as u32,
+        ..sharded(2)
+    });
+    server
+        .declare_topic(
+            "gated",
+            TopicConfig {
+                capacity: 16 * 1024,
+                ..TopicConfig::default()
+            },
+        )
+        .unwrap();
+    let gate = Arc::new(Semaphore::new(0));
+    server.set_async_topic_handler("gated", Gate(gate.clone()));
+    let handle = server.start().await.unwrap();
+
+    // more than the topic's ring holds, the rest waits in a shard's ring
+    publish(handle.local_addr(), "gated", 0, 400).await;
+    wait_until(|| handle.stats().messages_in == 400).await;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let mut client = BrokerClient::connect(handle.local_addr()).await.unwrap();
+    let topics = client.list_topics().await.unwrap();
+    let gated = topics.iter().find(|t| t.name == "gated").unwrap();
+    // every record carries a 4 byte length in the ring
+    let merged = gated.used / (MESSAGE_LEN + 4) as u64;
+    assert!(merged < 400);
+
+    client
+        .enable_flow_control(Backpressure::Fail)
+        .await
+        .unwrap();
+    for sequence in 0..4 {
+        client
+            .send_to("gated", &record(1, sequence, 1, MESSAGE_LEN))
+            .await
+            .unwrap();
+    }
+    client.flush().await.unwrap();
+    assert_eq!(client.credit(), Some(0));
+
+    // the consumer is past everything the topic's ring held when the batch came in,
+    // but not past the batch
+    gate.add_permits(merged as usize);
+    wait_until(|| handle.stats().messages_processed == merged).await;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    client.heartbeat().await.unwrap();
+    assert_eq!(client.credit(), Some(0));
+
+    gate.add_permits(404 - merged as usize);
+    for _ in 0..500 {
+        client.heartbeat().await.unwrap();
+        if client.credit() == Some(4 * MESSAGE_LEN as u64) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(client.credit(), Some(4 * MESSAGE_LEN as u64));
+
+    drop(client);
+    let report = handle
+        .shutdown_with_drain(Duration::from_secs(10))
+        .await
+        .unwrap();
+    assert_eq!(report.stats.messages_processed, 404);
+}
@@ -0,0 +1,142 @@
+//! per topic TTLs: what happens to messages the consumer gets to too late
+
+mod common;
+
+use broker::dead_letter::DeadLetterReader;
+use broker::net::message::{PayloadHeader, ProcessedMessage};
+use broker::{
+    BoxError, BrokerClient, BrokerServer, DeadLetterConfig, ExpiredAction, Message, MessageHandler,
+    ServerConfig, ShutdownReport, Staleness, TopicConfig,
+};
+use common::{now, temp_path, timed_message, LOOPBACK};
+use parking_lot::Mutex;
+use std::fs;
+use std::sync::Arc;
+use std::time::Duration;
+
+const SECOND: u64 = 1_000_000_000;
+
+/// sequence and expired flag of everything handled
+#[derive(Clone, Default)]
+struct Seen(Arc<Mutex<Vec<(u64, bool)>>>);
+
+impl MessageHandler for Seen {
+    fn on_message(&self, msg: &Message<'_>) -> Result<(), BoxError> {
+        self.0.lock().push((msg.sequence, msg.expired));
+        Ok(())
+    }
+}
+
+/// publish a current, an hour old and an hour early message to a topic under `staleness`
+async fn publish(staleness: Staleness, config: ServerConfig) -> (ShutdownReport, Vec<(u64, bool)>) {
+    let server = BrokerServer::with_config(config);
+    server
+        .declare_topic(
+            "quotes",
+            TopicConfig {
+                staleness: Some(staleness),
+                ..TopicConfig::default()
+            },
+        )
+        .unwrap();
+    let seen = Seen::default();
+    server.set_topic_handler("quotes", seen.clone());
+    let handle = server.start().await.unwrap();
+
+    let mut client = BrokerClient::connect(handle.local_addr()).await.unwrap();
+    client
+        .send_to("quotes", &timed_message(now(), 0))
+        .await
+        .unwrap();
+    client
+        .send_to("quotes", &timed_message(now() - 3600 * SECOND, 1))
+        .await
+        .unwrap();
+    client
+        .send_to("quotes", &timed_message(now() + 3600 * SECOND, 2))
+        .await
+        .unwrap();
+    client.flush().await.unwrap();
+
+    let report = handle
+        .shutdown_with_drain(Duration::from_secs(10))
+        .await
+        .unwrap();
+    let seen = seen.0.lock().clone();
+    (report, seen)
+}
+
+fn local() -> ServerConfig {
+    ServerConfig::bind(LOOPBACK)
+}
+
+#[test]
+fn clock_skew_is_tolerated() {
+    let staleness = Staleness::new(Duration::from_secs(1));
+    let now = 100 * SECOND;
+    assert!(!staleness.is_expired(now - SECOND / 2, now));
+    assert!(staleness.is_expired(now - 2 * SECOND, now));
+    // a producer clock slightly ahead is fine, one far ahead is not trusted
+    assert!(!staleness.is_expired(now + SECOND / 20, now));
+    assert!(staleness.is_expired(now + SECOND, now));
+}
+
+#[test]
+fn future_timestamps_do_not_panic() {
+    let body = b"payload";
+    let header = PayloadHeader::for_body(u64::MAX, 1, body);
+    let mut msg = header.to_le_bytes().to_vec();
+    msg.extend_from_slice(body);
+
+    let parsed = ProcessedMessage::from_bytes(&msg).unwrap();
+    assert!(parsed.process(None));
+    assert!(!parsed.process(Some(Staleness::new(Duration::from_secs(1)))));
+}
+
+#[tokio::test]
+async fn expired_messages_are_dropped() {
+    let staleness = Staleness::new(Duration::from_secs(60));
+    let (report, seen) = publish(staleness, local()).await;
+    assert_eq!(report.stats.messages_expired, 2);
+    assert_eq!(report.stats.messages_consumed, 3);
+    assert_eq!(report.stats.messages_processed, 1);
+    assert_eq!(seen, [(0, false)]);
+}
+
+#[tokio::test]
+async fn expired_messages_can_be_delivered_flagged() {
+    let staleness = Staleness {
+        action: ExpiredAction::Deliver,
+        ..Staleness::new(Duration::from_secs(60))
+    };
+    let (report, seen) = publish(staleness, local()).await;
+    assert_eq!(report.stats.messages_expired, 2);
+    assert_eq!(report.stats.messages_processed, 3);
+    assert_eq!(seen, [(0, false), (1, true), (2, true)]);
+}
+
+#[tokio::test]
+async fn expired_messages_can_be_dead_lettered() {
+    let path = temp_path("expired.dlq");
+    let _ = fs::remove_file(&path);
+    let staleness = Staleness {
+        action: ExpiredAction::DeadLetter,
+        ..Staleness::new(Duration::from_secs(60))
+    };
+    let config = ServerConfig {
+        dead_letters: Some(DeadLetterConfig::new(&path)),
+        ..local()
+    };
+    let (report, seen) = publish(staleness, config).await;
+    assert_eq!(report.stats.messages_expired, 2);
+    assert_eq!(report.stats.dead_letters, 2);
+    assert_eq!(seen, [(0, false)]);
+
+    let letters = DeadLetterReader::open(&path)
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(letters.len(), 2);
+    assert!(letters.iter().all(|l| l.reason == "expired"));
+    fs::remove_file(&path).unwrap();
+}
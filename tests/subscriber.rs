@@ -0,0 +1,338 @@
+//! subscriptions over the wire: streaming, start offsets, acks, redelivery and the
+//! in-flight window
+
+mod common;
+
+use broker::net::codec::error_code;
+use broker::topic::{StartPosition, TopicRef};
+use broker::{
+    BrokerClient, BrokerCodec, BrokerServer, BrokerSubscriber, Frame, NetworkError, Record,
+    ServerConfig, ServerHandle, SubscribeOptions, TopicConfig,
+};
+use common::{admin, message, wait_until, LOOPBACK};
+use futures_util::{SinkExt, StreamExt};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_util::codec::Framed;
+
+async fn server() -> ServerHandle {
+    let server = BrokerServer::with_config(ServerConfig::bind(LOOPBACK));
+    server
+        .declare_topic(
+            "orders",
+            TopicConfig {
+                capacity: 64 * 1024,
+                ..TopicConfig::default()
+            },
+        )
+        .unwrap();
+    server.start().await.unwrap()
+}
+
+async fn publish(handle: &ServerHandle, sequences: std::ops::Range<u64>) {
+    let mut client = BrokerClient::connect(handle.local_addr()).await.unwrap();
+    for sequence in sequences {
+        client.send_to("orders", &message(sequence)).await.unwrap();
+    }
+    client.flush().await.unwrap();
+    client.list_topics().await.unwrap();
+}
+
+async fn subscribe(
+    handle: &ServerHandle,
+    ack_timeout: Duration,
+    max_in_flight: u32,
+) -> BrokerSubscriber {
+    let options = SubscribeOptions {
+        ack_timeout: Some(ack_timeout),
+        max_in_flight,
+        ..SubscribeOptions::default()
+    };
+    BrokerSubscriber::connect_with_options(
+        handle.local_addr(),
+        "orders",
+        StartPosition::Earliest,
+        options,
+    )
+    .await
+    .unwrap()
+}
+
+async fn recv(subscriber: &mut BrokerSubscriber) -> Record {
+    tokio::time::timeout(Duration::from_secs(5), subscriber.recv())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap()
+}
+
+/// nothing arrives for a while
+async fn quiet(subscriber: &mut BrokerSubscriber) -> bool {
+    let received: Result<Result<Option<Record>, NetworkError>, _> =
+        tokio::time::timeout(Duration::from_millis(300), subscriber.recv()).await;
+    received.is_err()
+}
+
+fn used(handle: &ServerHandle) -> u64 {
+    let topics = handle.topics();
+    topics.iter().find(|t| t.name == "orders").unwrap().used
+}
+
+async fn subscribe_at(handle: &ServerHandle, start: StartPosition) -> BrokerSubscriber {
+    BrokerSubscriber::connect(handle.local_addr(), "orders", start)
+        .await
+        .unwrap()
+}
+
+fn code(subscribed: Result<BrokerSubscriber, NetworkError>) -> u16 {
+    match subscribed {
+        Err(NetworkError::Remote { code, .. }) => code,
+        Err(e) => panic!("expected an error frame, got {}", e),
+        Ok(_) => panic!("expected an error frame"),
+    }
+}
+
+#[tokio::test]
+async fn records_stream_in_order_and_resume_from_an_offset() {
+    let handle = server().await;
+    // unacked, so the ring keeps every record for the resumed subscriber
+    let mut subscriber = subscribe(&handle, Duration::from_secs(30), 256).await;
+    publish(&handle, 0..100).await;
+
+    let mut offsets = Vec::new();
+    for sequence in 0..100 {
+        let record = recv(&mut subscriber).await;
+        assert_eq!(&record.payload[..], &message(sequence)[..]);
+        offsets.push(record.offset);
+    }
+    assert!(offsets.windows(2).all(|pair| pair[0] < pair[1]));
+
+    // any offset handed out is a place to start again
+    let mut resumed = subscribe_at(&handle, StartPosition::Offset(offsets[60])).await;
+    assert_eq!(resumed.start_offset(), offsets[60]);
+    for sequence in 60..100 {
+        assert_eq!(
+            &recv(&mut resumed).await.payload[..],
+            &message(sequence)[..]
+        );
+    }
+    publish(&handle, 100..101).await;
+    assert_eq!(&recv(&mut resumed).await.payload[..], &message(100)[..]);
+
+    drop((subscriber, resumed));
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn start_offsets_must_be_record_boundaries() {
+    let handle = server().await;
+    let mut subscriber = subscribe(&handle, Duration::from_secs(30), 64).await;
+    publish(&handle, 0..3).await;
+    recv(&mut subscriber).await;
+    let second = recv(&mut subscriber).await.offset;
+
+    let addr = handle.local_addr();
+    let inside = BrokerSubscriber::connect(addr, "orders", StartPosition::Offset(second + 1));
+    assert_eq!(code(inside.await), error_code::INVALID_OFFSET);
+    let beyond = BrokerSubscriber::connect(addr, "orders", StartPosition::Offset(1 << 40));
+    assert_eq!(code(beyond.await), error_code::OFFSET_OUT_OF_RANGE);
+    let mut at = subscribe_at(&handle, StartPosition::Offset(second)).await;
+    assert_eq!(&recv(&mut at).await.payload[..], &message(1)[..]);
+
+    // the head is where the next record goes
+    let head = subscribe_at(&handle, StartPosition::Latest)
+        .await
+        .start_offset();
+    let mut next = subscribe_at(&handle, StartPosition::Offset(head)).await;
+    publish(&handle, 3..4).await;
+    assert_eq!(&recv(&mut next).await.payload[..], &message(3)[..]);
+
+    drop((subscriber, at, next));
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn acks_advance_retention() {
+    let handle = server().await;
+    let mut subscriber = subscribe(&handle, Duration::from_secs(30), 64).await;
+    publish(&handle, 0..10).await;
+
+    let mut offsets = Vec::new();
+    for sequence in 0..10 {
+        let record = recv(&mut subscriber).await;
+        assert_eq!(&record.payload[..], &message(sequence)[..]);
+        offsets.push(record.offset);
+    }
+    // delivered is not done with: the ring keeps every record
+    assert!(quiet(&mut subscriber).await);
+    assert!(used(&handle) > 0);
+
+    // out of order acks hold retention at the lowest unacked record
+    subscriber.ack(offsets[5]).await.unwrap();
+    subscriber.flush_acks().await.unwrap();
+    let held = used(&handle);
+    assert!(held > 0);
+
+    subscriber.ack_through(offsets[4]).await.unwrap();
+    wait_until(|| used(&handle) < held).await;
+    subscriber.ack_through(offsets[9]).await.unwrap();
+    wait_until(|| used(&handle) == 0).await;
+
+    drop(subscriber);
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn unacked_records_are_redelivered() {
+    let handle = server().await;
+    let mut subscriber = subscribe(&handle, Duration::from_millis(200), 64).await;
+    publish(&handle, 0..3).await;
+
+    let first: Vec<_> = [
+        recv(&mut subscriber).await,
+        recv(&mut subscriber).await,
+        recv(&mut subscriber).await,
+    ]
+    .into();
+    subscriber.ack(first[0].offset).await.unwrap();
+
+    // the two unacked ones come again once the timeout passed, as they were
+    let again = [recv(&mut subscriber).await, recv(&mut subscriber).await];
+    assert_eq!(again[0], first[1]);
+    assert_eq!(again[1], first[2]);
+
+    subscriber.ack_through(first[2].offset).await.unwrap();
+    assert!(quiet(&mut subscriber).await);
+    wait_until(|| used(&handle) == 0).await;
+
+    drop(subscriber);
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn in_flight_records_are_capped() {
+    let handle = server().await;
+    let mut subscriber = subscribe(&handle, Duration::from_secs(30), 2).await;
+    publish(&handle, 0..5).await;
+
+    let first = recv(&mut subscriber).await;
+    let second = recv(&mut subscriber).await;
+    assert!(quiet(&mut subscriber).await);
+
+    // every ack makes room for one more
+    subscriber.ack(first.offset).await.unwrap();
+    let third = recv(&mut subscriber).await;
+    assert_eq!(&third.payload[..], &message(2)[..]);
+    assert!(quiet(&mut subscriber).await);
+
+    subscriber.ack_through(third.offset).await.unwrap();
+    assert_eq!(&recv(&mut subscriber).await.payload[..], &message(3)[..]);
+    assert_eq!(&recv(&mut subscriber).await.payload[..], &message(4)[..]);
+    assert!(second.offset < third.offset);
+
+    drop(subscriber);
+    handle.shutdown().await.unwrap();
+}
+
+/// records delivered to a raw subscriber until it has been quiet for a while,
+/// or too many arrived
+async fn delivered(framed: &mut Framed<TcpStream, BrokerCodec>) -> u32 {
+    let mut records = 0;
+    while records < 100 {
+        let Ok(frame) = tokio::time::timeout(Duration::from_millis(300), framed.next()).await
+        else {
+            break;
+        };
+        match frame.unwrap().unwrap() {
+            Frame::Deliver(delivery) => records += delivery.count(),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+    records
+}
+
+#[tokio::test]
+async fn redeliveries_take_credit() {
+    let handle = server().await;
+
+    // a raw subscriber that hands out credit by hand
+    let socket = TcpStream::connect(handle.local_addr()).await.unwrap();
+    let mut framed = Framed::new(socket, BrokerCodec::new());
+    framed
+        .send(Frame::Subscribe {
+            topic: TopicRef::from("orders"),
+            start: StartPosition::Earliest,
+            credit: 2,
+            ack_timeout_ms: 20,
+            max_in_flight: 64,
+        })
+        .await
+        .unwrap();
+    let subscribed = framed.next().await.unwrap().unwrap();
+    assert!(matches!(subscribed, Frame::Subscribed { .. }));
+    publish(&handle, 0..2).await;
+
+    // many timeouts pass, but redelivering is paid for like delivering
+    assert_eq!(delivered(&mut framed).await, 2);
+    framed.send(Frame::Credit { messages: 2 }).await.unwrap();
+    assert_eq!(delivered(&mut framed).await, 2);
+
+    drop(framed);
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn stalled_at_most_once_subscribers_lose_records_instead_of_blocking() {
+    let server = BrokerServer::with_config(ServerConfig {
+        admin: Some(LOOPBACK),
+        ..ServerConfig::bind(LOOPBACK)
+    });
+    server
+        .declare_topic(
+            "orders",
+            TopicConfig {
+                capacity: 64 * 1024,
+                ..TopicConfig::default()
+            },
+        )
+        .unwrap();
+    let handle = server.start().await.unwrap();
+    // at-most-once, and never reads past its first window
+    let options = SubscribeOptions {
+        window: 16,
+        ..SubscribeOptions::default()
+    };
+    let mut subscriber = BrokerSubscriber::connect_with_options(
+        handle.local_addr(),
+        "orders",
+        StartPosition::Earliest,
+        options,
+    )
+    .await
+    .unwrap();
+
+    // several rings worth, none of it waiting for the subscriber
+    tokio::time::timeout(Duration::from_secs(10), publish(&handle, 0..5000))
+        .await
+        .unwrap();
+    wait_until(|| handle.stats().messages_processed == 5000).await;
+
+    let (_, topics) = admin(&handle, "GET", "/topics").await;
+    let orders = topics
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|t| t["name"] == "orders")
+        .unwrap();
+    let drops = orders["drops"].as_u64().unwrap();
+    assert!(drops > 0 && drops < 5000, "{}", drops);
+
+    // the window that was sent arrives whole, then the subscriber picks up past the drops
+    for sequence in 0..16 {
+        assert_eq!(recv(&mut subscriber).await.payload, message(sequence));
+    }
+    assert_eq!(recv(&mut subscriber).await.payload, message(16 + drops));
+
+    drop(subscriber);
+    handle.shutdown().await.unwrap();
+}
@@ -0,0 +1,178 @@
+//! TLS and mutual TLS, with a throwaway CA generated for every test
+
+mod common;
+
+use broker::net::tls::{client_config, server_config};
+use broker::topic::StartPosition;
+use broker::{
+    AuthConfig, BrokerClient, BrokerServer, BrokerSubscriber, ClientOptions, ClientTls,
+    ServerConfig, ServerHandle, SubscribeOptions,
+};
+use common::{connections, message, temp_path, wait_until, LOOPBACK};
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa,
+    KeyPair,
+};
+use std::path::{Path, PathBuf};
+
+/// a CA and PEM files for certificates it issued, in a directory of their own
+struct Pki {
+    dir: PathBuf,
+    ca: Certificate,
+    ca_key: KeyPair,
+}
+
+impl Pki {
+    fn new(name: &str) -> Self {
+        let dir = temp_path(&format!("{}-tls", name));
+        std::fs::create_dir_all(&dir).unwrap();
+        let ca_key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "test ca");
+        let ca = params.self_signed(&ca_key).unwrap();
+        std::fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+        Self { dir, ca, ca_key }
+    }
+
+    fn ca(&self) -> PathBuf {
+        self.dir.join("ca.pem")
+    }
+
+    /// certificate and key files for `name`, valid for localhost
+    fn issue(&self, name: &str, usage: ExtendedKeyUsagePurpose) -> (PathBuf, PathBuf) {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+        params.distinguished_name.push(DnType::CommonName, name);
+        params.extended_key_usages = vec![usage];
+        let cert = params.signed_by(&key, &self.ca, &self.ca_key).unwrap();
+        let cert_path = self.dir.join(format!("{}.pem", name));
+        let key_path = self.dir.join(format!("{}.key", name));
+        std::fs::write(&cert_path, cert.pem()).unwrap();
+        std::fs::write(&key_path, key.serialize_pem()).unwrap();
+        (cert_path, key_path)
+    }
+}
+
+impl Drop for Pki {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+async fn start(pki: &Pki, client_ca: Option<&Path>, auth: Option<AuthConfig>) -> ServerHandle {
+    let (cert, key) = pki.issue("broker", ExtendedKeyUsagePurpose::ServerAuth);
+    let server = BrokerServer::with_config(ServerConfig {
+        tls: Some(server_config(cert, key, client_ca).unwrap()),
+        admin: Some(LOOPBACK),
+        auth,
+        ..ServerConfig::bind(LOOPBACK)
+    });
+    server.start().await.unwrap()
+}
+
+fn options(tls: ClientTls) -> ClientOptions {
+    ClientOptions {
+        tls: Some(tls),
+        ..ClientOptions::default()
+    }
+}
+
+#[tokio::test]
+async fn producers_and_subscribers_connect_over_tls() {
+    let pki = Pki::new("plain");
+    let handle = start(&pki, None, None).await;
+    let tls = ClientTls::new(client_config(pki.ca(), None).unwrap(), "localhost").unwrap();
+
+    let subscribe = SubscribeOptions {
+        tls: Some(tls.clone()),
+        ..SubscribeOptions::default()
+    };
+    let mut subscriber = BrokerSubscriber::connect_with_options(
+        handle.local_addr(),
+        "orders",
+        StartPosition::Earliest,
+        subscribe,
+    )
+    .await
+    .unwrap();
+    let mut client = BrokerClient::connect_with_options(handle.local_addr(), options(tls))
+        .await
+        .unwrap();
+    for sequence in 0..10 {
+        client.send_to("orders", &message(sequence)).await.unwrap();
+    }
+    client.flush().await.unwrap();
+    for sequence in 0..10 {
+        let record = subscriber.recv().await.unwrap().unwrap();
+        assert_eq!(record.payload, message(sequence));
+    }
+    // without a client certificate nobody is named
+    assert!(connections(&handle).await[0]["principal"].is_null());
+
+    // plaintext doesn't get past the handshake
+    let mut plain = BrokerClient::connect(handle.local_addr()).await.unwrap();
+    assert!(plain.list_topics().await.is_err());
+    wait_until(|| handle.stats().tls_failures == 1).await;
+
+    drop((client, subscriber));
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn clients_check_the_broker_certificate() {
+    let pki = Pki::new("trust");
+    let handle = start(&pki, None, None).await;
+
+    let other = Pki::new("other");
+    let untrusted = client_config(other.ca(), None).unwrap();
+    let tls = ClientTls::new(untrusted, "localhost").unwrap();
+    let connected = BrokerClient::connect_with_options(handle.local_addr(), options(tls)).await;
+    assert!(connected.is_err());
+
+    let trusted = client_config(pki.ca(), None).unwrap();
+    let tls = ClientTls::new(trusted, "broker.example").unwrap();
+    let connected = BrokerClient::connect_with_options(handle.local_addr(), options(tls)).await;
+    assert!(
+        connected.is_err(),
+        "certificate is only valid for localhost"
+    );
+
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn client_certificates_name_the_principal() {
+    let pki = Pki::new("mutual");
+    let credentials = pki.dir.join("credentials");
+    std::fs::write(&credentials, "analytics token s3cret\n").unwrap();
+    let handle = start(&pki, Some(&pki.ca()), Some(AuthConfig::new(&credentials))).await;
+
+    // the certificate stands in for the authentication handshake
+    let (cert, key) = pki.issue("ingest", ExtendedKeyUsagePurpose::ClientAuth);
+    let config = client_config(pki.ca(), Some((&cert, &key))).unwrap();
+    let tls = ClientTls::new(config, "localhost").unwrap();
+    let mut client = BrokerClient::connect_with_options(handle.local_addr(), options(tls))
+        .await
+        .unwrap();
+    client.send_to("orders", &message(0)).await.unwrap();
+    assert!(!client.list_topics().await.unwrap().is_empty());
+    assert_eq!(connections(&handle).await[0]["principal"], "ingest");
+
+    // no certificate, no connection
+    let anonymous = client_config(pki.ca(), None).unwrap();
+    let tls = ClientTls::new(anonymous, "localhost").unwrap();
+    let rejected = async {
+        let mut client =
+            BrokerClient::connect_with_options(handle.local_addr(), options(tls)).await?;
+        client.list_topics().await
+    };
+    assert!(rejected.await.is_err());
+    wait_until(|| handle.stats().tls_failures == 1).await;
+    assert_eq!(handle.stats().messages_in, 1);
+
+    drop(client);
+    handle.shutdown().await.unwrap();
+}
@@ -0,0 +1,227 @@
+//! topics over the wire: routing by name and id, per-topic limits and listings
+
+mod common;
+
+use broker::net::codec::error_code;
+use broker::{
+    BoxError, BrokerClient, BrokerError, BrokerServer, Message, MessageHandler, NetworkError,
+    ServerConfig, TopicConfig,
+};
+use common::{record, start, LOOPBACK, MESSAGE_LEN};
+use parking_lot::Mutex;
+use std::sync::Arc;
+use std::time::Duration;
+
+const RING: usize = 64 * 1024;
+
+/// the first body byte of every message a topic handled
+#[derive(Clone, Default)]
+struct Seen(Arc<Mutex<Vec<u8>>>);
+
+impl MessageHandler for Seen {
+    fn on_message(&self, msg: &Message<'_>) -> Result<(), BoxError> {
+        self.0.lock().push(msg.body[0]);
+        Ok(())
+    }
+}
+
+fn small_topics() -> ServerConfig {
+    ServerConfig {
+        topic_defaults: TopicConfig {
+            capacity: RING,
+            ..TopicConfig::default()
+        },
+        ..ServerConfig::bind(LOOPBACK)
+    }
+}
+
+fn code(result: Result<impl Sized, NetworkError>) -> u16 {
+    match result {
+        Err(NetworkError::Remote { code, .. }) => code,
+        Err(e) => panic!("expected an error frame, got {}", e),
+        Ok(_) => panic!("expected an error frame"),
+    }
+}
+
+#[tokio::test]
+async fn publishes_are_routed_to_their_topic() {
+    let server = BrokerServer::with_config(small_topics());
+    let (orders, payments) = (Seen::default(), Seen::default());
+    server.set_topic_handler("orders", orders.clone());
+    server.set_topic_handler("payments", payments.clone());
+    let handle = server.start().await.unwrap();
+
+    let mut client = BrokerClient::connect(handle.local_addr()).await.unwrap();
+    let payments_id = client.declare_topic("payments").await.unwrap().id;
+    for sequence in 0..10 {
+        client
+            .send_to("orders", &record(1, sequence, b'o', MESSAGE_LEN))
+            .await
+            .unwrap();
+        client
+            .send_to_id(payments_id, &record(1, sequence, b'p', MESSAGE_LEN))
+            .await
+            .unwrap();
+    }
+    client.flush().await.unwrap();
+    drop(client);
+
+    let report = handle
+        .shutdown_with_drain(Duration::from_secs(10))
+        .await
+        .unwrap();
+    assert!(report.is_drained());
+    assert_eq!(*orders.0.lock(), [b'o'; 10]);
+    assert_eq!(*payments.0.lock(), [b'p'; 10]);
+}
+
+#[tokio::test]
+async fn unknown_ids_are_rejected() {
+    let handle = start(small_topics()).await;
+    let mut client = BrokerClient::connect(handle.local_addr()).await.unwrap();
+    client
+        .send_to_id(42, &record(1, 0, 0, MESSAGE_LEN))
+        .await
+        .unwrap();
+    client.flush().await.unwrap();
+    assert_eq!(code(client.list_topics().await), error_code::UNKNOWN_TOPIC);
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn topics_keep_their_own_limits() {
+    let server = BrokerServer::with_config(ServerConfig {
+        max_topics: 3,
+        ..small_topics()
+    });
+    let large = server
+        .declare_topic(
+            "large",
+            TopicConfig {
+                capacity: 4 * RING,
+                ..TopicConfig::default()
+            },
+        )
+        .unwrap();
+    assert_eq!(large.capacity, 4 * RING as u64);
+    let handle = server.start().await.unwrap();
+
+    // auto-created topics take the defaults
+    let mut client = BrokerClient::connect(handle.local_addr()).await.unwrap();
+    let small = client.declare_topic("small").await.unwrap();
+    assert_eq!(small.capacity, RING as u64);
+    assert_eq!(
+        code(client.declare_topic("third").await),
+        error_code::TOO_MANY_TOPICS
+    );
+    // declaring an existing topic isn't a new one
+    assert_eq!(client.declare_topic("small").await.unwrap(), small);
+
+    // a record may take up to a quarter of its ring
+    let mut big = record(1, 0, 0, RING / 2);
+    client.send_to("large", &big).await.unwrap();
+    client.flush().await.unwrap();
+    client.list_topics().await.unwrap();
+    big = record(1, 1, 0, RING / 2);
+    client.send_to("small", &big).await.unwrap();
+    client.flush().await.unwrap();
+    assert_eq!(
+        code(client.list_topics().await),
+        error_code::MESSAGE_TOO_LARGE
+    );
+
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn ring_memory_is_capped() {
+    let server = BrokerServer::with_config(ServerConfig {
+        ring_memory: 3 * RING,
+        ..small_topics()
+    });
+    let declared = server.declare_topic(
+        "huge",
+        TopicConfig {
+            capacity: 4 * RING,
+            ..TopicConfig::default()
+        },
+    );
+    assert!(matches!(
+        declared,
+        Err(BrokerError::RingMemoryExhausted {
+            requested,
+            available,
+        }) if requested == 4 * RING && available == 2 * RING
+    ));
+    let handle = server.start().await.unwrap();
+
+    // the default topic takes one ring, two more fit
+    let mut client = BrokerClient::connect(handle.local_addr()).await.unwrap();
+    client.declare_topic("second").await.unwrap();
+    client.declare_topic("third").await.unwrap();
+    assert_eq!(
+        code(client.declare_topic("fourth").await),
+        error_code::OVERLOADED
+    );
+    let names: Vec<_> = handle.topics().into_iter().map(|t| t.name).collect();
+    assert_eq!(names, ["default", "second", "third"]);
+
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn topics_are_listed_in_id_order() {
+    let config = small_topics();
+    let server = BrokerServer::with_config(config.clone());
+    server
+        .declare_topic("declared", config.topic_defaults)
+        .unwrap();
+    let handle = server.start().await.unwrap();
+
+    let mut client = BrokerClient::connect(handle.local_addr()).await.unwrap();
+    client
+        .send_to("published", &record(1, 0, 0, MESSAGE_LEN))
+        .await
+        .unwrap();
+    client.flush().await.unwrap();
+    let topics = client.list_topics().await.unwrap();
+
+    let listed: Vec<_> = topics.iter().map(|t| (t.id, t.name.as_str())).collect();
+    assert_eq!(listed, [(0, "default"), (1, "declared"), (2, "published")]);
+    assert!(topics.iter().all(|t| t.capacity == RING as u64));
+    assert!(topics.iter().all(|t| t.used <= t.capacity));
+
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn records_published_before_the_consumer_runs_are_kept() {
+    let server = BrokerServer::with_config(small_topics());
+    let seen = Seen::default();
+    server.set_topic_handler("orders", seen.clone());
+    let handle = server.start().await.unwrap();
+
+    // on this single-threaded runtime the connection fills the new topic's ring
+    // several times over before its consumer task gets to run
+    let count = 3 * RING / MESSAGE_LEN;
+    let mut client = BrokerClient::connect(handle.local_addr()).await.unwrap();
+    for sequence in 0..count {
+        client
+            .send_to(
+                "orders",
+                &record(1, sequence as u64, sequence as u8, MESSAGE_LEN),
+            )
+            .await
+            .unwrap();
+    }
+    client.flush().await.unwrap();
+    drop(client);
+
+    let report = handle
+        .shutdown_with_drain(Duration::from_secs(10))
+        .await
+        .unwrap();
+    assert!(report.is_drained());
+    let expected: Vec<u8> = (0..count).map(|sequence| sequence as u8).collect();
+    assert_eq!(*seen.0.lock(), expected);
+}
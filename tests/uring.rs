@@ -0,0 +1,131 @@
+//! the io_uring reader, `cargo test --features io-uring`
+
+#![cfg(feature = "io-uring")]
+
+mod common;
+
+use broker::{
+    BrokerClient, BrokerServer, BrokerSubscriber, ServerConfig, StartPosition, UnixListenConfig,
+    UringConfig,
+};
+use common::{sized_message, start, temp_path, wait_until, LOOPBACK};
+
+fn uring(io_uring: UringConfig) -> ServerConfig {
+    ServerConfig {
+        io_uring: Some(io_uring),
+        ..ServerConfig::bind(LOOPBACK)
+    }
+}
+
+/// a pool small enough that receives run out of buffers and get paused
+fn tiny_pool() -> UringConfig {
+    UringConfig {
+        buffers: 8,
+        buffer_size: 4096,
+        connection_buffers: 2,
+        ..UringConfig::default()
+    }
+}
+
+#[tokio::test]
+async fn tcp_and_unix_connections_are_read_through_io_uring() {
+    let path = temp_path("uring.sock");
+    let server = BrokerServer::with_config(ServerConfig {
+        io_uring: Some(UringConfig::default()),
+        unix_listen: vec![UnixListenConfig::new(&path)],
+        ..ServerConfig::bind(LOOPBACK)
+    });
+    let handle = server.start().await.unwrap();
+
+    let mut subscriber =
+        BrokerSubscriber::connect(handle.local_addr(), "orders", StartPosition::Earliest)
+            .await
+            .unwrap();
+    let mut tcp = BrokerClient::connect(handle.local_addr()).await.unwrap();
+    for sequence in 0..1000 {
+        tcp.send_to("orders", &sized_message(sequence, 1024))
+            .await
+            .unwrap();
+    }
+    tcp.flush().await.unwrap();
+    for sequence in 0..1000 {
+        let record = subscriber.recv().await.unwrap().unwrap();
+        assert_eq!(record.payload, sized_message(sequence, 1024));
+    }
+
+    let mut unix = BrokerClient::connect_unix(&path).await.unwrap();
+    unix.send(&sized_message(0, 64)).await.unwrap();
+    assert!(!unix.list_topics().await.unwrap().is_empty());
+    assert_eq!(handle.stats().messages_in, 1001);
+
+    // a client hanging up is noticed like with epoll
+    drop((tcp, unix));
+    wait_until(|| handle.stats().open_connections == 1).await;
+    drop(subscriber);
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn producers_share_a_small_buffer_pool() {
+    let handle = start(uring(tiny_pool())).await;
+    let addr = handle.local_addr();
+    let producers: Vec<_> = (0..4)
+        .map(|producer| {
+            tokio::spawn(async move {
+                let mut client = BrokerClient::connect(addr).await.unwrap();
+                let topic = format!("topic-{}", producer);
+                for sequence in 0..500 {
+                    client
+                        .send_to(&topic, &sized_message(sequence, 1024))
+                        .await
+                        .unwrap();
+                }
+                client.flush().await.unwrap();
+                client
+            })
+        })
+        .collect();
+    let mut clients = Vec::new();
+    for producer in producers {
+        clients.push(producer.await.unwrap());
+    }
+    wait_until(|| handle.stats().messages_in == 2000).await;
+    assert_eq!(handle.stats().sequence_gaps, 0);
+
+    drop(clients);
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn closed_connections_hand_their_buffers_back() {
+    let handle = start(uring(tiny_pool())).await;
+    for round in 0..20 {
+        let mut client = BrokerClient::connect(handle.local_addr()).await.unwrap();
+        for sequence in 0..50 {
+            client.send(&sized_message(sequence, 1024)).await.unwrap();
+        }
+        client.flush().await.unwrap();
+        wait_until(|| handle.stats().messages_in == (round + 1) * 50).await;
+    }
+    wait_until(|| handle.stats().open_connections == 0).await;
+
+    // every buffer is back, a new connection gets going as ever
+    let mut client = BrokerClient::connect(handle.local_addr()).await.unwrap();
+    client.send(&sized_message(0, 1024)).await.unwrap();
+    assert!(!client.list_topics().await.unwrap().is_empty());
+
+    drop(client);
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn pool_sizes_are_checked() {
+    let server = BrokerServer::with_config(ServerConfig {
+        io_uring: Some(UringConfig {
+            buffers: 100,
+            ..UringConfig::default()
+        }),
+        ..ServerConfig::bind(LOOPBACK)
+    });
+    assert!(server.start().await.is_err());
+}
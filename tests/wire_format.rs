@@ -0,0 +1,392 @@
+//! golden bytes for the wire format. if one of these fails the format changed,
+//! which breaks every deployed producer: bump the protocol instead of the test.
+
+use broker::net::codec::{Ack, Batch, BrokerCodec, DeliveryBuilder, Frame, Mechanism};
+use broker::net::message::{
+    FrameHeader, MessageHeader, PayloadHeader, ProcessedMessage, PROTOCOL_VERSION,
+};
+use broker::topic::{StartPosition, TopicInfo, TopicRef};
+use broker::NetworkError;
+use bytes::{Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+#[test]
+fn message_header_layout() {
+    let header = MessageHeader {
+        size: 0x0403_0201,
+        batch_size: 0x0807_0605,
+    };
+    let golden = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08];
+
+    assert_eq!(MessageHeader::LEN, 8);
+    assert_eq!(header.to_le_bytes(), golden);
+    assert_eq!(MessageHeader::from_le_bytes(golden), header);
+}
+
+#[test]
+fn payload_header_layout() {
+    let header = PayloadHeader {
+        timestamp: 0x0807_0605_0403_0201,
+        sequence: 0x100f_0e0d_0c0b_0a09,
+        checksum: 0x1413_1211,
+    };
+    let golden = [
+        0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, // timestamp
+        0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f, 0x10, // sequence
+        0x11, 0x12, 0x13, 0x14, // checksum
+    ];
+
+    assert_eq!(PayloadHeader::LEN, 20);
+    assert_eq!(header.to_le_bytes(), golden);
+    assert_eq!(PayloadHeader::from_le_bytes(golden), header);
+    assert_eq!(PayloadHeader::read(&golden[..19]), None);
+}
+
+/// `frame` must encode to exactly `golden`, and decode back from it
+fn assert_golden(frame: Frame, golden: &[u8]) {
+    let mut dst = BytesMut::new();
+    BrokerCodec::new().encode(frame.clone(), &mut dst).unwrap();
+    assert_eq!(&dst[..], golden);
+
+    let mut src = BytesMut::from(golden);
+    let decoded = BrokerCodec::new().decode(&mut src).unwrap();
+    assert_eq!(decoded, Some(frame));
+    assert!(src.is_empty());
+}
+
+#[test]
+fn frame_header_layout() {
+    let header = FrameHeader {
+        kind: 0x81,
+        version: 0x07,
+        len: 0x0403_0201,
+    };
+    let golden = [0x81, 0x07, 0x00, 0x00, 0x01, 0x02, 0x03, 0x04];
+
+    assert_eq!(FrameHeader::LEN, 8);
+    assert_eq!(header.to_le_bytes(), golden);
+    assert_eq!(FrameHeader::from_le_bytes(golden), header);
+}
+
+#[test]
+fn publish_by_name_layout() {
+    let batch = Batch::new(3, 2, Bytes::from_static(b"abcdef")).unwrap();
+    let golden: &[u8] = &[
+        0x01, 0x01, 0x00, 0x00, // kind, version, reserved
+        0x13, 0x00, 0x00, 0x00, // body len
+        0x01, 0x03, b'f', b'o', b'o', // topic by name
+        0x03, 0x00, 0x00, 0x00, // size
+        0x02, 0x00, 0x00, 0x00, // batch_size
+        b'a', b'b', b'c', b'd', b'e', b'f',
+    ];
+    assert_golden(
+        Frame::Publish {
+            topic: TopicRef::from("foo"),
+            batch,
+        },
+        golden,
+    );
+}
+
+#[test]
+fn publish_by_id_layout() {
+    let batch = Batch::new(2, 1, Bytes::from_static(b"hi")).unwrap();
+    let golden: &[u8] = &[
+        0x01, 0x01, 0x00, 0x00, // kind, version, reserved
+        0x0f, 0x00, 0x00, 0x00, // body len
+        0x00, 0x07, 0x00, 0x00, 0x00, // topic by id
+        0x02, 0x00, 0x00, 0x00, // size
+        0x01, 0x00, 0x00, 0x00, // batch_size
+        b'h', b'i',
+    ];
+    assert_golden(
+        Frame::Publish {
+            topic: TopicRef::Id(7),
+            batch,
+        },
+        golden,
+    );
+}
+
+#[test]
+fn declare_and_list_layout() {
+    assert_golden(
+        Frame::Declare {
+            topic: "ab".to_string(),
+        },
+        &[
+            0x02, 0x01, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x02, b'a', b'b',
+        ],
+    );
+    assert_golden(
+        Frame::ListTopics,
+        &[0x03, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+    );
+}
+
+#[test]
+fn topics_layout() {
+    let golden: &[u8] = &[
+        0x81, 0x01, 0x00, 0x00, // kind, version, reserved
+        0x1a, 0x00, 0x00, 0x00, // body len
+        0x01, 0x00, 0x00, 0x00, // count
+        0x02, 0x00, 0x00, 0x00, // id
+        0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // capacity
+        0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // used
+        0x01, b'x', // name
+    ];
+    assert_golden(
+        Frame::Topics(vec![TopicInfo {
+            id: 2,
+            name: "x".to_string(),
+            capacity: 4096,
+            used: 32,
+        }]),
+        golden,
+    );
+}
+
+#[test]
+fn error_layout() {
+    let golden: &[u8] = &[
+        0xff, 0x01, 0x00, 0x00, // kind, version, reserved
+        0x07, 0x00, 0x00, 0x00, // body len
+        0x02, 0x00, // code
+        0x03, 0x00, b'b', b'a', b'd', // message
+    ];
+    assert_golden(
+        Frame::Error {
+            code: 2,
+            message: "bad".to_string(),
+        },
+        golden,
+    );
+}
+
+#[test]
+fn subscribe_layout() {
+    let golden: &[u8] = &[
+        0x04, 0x01, 0x00, 0x00, // kind, version, reserved
+        0x1a, 0x00, 0x00, 0x00, // body len
+        0x00, 0x03, 0x00, 0x00, 0x00, // topic by id
+        0x02, // start at offset
+        0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // offset
+        0x40, 0x00, 0x00, 0x00, // credit
+        0xe8, 0x03, 0x00, 0x00, // ack_timeout_ms
+        0x20, 0x00, 0x00, 0x00, // max_in_flight
+    ];
+    assert_golden(
+        Frame::Subscribe {
+            topic: TopicRef::Id(3),
+            start: StartPosition::Offset(16),
+            credit: 64,
+            ack_timeout_ms: 1000,
+            max_in_flight: 32,
+        },
+        golden,
+    );
+}
+
+#[test]
+fn ack_layout() {
+    assert_golden(
+        Frame::Ack(Ack::Through(7)),
+        &[
+            0x06, 0x01, 0x00, 0x00, 0x0d, 0x00, 0x00, 0x00, // header
+            0x00, // cumulative
+            0x01, 0x00, 0x00, 0x00, // count
+            0x07, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // offset
+        ],
+    );
+    assert_golden(
+        Frame::Ack(Ack::Offsets(vec![1, 2])),
+        &[
+            0x06, 0x01, 0x00, 0x00, 0x15, 0x00, 0x00, 0x00, // header
+            0x01, // individual
+            0x02, 0x00, 0x00, 0x00, // count
+            0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // offset
+            0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // offset
+        ],
+    );
+}
+
+#[test]
+fn credit_and_subscribed_layout() {
+    assert_golden(
+        Frame::Credit { messages: 9 },
+        &[
+            0x05, 0x01, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x09, 0x00, 0x00, 0x00,
+        ],
+    );
+    assert_golden(
+        Frame::Subscribed {
+            topic: 1,
+            offset: 2,
+        },
+        &[
+            0x82, 0x01, 0x00, 0x00, 0x0c, 0x00, 0x00, 0x00, // header
+            0x01, 0x00, 0x00, 0x00, // topic
+            0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // offset
+        ],
+    );
+}
+
+#[test]
+fn flow_control_and_grant_layout() {
+    assert_golden(
+        Frame::FlowControl,
+        &[0x07, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+    );
+    assert_golden(
+        Frame::Grant { bytes: 0x1000 },
+        &[
+            0x84, 0x01, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00,
+        ],
+    );
+}
+
+#[test]
+fn heartbeat_layout() {
+    assert_golden(
+        Frame::Heartbeat,
+        &[0x08, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+    );
+}
+
+#[test]
+fn auth_layout() {
+    assert_golden(
+        Frame::Auth {
+            mechanism: Mechanism::Token,
+            principal: "ci".to_string(),
+            proof: Bytes::from_static(b"tk"),
+        },
+        &[
+            0x09, 0x01, 0x00, 0x00, 0x06, 0x00, 0x00, 0x00, // header
+            0x00, // token
+            0x02, b'c', b'i', // principal
+            b't', b'k', // proof
+        ],
+    );
+    assert_golden(
+        Frame::Auth {
+            mechanism: Mechanism::Hmac,
+            principal: "ci".to_string(),
+            proof: Bytes::new(),
+        },
+        &[
+            0x09, 0x01, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, // header
+            0x01, // hmac
+            0x02, b'c', b'i', // principal, no proof yet
+        ],
+    );
+}
+
+#[test]
+fn challenge_and_authenticated_layout() {
+    assert_golden(
+        Frame::Challenge {
+            nonce: Bytes::from_static(&[0xaa, 0xbb]),
+        },
+        &[0x85, 0x01, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0xaa, 0xbb],
+    );
+    assert_golden(
+        Frame::Authenticated {
+            principal: "ci".to_string(),
+        },
+        &[
+            0x86, 0x01, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x02, b'c', b'i',
+        ],
+    );
+}
+
+#[test]
+fn deliver_layout() {
+    let mut builder = DeliveryBuilder::new(5);
+    builder.push(0, b"ab");
+    builder.push(6, b"c");
+    let delivery = builder.finish();
+    let golden: &[u8] = &[
+        0x83, 0x01, 0x00, 0x00, // kind, version, reserved
+        0x23, 0x00, 0x00, 0x00, // body len
+        0x05, 0x00, 0x00, 0x00, // topic
+        0x02, 0x00, 0x00, 0x00, // count
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // offset
+        0x02, 0x00, 0x00, 0x00, b'a', b'b', // len, payload
+        0x06, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // offset
+        0x01, 0x00, 0x00, 0x00, b'c', // len, payload
+    ];
+
+    let records: Vec<_> = delivery.records().map(|r| (r.offset, r.payload)).collect();
+    assert_eq!(
+        records,
+        vec![
+            (0, Bytes::from_static(b"ab")),
+            (6, Bytes::from_static(b"c"))
+        ]
+    );
+    assert_golden(Frame::Deliver(delivery), golden);
+}
+
+#[test]
+fn truncated_delivery_is_rejected() {
+    let golden: &[u8] = &[
+        0x83, 0x01, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, // header
+        0x05, 0x00, 0x00, 0x00, // topic
+        0x01, 0x00, 0x00, 0x00, // count, with no record following
+    ];
+    let mut src = BytesMut::from(golden);
+    assert!(BrokerCodec::new().decode(&mut src).is_err());
+}
+
+#[test]
+fn partial_frame_is_not_decoded() {
+    let golden: &[u8] = &[0x03, 0x01, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x01];
+    let mut src = BytesMut::from(golden);
+    assert_eq!(BrokerCodec::new().decode(&mut src).unwrap(), None);
+    assert_eq!(src.len(), golden.len());
+}
+
+#[test]
+fn oversized_frame_is_rejected() {
+    let golden: &[u8] = &[0x01, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00];
+    let mut src = BytesMut::from(golden);
+    assert!(BrokerCodec::with_max_frame_len(255)
+        .decode(&mut src)
+        .is_err());
+}
+
+#[test]
+fn other_versions_are_rejected() {
+    assert_eq!(PROTOCOL_VERSION, 1);
+    for version in [0x00, 0x02] {
+        let golden: &[u8] = &[0x08, version, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+        let mut src = BytesMut::from(golden);
+        assert!(matches!(
+            BrokerCodec::new().decode(&mut src),
+            Err(NetworkError::UnsupportedVersion(v)) if v == version
+        ));
+    }
+}
+
+#[test]
+fn unknown_kind_is_rejected() {
+    let golden: &[u8] = &[0x7e, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+    let mut src = BytesMut::from(golden);
+    assert!(BrokerCodec::new().decode(&mut src).is_err());
+}
+
+#[test]
+fn checksummed_message_round_trips() {
+    let body = b"payload";
+    let header = PayloadHeader::for_body(1, 2, body);
+    let mut msg = header.to_le_bytes().to_vec();
+    msg.extend_from_slice(body);
+
+    let parsed = ProcessedMessage::from_bytes(&msg).unwrap();
+    assert_eq!(parsed.sequence, 2);
+    assert_eq!(parsed.payload, body);
+
+    msg[PayloadHeader::LEN] ^= 0xff;
+    assert!(ProcessedMessage::from_bytes(&msg).is_none());
+}